@@ -0,0 +1,64 @@
+use std::{collections::HashMap, net::IpAddr, sync::Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanReason {
+    /// Banned through the session.
+    Manual,
+    /// Sent a block that made a piece fail its hash check.
+    CorruptData,
+}
+
+#[derive(Default)]
+struct Bans {
+    banned: HashMap<IpAddr, BanReason>,
+    hash_failures: HashMap<IpAddr, u32>,
+}
+
+/// Banned peers by IP, and how often pieces failed with data from each IP.
+/// Shared by the session and its torrents.
+#[derive(Default)]
+pub struct BanList {
+    bans: Mutex<Bans>,
+}
+
+impl BanList {
+    /// Returns whether the IP was not banned before.
+    pub fn ban(&self, ip: IpAddr, reason: BanReason) -> bool {
+        self.bans
+            .lock()
+            .unwrap()
+            .banned
+            .insert(ip, reason)
+            .is_none()
+    }
+
+    /// Returns whether the IP was banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.bans.lock().unwrap().banned.remove(&ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.lock().unwrap().banned.contains_key(&ip)
+    }
+
+    pub fn banned(&self) -> Vec<(IpAddr, BanReason)> {
+        let bans = self.bans.lock().unwrap();
+        bans.banned
+            .iter()
+            .map(|(ip, reason)| (*ip, *reason))
+            .collect()
+    }
+
+    /// Counts a failed piece the IP sent data for, returning the new count.
+    pub fn add_hash_failure(&self, ip: IpAddr) -> u32 {
+        let mut bans = self.bans.lock().unwrap();
+        let count = bans.hash_failures.entry(ip).or_default();
+        *count += 1;
+        *count
+    }
+
+    pub fn hash_failures(&self, ip: IpAddr) -> u32 {
+        let bans = self.bans.lock().unwrap();
+        bans.hash_failures.get(&ip).copied().unwrap_or_default()
+    }
+}
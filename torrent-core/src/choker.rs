@@ -0,0 +1,252 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// What the choker knows about a connected peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChokerPeer {
+    pub addr: SocketAddr,
+    /// The peer is interested in our pieces.
+    pub interested: bool,
+    /// Bytes per second we receive from the peer.
+    pub download_rate: f64,
+    /// Bytes per second we send to the peer.
+    pub upload_rate: f64,
+    /// Bytes received from the peer since it connected.
+    pub downloaded: u64,
+    /// Bytes sent to the peer since it connected.
+    pub uploaded: u64,
+    pub connected_at: Instant,
+}
+
+/// Decides which interested peers get the regular upload slots.
+pub trait ChokeStrategy: Send {
+    /// Orders the interested `peers`, best first.
+    fn rank(&mut self, peers: &[&ChokerPeer], seeding: bool) -> Vec<SocketAddr>;
+}
+
+/// Tit-for-tat: unchokes the peers we download fastest from, or when
+/// seeding the ones we upload fastest to, as they make best use of it.
+#[derive(Debug, Default)]
+pub struct RateBased;
+
+impl ChokeStrategy for RateBased {
+    fn rank(&mut self, peers: &[&ChokerPeer], seeding: bool) -> Vec<SocketAddr> {
+        let rate = |peer: &ChokerPeer| match seeding {
+            true => peer.upload_rate,
+            false => peer.download_rate,
+        };
+        let mut peers = peers.to_vec();
+        peers.sort_by(|a, b| rate(b).total_cmp(&rate(a)));
+        peers.into_iter().map(|peer| peer.addr).collect()
+    }
+}
+
+/// Shares the upload evenly: unchokes the peers we uploaded the least to
+/// compared to what they gave us.
+#[derive(Debug, Default)]
+pub struct FairShare;
+
+impl ChokeStrategy for FairShare {
+    fn rank(&mut self, peers: &[&ChokerPeer], seeding: bool) -> Vec<SocketAddr> {
+        let debt = |peer: &ChokerPeer| match seeding {
+            true => -(peer.uploaded as f64),
+            false => peer.downloaded as f64 - peer.uploaded as f64,
+        };
+        let mut peers = peers.to_vec();
+        peers.sort_by(|a, b| debt(b).total_cmp(&debt(a)));
+        peers.into_iter().map(|peer| peer.addr).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChokerConfig {
+    /// Peers unchoked by the strategy, besides the optimistic unchoke.
+    pub upload_slots: usize,
+    pub rechoke_interval: Duration,
+    pub optimistic_interval: Duration,
+    /// Peers connected for less than this are new and three times as likely
+    /// to be unchoked optimistically, as they have nothing to offer yet.
+    pub new_peer_age: Duration,
+    /// Seeds the optimistic unchoke.
+    pub seed: u64,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        ChokerConfig {
+            upload_slots: 4,
+            rechoke_interval: Duration::from_secs(10),
+            optimistic_interval: Duration::from_secs(30),
+            new_peer_age: Duration::from_secs(60),
+            seed: rand::random(),
+        }
+    }
+}
+
+/// Peers to choke and unchoke after a rechoke.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChokeDecision {
+    pub unchoke: Vec<SocketAddr>,
+    pub choke: Vec<SocketAddr>,
+}
+
+impl ChokeDecision {
+    pub fn is_empty(&self) -> bool {
+        self.unchoke.is_empty() && self.choke.is_empty()
+    }
+}
+
+/// Picks the peers we upload to: the best ones by the strategy in the
+/// regular slots, re-evaluated every rechoke interval, plus one peer
+/// unchoked optimistically, rotated every optimistic interval, so new peers
+/// get a chance to show their rate.
+pub struct Choker {
+    config: ChokerConfig,
+    strategy: Box<dyn ChokeStrategy>,
+    rng: StdRng,
+    unchoked: HashSet<SocketAddr>,
+    optimistic: Option<SocketAddr>,
+    last_rechoke: Option<Instant>,
+    last_optimistic: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(config: ChokerConfig, strategy: Box<dyn ChokeStrategy>) -> Self {
+        Choker {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            strategy,
+            unchoked: HashSet::new(),
+            optimistic: None,
+            last_rechoke: None,
+            last_optimistic: None,
+        }
+    }
+
+    pub fn set_strategy(&mut self, strategy: Box<dyn ChokeStrategy>) {
+        self.strategy = strategy;
+        self.last_rechoke = None;
+    }
+
+    /// Takes effect at the next rechoke, which is made due.
+    pub fn set_upload_slots(&mut self, slots: usize) {
+        self.config.upload_slots = slots;
+        self.last_rechoke = None;
+    }
+
+    pub fn upload_slots(&self) -> usize {
+        self.config.upload_slots
+    }
+
+    pub fn is_unchoked(&self, peer: SocketAddr) -> bool {
+        self.unchoked.contains(&peer)
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    /// A peer became interested. A free upload slot is filled at the next
+    /// poll instead of after the rechoke interval.
+    pub fn on_interested(&mut self) {
+        if self.unchoked.len() < self.config.upload_slots {
+            self.last_rechoke = None;
+        }
+    }
+
+    /// Rechokes if it is due, returning the changes to send. `peers` are all
+    /// connected peers, `seeding` whether we have the whole torrent.
+    pub fn poll(
+        &mut self,
+        peers: &[ChokerPeer],
+        seeding: bool,
+        now: Instant,
+    ) -> Option<ChokeDecision> {
+        let due = |last: Option<Instant>, interval| {
+            last.is_none_or(|last| now.duration_since(last) >= interval)
+        };
+        let rotate = due(self.last_optimistic, self.config.optimistic_interval);
+        if !rotate && !due(self.last_rechoke, self.config.rechoke_interval) {
+            return None;
+        }
+        self.last_rechoke = Some(now);
+
+        let interested = peers
+            .iter()
+            .filter(|peer| peer.interested)
+            .collect::<Vec<_>>();
+        let regular = self
+            .strategy
+            .rank(&interested, seeding)
+            .into_iter()
+            .take(self.config.upload_slots)
+            .collect::<HashSet<_>>();
+
+        let optimistic_gone = self.optimistic.is_none_or(|optimistic| {
+            regular.contains(&optimistic) || !interested.iter().any(|peer| peer.addr == optimistic)
+        });
+        if rotate || optimistic_gone {
+            self.last_optimistic = Some(now);
+            self.optimistic = self.pick_optimistic(&interested, &regular, now);
+        }
+
+        let unchoked = regular
+            .into_iter()
+            .chain(self.optimistic)
+            .collect::<HashSet<_>>();
+        let decision = ChokeDecision {
+            unchoke: unchoked.difference(&self.unchoked).copied().collect(),
+            choke: self.unchoked.difference(&unchoked).copied().collect(),
+        };
+        self.unchoked = unchoked;
+        Some(decision)
+    }
+
+    fn pick_optimistic(
+        &mut self,
+        interested: &[&ChokerPeer],
+        regular: &HashSet<SocketAddr>,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        let new_peer_age = self.config.new_peer_age;
+        let weight = |peer: &ChokerPeer| {
+            if now.duration_since(peer.connected_at) < new_peer_age {
+                3
+            } else {
+                1
+            }
+        };
+        let candidates = interested
+            .iter()
+            .filter(|peer| !regular.contains(&peer.addr))
+            .map(|peer| (peer.addr, weight(peer)))
+            .collect::<Vec<_>>();
+        let total = candidates.iter().map(|(_, weight)| weight).sum::<u32>();
+        if total == 0 {
+            return None;
+        }
+        let mut choice = self.rng.gen_range(0..total);
+        for (addr, weight) in candidates {
+            if choice < weight {
+                return Some(addr);
+            }
+            choice -= weight;
+        }
+        None
+    }
+
+    /// Forgets a disconnected peer; its slot is filled at the next rechoke.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        if self.unchoked.remove(&peer) {
+            self.last_rechoke = None;
+        }
+        if self.optimistic == Some(peer) {
+            self.optimistic = None;
+        }
+    }
+}
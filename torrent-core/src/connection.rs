@@ -0,0 +1,655 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    select,
+    sync::{
+        broadcast,
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    },
+    task::spawn_blocking,
+    time::{interval, MissedTickBehavior},
+};
+use tokio_util::{codec::Framed, sync::CancellationToken};
+use torrent_pwp::{
+    extension::ExtendedHandshake,
+    message::{BlockInfo, Message, MessageCodec},
+    mse::MseStream,
+    Connection, Direction, PeerHandler, PeerStream,
+};
+use tracing::debug;
+
+use crate::{
+    choker::ChokerPeer,
+    error::RustyTorrentResult,
+    metadata::MetadataExtension,
+    peer::{
+        session::{DisconnectReason, PeerEvent, PeerSession, PeerSnapshot},
+        Peer,
+    },
+    pex::{PexExtension, PexFlags, PexState},
+    ratelimit::{self, BandwidthLimiter},
+    scheduler::{BlockOutcome, BlockScheduler, PipelineStats},
+    session::{TorrentEvent, TorrentId},
+    torrent::ManagedTorrent,
+    upload::{UploadQueue, MAX_QUEUED_REQUESTS},
+};
+
+/// How often a connection checks for timed out requests, rechokes and polls
+/// its extensions.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(100);
+// peers close connections that stay silent for two minutes
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Disconnect reasons are kept for this many of the last disconnected peers.
+pub const MAX_DISCONNECTS: usize = 256;
+
+type Wire = Framed<MseStream<PeerStream>, MessageCodec>;
+
+// sent to a connection by the other connections of its torrent
+enum Command {
+    Have(u32),
+    Choke,
+    Unchoke,
+    Cancel(BlockInfo),
+    Close(DisconnectReason),
+}
+
+// a connection as the other connections of its torrent, and the session,
+// see it
+struct Link {
+    commands: UnboundedSender<Command>,
+    stats: ChokerPeer,
+    snapshot: PeerSnapshot,
+}
+
+fn local(e: impl ToString) -> DisconnectReason {
+    DisconnectReason::Local(e.to_string())
+}
+
+/// Runs the connections of a started torrent: announces our pieces, requests
+/// the blocks the scheduler picks, stores and verifies what arrives, and
+/// serves the requests of the peers the choker unchokes. Payload is sent and
+/// read as the session's bandwidth limiter allows. Scheduler events go to the
+/// session's subscribers.
+pub struct TorrentConnections {
+    id: TorrentId,
+    torrent: Arc<ManagedTorrent>,
+    bandwidth: Arc<Mutex<BandwidthLimiter>>,
+    events: broadcast::Sender<TorrentEvent>,
+    links: Mutex<HashMap<SocketAddr, Link>>,
+    // the peers we tell others about over PEX, by their listen address
+    pex_peers: Arc<Mutex<HashMap<SocketAddr, PexFlags>>>,
+    // why the last connections ended, oldest first
+    disconnects: Mutex<VecDeque<(SocketAddr, DisconnectReason)>>,
+}
+
+impl TorrentConnections {
+    pub fn new(
+        id: TorrentId,
+        torrent: Arc<ManagedTorrent>,
+        bandwidth: Arc<Mutex<BandwidthLimiter>>,
+        events: broadcast::Sender<TorrentEvent>,
+    ) -> Self {
+        TorrentConnections {
+            id,
+            torrent,
+            bandwidth,
+            events,
+            links: Default::default(),
+            pex_peers: Default::default(),
+            disconnects: Default::default(),
+        }
+    }
+
+    pub fn torrent(&self) -> &Arc<ManagedTorrent> {
+        &self.torrent
+    }
+
+    /// Addresses of the connected peers.
+    pub fn connected(&self) -> Vec<SocketAddr> {
+        self.links.lock().unwrap().keys().copied().collect()
+    }
+
+    /// The state of each connected peer.
+    pub fn peer_snapshots(&self) -> Vec<PeerSnapshot> {
+        self.links
+            .lock()
+            .unwrap()
+            .values()
+            .map(|link| link.snapshot.clone())
+            .collect()
+    }
+
+    /// The request pipeline of each connected peer.
+    pub fn pipeline_stats(&self) -> Vec<(SocketAddr, PipelineStats)> {
+        let now = Instant::now();
+        let connected = self.connected();
+        let mut scheduler = self.torrent.scheduler.lock().unwrap();
+        connected
+            .into_iter()
+            .filter_map(|peer| Some((peer, scheduler.stats(peer, now)?)))
+            .collect()
+    }
+
+    /// Why the last connection to `peer` ended, if it was one of the last
+    /// [`MAX_DISCONNECTS`] to end.
+    pub fn disconnect_reason(&self, peer: SocketAddr) -> Option<DisconnectReason> {
+        self.disconnects
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(addr, _)| *addr == peer)
+            .map(|(_, reason)| reason.clone())
+    }
+
+    fn record_disconnect(&self, peer: SocketAddr, reason: DisconnectReason) {
+        let mut disconnects = self.disconnects.lock().unwrap();
+        disconnects.retain(|(addr, _)| *addr != peer);
+        if disconnects.len() >= MAX_DISCONNECTS {
+            disconnects.pop_front();
+        }
+        disconnects.push_back((peer, reason));
+    }
+
+    fn send(&self, peer: SocketAddr, command: Command) {
+        if let Some(link) = self.links.lock().unwrap().get(&peer) {
+            let _ = link.commands.send(command);
+        }
+    }
+
+    fn send_where(&self, filter: impl Fn(SocketAddr) -> bool, command: impl Fn() -> Command) {
+        for (addr, link) in self.links.lock().unwrap().iter() {
+            if filter(*addr) {
+                let _ = link.commands.send(command());
+            }
+        }
+    }
+
+    // rechokes if it is due and tells the peers whose state changed
+    fn rechoke(&self, now: Instant) {
+        let seeding = self.torrent.scheduler.lock().unwrap().is_complete();
+        let peers = self
+            .links
+            .lock()
+            .unwrap()
+            .values()
+            .map(|link| link.stats.clone())
+            .collect::<Vec<_>>();
+        let decision = self
+            .torrent
+            .choker
+            .lock()
+            .unwrap()
+            .poll(&peers, seeding, now);
+        let Some(decision) = decision else {
+            return;
+        };
+        for peer in decision.choke {
+            self.send(peer, Command::Choke);
+        }
+        for peer in decision.unchoke {
+            self.send(peer, Command::Unchoke);
+        }
+    }
+}
+
+#[async_trait]
+impl PeerHandler for TorrentConnections {
+    async fn run(&self, connection: Connection) {
+        let Connection {
+            addr,
+            direction,
+            handshake,
+            extensions,
+            mut framed,
+            shutdown,
+            ..
+        } = connection;
+        let (commands, receiver) = unbounded_channel();
+        let stats = ChokerPeer {
+            addr,
+            interested: false,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            downloaded: 0,
+            uploaded: 0,
+            connected_at: Instant::now(),
+        };
+        let mut peer = Peer::from(addr);
+        peer.id = Some(handshake.peer_id);
+        let num_pieces = self.torrent.scheduler.lock().unwrap().num_pieces();
+        let session = PeerSession::with_extensions(peer, num_pieces, extensions);
+        let link = Link {
+            commands,
+            stats,
+            snapshot: session.snapshot(),
+        };
+        self.links.lock().unwrap().insert(addr, link);
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .add_peer(addr, self.id, Instant::now());
+
+        let mut connection = PeerConnection {
+            handler: self,
+            addr,
+            uploads: UploadQueue::new(session.fast_enabled()),
+            session,
+            downloaded: 0,
+            unpaid: 0,
+            upload_quota: Quota::default(),
+            download_quota: Quota::default(),
+            outgoing: Vec::new(),
+            last_sent: Instant::now(),
+            // incoming peers are advertised once they tell their listen port
+            listen_addr: None,
+        };
+        if direction == Direction::Outgoing {
+            connection.advertise(addr, PexFlags::OUTGOING);
+        }
+        // the peer id from the handshake
+        connection.remember_peer().await;
+        if let Err(reason) = connection.run(&mut framed, &shutdown, receiver).await {
+            debug!("disconnected from {}: {}", addr, reason);
+            connection.session.disconnect(reason.clone());
+            self.record_disconnect(addr, reason);
+        }
+        connection.close();
+    }
+}
+
+// bandwidth granted to a connection and not used yet
+#[derive(Default)]
+struct Quota {
+    granted: u64,
+    // more was asked for and not granted yet
+    waiting: bool,
+}
+
+// the state of one connection of a torrent
+struct PeerConnection<'a> {
+    handler: &'a TorrentConnections,
+    addr: SocketAddr,
+    session: PeerSession,
+    uploads: UploadQueue,
+    // payload bytes received from the peer that we kept
+    downloaded: u64,
+    // payload bytes read from the peer that the limiter did not grant yet;
+    // nothing more is read until it does
+    unpaid: u64,
+    upload_quota: Quota,
+    download_quota: Quota,
+    outgoing: Vec<Message>,
+    last_sent: Instant,
+    listen_addr: Option<SocketAddr>,
+}
+
+impl PeerConnection<'_> {
+    fn torrent(&self) -> &ManagedTorrent {
+        &self.handler.torrent
+    }
+
+    fn scheduler(&self) -> MutexGuard<'_, BlockScheduler> {
+        self.handler.torrent.scheduler.lock().unwrap()
+    }
+
+    async fn run(
+        &mut self,
+        wire: &mut Wire,
+        shutdown: &CancellationToken,
+        mut commands: UnboundedReceiver<Command>,
+    ) -> Result<(), DisconnectReason> {
+        let (discovered, mut pex) = unbounded_channel();
+        self.start(discovered);
+        let mut ticks = interval(TICK_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            self.update();
+            self.publish();
+            self.flush(wire).await?;
+            self.serve(wire).await?;
+            if self.unpaid > 0 && self.bandwidth(ratelimit::Direction::Download, self.unpaid) {
+                self.unpaid = 0;
+            }
+            select! {
+                _ = shutdown.cancelled() => return Err(local("shut down")),
+                message = wire.next(), if self.unpaid == 0 => match message {
+                    Some(Ok(message)) => self.on_message(message).await?,
+                    Some(Err(e)) => return Err(local(e)),
+                    None => return Err(DisconnectReason::Closed),
+                },
+                Some(command) = commands.recv() => self.on_command(command)?,
+                Some(peers) = pex.recv() => {
+                    // only fails for private torrents, which have no PEX
+                    let _ = self.torrent().add_pex_peers(peers).await;
+                }
+                _ = ticks.tick() => self.on_tick(Instant::now()),
+            }
+        }
+    }
+
+    // our pieces, the allowed fast set and the extended handshake
+    fn start(&mut self, discovered: UnboundedSender<Vec<SocketAddr>>) {
+        let have = self.scheduler().have().clone();
+        self.outgoing.push(self.session.initial_message(&have));
+        if let Some(info_hash) = self.torrent().info_hash() {
+            let allowed = self.session.allow_fast(&info_hash);
+            self.outgoing.extend(allowed);
+        }
+        let metadata = MetadataExtension::new(self.torrent().metadata_source(), self.addr);
+        self.session.register_extension(Box::new(metadata));
+        if self.torrent().pex_enabled() {
+            let pex = PexExtension::new(
+                PexState::new(),
+                self.addr,
+                Arc::clone(&self.handler.pex_peers),
+                discovered,
+            );
+            self.session.register_extension(Box::new(pex));
+        }
+        let handshake = ExtendedHandshake {
+            client: Some(format!("rusty-torrent/{}", env!("CARGO_PKG_VERSION"))),
+            listen_port: Some(self.torrent().listen_port()),
+            reqq: Some(MAX_QUEUED_REQUESTS as u32),
+            ..Default::default()
+        };
+        self.outgoing
+            .extend(self.session.extended_handshake(handshake));
+    }
+
+    async fn on_message(&mut self, message: Message) -> Result<(), DisconnectReason> {
+        let events = self.session.receive(message)?;
+        self.outgoing.extend(self.session.drain_outgoing());
+        for event in events {
+            self.on_event(event).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_event(&mut self, event: PeerEvent) -> Result<(), DisconnectReason> {
+        match event {
+            PeerEvent::Have(index) => self.scheduler().picker_mut().add_have(index),
+            PeerEvent::Bitfield(_) => {
+                let bitfield = &self.session.peer().bitfield;
+                self.scheduler().picker_mut().add_bitfield(bitfield);
+            }
+            PeerEvent::Choked => {
+                let session = &self.session;
+                self.scheduler()
+                    .on_choke(self.addr, |index| session.can_request(index));
+            }
+            PeerEvent::Interested => {
+                self.update_stats(Instant::now());
+                self.torrent().choker.lock().unwrap().on_interested();
+            }
+            PeerEvent::NotInterested => self.update_stats(Instant::now()),
+            PeerEvent::Block { index, begin, data } => {
+                self.unpaid += data.len() as u64;
+                self.on_block(index, begin, data).await?;
+            }
+            PeerEvent::Request(block) => {
+                let reject = self.handler.torrent.queue_request(&mut self.uploads, block);
+                self.outgoing.extend(reject);
+            }
+            PeerEvent::Cancel(block) => {
+                self.uploads.on_cancel(&block);
+            }
+            PeerEvent::Rejected(block) => self.scheduler().on_reject(self.addr, block),
+            PeerEvent::ExtendedHandshake(handshake) => {
+                if let Some(reqq) = handshake.reqq {
+                    self.scheduler().set_reqq(self.addr, reqq);
+                }
+                self.remember_peer().await;
+                match handshake.listen_port {
+                    Some(port) if self.listen_addr.is_none() => {
+                        self.advertise(SocketAddr::new(self.addr.ip(), port), PexFlags::NONE);
+                    }
+                    _ => {}
+                }
+            }
+            // requests follow from the new state after every message
+            PeerEvent::Unchoked
+            | PeerEvent::AllowedFast(_)
+            | PeerEvent::Suggest(_)
+            | PeerEvent::Port(_) => {}
+        }
+        Ok(())
+    }
+
+    async fn on_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    ) -> Result<(), DisconnectReason> {
+        let torrent = &self.handler.torrent;
+        let (addr, length) = (self.addr, data.len() as u32);
+        let offset = index as u64 * torrent.metadata.info.piece_length as u64 + begin as u64;
+        let scheduler = Arc::clone(&torrent.scheduler);
+        let storage = Arc::clone(&torrent.storage);
+        // stored while the scheduler is locked, so the piece cannot be
+        // completed and checked by another connection before the block is in
+        let outcome = spawn_blocking(move || {
+            let mut scheduler = scheduler.lock().unwrap();
+            let outcome = scheduler.on_block(addr, index, begin, length, Instant::now());
+            if let BlockOutcome::Accepted { .. } = outcome {
+                storage.write(offset, &data)?;
+            }
+            RustyTorrentResult::Ok(outcome)
+        })
+        .await
+        .map_err(local)?
+        .map_err(local)?;
+        let BlockOutcome::Accepted {
+            piece_complete,
+            cancels,
+        } = outcome
+        else {
+            return Ok(());
+        };
+
+        self.downloaded += length as u64;
+        *torrent.downloaded.write().await += length as u64;
+        for (peer, block) in cancels {
+            self.handler.send(peer, Command::Cancel(block));
+        }
+        if piece_complete {
+            let check = torrent.verify_piece(index).await.map_err(local)?;
+            if check.passed {
+                self.handler.send_where(|_| true, || Command::Have(index));
+            }
+            let banned = |peer: SocketAddr| check.banned.contains(&peer.ip());
+            self.handler
+                .send_where(banned, || Command::Close(local("banned for bad data")));
+        }
+        Ok(())
+    }
+
+    fn on_command(&mut self, command: Command) -> Result<(), DisconnectReason> {
+        match command {
+            Command::Have(index) => {
+                if !self.session.peer().bitfield.has(index as usize) {
+                    self.outgoing.push(Message::Have(index));
+                }
+            }
+            Command::Choke => {
+                if let Some(choke) = self.session.choke() {
+                    self.outgoing.push(choke);
+                    let session = &self.session;
+                    let rejects = self
+                        .uploads
+                        .on_choke(|index| session.peer_allowed_fast(index));
+                    self.outgoing.extend(rejects);
+                }
+            }
+            Command::Unchoke => self.outgoing.extend(self.session.unchoke()),
+            Command::Cancel(block) => self.outgoing.push(Message::Cancel(block)),
+            Command::Close(reason) => return Err(reason),
+        }
+        Ok(())
+    }
+
+    fn on_tick(&mut self, now: Instant) {
+        self.scheduler().poll_timeouts(now);
+        let events = self.scheduler().drain_events();
+        for event in events {
+            // no one may be listening
+            let _ = self.handler.events.send(TorrentEvent {
+                torrent: self.handler.id,
+                event,
+            });
+        }
+        self.update_stats(now);
+        self.handler.rechoke(now);
+        self.outgoing.extend(self.session.poll_extensions());
+        if self.outgoing.is_empty() && now.duration_since(self.last_sent) >= KEEP_ALIVE_INTERVAL {
+            self.outgoing.push(Message::KeepAlive);
+        }
+    }
+
+    // what the session shows of the peer
+    fn publish(&self) {
+        if let Some(link) = self.handler.links.lock().unwrap().get_mut(&self.addr) {
+            link.snapshot = self.session.snapshot();
+        }
+    }
+
+    // what the choker knows about the peer
+    fn update_stats(&mut self, now: Instant) {
+        let download_rate = self
+            .scheduler()
+            .stats(self.addr, now)
+            .map(|stats| stats.download_rate)
+            .unwrap_or_default();
+        let upload_rate = self.uploads.upload_rate(now);
+        if let Some(link) = self.handler.links.lock().unwrap().get_mut(&self.addr) {
+            link.stats.interested = self.session.peer().peer_interested;
+            link.stats.download_rate = download_rate;
+            link.stats.upload_rate = upload_rate;
+            link.stats.downloaded = self.downloaded;
+            link.stats.uploaded = self.uploads.uploaded();
+        }
+    }
+
+    // our interest and new requests, after anything that may change them
+    fn update(&mut self) {
+        let mut scheduler = self.handler.torrent.scheduler.lock().unwrap();
+        self.outgoing
+            .extend(self.session.update_interest(scheduler.have()));
+        if !self.session.peer().am_interested {
+            return;
+        }
+        let session = &self.session;
+        let requests = scheduler.request_blocks(
+            self.addr,
+            &session.peer().bitfield,
+            |index| session.can_request(index),
+            Instant::now(),
+        );
+        self.outgoing
+            .extend(requests.into_iter().map(Message::Request));
+    }
+
+    async fn flush(&mut self, wire: &mut Wire) -> Result<(), DisconnectReason> {
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+        for message in self.outgoing.drain(..) {
+            wire.feed(message).await.map_err(local)?;
+        }
+        wire.flush().await.map_err(local)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn serve(&mut self, wire: &mut Wire) -> Result<(), DisconnectReason> {
+        let torrent = Arc::clone(&self.handler.torrent);
+        while let Some(block) = self.uploads.peek() {
+            if !self.bandwidth(ratelimit::Direction::Upload, block.length as u64) {
+                break;
+            }
+            let Some(piece) = torrent.serve(&mut self.uploads).await.map_err(local)? else {
+                break;
+            };
+            wire.send(piece).await.map_err(local)?;
+            self.last_sent = Instant::now();
+        }
+        Ok(())
+    }
+
+    // takes `bytes` from what the limiter granted, asking for the rest if
+    // there is not enough; false if it has to wait
+    fn bandwidth(&mut self, direction: ratelimit::Direction, bytes: u64) -> bool {
+        let quota = match direction {
+            ratelimit::Direction::Upload => &mut self.upload_quota,
+            ratelimit::Direction::Download => &mut self.download_quota,
+        };
+        let mut limiter = self.handler.bandwidth.lock().unwrap();
+        if quota.granted < bytes && !quota.waiting {
+            limiter.request(self.addr, direction, bytes - quota.granted);
+            quota.waiting = true;
+        }
+        let granted = limiter.poll_peer(self.addr, direction, Instant::now());
+        if granted > 0 {
+            quota.granted += granted;
+            quota.waiting = false;
+        }
+        if quota.granted < bytes {
+            return false;
+        }
+        quota.granted -= bytes;
+        true
+    }
+
+    // writes what the peer told about itself to its entry in the torrent's
+    // peer list, found by the address connected to or its listen address
+    async fn remember_peer(&mut self) {
+        let peer = self.session.peer().clone();
+        let mut peers = self.torrent().peers.write().await;
+        let known = peers.iter_mut().find(|known| {
+            known.ip == peer.ip && (known.port == peer.port || Some(known.port) == peer.listen_port)
+        });
+        if let Some(known) = known {
+            known.id = peer.id.or(known.id);
+            known.client = peer.client.or(known.client.take());
+            known.listen_port = peer.listen_port.or(known.listen_port);
+        }
+    }
+
+    fn advertise(&mut self, listen_addr: SocketAddr, flags: PexFlags) {
+        self.listen_addr = Some(listen_addr);
+        self.handler
+            .pex_peers
+            .lock()
+            .unwrap()
+            .insert(listen_addr, flags);
+    }
+
+    // forgets the peer everywhere it was counted
+    fn close(&mut self) {
+        self.handler.links.lock().unwrap().remove(&self.addr);
+        if let Some(listen_addr) = self.listen_addr {
+            self.handler.pex_peers.lock().unwrap().remove(&listen_addr);
+        }
+        {
+            let mut scheduler = self.scheduler();
+            scheduler
+                .picker_mut()
+                .remove_bitfield(&self.session.peer().bitfield);
+            scheduler.remove_peer(self.addr);
+        }
+        self.torrent().choker.lock().unwrap().remove_peer(self.addr);
+        self.handler
+            .bandwidth
+            .lock()
+            .unwrap()
+            .remove_peer(self.addr);
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
+use torrent_dht::error::DhtError;
 use torrent_parser::error::TorrentParserError;
+use torrent_pwp::error::PwpError;
 
 use crate::session::TorrentId;
 
@@ -13,6 +15,48 @@ pub enum RustyTorrentError {
 
     #[error("IO Error: {0}")]
     IOError(#[from] std::io::Error),
+
+    #[error("HTTP Error: {0}")]
+    HttpError(#[from] reqwest::Error),
+
+    #[error("Unsupported Tracker Scheme: {0}")]
+    UnsupportedTrackerScheme(String),
+
+    #[error("Scrape Not Supported: {0}")]
+    ScrapeNotSupported(String),
+
+    #[error("Tracker Error: {0}")]
+    TrackerError(String),
+
+    #[error("Tracker Timeout")]
+    TrackerTimeout,
+
+    #[error("DHT Error: {0}")]
+    DhtError(#[from] DhtError),
+
+    #[error("Peer Wire Error: {0}")]
+    PwpError(#[from] PwpError),
+
+    #[error("Invalid PEX Message: {0}")]
+    InvalidPexMessage(String),
+
+    #[error("PEX Rate Limited")]
+    PexRateLimited,
+
+    #[error("PEX Disabled For Private Torrent")]
+    PexDisabled,
+
+    #[error("Invalid Metadata Message: {0}")]
+    InvalidMetadataMessage(String),
+
+    #[error("Invalid Magnet Link: {0}")]
+    InvalidMagnetLink(String),
+
+    #[error("File Not Found: {0}")]
+    FileNotFound(usize),
+
+    #[error("Invalid IP Filter: {0}")]
+    InvalidIpFilter(String),
 }
 
 pub type RustyTorrentResult<T> = Result<T, RustyTorrentError>;
@@ -0,0 +1,178 @@
+use std::fmt;
+
+use torrent_pwp::PeerId;
+
+/// A client as told by its peer id or extended handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// two letter codes of `-XXyyyy-` ids
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AR", "Arctic"),
+    ("AT", "Artemis"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BF", "Bitflu"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("CD", "Enhanced CTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("FW", "FrostWire"),
+    ("HL", "Halite"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("LW", "LimeWire"),
+    ("MG", "MediaGet"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("RT", "rusty-torrent"),
+    ("SD", "Thunder"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("TT", "TuoTu"),
+    ("TX", "Tixati"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+// first letter of shadow style ids
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT BitTorrent"),
+];
+
+// first letter of mainline style ids
+const MAINLINE_CLIENTS: &[(u8, &str)] = &[(b'M', "Mainline"), (b'Q', "Queen Bee")];
+
+/// Identifies the client behind a peer id. Knows the Azureus style
+/// `-XXyyyy-`, the Shadow style `S58B-----` and the Mainline style
+/// `M4-3-6--`.
+pub fn from_peer_id(id: &PeerId) -> Option<ClientInfo> {
+    azureus(id).or_else(|| mainline(id)).or_else(|| shadow(id))
+}
+
+/// Splits the `v` field of an extended handshake, as in `qBittorrent/4.6.2`
+/// or `Transmission 4.0.5`, into name and version.
+pub fn from_extended_handshake(v: &str) -> Option<ClientInfo> {
+    let v = v.trim();
+    if v.is_empty() {
+        return None;
+    }
+    let split = v.rfind([' ', '/']).filter(|at| {
+        let version = v[at + 1..].trim_start_matches('v');
+        version.starts_with(|c: char| c.is_ascii_digit())
+    });
+    Some(match split {
+        Some(at) => ClientInfo {
+            name: v[..at].trim().to_string(),
+            version: Some(v[at + 1..].trim_start_matches('v').to_string()),
+        },
+        None => ClientInfo {
+            name: v.to_string(),
+            version: None,
+        },
+    })
+}
+
+// `-XXyyyy-`, two letters for the client and four version characters
+fn azureus(id: &PeerId) -> Option<ClientInfo> {
+    if id[0] != b'-' || id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&id[1..3]).ok()?;
+    let name = match AZUREUS_CLIENTS.iter().find(|(known, _)| *known == code) {
+        Some((_, name)) => name.to_string(),
+        None => code.to_string(),
+    };
+    let digits = id[3..7]
+        .iter()
+        .map(|c| match c {
+            b'0'..=b'9' => Some((c - b'0') as u32),
+            b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let mut version = digits[..3].iter().map(u32::to_string).collect::<Vec<_>>();
+    // the fourth digit is a build number, or a letter for the release kind
+    if id[6].is_ascii_digit() && digits[3] != 0 {
+        version.push(digits[3].to_string());
+    }
+    Some(ClientInfo {
+        name,
+        version: Some(version.join(".")),
+    })
+}
+
+// a letter and three numbers each followed by a dash, within the first
+// eight bytes, as in `M4-3-6--` or `M4-20-8-`
+fn mainline(id: &PeerId) -> Option<ClientInfo> {
+    let (_, name) = MAINLINE_CLIENTS.iter().find(|(code, _)| *code == id[0])?;
+    let mut rest = &id[1..];
+    let mut parts = Vec::new();
+    for _ in 0..3 {
+        let length = rest.iter().position(|c| !c.is_ascii_digit())?;
+        if length == 0 || rest[length] != b'-' {
+            return None;
+        }
+        parts.push(String::from_utf8_lossy(&rest[..length]).into_owned());
+        rest = &rest[length + 1..];
+    }
+    if id.len() - rest.len() > 8 {
+        return None;
+    }
+    Some(ClientInfo {
+        name: name.to_string(),
+        version: Some(parts.join(".")),
+    })
+}
+
+// a letter, then up to five version characters ended by dashes
+fn shadow(id: &PeerId) -> Option<ClientInfo> {
+    let (_, name) = SHADOW_CLIENTS.iter().find(|(code, _)| *code == id[0])?;
+    let value = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    };
+    let digits = id[1..6]
+        .iter()
+        .map_while(|c| value(*c))
+        .map(|digit| digit.to_string())
+        .collect::<Vec<_>>();
+    if digits.is_empty() || id[1 + digits.len()..].get(..2) != Some(b"--") {
+        return None;
+    }
+    Some(ClientInfo {
+        name: name.to_string(),
+        version: Some(digits.join(".")),
+    })
+}
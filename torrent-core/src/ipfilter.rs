@@ -0,0 +1,198 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use crate::error::{RustyTorrentError, RustyTorrentResult};
+
+/// Entries of `ipfilter.dat` with an access level above this are allowed.
+const ALLOWED_LEVEL: u32 = 127;
+
+/// Ranges of addresses we never connect to or accept connections from, kept
+/// sorted and merged so a lookup is a binary search.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    v4: Vec<(u128, u128)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Reads a list in any of the formats of [`IpFilter::parse`].
+    pub fn load(path: impl AsRef<Path>) -> RustyTorrentResult<Self> {
+        let bytes = std::fs::read(path)?;
+        // descriptions are not always UTF-8
+        IpFilter::parse(&String::from_utf8_lossy(&bytes))
+    }
+
+    /// Parses a list with one range per line, in eMule `ipfilter.dat`
+    /// (`1.2.3.0 - 1.2.3.255 , 000 , description`), PeerGuardian P2P
+    /// (`description:1.2.3.0-1.2.3.255`) or CIDR (`1.2.3.0/24`) format, which
+    /// may be mixed. Single addresses and IPv6 work in all but P2P. Lines
+    /// starting with `#` or `//` are comments.
+    pub fn parse(text: &str) -> RustyTorrentResult<Self> {
+        let mut filter = IpFilter::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let range = parse_line(line).ok_or_else(|| {
+                RustyTorrentError::InvalidIpFilter(format!("line {}: {}", number + 1, line))
+            })?;
+            if let Some((start, end)) = range {
+                filter.push(start, end)?;
+            }
+        }
+        filter.normalize();
+        Ok(filter)
+    }
+
+    /// Blocks the addresses from `start` to `end`, both included.
+    pub fn add_range(&mut self, start: IpAddr, end: IpAddr) -> RustyTorrentResult<()> {
+        self.push(start, end)?;
+        self.normalize();
+        Ok(())
+    }
+
+    /// Merged ranges in the filter.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip).into()),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, u32::from(ip).into()),
+                None => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    fn push(&mut self, start: IpAddr, end: IpAddr) -> RustyTorrentResult<()> {
+        let invalid = || RustyTorrentError::InvalidIpFilter(format!("{} - {}", start, end));
+        match (start, end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) if start <= end => self
+                .v4
+                .push((u32::from(start).into(), u32::from(end).into())),
+            (IpAddr::V6(start), IpAddr::V6(end)) if start <= end => {
+                self.v6.push((start.into(), end.into()))
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+
+    fn normalize(&mut self) {
+        merge(&mut self.v4);
+        merge(&mut self.v6);
+    }
+}
+
+// sorts the ranges and joins those that overlap or touch
+fn merge(ranges: &mut Vec<(u128, u128)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains(ranges: &[(u128, u128)], ip: u128) -> bool {
+    let after = ranges.partition_point(|(start, _)| *start <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+// the range of a line, `None` for a line that does not parse and `Some(None)`
+// for an allowed range
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    if let Some(range) = parse_dat(line) {
+        return Some(range);
+    }
+    if let Some(range) = parse_range(line) {
+        return Some(Some(range));
+    }
+    // P2P descriptions may hold colons, the range holds none
+    let (_, range) = line.rsplit_once(':')?;
+    parse_range(range).map(Some)
+}
+
+fn parse_dat(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    let (range, rest) = line.split_once(',')?;
+    let level = rest.split(',').next()?.trim().parse::<u32>().ok()?;
+    let range = parse_range(range)?;
+    Some((level <= ALLOWED_LEVEL).then_some(range))
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let range = range.trim();
+    if let Some((start, end)) = range.split_once('-') {
+        return Some((parse_ip(start.trim())?, parse_ip(end.trim())?));
+    }
+    if let Some((ip, prefix)) = range.split_once('/') {
+        let prefix = prefix.trim().parse::<u32>().ok()?;
+        return match parse_ip(ip.trim())? {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - prefix.min(32)).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+                (prefix <= 32).then(|| {
+                    let end = start | !mask;
+                    (Ipv4Addr::from(start).into(), Ipv4Addr::from(end).into())
+                })
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - prefix.min(128)).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+                (prefix <= 128).then(|| {
+                    let end = start | !mask;
+                    (Ipv6Addr::from(start).into(), Ipv6Addr::from(end).into())
+                })
+            }
+        };
+    }
+    let ip = parse_ip(range)?;
+    Some((ip, ip))
+}
+
+// lists pad IPv4 addresses with zeros, as in `001.002.003.004`
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    if let Ok(ip) = ip.parse() {
+        return Some(ip);
+    }
+    let octets = ip
+        .split('.')
+        .map(|octet| octet.parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(Ipv4Addr::from(octets).into())
+}
+
+/// An [`IpFilter`] shared by the session and its torrents, which can be
+/// replaced while they run.
+#[derive(Debug, Default)]
+pub struct SharedIpFilter {
+    filter: RwLock<Arc<IpFilter>>,
+}
+
+impl SharedIpFilter {
+    pub fn set(&self, filter: IpFilter) {
+        *self.filter.write().unwrap() = Arc::new(filter);
+    }
+
+    pub fn get(&self) -> Arc<IpFilter> {
+        Arc::clone(&self.filter.read().unwrap())
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.filter.read().unwrap().is_blocked(ip)
+    }
+}
@@ -1,5 +1,22 @@
+pub mod ban;
+pub mod choker;
+pub mod connection;
 pub mod error;
+pub mod fingerprint;
+pub mod ipfilter;
+pub mod lsd;
+pub mod magnet;
+pub mod metadata;
 pub mod peer;
+pub mod pex;
+pub mod picker;
+pub mod rate;
+pub mod ratelimit;
+pub mod scheduler;
 pub mod session;
+pub mod smartban;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
+pub mod udp;
+pub mod upload;
@@ -0,0 +1,410 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, spawn, sync::RwLock, task::JoinHandle, time::interval};
+use torrent_pwp::PeerFilter;
+use tracing::debug;
+
+use crate::{error::RustyTorrentResult, peer::Peer};
+
+/// BEP 14 multicast group for IPv4.
+pub const LSD_MULTICAST_V4: SocketAddrV4 =
+    SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+/// BEP 14 multicast group for IPv6.
+pub const LSD_MULTICAST_V6: SocketAddrV6 = SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+);
+/// Every registered torrent is announced this often.
+pub const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// A torrent is announced at most once per minute, and announces for the same
+/// torrent from the same host are ignored for as long.
+pub const LSD_MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// keeps a packet well below a typical MTU
+const MAX_INFO_HASHES_PER_PACKET: usize = 20;
+const MAX_PACKET_SIZE: usize = 1400;
+
+/// A local interface to announce on and listen to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LsdInterface {
+    /// An IPv4 interface by its address; unspecified lets the OS pick.
+    V4(Ipv4Addr),
+    /// An IPv6 interface by its index; 0 lets the OS pick.
+    V6(u32),
+}
+
+pub struct LsdConfig {
+    pub interfaces: Vec<LsdInterface>,
+    pub multicast_v4: SocketAddrV4,
+    pub multicast_v6: SocketAddrV6,
+    pub announce_interval: Duration,
+    pub min_announce_interval: Duration,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        LsdConfig {
+            interfaces: vec![LsdInterface::V4(Ipv4Addr::UNSPECIFIED), LsdInterface::V6(0)],
+            multicast_v4: LSD_MULTICAST_V4,
+            multicast_v6: LSD_MULTICAST_V6,
+            announce_interval: LSD_ANNOUNCE_INTERVAL,
+            min_announce_interval: LSD_MIN_ANNOUNCE_INTERVAL,
+        }
+    }
+}
+
+/// A `BT-SEARCH` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    pub info_hashes: Vec<Vec<u8>>,
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    /// Encodes the message for the given `Host` header, which is the group
+    /// address the message is sent to.
+    pub fn encode(&self, host: &str) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            host, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str("Infohash: ");
+            for byte in info_hash {
+                let _ = write!(message, "{:02x}", byte);
+            }
+            message.push_str("\r\n");
+        }
+        if let Some(cookie) = &self.cookie {
+            let _ = write!(message, "cookie: {}\r\n", cookie);
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Parses a message, returning `None` for anything that is not a valid
+    /// `BT-SEARCH`.
+    pub fn decode(bytes: &[u8]) -> Option<LsdAnnounce> {
+        let message = std::str::from_utf8(bytes).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            // header names are case insensitive like in HTTP
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse::<u16>().ok().filter(|port| *port != 0),
+                "infohash" => info_hashes.push(decode_hex(value)?),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(LsdAnnounce {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() != 40 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+struct LsdTorrent {
+    peers: Arc<RwLock<Vec<Peer>>>,
+    filter: PeerFilter,
+    last_announce: Option<Instant>,
+}
+
+struct LsdState {
+    sockets: Vec<(Arc<UdpSocket>, SocketAddr)>,
+    cookie: String,
+    port: u16,
+    min_announce_interval: Duration,
+    torrents: Mutex<HashMap<Vec<u8>, LsdTorrent>>,
+    // last accepted announce per source and info hash
+    seen: Mutex<HashMap<(IpAddr, Vec<u8>), Instant>>,
+}
+
+/// Local Service Discovery (BEP 14): finds peers on the local network by
+/// multicasting the info hashes of running torrents.
+///
+/// Private torrents must not be registered. All tasks are stopped when this is
+/// dropped.
+pub struct LocalServiceDiscovery {
+    state: Arc<LsdState>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+impl LocalServiceDiscovery {
+    /// Joins the multicast groups on the configured interfaces. Interfaces that
+    /// cannot be used are skipped; it is an error if none can.
+    pub async fn start(config: LsdConfig, port: u16) -> RustyTorrentResult<Self> {
+        let mut sockets = Vec::new();
+        let mut first_error = None;
+        for interface in &config.interfaces {
+            match bind_interface(*interface, &config) {
+                Ok(socket) => sockets.push(socket),
+                Err(e) => {
+                    debug!("lsd unavailable on {:?}: {}", interface, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let (true, Some(e)) = (sockets.is_empty(), first_error) {
+            return Err(e.into());
+        }
+
+        let cookie = format!("{:08x}", rand::thread_rng().gen::<u32>());
+        let state = Arc::new(LsdState {
+            sockets,
+            cookie,
+            port,
+            min_announce_interval: config.min_announce_interval,
+            torrents: Default::default(),
+            seen: Default::default(),
+        });
+
+        let mut task_handles = Vec::new();
+        for (socket, _) in &state.sockets {
+            let socket = Arc::clone(socket);
+            let state = Arc::clone(&state);
+            task_handles.push(spawn(async move {
+                let mut buf = [0u8; MAX_PACKET_SIZE];
+                loop {
+                    match socket.recv_from(&mut buf).await {
+                        Ok((len, from)) => {
+                            state.handle_packet(&buf[..len], from).await;
+                        }
+                        Err(e) => debug!("lsd receive failed: {}", e),
+                    }
+                }
+            }));
+        }
+
+        let announce_state = Arc::clone(&state);
+        let announce_interval = config.announce_interval;
+        task_handles.push(spawn(async move {
+            let mut ticker = interval(announce_interval);
+            loop {
+                ticker.tick().await;
+                announce_state.announce_due().await;
+            }
+        }));
+
+        Ok(LocalServiceDiscovery {
+            state,
+            task_handles,
+        })
+    }
+
+    /// The random cookie sent with our announces, used to recognise our own
+    /// packets when they are looped back.
+    pub fn cookie(&self) -> &str {
+        &self.state.cookie
+    }
+
+    /// Starts announcing `info_hash` and adds peers found for it to `peers`,
+    /// those that `filter` allows.
+    pub async fn add_torrent(
+        &self,
+        info_hash: Vec<u8>,
+        peers: Arc<RwLock<Vec<Peer>>>,
+        filter: PeerFilter,
+    ) {
+        self.state.torrents.lock().unwrap().insert(
+            info_hash,
+            LsdTorrent {
+                peers,
+                filter,
+                last_announce: None,
+            },
+        );
+        self.state.announce_due().await;
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8]) {
+        self.state.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    /// Announces a single torrent right away, unless it was announced less
+    /// than the minimum interval ago. Returns whether it was sent.
+    pub async fn announce(&self, info_hash: &[u8]) -> bool {
+        let due = {
+            let mut torrents = self.state.torrents.lock().unwrap();
+            match torrents.get_mut(info_hash) {
+                Some(torrent) if self.state.is_due(torrent) => {
+                    torrent.last_announce = Some(Instant::now());
+                    true
+                }
+                _ => false,
+            }
+        };
+        if due {
+            self.state.send(&[info_hash.to_vec()]).await;
+        }
+        due
+    }
+
+    /// Handles a packet received from `from`, returning the peers that were
+    /// added to registered torrents.
+    pub async fn handle_packet(&self, bytes: &[u8], from: SocketAddr) -> Vec<SocketAddr> {
+        self.state.handle_packet(bytes, from).await
+    }
+}
+
+impl Drop for LocalServiceDiscovery {
+    fn drop(&mut self) {
+        for handle in self.task_handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+impl LsdState {
+    fn is_due(&self, torrent: &LsdTorrent) -> bool {
+        torrent
+            .last_announce
+            .is_none_or(|last| last.elapsed() >= self.min_announce_interval)
+    }
+
+    /// Announces every torrent that was not announced recently.
+    async fn announce_due(&self) {
+        let info_hashes = {
+            let mut torrents = self.torrents.lock().unwrap();
+            let now = Instant::now();
+            torrents
+                .iter_mut()
+                .filter(|(_, torrent)| self.is_due(torrent))
+                .map(|(info_hash, torrent)| {
+                    torrent.last_announce = Some(now);
+                    info_hash.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        for chunk in info_hashes.chunks(MAX_INFO_HASHES_PER_PACKET) {
+            self.send(chunk).await;
+        }
+    }
+
+    async fn send(&self, info_hashes: &[Vec<u8>]) {
+        let announce = LsdAnnounce {
+            port: self.port,
+            info_hashes: info_hashes.to_vec(),
+            cookie: Some(self.cookie.clone()),
+        };
+        for (socket, group) in &self.sockets {
+            let packet = announce.encode(&group.to_string());
+            if let Err(e) = socket.send_to(&packet, group).await {
+                debug!("lsd announce to {} failed: {}", group, e);
+            }
+        }
+    }
+
+    async fn handle_packet(&self, bytes: &[u8], from: SocketAddr) -> Vec<SocketAddr> {
+        let Some(announce) = LsdAnnounce::decode(bytes) else {
+            return Vec::new();
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return Vec::new();
+        }
+        let peer_addr = SocketAddr::new(from.ip(), announce.port);
+
+        let mut matched = Vec::new();
+        {
+            let torrents = self.torrents.lock().unwrap();
+            let mut seen = self.seen.lock().unwrap();
+            let now = Instant::now();
+            seen.retain(|_, last| now.duration_since(*last) < self.min_announce_interval);
+            for info_hash in announce.info_hashes {
+                let Some(torrent) = torrents.get(&info_hash) else {
+                    continue;
+                };
+                if !(torrent.filter)(peer_addr.ip()) {
+                    continue;
+                }
+                let key = (from.ip(), info_hash);
+                if seen.contains_key(&key) {
+                    continue;
+                }
+                seen.insert(key, now);
+                matched.push(Arc::clone(&torrent.peers));
+            }
+        }
+
+        let mut added = Vec::new();
+        for peers in matched {
+            let peer = Peer::from(peer_addr);
+            let mut peers = peers.write().await;
+            if !peers.contains(&peer) {
+                peers.push(peer);
+                added.push(peer_addr);
+            }
+        }
+        added
+    }
+}
+
+/// Binds a socket to the group port on `interface`, joins the group on it and
+/// makes it send through that interface.
+fn bind_interface(
+    interface: LsdInterface,
+    config: &LsdConfig,
+) -> std::io::Result<(Arc<UdpSocket>, SocketAddr)> {
+    let (socket, group) = match interface {
+        LsdInterface::V4(addr) => {
+            let group = config.multicast_v4;
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            // other clients on this host listen on the same port
+            socket.set_reuse_address(true)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v4(group.ip(), &addr)?;
+            socket.set_multicast_if_v4(&addr)?;
+            socket.set_multicast_loop_v4(true)?;
+            (socket, SocketAddr::V4(group))
+        }
+        LsdInterface::V6(index) => {
+            let group = config.multicast_v6;
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_address(true)?;
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port())).into())?;
+            socket.join_multicast_v6(group.ip(), index)?;
+            socket.set_multicast_if_v6(index)?;
+            socket.set_multicast_loop_v6(true)?;
+            (socket, SocketAddr::V6(group))
+        }
+    };
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    Ok((Arc::new(socket), group))
+}
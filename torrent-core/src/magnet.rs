@@ -0,0 +1,253 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{spawn, sync::RwLock, task::JoinHandle, time::sleep};
+use torrent_dht::DhtNode;
+use torrent_parser::{
+    model::{TorrentMetadata, TrackerResponse},
+    parse_info_dict,
+};
+use torrent_pwp::PeerFilter;
+use tracing::debug;
+
+use crate::{
+    error::{RustyTorrentError, RustyTorrentResult},
+    metadata::{
+        MetadataFetcher, MetadataProgress, MetadataSource, METADATA_PIECE_SIZE,
+        METADATA_REQUEST_TIMEOUT,
+    },
+    peer::Peer,
+    tracker::client::{AnnounceRequest, TrackerClientRegistry},
+};
+
+// peers are looked up more often than for a running torrent, the metadata is
+// usually small and one good peer is enough
+const PEER_LOOKUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A parsed `magnet:` URI. Only BitTorrent v1 info hashes are supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: Vec<u8>,
+    /// `dn`: display name.
+    pub name: Option<String>,
+    /// `tr`: tracker URLs.
+    pub trackers: Vec<String>,
+    /// `x.pe`: peers to try directly.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> RustyTorrentResult<MagnetLink> {
+        let invalid = |reason: &str| RustyTorrentError::InvalidMagnetLink(reason.to_string());
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| invalid("not a magnet URI"))?;
+
+        let mut info_hash = None;
+        let mut link = MagnetLink {
+            info_hash: Vec::new(),
+            name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+        };
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            let value = percent_decode(value).ok_or_else(|| invalid("bad percent encoding"))?;
+            match key {
+                "xt" if info_hash.is_none() => {
+                    // other hash types such as btmh are skipped
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash =
+                            Some(decode_info_hash(hash).ok_or_else(|| invalid("bad info hash"))?);
+                    }
+                }
+                "dn" => link.name = Some(value),
+                "tr" => link.trackers.push(value),
+                "x.pe" => match value.parse() {
+                    Ok(addr) => link.peers.push(addr),
+                    Err(_) => debug!("magnet peer {} ignored", value),
+                },
+                _ => {}
+            }
+        }
+        link.info_hash = info_hash.ok_or_else(|| invalid("missing urn:btih"))?;
+        Ok(link)
+    }
+}
+
+// 40 hex digits or 32 base32 characters
+fn decode_info_hash(hash: &str) -> Option<Vec<u8>> {
+    match hash.len() {
+        40 => (0..40)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hash.get(i..i + 2)?, 16).ok())
+            .collect(),
+        32 => {
+            let mut bytes = Vec::with_capacity(20);
+            let (mut buffer, mut bits) = (0u64, 0);
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return None,
+                };
+                buffer = buffer << 5 | value as u64;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    bytes.push((buffer >> bits) as u8);
+                }
+            }
+            Some(bytes)
+        }
+        _ => None,
+    }
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(c) = input.next() {
+        match c {
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            c => bytes.push(c),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// A torrent added from a magnet link whose metadata is still being fetched
+/// from peers. Peers are looked up through the link, the DHT and the link's
+/// trackers until the metadata is complete.
+pub struct MagnetTorrent {
+    pub link: MagnetLink,
+    pub name: Option<String>,
+    pub location: String,
+    /// Whether to start the torrent once the metadata is in.
+    pub start: bool,
+    pub peers: Arc<RwLock<Vec<Peer>>>,
+    fetcher: Arc<Mutex<MetadataFetcher>>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+impl MagnetTorrent {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        link: MagnetLink,
+        name: Option<String>,
+        location: String,
+        start: bool,
+        peer_id: String,
+        port: u32,
+        tracker_clients: Arc<TrackerClientRegistry>,
+        dht: Option<Arc<DhtNode>>,
+        filter: PeerFilter,
+    ) -> Self {
+        let peers = Arc::new(RwLock::new(
+            link.peers
+                .iter()
+                .copied()
+                .filter(|addr| filter(addr.ip()))
+                .map(Peer::from)
+                .collect::<Vec<_>>(),
+        ));
+        let fetcher = MetadataFetcher::new(link.info_hash.clone(), METADATA_REQUEST_TIMEOUT);
+
+        let info_hash = link.info_hash.clone();
+        let trackers = link.trackers.clone();
+        let found = Arc::clone(&peers);
+        let handle = spawn(async move {
+            loop {
+                let mut candidates = Vec::new();
+                if let Some(dht) = &dht {
+                    match dht.get_peers(&info_hash).await {
+                        Ok(addrs) => candidates.extend(addrs.into_iter().map(Peer::from)),
+                        Err(e) => debug!("dht lookup for metadata failed: {}", e),
+                    }
+                }
+                for tracker in &trackers {
+                    let request = AnnounceRequest {
+                        info_hash: info_hash.clone(),
+                        peer_id: peer_id.clone(),
+                        port,
+                        uploaded: 0,
+                        downloaded: 0,
+                        // the size is unknown, anything but 0 keeps us from
+                        // looking like a seed
+                        left: METADATA_PIECE_SIZE as u64,
+                        event: None,
+                        tracker_id: None,
+                        num_want: None,
+                    };
+                    match tracker_clients.announce(tracker, &request).await {
+                        Ok(TrackerResponse::Success(resp)) => {
+                            candidates.extend(resp.peers.into_iter().map(Peer::from))
+                        }
+                        Ok(_) => {}
+                        Err(e) => debug!("tracker {} for metadata failed: {}", tracker, e),
+                    }
+                }
+                let mut peers = found.write().await;
+                for peer in candidates {
+                    let allowed = peer.ip_addr().is_none_or(|ip| filter(ip));
+                    if allowed && !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+                drop(peers);
+                sleep(PEER_LOOKUP_INTERVAL).await;
+            }
+        });
+
+        MagnetTorrent {
+            link,
+            name,
+            location,
+            start,
+            peers,
+            fetcher: Arc::new(Mutex::new(fetcher)),
+            task_handles: vec![handle],
+        }
+    }
+
+    pub fn progress(&self) -> MetadataProgress {
+        self.fetcher.lock().unwrap().progress()
+    }
+
+    /// For the `ut_metadata` extension of connections to this torrent's
+    /// peers.
+    pub fn metadata_source(&self) -> MetadataSource {
+        MetadataSource::Fetching(Arc::clone(&self.fetcher))
+    }
+
+    /// The metadata once it is complete, with the link's trackers as
+    /// announce list.
+    pub fn metadata(&self) -> Option<RustyTorrentResult<TorrentMetadata>> {
+        let info = self.fetcher.lock().unwrap().metadata()?;
+        Some(
+            parse_info_dict(&info)
+                .map_err(Into::into)
+                .map(|mut metadata| {
+                    metadata.announce = self.link.trackers.first().cloned();
+                    if !self.link.trackers.is_empty() {
+                        metadata.announce_list = Some(vec![self.link.trackers.clone()]);
+                    }
+                    metadata
+                }),
+        )
+    }
+}
+
+impl Drop for MagnetTorrent {
+    fn drop(&mut self) {
+        for handle in self.task_handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
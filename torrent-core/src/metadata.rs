@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use torrent_parser::{
+    field::{dict, info_hash, Field},
+    parse_bencode_prefix,
+};
+use torrent_pwp::{
+    error::{PwpError, PwpResult},
+    extension::{ExtendedHandshake, Extension},
+};
+use tracing::debug;
+
+use crate::error::{RustyTorrentError, RustyTorrentResult};
+
+/// Name of the extension in the BEP 10 handshake.
+pub const EXTENSION_NAME: &str = "ut_metadata";
+/// Metadata is transferred in pieces of 16 KiB, only the last may be shorter.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+/// Sizes above this advertised by peers are ignored.
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+/// Requests without an answer are handed to another peer after this.
+pub const METADATA_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// spread the pieces over peers rather than asking one peer for everything
+const MAX_REQUESTS_PER_PEER: usize = 2;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request(piece) => (MSG_REQUEST, piece),
+            MetadataMessage::Data { piece, .. } => (MSG_DATA, piece),
+            MetadataMessage::Reject(piece) => (MSG_REJECT, piece),
+        };
+        let mut entries = vec![
+            ("msg_type", Field::Integer(msg_type)),
+            ("piece", Field::Integer(*piece as i64)),
+        ];
+        if let MetadataMessage::Data { total_size, .. } = self {
+            entries.push(("total_size", Field::Integer(*total_size as i64)));
+        }
+        let mut payload = dict(entries).encode();
+        // the piece itself follows the dictionary
+        if let MetadataMessage::Data { data, .. } = self {
+            payload.extend_from_slice(data);
+        }
+        payload
+    }
+
+    /// Decodes a message. Unknown message types give `None`, BEP 9 asks for
+    /// them to be ignored.
+    pub fn decode(payload: &[u8]) -> RustyTorrentResult<Option<MetadataMessage>> {
+        let invalid = |reason: &str| RustyTorrentError::InvalidMetadataMessage(reason.to_string());
+        let (root, len) = parse_bencode_prefix(payload)?;
+        let integer = |key: &str| root.get(key).and_then(Field::as_integer);
+        let piece = integer("piece")
+            .and_then(|piece| u32::try_from(piece).ok())
+            .ok_or_else(|| invalid("missing piece"))?;
+
+        Ok(Some(match integer("msg_type") {
+            Some(MSG_REQUEST) => MetadataMessage::Request(piece),
+            Some(MSG_DATA) => MetadataMessage::Data {
+                piece,
+                total_size: integer("total_size")
+                    .and_then(|size| usize::try_from(size).ok())
+                    .ok_or_else(|| invalid("missing total_size"))?,
+                data: payload[len..].to_vec(),
+            },
+            Some(MSG_REJECT) => MetadataMessage::Reject(piece),
+            Some(_) => return Ok(None),
+            None => return Err(invalid("missing msg_type")),
+        }))
+    }
+}
+
+/// How far the metadata download of a magnet link is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetadataProgress {
+    pub pieces_received: usize,
+    /// Unknown until a peer tells us the size.
+    pub pieces_total: Option<usize>,
+}
+
+/// Assembles the info dictionary of a torrent from pieces sent by several
+/// peers and checks it against the info hash. Shared by the `ut_metadata`
+/// extensions of all connections of the torrent.
+pub struct MetadataFetcher {
+    info_hash: Vec<u8>,
+    request_timeout: Duration,
+    size: Option<usize>,
+    pieces: Vec<Option<Vec<u8>>>,
+    requested: HashMap<u32, (SocketAddr, Instant)>,
+    metadata: Option<Arc<Vec<u8>>>,
+    hash_failures: u32,
+}
+
+impl MetadataFetcher {
+    pub fn new(info_hash: Vec<u8>, request_timeout: Duration) -> Self {
+        MetadataFetcher {
+            info_hash,
+            request_timeout,
+            size: None,
+            pieces: Vec::new(),
+            requested: HashMap::new(),
+            metadata: None,
+            hash_failures: 0,
+        }
+    }
+
+    pub fn info_hash(&self) -> &[u8] {
+        &self.info_hash
+    }
+
+    /// Takes the size advertised by a peer. The first plausible size wins; if
+    /// it was a lie the hash check fails and the next size is taken.
+    pub fn set_size(&mut self, size: u64) -> bool {
+        let Ok(size) = usize::try_from(size) else {
+            return false;
+        };
+        if self.metadata.is_some() || size == 0 || size > MAX_METADATA_SIZE {
+            return false;
+        }
+        match self.size {
+            Some(current) => current == size,
+            None => {
+                self.size = Some(size);
+                self.pieces = vec![None; size.div_ceil(METADATA_PIECE_SIZE)];
+                true
+            }
+        }
+    }
+
+    pub fn progress(&self) -> MetadataProgress {
+        if self.metadata.is_some() {
+            let pieces = self.size.unwrap_or_default().div_ceil(METADATA_PIECE_SIZE);
+            return MetadataProgress {
+                pieces_received: pieces,
+                pieces_total: Some(pieces),
+            };
+        }
+        MetadataProgress {
+            pieces_received: self.pieces.iter().flatten().count(),
+            pieces_total: self.size.map(|_| self.pieces.len()),
+        }
+    }
+
+    /// The verified info dictionary, once complete.
+    pub fn metadata(&self) -> Option<Arc<Vec<u8>>> {
+        self.metadata.clone()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.metadata.is_some()
+    }
+
+    /// How often the assembled metadata did not match the info hash.
+    pub fn hash_failures(&self) -> u32 {
+        self.hash_failures
+    }
+
+    /// Picks the next piece to request from `peer`: one nobody was asked for,
+    /// or one whose request to another peer timed out.
+    pub fn next_request(&mut self, peer: SocketAddr) -> Option<u32> {
+        let outstanding = self
+            .requested
+            .values()
+            .filter(|(owner, _)| *owner == peer)
+            .count();
+        if self.metadata.is_some() || outstanding >= MAX_REQUESTS_PER_PEER {
+            return None;
+        }
+        let now = Instant::now();
+        let piece = (0..self.pieces.len() as u32).find(|piece| {
+            self.pieces[*piece as usize].is_none()
+                && self.requested.get(piece).is_none_or(|(owner, at)| {
+                    *owner != peer && now.duration_since(*at) >= self.request_timeout
+                })
+        })?;
+        self.requested.insert(piece, (peer, now));
+        Some(piece)
+    }
+
+    /// Stores a piece, returning whether it was accepted. When the last piece
+    /// arrives the metadata is checked against the info hash, and fetched
+    /// again from scratch if it does not match.
+    pub fn on_data(
+        &mut self,
+        peer: SocketAddr,
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    ) -> bool {
+        if self.size.is_none() {
+            self.set_size(total_size as u64);
+        }
+        let Some(size) = self.size.filter(|size| *size == total_size) else {
+            return false;
+        };
+        let index = piece as usize;
+        if self.metadata.is_some() || index >= self.pieces.len() {
+            return false;
+        }
+        if data.len() != (size - index * METADATA_PIECE_SIZE).min(METADATA_PIECE_SIZE) {
+            debug!("unusable metadata piece {} from {}", piece, peer);
+            return false;
+        }
+        self.requested.remove(&piece);
+        self.pieces[index] = Some(data);
+        if self.pieces.iter().all(Option::is_some) {
+            self.verify();
+        }
+        true
+    }
+
+    fn verify(&mut self) {
+        let metadata = self
+            .pieces
+            .drain(..)
+            .flatten()
+            .flatten()
+            .collect::<Vec<_>>();
+        self.requested.clear();
+        if info_hash(&metadata) == self.info_hash {
+            self.metadata = Some(Arc::new(metadata));
+        } else {
+            // the size may have been wrong as well, so start over completely
+            debug!("metadata does not match the info hash");
+            self.hash_failures += 1;
+            self.size = None;
+        }
+    }
+
+    pub fn on_reject(&mut self, peer: SocketAddr, piece: u32) {
+        if self
+            .requested
+            .get(&piece)
+            .is_some_and(|(owner, _)| *owner == peer)
+        {
+            self.requested.remove(&piece);
+        }
+    }
+
+    /// Frees the requests of a peer that went away.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        self.requested.retain(|_, (owner, _)| *owner != peer);
+    }
+}
+
+/// Where a connection gets the info dictionary it serves from.
+#[derive(Clone)]
+pub enum MetadataSource {
+    Complete(Arc<Vec<u8>>),
+    /// Metadata still being fetched; it is served once complete.
+    Fetching(Arc<Mutex<MetadataFetcher>>),
+}
+
+impl MetadataSource {
+    fn metadata(&self) -> Option<Arc<Vec<u8>>> {
+        match self {
+            MetadataSource::Complete(metadata) => Some(Arc::clone(metadata)),
+            MetadataSource::Fetching(fetcher) => fetcher.lock().unwrap().metadata(),
+        }
+    }
+}
+
+/// `ut_metadata` on a single connection, fetching pieces for a magnet link
+/// and serving the metadata to the remote once we have it.
+pub struct MetadataExtension {
+    source: MetadataSource,
+    remote: SocketAddr,
+}
+
+impl MetadataExtension {
+    pub fn new(source: MetadataSource, remote: SocketAddr) -> Self {
+        MetadataExtension { source, remote }
+    }
+
+    fn fetcher(&self) -> Option<&Arc<Mutex<MetadataFetcher>>> {
+        match &self.source {
+            MetadataSource::Fetching(fetcher) => Some(fetcher),
+            MetadataSource::Complete(_) => None,
+        }
+    }
+
+    fn serve(&self, piece: u32) -> MetadataMessage {
+        let Some(metadata) = self.source.metadata() else {
+            return MetadataMessage::Reject(piece);
+        };
+        let begin = piece as usize * METADATA_PIECE_SIZE;
+        match metadata.get(begin..metadata.len().min(begin + METADATA_PIECE_SIZE)) {
+            Some(data) if !data.is_empty() => MetadataMessage::Data {
+                piece,
+                total_size: metadata.len(),
+                data: data.to_vec(),
+            },
+            _ => MetadataMessage::Reject(piece),
+        }
+    }
+}
+
+impl Extension for MetadataExtension {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        if let Some(metadata) = self.source.metadata() {
+            handshake.metadata_size = Some(metadata.len() as u64);
+        }
+    }
+
+    fn on_handshake(&mut self, remote: &ExtendedHandshake) {
+        if let (Some(fetcher), Some(size)) = (self.fetcher(), remote.metadata_size) {
+            if !fetcher.lock().unwrap().set_size(size) {
+                debug!("metadata size {} from {} ignored", size, self.remote);
+            }
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> PwpResult<Vec<Vec<u8>>> {
+        let message = MetadataMessage::decode(payload)
+            .map_err(|e| PwpError::InvalidExtensionMessage(e.to_string()))?;
+        match (message, self.fetcher()) {
+            (Some(MetadataMessage::Request(piece)), _) => {
+                return Ok(vec![self.serve(piece).encode()])
+            }
+            (
+                Some(MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data,
+                }),
+                Some(fetcher),
+            ) => {
+                fetcher
+                    .lock()
+                    .unwrap()
+                    .on_data(self.remote, piece, total_size, data);
+            }
+            (Some(MetadataMessage::Reject(piece)), Some(fetcher)) => {
+                fetcher.lock().unwrap().on_reject(self.remote, piece);
+            }
+            _ => {}
+        }
+        Ok(Vec::new())
+    }
+
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        let Some(fetcher) = self.fetcher() else {
+            return Vec::new();
+        };
+        let mut fetcher = fetcher.lock().unwrap();
+        std::iter::from_fn(|| fetcher.next_request(self.remote))
+            .map(|piece| MetadataMessage::Request(piece).encode())
+            .collect()
+    }
+}
+
+impl Drop for MetadataExtension {
+    fn drop(&mut self) {
+        if let Some(fetcher) = self.fetcher() {
+            fetcher.lock().unwrap().remove_peer(self.remote);
+        }
+    }
+}
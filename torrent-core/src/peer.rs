@@ -1,34 +1,84 @@
+use std::net::{IpAddr, SocketAddr};
+
 use torrent_parser::model::TrackerResponsePeer;
+use torrent_pwp::{bitfield::Bitfield, PeerId};
+
+use crate::fingerprint::{self, ClientInfo};
+
+pub mod session;
 
+#[derive(Debug, Clone)]
 pub struct Peer {
-    pub id: String,
+    /// Known once the handshake is done, or from a tracker.
+    pub id: Option<PeerId>,
     pub ip: String,
     pub port: u16,
     pub am_choking: bool,
     pub am_interested: bool,
     pub peer_choking: bool,
     pub peer_interested: bool,
-    pub bitfield: Vec<u8>,
+    pub bitfield: Bitfield,
+    /// Client name and version from the extended handshake.
+    pub client: Option<String>,
+    /// Listen port from the extended handshake, which may differ from the
+    /// port of an incoming connection.
+    pub listen_port: Option<u16>,
 }
 
 impl PartialEq for Peer {
-    // we consider two peers equal if they have the same id or ip&port
+    // we consider two peers equal if they have the same id or ip&port, peers
+    // found without an id only until the handshake tells us theirs
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id || (self.ip == other.ip && self.port == other.port)
+        (self.id.is_some() && self.id == other.id)
+            || (self.ip == other.ip && self.port == other.port)
+    }
+}
+
+impl Peer {
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        self.ip.parse().ok()
+    }
+
+    /// The client of the peer, from the extended handshake if it sent one,
+    /// as that is more precise, or from the peer id.
+    pub fn client_info(&self) -> Option<ClientInfo> {
+        self.client
+            .as_deref()
+            .and_then(fingerprint::from_extended_handshake)
+            .or_else(|| self.id.as_ref().and_then(fingerprint::from_peer_id))
     }
 }
 
 impl From<TrackerResponsePeer> for Peer {
     fn from(peer: TrackerResponsePeer) -> Self {
         Peer {
-            id: peer.peer_id.unwrap_or_default(),
+            id: peer.peer_id.and_then(|id| PeerId::try_from(id).ok()),
             ip: peer.ip,
             port: peer.port as u16,
             am_choking: true,
             am_interested: false,
             peer_choking: true,
             peer_interested: false,
-            bitfield: vec![0; 0],
+            bitfield: Bitfield::default(),
+            client: None,
+            listen_port: None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer {
+            id: None,
+            ip: addr.ip().to_string(),
+            port: addr.port(),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            bitfield: Bitfield::default(),
+            client: None,
+            listen_port: None,
         }
     }
 }
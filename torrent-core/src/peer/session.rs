@@ -0,0 +1,432 @@
+use std::{collections::HashSet, fmt, net::IpAddr};
+
+use bytes::Bytes;
+use torrent_pwp::{
+    bitfield::Bitfield,
+    extension::{ExtendedHandshake, Extension, ExtensionRegistry},
+    fast::{allowed_fast_set, ALLOWED_FAST_COUNT},
+    handshake::{Feature, Reserved},
+    message::{BlockInfo, Message},
+    InfoHash, PeerId,
+};
+
+use super::Peer;
+
+/// Why a peer was or should be disconnected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// A bitfield, have all or have none arrived after other messages.
+    BitfieldNotFirst,
+    /// A fast extension message without the extension being negotiated.
+    FastNotNegotiated,
+    /// The bitfield has the wrong length or spare bits set.
+    InvalidBitfield,
+    /// An extended message without the extension protocol being negotiated.
+    ExtensionNotNegotiated,
+    /// An extended message that could not be handled.
+    InvalidExtensionMessage(String),
+    /// A message refers to a piece the torrent does not have.
+    InvalidPieceIndex(u32),
+    /// Disconnected by us for a reason outside the protocol, e.g. a timeout.
+    Local(String),
+    /// The peer closed the connection.
+    Closed,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::BitfieldNotFirst => write!(f, "bitfield after first message"),
+            DisconnectReason::FastNotNegotiated => write!(f, "fast extension not negotiated"),
+            DisconnectReason::ExtensionNotNegotiated => {
+                write!(f, "extension protocol not negotiated")
+            }
+            DisconnectReason::InvalidExtensionMessage(e) => {
+                write!(f, "invalid extension message: {}", e)
+            }
+            DisconnectReason::InvalidBitfield => write!(f, "invalid bitfield"),
+            DisconnectReason::InvalidPieceIndex(index) => {
+                write!(f, "invalid piece index {}", index)
+            }
+            DisconnectReason::Local(reason) => write!(f, "{}", reason),
+            DisconnectReason::Closed => write!(f, "closed by peer"),
+        }
+    }
+}
+
+/// What the piece picker and choker need to know about a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer has a new piece.
+    Have(u32),
+    /// The peer announced all pieces it has at once.
+    Bitfield(Vec<u32>),
+    Choked,
+    Unchoked,
+    Interested,
+    NotInterested,
+    Block {
+        index: u32,
+        begin: u32,
+        data: Bytes,
+    },
+    Request(BlockInfo),
+    Cancel(BlockInfo),
+    /// The peer's DHT node listens on this port.
+    Port(u16),
+    /// The peer suggests downloading this piece from it.
+    Suggest(u32),
+    /// The peer will not serve a block we requested.
+    Rejected(BlockInfo),
+    /// We may request this piece even while choked.
+    AllowedFast(u32),
+    /// The peer sent its extended handshake.
+    ExtendedHandshake(ExtendedHandshake),
+}
+
+/// The state of a peer at one point in time, for display.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSnapshot {
+    pub id: Option<PeerId>,
+    pub ip: String,
+    pub port: u16,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// Client name and version, e.g. `qBittorrent 4.6.2`.
+    pub client: Option<String>,
+    pub pieces: usize,
+    pub num_pieces: usize,
+    pub disconnect_reason: Option<DisconnectReason>,
+}
+
+/// The protocol state of one connection: applies incoming messages to the
+/// [`Peer`] flags and availability, and decides on our interest.
+pub struct PeerSession {
+    peer: Peer,
+    num_pieces: usize,
+    received_any: bool,
+    disconnect_reason: Option<DisconnectReason>,
+    fast: bool,
+    // pieces the peer lets us request while choked
+    allowed_fast: HashSet<u32>,
+    // pieces we let the peer request while choked
+    allowed_for_peer: HashSet<u32>,
+    extended: bool,
+    extensions: ExtensionRegistry,
+    remote_reqq: Option<u32>,
+    outgoing: Vec<Message>,
+}
+
+impl PeerSession {
+    pub fn new(peer: Peer, num_pieces: usize) -> Self {
+        PeerSession::with_extensions(peer, num_pieces, Reserved::default())
+    }
+
+    /// A session for a connection where `extensions` were negotiated in the
+    /// handshake.
+    pub fn with_extensions(mut peer: Peer, num_pieces: usize, extensions: Reserved) -> Self {
+        peer.bitfield = Bitfield::new(num_pieces);
+        PeerSession {
+            peer,
+            num_pieces,
+            received_any: false,
+            disconnect_reason: None,
+            fast: extensions.supports(Feature::FAST),
+            allowed_fast: HashSet::new(),
+            allowed_for_peer: HashSet::new(),
+            extended: extensions.supports(Feature::EXTENSION),
+            extensions: ExtensionRegistry::new(),
+            remote_reqq: None,
+            outgoing: Vec::new(),
+        }
+    }
+
+    pub fn fast_enabled(&self) -> bool {
+        self.fast
+    }
+
+    /// The first message to send, announcing the pieces we have. With the
+    /// fast extension the common cases need no bitfield.
+    pub fn initial_message(&self, ours: &Bitfield) -> Message {
+        match (self.fast, ours.count_ones()) {
+            (true, 0) => Message::HaveNone,
+            (true, _) if ours.is_full() => Message::HaveAll,
+            _ => Message::Bitfield(ours.as_bytes().to_vec().into()),
+        }
+    }
+
+    /// Computes the allowed fast set for the peer and returns the messages
+    /// announcing it. Nothing is allowed without the fast extension.
+    pub fn allow_fast(&mut self, info_hash: &InfoHash) -> Vec<Message> {
+        let Some(ip) = self
+            .fast
+            .then(|| self.peer.ip.parse::<IpAddr>().ok())
+            .flatten()
+        else {
+            return Vec::new();
+        };
+        let set = allowed_fast_set(ip, info_hash, self.num_pieces as u32, ALLOWED_FAST_COUNT);
+        self.allowed_for_peer.extend(set.iter().copied());
+        set.into_iter().map(Message::AllowedFast).collect()
+    }
+
+    /// Whether we may request blocks of `index` from the peer right now.
+    pub fn can_request(&self, index: u32) -> bool {
+        !self.peer.peer_choking || self.allowed_fast.contains(&index)
+    }
+
+    /// Whether the peer may request blocks of `index` while we choke it.
+    pub fn peer_allowed_fast(&self, index: u32) -> bool {
+        self.allowed_for_peer.contains(&index)
+    }
+
+    pub fn extensions_enabled(&self) -> bool {
+        self.extended
+    }
+
+    /// Registers a BEP 10 extension for this connection. Must happen before
+    /// the extended handshake is sent.
+    pub fn register_extension(&mut self, extension: Box<dyn Extension>) -> u8 {
+        self.extensions.register(extension)
+    }
+
+    /// Our extended handshake, if the extension protocol was negotiated.
+    pub fn extended_handshake(&self, base: ExtendedHandshake) -> Option<Message> {
+        self.extended
+            .then(|| self.extensions.handshake_message(base))
+    }
+
+    /// Builds a message for extension `name` if the peer supports it.
+    pub fn extension_message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        self.extensions.message(name, payload)
+    }
+
+    /// Messages extensions want to send on their own.
+    pub fn poll_extensions(&mut self) -> Vec<Message> {
+        self.extensions.poll()
+    }
+
+    /// How many outstanding requests the peer accepts, if it told us.
+    pub fn remote_reqq(&self) -> Option<u32> {
+        self.remote_reqq
+    }
+
+    /// Messages the session decided to send on its own, such as rejects.
+    pub fn drain_outgoing(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    pub fn disconnect_reason(&self) -> Option<&DisconnectReason> {
+        self.disconnect_reason.as_ref()
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnect_reason.is_some()
+    }
+
+    /// Records why the peer is disconnected. The first reason wins.
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        self.disconnect_reason.get_or_insert(reason);
+    }
+
+    fn fail(&mut self, reason: DisconnectReason) -> Result<Vec<PeerEvent>, DisconnectReason> {
+        self.disconnect(reason.clone());
+        Err(reason)
+    }
+
+    fn check_index(&mut self, index: u32) -> Result<(), DisconnectReason> {
+        if index as usize >= self.num_pieces {
+            self.fail(DisconnectReason::InvalidPieceIndex(index))?;
+        }
+        Ok(())
+    }
+
+    /// Applies a message from the peer. An error means the peer broke the
+    /// protocol and must be disconnected; the reason is recorded.
+    pub fn receive(&mut self, message: Message) -> Result<Vec<PeerEvent>, DisconnectReason> {
+        if let Some(reason) = &self.disconnect_reason {
+            return Err(reason.clone());
+        }
+        let first = !self.received_any;
+        // some clients send the extended handshake before their bitfield
+        if !matches!(message, Message::KeepAlive | Message::Extended { .. }) {
+            self.received_any = true;
+        }
+
+        let event = match message {
+            Message::KeepAlive => return Ok(Vec::new()),
+            Message::Choke => {
+                self.peer.peer_choking = true;
+                PeerEvent::Choked
+            }
+            Message::Unchoke => {
+                self.peer.peer_choking = false;
+                PeerEvent::Unchoked
+            }
+            Message::Interested => {
+                self.peer.peer_interested = true;
+                PeerEvent::Interested
+            }
+            Message::NotInterested => {
+                self.peer.peer_interested = false;
+                PeerEvent::NotInterested
+            }
+            Message::Have(index) => {
+                self.check_index(index)?;
+                if self.peer.bitfield.set(index as usize, true) {
+                    return Ok(Vec::new());
+                }
+                PeerEvent::Have(index)
+            }
+            Message::SuggestPiece(_)
+            | Message::HaveAll
+            | Message::HaveNone
+            | Message::RejectRequest(_)
+            | Message::AllowedFast(_)
+                if !self.fast =>
+            {
+                return self.fail(DisconnectReason::FastNotNegotiated);
+            }
+            Message::Extended { .. } if !self.extended => {
+                return self.fail(DisconnectReason::ExtensionNotNegotiated);
+            }
+            Message::Extended { id, payload } => {
+                let output = match self.extensions.receive(id, &payload) {
+                    Ok(output) => output,
+                    Err(e) => {
+                        return self.fail(DisconnectReason::InvalidExtensionMessage(e.to_string()))
+                    }
+                };
+                self.outgoing.extend(output.messages);
+                let Some(handshake) = output.handshake else {
+                    return Ok(Vec::new());
+                };
+                if handshake.client.is_some() {
+                    self.peer.client = handshake.client.clone();
+                }
+                if handshake.listen_port.is_some() {
+                    self.peer.listen_port = handshake.listen_port;
+                }
+                if handshake.reqq.is_some() {
+                    self.remote_reqq = handshake.reqq;
+                }
+                PeerEvent::ExtendedHandshake(handshake)
+            }
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone if !first => {
+                return self.fail(DisconnectReason::BitfieldNotFirst);
+            }
+            Message::HaveAll => {
+                self.peer.bitfield = Bitfield::full(self.num_pieces);
+                PeerEvent::Bitfield((0..self.num_pieces as u32).collect())
+            }
+            Message::HaveNone => return Ok(Vec::new()),
+            Message::Bitfield(bitfield) => {
+                match Bitfield::from_bytes(&bitfield, self.num_pieces) {
+                    Ok(bitfield) => self.peer.bitfield = bitfield,
+                    Err(_) => return self.fail(DisconnectReason::InvalidBitfield),
+                }
+                PeerEvent::Bitfield(
+                    self.peer
+                        .bitfield
+                        .iter_set()
+                        .map(|index| index as u32)
+                        .collect(),
+                )
+            }
+            Message::Request(block) => {
+                self.check_index(block.index)?;
+                if self.peer.am_choking && !self.allowed_for_peer.contains(&block.index) {
+                    // requests while choked are dropped, the peer may not
+                    // know yet; with the fast extension they are rejected
+                    if self.fast {
+                        self.outgoing.push(Message::RejectRequest(block));
+                    }
+                    return Ok(Vec::new());
+                }
+                PeerEvent::Request(block)
+            }
+            Message::Piece { index, begin, data } => {
+                self.check_index(index)?;
+                PeerEvent::Block { index, begin, data }
+            }
+            Message::Cancel(block) => {
+                self.check_index(block.index)?;
+                PeerEvent::Cancel(block)
+            }
+            Message::Port(port) => PeerEvent::Port(port),
+            Message::SuggestPiece(index) => {
+                self.check_index(index)?;
+                PeerEvent::Suggest(index)
+            }
+            Message::RejectRequest(block) => {
+                self.check_index(block.index)?;
+                PeerEvent::Rejected(block)
+            }
+            Message::AllowedFast(index) => {
+                self.check_index(index)?;
+                if !self.allowed_fast.insert(index) {
+                    return Ok(Vec::new());
+                }
+                PeerEvent::AllowedFast(index)
+            }
+        };
+        Ok(vec![event])
+    }
+
+    /// Whether the peer has a piece that `ours` lacks.
+    pub fn has_interesting_pieces(&self, ours: &Bitfield) -> bool {
+        self.peer.bitfield.has_interesting(ours)
+    }
+
+    /// Re-evaluates our interest against the pieces we have, returning the
+    /// message to send if it changed.
+    pub fn update_interest(&mut self, ours: &Bitfield) -> Option<Message> {
+        let interested = self.has_interesting_pieces(ours);
+        if interested == self.peer.am_interested {
+            return None;
+        }
+        self.peer.am_interested = interested;
+        Some(match interested {
+            true => Message::Interested,
+            false => Message::NotInterested,
+        })
+    }
+
+    /// Returns the message to send if we were not choking the peer already.
+    pub fn choke(&mut self) -> Option<Message> {
+        (!self.peer.am_choking).then(|| {
+            self.peer.am_choking = true;
+            Message::Choke
+        })
+    }
+
+    /// Returns the message to send if we were choking the peer.
+    pub fn unchoke(&mut self) -> Option<Message> {
+        self.peer.am_choking.then(|| {
+            self.peer.am_choking = false;
+            Message::Unchoke
+        })
+    }
+
+    pub fn snapshot(&self) -> PeerSnapshot {
+        PeerSnapshot {
+            id: self.peer.id,
+            ip: self.peer.ip.clone(),
+            port: self.peer.port,
+            am_choking: self.peer.am_choking,
+            am_interested: self.peer.am_interested,
+            peer_choking: self.peer.peer_choking,
+            peer_interested: self.peer.peer_interested,
+            client: self.peer.client_info().map(|client| client.to_string()),
+            pieces: self.peer.bitfield.count_ones(),
+            num_pieces: self.num_pieces,
+            disconnect_reason: self.disconnect_reason.clone(),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::BitOr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+use torrent_parser::{
+    field::{dict, Field},
+    parse_bencode,
+};
+use torrent_pwp::{
+    error::{PwpError, PwpResult},
+    extension::{ExtendedHandshake, Extension},
+};
+use tracing::debug;
+
+use crate::error::{RustyTorrentError, RustyTorrentResult};
+
+/// Name of the extension in the BEP 10 handshake.
+pub const EXTENSION_NAME: &str = "ut_pex";
+/// PEX messages are sent at most once a minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// BEP 11 limits each of added and dropped to 50 peers per message.
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+// leave some slack for timer jitter on the remote side
+const MIN_INCOMING_INTERVAL: Duration = Duration::from_secs(45);
+// remote peers that keep flooding us are ignored entirely
+const MAX_VIOLATIONS: u32 = 3;
+
+/// Per-peer flags sent in `added.f`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    pub const NONE: PexFlags = PexFlags(0);
+    pub const ENCRYPTION: PexFlags = PexFlags(0x01);
+    pub const SEED: PexFlags = PexFlags(0x02);
+    pub const UTP: PexFlags = PexFlags(0x04);
+    pub const HOLEPUNCH: PexFlags = PexFlags(0x08);
+    pub const OUTGOING: PexFlags = PexFlags(0x10);
+
+    pub fn contains(&self, other: PexFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PexFlags {
+    type Output = PexFlags;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        PexFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut added = Vec::new();
+        let mut added_flags = Vec::new();
+        let mut added6 = Vec::new();
+        let mut added6_flags = Vec::new();
+        for (addr, flags) in &self.added {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    added.extend_from_slice(&ip.octets());
+                    added.extend_from_slice(&addr.port().to_be_bytes());
+                    added_flags.push(flags.0);
+                }
+                IpAddr::V6(ip) => {
+                    added6.extend_from_slice(&ip.octets());
+                    added6.extend_from_slice(&addr.port().to_be_bytes());
+                    added6_flags.push(flags.0);
+                }
+            }
+        }
+
+        let mut dropped = Vec::new();
+        let mut dropped6 = Vec::new();
+        for addr in &self.dropped {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    dropped.extend_from_slice(&ip.octets());
+                    dropped.extend_from_slice(&addr.port().to_be_bytes());
+                }
+                IpAddr::V6(ip) => {
+                    dropped6.extend_from_slice(&ip.octets());
+                    dropped6.extend_from_slice(&addr.port().to_be_bytes());
+                }
+            }
+        }
+
+        dict([
+            ("added", Field::String(added)),
+            ("added.f", Field::String(added_flags)),
+            ("added6", Field::String(added6)),
+            ("added6.f", Field::String(added6_flags)),
+            ("dropped", Field::String(dropped)),
+            ("dropped6", Field::String(dropped6)),
+        ])
+        .encode()
+    }
+
+    pub fn decode(payload: &[u8]) -> RustyTorrentResult<PexMessage> {
+        let root = parse_bencode(payload.to_vec())?;
+        if root.as_dict().is_none() {
+            return Err(RustyTorrentError::InvalidPexMessage(
+                "not a dictionary".to_string(),
+            ));
+        }
+        let bytes = |key: &str| root.get(key).and_then(Field::as_bytes).unwrap_or_default();
+
+        let mut added = Vec::new();
+        for (key, flags_key, len) in [("added", "added.f", 6), ("added6", "added6.f", 18)] {
+            let addrs = compact_addrs(bytes(key), len, key)?;
+            let flags = bytes(flags_key);
+            added.extend(addrs.into_iter().enumerate().map(|(i, addr)| {
+                // flags are optional, missing ones default to none
+                (addr, PexFlags(flags.get(i).copied().unwrap_or_default()))
+            }));
+        }
+
+        let mut dropped = compact_addrs(bytes("dropped"), 6, "dropped")?;
+        dropped.extend(compact_addrs(bytes("dropped6"), 18, "dropped6")?);
+
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+fn compact_addrs(bytes: &[u8], len: usize, key: &str) -> RustyTorrentResult<Vec<SocketAddr>> {
+    if !bytes.len().is_multiple_of(len) {
+        return Err(RustyTorrentError::InvalidPexMessage(format!(
+            "invalid length for {}",
+            key
+        )));
+    }
+    Ok(bytes
+        .chunks_exact(len)
+        .map(|chunk| {
+            let (ip, port) = chunk.split_at(len - 2);
+            let ip = match ip.len() {
+                4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())),
+                _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect())
+}
+
+/// Whether `addr` could plausibly be a peer.
+fn is_valid_peer(addr: &SocketAddr) -> bool {
+    if addr.port() == 0 {
+        return false;
+    }
+    match addr.ip() {
+        IpAddr::V4(ip) => !(ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast()),
+        IpAddr::V6(ip) => !(ip.is_unspecified() || ip.is_multicast()),
+    }
+}
+
+/// PEX bookkeeping for a single connection.
+///
+/// Outgoing messages carry the difference between the peers we are connected
+/// to now and the peers we told the remote about last time. Incoming messages
+/// are rate limited and stripped of addresses that cannot be peers.
+pub struct PexState {
+    interval: Duration,
+    advertised: HashMap<SocketAddr, PexFlags>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    violations: u32,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        PexState::with_interval(PEX_INTERVAL)
+    }
+
+    pub fn with_interval(interval: Duration) -> Self {
+        PexState {
+            interval,
+            advertised: HashMap::new(),
+            last_sent: None,
+            last_received: None,
+            violations: 0,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_sent
+            .is_none_or(|last_sent| last_sent.elapsed() >= self.interval)
+    }
+
+    /// Builds the next message from the currently connected peers, excluding
+    /// the remote itself, by the address of the connection and by its listen
+    /// address if known. Returns `None` if it is not time yet or nothing
+    /// changed.
+    pub fn next_message(
+        &mut self,
+        remote: SocketAddr,
+        listen_addr: Option<SocketAddr>,
+        connected: &HashMap<SocketAddr, PexFlags>,
+    ) -> Option<PexMessage> {
+        if !self.is_due() {
+            return None;
+        }
+
+        let is_remote = |addr: &SocketAddr| *addr == remote || Some(*addr) == listen_addr;
+        let added = connected
+            .iter()
+            .filter(|(addr, flags)| !is_remote(addr) && self.advertised.get(addr) != Some(flags))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .map(|(addr, flags)| (*addr, *flags))
+            .collect::<Vec<_>>();
+        let dropped = self
+            .advertised
+            .keys()
+            .filter(|addr| !connected.contains_key(addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let message = PexMessage { added, dropped };
+        if message.is_empty() {
+            return None;
+        }
+        // whatever did not fit is picked up by the next message
+        for (addr, flags) in &message.added {
+            self.advertised.insert(*addr, *flags);
+        }
+        for addr in &message.dropped {
+            self.advertised.remove(addr);
+        }
+        self.last_sent = Some(Instant::now());
+        Some(message)
+    }
+
+    /// Checks an incoming message and returns the peers worth adding.
+    pub fn receive(&mut self, message: PexMessage) -> RustyTorrentResult<Vec<SocketAddr>> {
+        if self.violations >= MAX_VIOLATIONS {
+            return Err(RustyTorrentError::PexRateLimited);
+        }
+        let too_soon = self
+            .last_received
+            .is_some_and(|last| last.elapsed() < MIN_INCOMING_INTERVAL);
+        if too_soon
+            || message.added.len() > MAX_PEERS_PER_MESSAGE
+            || message.dropped.len() > MAX_PEERS_PER_MESSAGE
+        {
+            self.violations += 1;
+            return Err(RustyTorrentError::PexRateLimited);
+        }
+        self.last_received = Some(Instant::now());
+
+        Ok(message
+            .added
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .filter(is_valid_peer)
+            .collect())
+    }
+}
+
+impl Default for PexState {
+    fn default() -> Self {
+        PexState::new()
+    }
+}
+
+/// `ut_pex` on a single connection.
+///
+/// Connected peers are read from `connected`, which the torrent keeps up to
+/// date; peers learned from the remote are sent to `discovered`. Must not be
+/// registered for private torrents.
+pub struct PexExtension {
+    state: PexState,
+    remote: SocketAddr,
+    // from the remote's extended handshake
+    listen_addr: Option<SocketAddr>,
+    connected: Arc<Mutex<HashMap<SocketAddr, PexFlags>>>,
+    discovered: UnboundedSender<Vec<SocketAddr>>,
+}
+
+impl PexExtension {
+    pub fn new(
+        state: PexState,
+        remote: SocketAddr,
+        connected: Arc<Mutex<HashMap<SocketAddr, PexFlags>>>,
+        discovered: UnboundedSender<Vec<SocketAddr>>,
+    ) -> Self {
+        PexExtension {
+            state,
+            remote,
+            listen_addr: None,
+            connected,
+            discovered,
+        }
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &'static str {
+        EXTENSION_NAME
+    }
+
+    fn on_handshake(&mut self, remote: &ExtendedHandshake) {
+        if let Some(port) = remote.listen_port {
+            self.listen_addr = Some(SocketAddr::new(self.remote.ip(), port));
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> PwpResult<Vec<Vec<u8>>> {
+        let message = PexMessage::decode(payload)
+            .map_err(|e| PwpError::InvalidExtensionMessage(e.to_string()))?;
+        match self.state.receive(message) {
+            Ok(peers) if !peers.is_empty() => {
+                let _ = self.discovered.send(peers);
+            }
+            Ok(_) => {}
+            // flooding is not worth a disconnect, the messages are ignored
+            Err(e) => debug!("pex from {} ignored: {}", self.remote, e),
+        }
+        Ok(Vec::new())
+    }
+
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        let connected = self.connected.lock().unwrap();
+        self.state
+            .next_message(self.remote, self.listen_addr, &connected)
+            .map(|message| message.encode())
+            .into_iter()
+            .collect()
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+    time::Instant,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use torrent_pwp::bitfield::Bitfield;
+
+/// How much we want a piece or file. Pieces shared by several files get the
+/// highest priority of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Not downloaded at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone)]
+pub struct PickerConfig {
+    /// Pieces are picked at random until this many are complete, so there is
+    /// something to trade quickly instead of waiting for the rarest pieces.
+    pub random_first_pieces: usize,
+    /// Seeds the random choices; the same seed and calls give the same picks.
+    pub seed: u64,
+    /// While streaming, every this many picks come from the normal order so
+    /// other pieces keep downloading in the background. Zero turns that off.
+    pub background_interval: usize,
+}
+
+impl Default for PickerConfig {
+    fn default() -> Self {
+        PickerConfig {
+            random_first_pieces: 4,
+            seed: rand::random(),
+            background_interval: 4,
+        }
+    }
+}
+
+/// A piece being downloaded, as seen by the picker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InProgress {
+    pub index: u32,
+    /// Some blocks are neither received nor requested.
+    pub has_free_blocks: bool,
+    /// A slow peer is downloading part of it.
+    pub slow: bool,
+}
+
+/// Orders the pieces to download from a peer: partial pieces first, then new
+/// pieces at random for the first few and rarest first after that, always
+/// by priority. Pieces with a deadline or in sequential files go ahead of
+/// that order. Availability is counted from the bitfields and `have` messages
+/// of the connected peers.
+pub struct PiecePicker {
+    config: PickerConfig,
+    rng: StdRng,
+    piece_length: u64,
+    availability: Vec<u32>,
+    // file priorities by offset, with the file length
+    file_priorities: BTreeMap<u64, (u64, Priority)>,
+    piece_priorities: Vec<Option<Priority>>,
+    priorities: Vec<Priority>,
+    sequential: bool,
+    // sequential files by offset, with the file length
+    sequential_files: BTreeMap<u64, u64>,
+    sequential_pieces: Vec<bool>,
+    deadlines: HashMap<u32, Instant>,
+}
+
+impl PiecePicker {
+    pub fn new(piece_length: u64, num_pieces: usize, config: PickerConfig) -> Self {
+        PiecePicker {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            piece_length,
+            availability: vec![0; num_pieces],
+            file_priorities: BTreeMap::new(),
+            piece_priorities: vec![None; num_pieces],
+            priorities: vec![Priority::Normal; num_pieces],
+            sequential: false,
+            sequential_files: BTreeMap::new(),
+            sequential_pieces: vec![false; num_pieces],
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Number of connected peers with the piece.
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability
+            .get(index as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// A peer connected with these pieces.
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            self.add_have(index as u32);
+        }
+    }
+
+    /// A peer with these pieces went away.
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_have(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn priority(&self, index: u32) -> Priority {
+        self.priorities
+            .get(index as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Overrides the priority the piece gets from its files.
+    pub fn set_piece_priority(&mut self, index: u32, priority: Priority) {
+        if let Some(piece) = self.piece_priorities.get_mut(index as usize) {
+            *piece = Some(priority);
+            self.update_priorities(index as usize..index as usize + 1);
+        }
+    }
+
+    /// Sets the priority of the file at `offset` in the torrent.
+    pub fn set_file_priority(&mut self, offset: u64, length: u64, priority: Priority) {
+        self.file_priorities.insert(offset, (length, priority));
+        self.update_priorities(self.pieces_of(offset..offset + length));
+    }
+
+    fn pieces_of(&self, bytes: Range<u64>) -> Range<usize> {
+        if self.piece_length == 0 || bytes.is_empty() {
+            return 0..0;
+        }
+        let end = (bytes.end.div_ceil(self.piece_length) as usize).min(self.priorities.len());
+        (bytes.start / self.piece_length) as usize..end
+    }
+
+    fn update_priorities(&mut self, pieces: Range<usize>) {
+        for index in pieces {
+            let start = index as u64 * self.piece_length;
+            let end = start + self.piece_length;
+            let from_files = self
+                .file_priorities
+                .range(..end)
+                .filter(|(offset, (length, _))| **offset + *length > start)
+                .map(|(_, (_, priority))| *priority)
+                .max()
+                .unwrap_or_default();
+            self.priorities[index] = self.piece_priorities[index].unwrap_or(from_files);
+            self.sequential_pieces[index] = self
+                .sequential_files
+                .range(..end)
+                .any(|(offset, length)| *offset + *length > start);
+        }
+    }
+
+    /// Downloads the whole torrent in order, e.g. to follow a log while it
+    /// downloads.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    /// Downloads the file at `offset` in the torrent in order.
+    pub fn set_file_sequential(&mut self, offset: u64, length: u64, sequential: bool) {
+        match sequential {
+            true => self.sequential_files.insert(offset, length),
+            false => self.sequential_files.remove(&offset),
+        };
+        self.update_priorities(self.pieces_of(offset..offset + length));
+    }
+
+    /// Moves the piece ahead of the others until it is cleared; pieces with
+    /// earlier deadlines come first. It is downloaded even if skipped.
+    pub fn set_deadline(&mut self, index: u32, deadline: Instant) {
+        if (index as usize) < self.priorities.len() {
+            self.deadlines.insert(index, deadline);
+        }
+    }
+
+    /// Sets a deadline for the pieces holding the `length` bytes at `offset`.
+    pub fn set_range_deadline(&mut self, offset: u64, length: u64, deadline: Instant) {
+        for index in self.pieces_of(offset..offset + length) {
+            self.set_deadline(index as u32, deadline);
+        }
+    }
+
+    pub fn deadline(&self, index: u32) -> Option<Instant> {
+        self.deadlines.get(&index).copied()
+    }
+
+    /// Removes the deadline, also done by the scheduler once the piece passed
+    /// its hash check.
+    pub fn clear_deadline(&mut self, index: u32) {
+        self.deadlines.remove(&index);
+    }
+
+    /// Pieces to request from a peer with the pieces in `available`, best
+    /// first. Pieces with a deadline and sequential pieces come first, with
+    /// every few picks taken from the normal order so the swarm keeps getting
+    /// rare pieces from us. In the normal order partial pieces come first,
+    /// except those a slow peer is working on when `peer_is_slow` is false: a
+    /// fast peer joining them would wait for the slow one to finish the piece,
+    /// so they come last. At most `new_pieces` pieces not yet started are
+    /// included from each order.
+    pub fn pick(
+        &mut self,
+        available: &Bitfield,
+        have: &Bitfield,
+        in_progress: &[InProgress],
+        peer_is_slow: bool,
+        new_pieces: usize,
+    ) -> Vec<u32> {
+        let wanted = |index: u32| {
+            available.has(index as usize)
+                && !have.has(index as usize)
+                && self.priority(index) != Priority::Skip
+        };
+        let started = in_progress
+            .iter()
+            .map(|piece| (piece.index, piece.has_free_blocks))
+            .collect::<HashMap<_, _>>();
+        let startable = |index: u32| started.get(&index).copied().unwrap_or(true);
+
+        let mut deadlines = self
+            .deadlines
+            .iter()
+            .filter(|(index, _)| {
+                available.has(**index as usize) && !have.has(**index as usize) && startable(**index)
+            })
+            .map(|(index, deadline)| (*deadline, *index))
+            .collect::<Vec<_>>();
+        deadlines.sort();
+        let mut streaming = deadlines
+            .into_iter()
+            .map(|(_, index)| index)
+            .collect::<Vec<_>>();
+        streaming.extend(
+            available
+                .iter_set()
+                .map(|index| index as u32)
+                .filter(|index| {
+                    (self.sequential || self.sequential_pieces[*index as usize])
+                        && !self.deadlines.contains_key(index)
+                        && wanted(*index)
+                        && startable(*index)
+                }),
+        );
+        streaming.truncate(new_pieces + in_progress.len());
+        let streamed = streaming.iter().copied().collect::<HashSet<_>>();
+
+        let mut partial = in_progress
+            .iter()
+            .filter(|piece| {
+                piece.has_free_blocks && wanted(piece.index) && !streamed.contains(&piece.index)
+            })
+            .collect::<Vec<_>>();
+        partial.sort_by_key(|piece| {
+            (
+                Reverse(self.priority(piece.index)),
+                self.availability(piece.index),
+                piece.index,
+            )
+        });
+        let (matching, mismatched): (Vec<&InProgress>, Vec<_>) = partial
+            .into_iter()
+            .partition(|piece| peer_is_slow || !piece.slow);
+
+        let mut fresh = available
+            .iter_set()
+            .map(|index| index as u32)
+            .filter(|index| {
+                wanted(*index) && !started.contains_key(index) && !streamed.contains(index)
+            })
+            .collect::<Vec<_>>();
+        // shuffling first breaks ties at random
+        fresh.shuffle(&mut self.rng);
+        if have.count_ones() < self.config.random_first_pieces {
+            fresh.sort_by_key(|index| Reverse(self.priority(*index)));
+        } else {
+            fresh.sort_by_key(|index| (Reverse(self.priority(*index)), self.availability(*index)));
+        }
+        fresh.truncate(new_pieces);
+
+        let mut background = matching
+            .into_iter()
+            .map(|piece| piece.index)
+            .chain(fresh)
+            .chain(mismatched.into_iter().map(|piece| piece.index));
+        let mut streaming = streaming.into_iter();
+        let mut picks = Vec::new();
+        loop {
+            let background_turn = self.config.background_interval > 0
+                && (picks.len() + 1).is_multiple_of(self.config.background_interval);
+            let next = match background_turn {
+                true => background.next().or_else(|| streaming.next()),
+                false => streaming.next().or_else(|| background.next()),
+            };
+            match next {
+                Some(index) => picks.push(index),
+                None => return picks,
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Transfer rate over a sliding window.
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    window: Duration,
+    started: Option<Instant>,
+    samples: VecDeque<(Instant, u64)>,
+    // bytes in `samples`
+    bytes: u64,
+    total: u64,
+}
+
+impl RateMeter {
+    pub fn new(window: Duration) -> Self {
+        RateMeter {
+            window,
+            started: None,
+            samples: VecDeque::new(),
+            bytes: 0,
+            total: 0,
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64, now: Instant) {
+        self.started.get_or_insert(now);
+        self.expire(now);
+        self.samples.push_back((now, bytes));
+        self.bytes += bytes;
+        self.total += bytes;
+    }
+
+    /// Bytes per second. A meter younger than its window divides by its age,
+    /// but by at least a second so a single sample does not look like a burst.
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        let Some(started) = self.started else {
+            return 0.0;
+        };
+        self.expire(now);
+        let elapsed = now.duration_since(started).clamp(
+            Duration::from_secs(1),
+            self.window.max(Duration::from_secs(1)),
+        );
+        self.bytes as f64 / elapsed.as_secs_f64()
+    }
+
+    /// Bytes counted since the meter was created.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, bytes)) = self.samples.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            self.bytes -= bytes;
+            self.samples.pop_front();
+        }
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        RateMeter::new(Duration::from_secs(5))
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
+
+use crate::session::TorrentId;
+
+/// Limits are raised to this many bytes per second, so no peer is cut off.
+pub const MIN_RATE: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Bytes per second in each direction, `None` for no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn get(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthConfig {
+    pub global: RateLimit,
+    /// Peers on the local network are not limited or counted.
+    pub exempt_lan: bool,
+    /// Protocol overhead, such as headers and control messages, counts
+    /// against the limits besides the payload.
+    pub count_overhead: bool,
+}
+
+/// Bytes waiting for bandwidth that may now be sent or read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Grant {
+    pub peer: SocketAddr,
+    pub direction: Direction,
+    pub bytes: u64,
+}
+
+// holds up to one second of its rate; a grant may take it below zero, which
+// later refills pay back
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Option<u64>, now: Instant) -> Self {
+        let rate = rate.map(|rate| rate.max(MIN_RATE));
+        TokenBucket {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            last: now,
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+        self.refill(now);
+        self.rate = rate.map(|rate| rate.max(MIN_RATE));
+        if let Some(rate) = self.rate {
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if let Some(rate) = self.rate {
+            self.tokens = (self.tokens + rate as f64 * elapsed).min(rate as f64);
+        }
+    }
+
+    fn has_tokens(&self) -> bool {
+        self.rate.is_none() || self.tokens > 0.0
+    }
+
+    fn take(&mut self, bytes: u64) {
+        if self.rate.is_some() {
+            self.tokens -= bytes as f64;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Buckets {
+            upload: TokenBucket::new(limit.upload, now),
+            download: TokenBucket::new(limit.download, now),
+        }
+    }
+
+    fn get(&self, direction: Direction) -> &TokenBucket {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+
+    fn get_mut(&mut self, direction: Direction) -> &mut TokenBucket {
+        match direction {
+            Direction::Upload => &mut self.upload,
+            Direction::Download => &mut self.download,
+        }
+    }
+
+    fn set_limit(&mut self, limit: RateLimit, now: Instant) {
+        self.upload.set_rate(limit.upload, now);
+        self.download.set_rate(limit.download, now);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.upload.refill(now);
+        self.download.refill(now);
+    }
+}
+
+#[derive(Debug)]
+struct LimitedPeer {
+    torrent: TorrentId,
+    buckets: Buckets,
+}
+
+/// Token buckets for the whole session, each torrent and each peer, in both
+/// directions. Bytes pass once all buckets above the peer have tokens. It
+/// does no IO: connections ask for bandwidth before sending or reading and
+/// go ahead with what [`BandwidthLimiter::poll`] grants. Requests are served
+/// in order, and a request skipped for its own peer's or torrent's limit
+/// keeps its place, so every peer gets its turn.
+pub struct BandwidthLimiter {
+    config: BandwidthConfig,
+    global: Buckets,
+    torrents: HashMap<TorrentId, Buckets>,
+    peers: HashMap<SocketAddr, LimitedPeer>,
+    queue: VecDeque<Grant>,
+    // granted by `poll_peer` to other peers, until their connections ask
+    granted: HashMap<(SocketAddr, Direction), u64>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(config: BandwidthConfig, now: Instant) -> Self {
+        BandwidthLimiter {
+            global: Buckets::new(config.global, now),
+            config,
+            torrents: HashMap::new(),
+            peers: HashMap::new(),
+            queue: VecDeque::new(),
+            granted: HashMap::new(),
+        }
+    }
+
+    pub fn set_global_limit(&mut self, limit: RateLimit, now: Instant) {
+        self.config.global = limit;
+        self.global.set_limit(limit, now);
+    }
+
+    pub fn global_limit(&self) -> RateLimit {
+        self.config.global
+    }
+
+    pub fn set_torrent_limit(&mut self, torrent: TorrentId, limit: RateLimit, now: Instant) {
+        match self.torrents.get_mut(&torrent) {
+            Some(buckets) => buckets.set_limit(limit, now),
+            None => {
+                self.torrents.insert(torrent, Buckets::new(limit, now));
+            }
+        }
+    }
+
+    /// Limits a peer added with [`BandwidthLimiter::add_peer`], returning
+    /// whether it is known.
+    pub fn set_peer_limit(&mut self, peer: SocketAddr, limit: RateLimit, now: Instant) -> bool {
+        match self.peers.get_mut(&peer) {
+            Some(limited) => {
+                limited.buckets.set_limit(limit, now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_exempt_lan(&mut self, exempt_lan: bool) {
+        self.config.exempt_lan = exempt_lan;
+    }
+
+    pub fn set_count_overhead(&mut self, count_overhead: bool) {
+        self.config.count_overhead = count_overhead;
+    }
+
+    /// A peer connected for `torrent`, without a limit of its own.
+    pub fn add_peer(&mut self, peer: SocketAddr, torrent: TorrentId, now: Instant) {
+        self.peers.entry(peer).or_insert_with(|| LimitedPeer {
+            torrent,
+            buckets: Buckets::new(RateLimit::default(), now),
+        });
+    }
+
+    /// Forgets a peer along with its waiting requests.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        self.peers.remove(&peer);
+        self.queue.retain(|request| request.peer != peer);
+        self.granted.retain(|(granted, _), _| *granted != peer);
+    }
+
+    pub fn remove_torrent(&mut self, torrent: TorrentId) {
+        self.torrents.remove(&torrent);
+    }
+
+    /// Asks for bandwidth to send or read `bytes` of payload, granted by a
+    /// later [`BandwidthLimiter::poll`].
+    pub fn request(&mut self, peer: SocketAddr, direction: Direction, bytes: u64) {
+        self.queue.push_back(Grant {
+            peer,
+            direction,
+            bytes,
+        });
+    }
+
+    /// Counts protocol overhead sent or received, if enabled. It is never
+    /// held back, but leaves less for the payload.
+    pub fn add_overhead(&mut self, peer: SocketAddr, direction: Direction, bytes: u64) {
+        if self.config.count_overhead && !self.is_exempt(peer) {
+            self.take(peer, direction, bytes);
+        }
+    }
+
+    /// Requests still waiting.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Hands out the bandwidth that became available, oldest requests first.
+    pub fn poll(&mut self, now: Instant) -> Vec<Grant> {
+        self.global.refill(now);
+        for buckets in self.torrents.values_mut() {
+            buckets.refill(now);
+        }
+        for peer in self.peers.values_mut() {
+            peer.buckets.refill(now);
+        }
+
+        let mut grants = Vec::new();
+        for request in std::mem::take(&mut self.queue) {
+            if self.is_exempt(request.peer) {
+                grants.push(request);
+            } else if self.has_tokens(request.peer, request.direction) {
+                self.take(request.peer, request.direction, request.bytes);
+                grants.push(request);
+            } else {
+                self.queue.push_back(request);
+            }
+        }
+        grants
+    }
+
+    /// Polls for a single connection: returns the bytes granted to `peer` in
+    /// `direction`, keeping the grants of other peers until they ask.
+    pub fn poll_peer(&mut self, peer: SocketAddr, direction: Direction, now: Instant) -> u64 {
+        for grant in self.poll(now) {
+            *self
+                .granted
+                .entry((grant.peer, grant.direction))
+                .or_default() += grant.bytes;
+        }
+        self.granted.remove(&(peer, direction)).unwrap_or_default()
+    }
+
+    fn is_exempt(&self, peer: SocketAddr) -> bool {
+        self.config.exempt_lan && is_local(peer.ip())
+    }
+
+    fn has_tokens(&self, peer: SocketAddr, direction: Direction) -> bool {
+        let limited = self.peers.get(&peer);
+        self.global.get(direction).has_tokens()
+            && limited.is_none_or(|limited| limited.buckets.get(direction).has_tokens())
+            && limited
+                .and_then(|limited| self.torrents.get(&limited.torrent))
+                .is_none_or(|buckets| buckets.get(direction).has_tokens())
+    }
+
+    fn take(&mut self, peer: SocketAddr, direction: Direction, bytes: u64) {
+        self.global.get_mut(direction).take(bytes);
+        if let Some(limited) = self.peers.get_mut(&peer) {
+            limited.buckets.get_mut(direction).take(bytes);
+            if let Some(buckets) = self.torrents.get_mut(&limited.torrent) {
+                buckets.get_mut(direction).take(bytes);
+            }
+        }
+    }
+}
+
+/// Loopback, private and link-local addresses.
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(ip.into()),
+            None => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+        },
+    }
+}
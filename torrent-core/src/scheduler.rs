@@ -0,0 +1,682 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use torrent_pwp::{bitfield::Bitfield, message::BlockInfo};
+
+use crate::{
+    picker::{InProgress, PickerConfig, PiecePicker, Priority},
+    rate::RateMeter,
+};
+
+/// Pieces are requested in blocks of 16 KiB, only the last block of the last
+/// piece may be shorter.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    /// Each peer gets enough requests queued to keep it busy this long at its
+    /// measured rate, on top of its latency.
+    pub queue_time: Duration,
+    /// Pipeline depth of a peer we know nothing about yet.
+    pub min_pipeline: usize,
+    /// Upper bound of the pipeline depth, lowered further by the peer's
+    /// `reqq`.
+    pub max_pipeline: usize,
+    /// Requests are given to another peer after this, or after a few times the
+    /// peer's latency if that is longer.
+    pub request_timeout: Duration,
+    /// Peers sending fewer bytes per second are slow, and fast peers keep out
+    /// of the pieces they work on.
+    pub slow_peer_rate: f64,
+    /// In end-game, a block is requested from at most this many peers at a
+    /// time.
+    pub endgame_requests_per_block: usize,
+    pub picker: PickerConfig,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            queue_time: Duration::from_secs(3),
+            min_pipeline: 2,
+            max_pipeline: 250,
+            request_timeout: Duration::from_secs(20),
+            slow_peer_rate: 2.0 * BLOCK_SIZE as f64,
+            endgame_requests_per_block: 2,
+            picker: PickerConfig::default(),
+        }
+    }
+}
+
+/// The request pipeline of a peer, for display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineStats {
+    /// How many requests the peer should have outstanding.
+    pub depth: usize,
+    pub outstanding: usize,
+    /// Bytes per second received from the peer.
+    pub download_rate: f64,
+    /// Average time from a request to its block.
+    pub latency: Option<Duration>,
+}
+
+/// What to do with a block received from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// The block is new and should be stored. The same block was also
+    /// requested from the peers in `cancels`, who should be sent a `cancel`.
+    Accepted {
+        /// All blocks of the piece are in, it can be checked.
+        piece_complete: bool,
+        cancels: Vec<(SocketAddr, BlockInfo)>,
+    },
+    /// We have the block already.
+    Duplicate,
+    /// Not a block of a piece we are downloading.
+    Unexpected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerEvent {
+    /// Every missing block is requested, so idle peers are sent duplicate
+    /// requests to not wait on the slowest peer.
+    EndGameStarted,
+    /// The download finished, or blocks have to be requested again.
+    EndGameEnded,
+}
+
+#[derive(Debug)]
+struct Request {
+    block: BlockInfo,
+    sent_at: Instant,
+    timed_out: bool,
+}
+
+#[derive(Debug)]
+struct PeerPipeline {
+    // in the order they were sent
+    outstanding: Vec<Request>,
+    reqq: Option<u32>,
+    rate: RateMeter,
+    latency: Option<Duration>,
+}
+
+impl PeerPipeline {
+    fn new() -> Self {
+        PeerPipeline {
+            outstanding: Vec::new(),
+            reqq: None,
+            rate: RateMeter::default(),
+            latency: None,
+        }
+    }
+
+    /// Enough requests to cover the bandwidth-delay product plus the queue
+    /// time, within what the peer accepts.
+    fn depth(&mut self, config: &SchedulerConfig, now: Instant) -> usize {
+        let max = self
+            .reqq
+            .map_or(config.max_pipeline, |reqq| {
+                config.max_pipeline.min(reqq as usize)
+            })
+            .max(1);
+        let window = config.queue_time + self.latency.unwrap_or_default();
+        let wanted = self.rate.rate(now) * window.as_secs_f64() / BLOCK_SIZE as f64;
+        (wanted.ceil() as usize).clamp(config.min_pipeline.min(max), max)
+    }
+
+    fn timeout(&self, config: &SchedulerConfig) -> Duration {
+        self.latency.map_or(config.request_timeout, |latency| {
+            config.request_timeout.max(latency * 3)
+        })
+    }
+
+    fn take(&mut self, block: &BlockInfo) -> Option<Request> {
+        let position = self
+            .outstanding
+            .iter()
+            .position(|request| request.block == *block)?;
+        Some(self.outstanding.remove(position))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Block {
+    received: bool,
+    requested_from: Vec<SocketAddr>,
+    // requests in `requested_from` that did not time out
+    active: usize,
+    // the peer the block was taken from
+    from: Option<SocketAddr>,
+}
+
+impl Block {
+    fn is_free(&self) -> bool {
+        !self.received && self.active == 0
+    }
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    blocks: Vec<Block>,
+    received: usize,
+}
+
+impl PartialPiece {
+    fn is_complete(&self) -> bool {
+        self.received == self.blocks.len()
+    }
+}
+
+/// Decides which blocks to request from which peer and keeps track of the
+/// requests in flight. It does no IO: the connection tells it about chokes,
+/// rejects and received blocks, and sends the requests and cancels it hands
+/// out. Peers are told apart by their address.
+pub struct BlockScheduler {
+    config: SchedulerConfig,
+    piece_length: u64,
+    total_length: u64,
+    have: Bitfield,
+    partial: BTreeMap<u32, PartialPiece>,
+    peers: HashMap<SocketAddr, PeerPipeline>,
+    picker: PiecePicker,
+    // pieces that failed their hash check, with the one peer downloading
+    // them again
+    redownloads: HashMap<u32, Option<SocketAddr>>,
+    endgame: bool,
+    events: Vec<SchedulerEvent>,
+}
+
+impl BlockScheduler {
+    pub fn new(piece_length: u64, total_length: u64, config: SchedulerConfig) -> Self {
+        let num_pieces = match piece_length {
+            0 => 0,
+            _ => total_length.div_ceil(piece_length) as usize,
+        };
+        BlockScheduler {
+            picker: PiecePicker::new(piece_length, num_pieces, config.picker.clone()),
+            config,
+            piece_length,
+            total_length,
+            have: Bitfield::new(num_pieces),
+            partial: BTreeMap::new(),
+            peers: HashMap::new(),
+            redownloads: HashMap::new(),
+            endgame: false,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.have.len()
+    }
+
+    /// Length of a piece, only the last may be shorter.
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let begin = index as u64 * self.piece_length;
+        self.total_length
+            .saturating_sub(begin)
+            .min(self.piece_length) as u32
+    }
+
+    pub fn blocks_in_piece(&self, index: u32) -> usize {
+        self.piece_size(index).div_ceil(BLOCK_SIZE) as usize
+    }
+
+    fn block_info(&self, index: u32, block: usize) -> BlockInfo {
+        let begin = block as u32 * BLOCK_SIZE;
+        BlockInfo {
+            index,
+            begin,
+            length: (self.piece_size(index) - begin).min(BLOCK_SIZE),
+        }
+    }
+
+    /// Verified pieces.
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.is_full()
+    }
+
+    /// Availability and priorities of the pieces. The connections report the
+    /// pieces of their peers to it.
+    pub fn picker(&self) -> &PiecePicker {
+        &self.picker
+    }
+
+    pub fn picker_mut(&mut self) -> &mut PiecePicker {
+        &mut self.picker
+    }
+
+    pub fn is_endgame(&self) -> bool {
+        self.endgame
+    }
+
+    pub fn drain_events(&mut self) -> Vec<SchedulerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Pieces with some blocks requested or received.
+    pub fn partial_pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.partial.keys().copied()
+    }
+
+    pub fn add_peer(&mut self, peer: SocketAddr) {
+        self.peers.entry(peer).or_insert_with(PeerPipeline::new);
+    }
+
+    /// Frees the requests of a peer that went away.
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        if let Some(pipeline) = self.peers.remove(&peer) {
+            for request in pipeline.outstanding {
+                self.release(peer, &request);
+            }
+        }
+        self.release_redownloads(peer);
+        self.check_endgame();
+    }
+
+    /// Limits the pipeline to the `reqq` from the peer's extended handshake.
+    pub fn set_reqq(&mut self, peer: SocketAddr, reqq: u32) {
+        self.add_peer(peer);
+        if let Some(pipeline) = self.peers.get_mut(&peer) {
+            pipeline.reqq = Some(reqq);
+        }
+    }
+
+    pub fn stats(&mut self, peer: SocketAddr, now: Instant) -> Option<PipelineStats> {
+        let pipeline = self.peers.get_mut(&peer)?;
+        Some(PipelineStats {
+            depth: pipeline.depth(&self.config, now),
+            outstanding: pipeline.outstanding.len(),
+            download_rate: pipeline.rate.rate(now),
+            latency: pipeline.latency,
+        })
+    }
+
+    /// Fills the pipeline of `peer`, which has the pieces in `available`, with
+    /// blocks of the pieces the picker chooses. `can_request` tells whether a
+    /// piece may be requested right now, which depends on the choke state and
+    /// allowed fast set of the connection. In end-game, room left in the
+    /// pipeline is filled with blocks already requested from other peers. A
+    /// piece that failed its hash check is downloaded again from one peer
+    /// only, so a bad copy points at that peer.
+    pub fn request_blocks(
+        &mut self,
+        peer: SocketAddr,
+        available: &Bitfield,
+        can_request: impl Fn(u32) -> bool,
+        now: Instant,
+    ) -> Vec<BlockInfo> {
+        self.add_peer(peer);
+        let slow = self
+            .peers
+            .iter_mut()
+            .map(|(addr, pipeline)| (*addr, pipeline.rate.rate(now) < self.config.slow_peer_rate))
+            .collect::<HashMap<_, _>>();
+        let Some(pipeline) = self.peers.get_mut(&peer) else {
+            return Vec::new();
+        };
+        let mut room = pipeline
+            .depth(&self.config, now)
+            .saturating_sub(pipeline.outstanding.len());
+        if room == 0 {
+            return Vec::new();
+        }
+
+        let mut requestable = Bitfield::new(available.len());
+        for index in available.iter_set().filter(|index| {
+            can_request(*index as u32)
+                && self
+                    .redownloads
+                    .get(&(*index as u32))
+                    .is_none_or(|owner| owner.is_none_or(|owner| owner == peer))
+        }) {
+            requestable.set(index, true);
+        }
+        let in_progress = self
+            .partial
+            .iter()
+            .map(|(index, piece)| InProgress {
+                index: *index,
+                has_free_blocks: piece
+                    .blocks
+                    .iter()
+                    .any(|block| block.is_free() && !block.requested_from.contains(&peer)),
+                slow: piece.blocks.iter().any(|block| {
+                    !block.received
+                        && block
+                            .requested_from
+                            .iter()
+                            .any(|owner| slow.get(owner).copied().unwrap_or_default())
+                }),
+            })
+            .collect::<Vec<_>>();
+        let pieces = self
+            .picker
+            .pick(&requestable, &self.have, &in_progress, slow[&peer], room);
+
+        let mut requests = Vec::new();
+        for index in pieces {
+            if room == 0 {
+                break;
+            }
+            let blocks = self.blocks_in_piece(index);
+            let piece = self.partial.entry(index).or_insert_with(|| PartialPiece {
+                blocks: vec![Block::default(); blocks],
+                received: 0,
+            });
+            for (block, state) in piece.blocks.iter_mut().enumerate() {
+                if room == 0 {
+                    break;
+                }
+                if !state.is_free() || state.requested_from.contains(&peer) {
+                    continue;
+                }
+                state.requested_from.push(peer);
+                state.active += 1;
+                requests.push((index, block));
+                room -= 1;
+                if let Some(owner) = self.redownloads.get_mut(&index) {
+                    *owner = Some(peer);
+                }
+            }
+        }
+
+        if room > 0 && !self.endgame && self.all_requested() {
+            self.endgame = true;
+            self.events.push(SchedulerEvent::EndGameStarted);
+        }
+        if room > 0 && self.endgame {
+            requests.extend(self.duplicate_requests(peer, &requestable, room));
+        }
+
+        let requests = requests
+            .into_iter()
+            .map(|(index, block)| self.block_info(index, block))
+            .collect::<Vec<_>>();
+        if let Some(pipeline) = self.peers.get_mut(&peer) {
+            pipeline
+                .outstanding
+                .extend(requests.iter().map(|block| Request {
+                    block: *block,
+                    sent_at: now,
+                    timed_out: false,
+                }));
+        }
+        requests
+    }
+
+    /// Records a block received from `peer`. Blocks that were not requested
+    /// are still taken if we miss them, the peer may have sent them before
+    /// seeing our cancel or while choking us.
+    pub fn on_block(
+        &mut self,
+        peer: SocketAddr,
+        index: u32,
+        begin: u32,
+        length: u32,
+        now: Instant,
+    ) -> BlockOutcome {
+        let block = BlockInfo {
+            index,
+            begin,
+            length,
+        };
+        let request = self
+            .peers
+            .get_mut(&peer)
+            .and_then(|pipeline| pipeline.take(&block));
+        if let Some(pipeline) = self.peers.get_mut(&peer) {
+            pipeline.rate.add(length as u64, now);
+            if let Some(request) = request.as_ref().filter(|request| !request.timed_out) {
+                let sample = now.duration_since(request.sent_at);
+                pipeline.latency = Some(match pipeline.latency {
+                    Some(latency) => (latency * 7 + sample) / 8,
+                    None => sample,
+                });
+            }
+        }
+
+        if index as usize >= self.num_pieces()
+            || !begin.is_multiple_of(BLOCK_SIZE)
+            || begin >= self.piece_size(index)
+            || self.block_info(index, (begin / BLOCK_SIZE) as usize) != block
+        {
+            return BlockOutcome::Unexpected;
+        }
+        if self.have.has(index as usize) {
+            return BlockOutcome::Duplicate;
+        }
+        let blocks = self.blocks_in_piece(index);
+        let piece = self.partial.entry(index).or_insert_with(|| PartialPiece {
+            blocks: vec![Block::default(); blocks],
+            received: 0,
+        });
+        let state = &mut piece.blocks[(begin / BLOCK_SIZE) as usize];
+        if state.received {
+            return BlockOutcome::Duplicate;
+        }
+        state.received = true;
+        state.active = 0;
+        state.from = Some(peer);
+        let others = std::mem::take(&mut state.requested_from);
+        piece.received += 1;
+        let piece_complete = piece.is_complete();
+
+        let mut cancels = Vec::new();
+        for other in others.into_iter().filter(|other| *other != peer) {
+            if let Some(pipeline) = self.peers.get_mut(&other) {
+                if pipeline.take(&block).is_some() {
+                    cancels.push((other, block));
+                }
+            }
+        }
+        BlockOutcome::Accepted {
+            piece_complete,
+            cancels,
+        }
+    }
+
+    /// The peer choked us, which drops our requests unless `can_request`
+    /// still allows their piece, as for allowed fast pieces.
+    pub fn on_choke(&mut self, peer: SocketAddr, can_request: impl Fn(u32) -> bool) {
+        let Some(pipeline) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        let (kept, dropped) = std::mem::take(&mut pipeline.outstanding)
+            .into_iter()
+            .partition(|request| can_request(request.block.index));
+        pipeline.outstanding = kept;
+        for request in dropped {
+            self.release(peer, &request);
+        }
+        self.release_redownloads(peer);
+        self.check_endgame();
+    }
+
+    pub fn on_reject(&mut self, peer: SocketAddr, block: BlockInfo) {
+        let request = self
+            .peers
+            .get_mut(&peer)
+            .and_then(|pipeline| pipeline.take(&block));
+        if let Some(request) = request {
+            self.release(peer, &request);
+        }
+        self.check_endgame();
+    }
+
+    /// Lets other peers be asked for blocks whose requests took too long.
+    /// The late requests stay in their pipeline, so a slow peer gets no new
+    /// ones, and are cancelled once the block arrives from elsewhere. A piece
+    /// downloaded again is handed to another peer as well. Returns the
+    /// requests that timed out now.
+    pub fn poll_timeouts(&mut self, now: Instant) -> Vec<(SocketAddr, BlockInfo)> {
+        let mut timed_out = Vec::new();
+        for (peer, pipeline) in &mut self.peers {
+            let timeout = pipeline.timeout(&self.config);
+            for request in &mut pipeline.outstanding {
+                if !request.timed_out && now.duration_since(request.sent_at) >= timeout {
+                    request.timed_out = true;
+                    timed_out.push((*peer, request.block));
+                }
+            }
+        }
+        for (peer, block) in &timed_out {
+            if let Some(state) = self.block_mut(block) {
+                state.active = state.active.saturating_sub(1);
+            }
+            if let Some(owner) = self.redownloads.get_mut(&block.index) {
+                if *owner == Some(*peer) {
+                    *owner = None;
+                }
+            }
+        }
+        self.check_endgame();
+        timed_out
+    }
+
+    /// The piece matched its hash. Returns its blocks with the peers they
+    /// came from.
+    pub fn piece_passed(&mut self, index: u32) -> Vec<(BlockInfo, Option<SocketAddr>)> {
+        let senders = self.take_piece(index);
+        self.redownloads.remove(&index);
+        self.have.set(index as usize, true);
+        self.picker.clear_deadline(index);
+        self.check_endgame();
+        senders
+    }
+
+    /// The piece did not match its hash, so all of it is downloaded again.
+    /// Returns its blocks with the peers they came from.
+    pub fn piece_failed(&mut self, index: u32) -> Vec<(BlockInfo, Option<SocketAddr>)> {
+        let senders = self.take_piece(index);
+        self.redownloads.insert(index, None);
+        self.check_endgame();
+        senders
+    }
+
+    // removes the piece and its requests, returning the senders of the blocks
+    fn take_piece(&mut self, index: u32) -> Vec<(BlockInfo, Option<SocketAddr>)> {
+        let Some(piece) = self.partial.remove(&index) else {
+            return Vec::new();
+        };
+        let mut senders = Vec::new();
+        for (block, state) in piece.blocks.into_iter().enumerate() {
+            let block = self.block_info(index, block);
+            for peer in state.requested_from {
+                if let Some(pipeline) = self.peers.get_mut(&peer) {
+                    pipeline.take(&block);
+                }
+            }
+            senders.push((block, state.from));
+        }
+        senders
+    }
+
+    // pieces downloaded again by `peer` may go to another peer, unless it
+    // still has requests for them
+    fn release_redownloads(&mut self, peer: SocketAddr) {
+        let pipeline = self.peers.get(&peer);
+        for (index, owner) in &mut self.redownloads {
+            let requested = pipeline.is_some_and(|pipeline| {
+                pipeline
+                    .outstanding
+                    .iter()
+                    .any(|request| request.block.index == *index)
+            });
+            if *owner == Some(peer) && !requested {
+                *owner = None;
+            }
+        }
+    }
+
+    // every block of the pieces we want is received or requested, and some
+    // are still outstanding
+    fn all_requested(&self) -> bool {
+        let mut outstanding = false;
+        for index in self.have.iter_unset().map(|index| index as u32) {
+            if self.picker.priority(index) == Priority::Skip {
+                continue;
+            }
+            let Some(piece) = self.partial.get(&index) else {
+                return false;
+            };
+            if piece.blocks.iter().any(Block::is_free) {
+                return false;
+            }
+            outstanding |= !piece.is_complete();
+        }
+        outstanding
+    }
+
+    // blocks already requested from others, least requested first
+    fn duplicate_requests(
+        &mut self,
+        peer: SocketAddr,
+        available: &Bitfield,
+        room: usize,
+    ) -> Vec<(u32, usize)> {
+        let mut candidates = Vec::new();
+        for (index, piece) in &self.partial {
+            if !available.has(*index as usize) || self.picker.priority(*index) == Priority::Skip {
+                continue;
+            }
+            for (block, state) in piece.blocks.iter().enumerate() {
+                if !state.received
+                    && state.active < self.config.endgame_requests_per_block
+                    && !state.requested_from.contains(&peer)
+                {
+                    candidates.push((state.active, *index, block));
+                }
+            }
+        }
+        candidates.sort();
+        candidates.truncate(room);
+        for (_, index, block) in &candidates {
+            if let Some(state) = self
+                .partial
+                .get_mut(index)
+                .and_then(|piece| piece.blocks.get_mut(*block))
+            {
+                state.requested_from.push(peer);
+                state.active += 1;
+            }
+        }
+        candidates
+            .into_iter()
+            .map(|(_, index, block)| (index, block))
+            .collect()
+    }
+
+    // blocks became free again or the download finished
+    fn check_endgame(&mut self) {
+        if self.endgame && !self.all_requested() {
+            self.endgame = false;
+            self.events.push(SchedulerEvent::EndGameEnded);
+        }
+    }
+
+    fn block_mut(&mut self, block: &BlockInfo) -> Option<&mut Block> {
+        self.partial
+            .get_mut(&block.index)?
+            .blocks
+            .get_mut((block.begin / BLOCK_SIZE) as usize)
+    }
+
+    // a request that was removed from the pipeline of `peer`
+    fn release(&mut self, peer: SocketAddr, request: &Request) {
+        if let Some(state) = self.block_mut(&request.block) {
+            state.requested_from.retain(|owner| *owner != peer);
+            if !request.timed_out {
+                state.active = state.active.saturating_sub(1);
+            }
+        }
+    }
+}
@@ -1,20 +1,81 @@
-use std::{collections::HashMap, path::Path, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use reqwest::Client;
-use tokio::sync::RwLock;
+use futures_util::future::join_all;
+use tokio::{
+    spawn,
+    sync::{broadcast, RwLock},
+    task::JoinHandle,
+    time::sleep,
+};
+use torrent_dht::{DhtConfig, DhtNode};
 use torrent_parser::parse_torrent_file;
+use torrent_pwp::{
+    error::PwpError, utp::UtpSocket, ConnectionConfig, InfoHash, PeerFilter, PeerId,
+    TcpConnectionManager,
+};
+use tracing::{debug, warn};
 use uuid::Uuid;
 
 use crate::{
+    ban::{BanList, BanReason},
+    connection::TorrentConnections,
     error::{RustyTorrentError, RustyTorrentResult},
-    torrent::ManagedTorrent,
+    ipfilter::{IpFilter, SharedIpFilter},
+    lsd::{LocalServiceDiscovery, LsdConfig},
+    magnet::{MagnetLink, MagnetTorrent},
+    metadata::MetadataExtension,
+    peer::{
+        session::{DisconnectReason, PeerSnapshot},
+        Peer,
+    },
+    ratelimit::{BandwidthConfig, BandwidthLimiter, RateLimit},
+    scheduler::{PipelineStats, SchedulerEvent},
+    torrent::{allowed, peer_filter, ManagedTorrent, TorrentState},
+    tracker::client::{TrackerClient, TrackerClientRegistry},
+    udp::SharedUdpSocket,
 };
 
 pub type TorrentId = Uuid;
 
+/// Started torrents dial the peers they know of this often, within the
+/// connection limits.
+pub const DIAL_INTERVAL: Duration = Duration::from_secs(5);
+/// A peer that was dialed is not dialed again for this long.
+pub const REDIAL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Events a subscriber has not received yet are dropped beyond this many.
+pub const EVENT_CAPACITY: usize = 64;
+
+/// Something that happened to a started torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TorrentEvent {
+    pub torrent: TorrentId,
+    pub event: SchedulerEvent,
+}
+
 pub struct RustyTorrentSession {
-    torrents: RwLock<HashMap<TorrentId, ManagedTorrent>>,
-    http_client: Arc<Client>,
+    torrents: RwLock<HashMap<TorrentId, Arc<ManagedTorrent>>>,
+    // torrents still fetching their metadata, moved to `torrents` when done
+    magnets: RwLock<HashMap<TorrentId, MagnetTorrent>>,
+    tracker_clients: Arc<TrackerClientRegistry>,
+    dht: RwLock<Option<Arc<DhtNode>>>,
+    lsd: RwLock<Option<Arc<LocalServiceDiscovery>>>,
+    udp: RwLock<Option<SharedUdpSocket>>,
+    // shared by all torrents, so a peer sending bad data is banned everywhere
+    bans: Arc<BanList>,
+    ip_filter: Arc<SharedIpFilter>,
+    bandwidth: Arc<Mutex<BandwidthLimiter>>,
+    connections: Arc<TcpConnectionManager>,
+    // one per started torrent, running its connections
+    handlers: Mutex<HashMap<TorrentId, Arc<TorrentConnections>>>,
+    // one per started torrent, dialing its peers
+    dialers: Mutex<HashMap<TorrentId, JoinHandle<()>>>,
+    events: broadcast::Sender<TorrentEvent>,
     default_location: String,
     peer_id: String,
     port: u32,
@@ -36,7 +97,7 @@ impl RustyTorrentSession {
             .as_secs();
 
         // generate 12 byte string based on pid and time
-        let pid_time = format!("{:x}{:x}", pid, time);
+        let pid_time = format!("{:06x}{:x}", pid, time);
         let pid_time = &pid_time[..12];
 
         let peer_id = format!(
@@ -45,58 +106,537 @@ impl RustyTorrentSession {
             version.iter().collect::<String>(),
             pid_time
         );
+        let mut wire_id = PeerId::default();
+        for (to, from) in wire_id.iter_mut().zip(peer_id.bytes()) {
+            *to = from;
+        }
+        let bans = Arc::new(BanList::default());
+        let ip_filter = Arc::new(SharedIpFilter::default());
+        // incoming connections are checked before their handshake is read
+        let connections = TcpConnectionManager::new(
+            ConnectionConfig {
+                listen_addr: SocketAddr::from(([0, 0, 0, 0], port as u16)),
+                peer_filter: Some(peer_filter(&bans, &ip_filter)),
+                ..Default::default()
+            },
+            wire_id,
+        );
 
         RustyTorrentSession {
             torrents: RwLock::new(HashMap::new()),
-            http_client: Default::default(),
+            magnets: Default::default(),
+            tracker_clients: Default::default(),
+            dht: Default::default(),
+            lsd: Default::default(),
+            udp: Default::default(),
+            bans,
+            ip_filter,
+            bandwidth: Arc::new(Mutex::new(BandwidthLimiter::new(
+                BandwidthConfig::default(),
+                Instant::now(),
+            ))),
+            connections: Arc::new(connections),
+            handlers: Default::default(),
+            dialers: Default::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
             default_location,
             peer_id,
             port,
         }
     }
 
+    /// Registers a tracker transport for announce URLs with the given scheme,
+    /// replacing any transport previously registered for it.
+    pub fn register_tracker_client(&self, scheme: &str, client: Arc<dyn TrackerClient>) {
+        self.tracker_clients.register(scheme, client);
+    }
+
+    /// Accepts peer connections on the session's port. Returns the bound
+    /// address.
+    pub async fn listen(&self) -> RustyTorrentResult<SocketAddr> {
+        Ok(self.connections.listen().await?)
+    }
+
+    /// Binds one UDP socket for uTP, UDP trackers and the DHT if it is
+    /// enabled afterwards. Peer connections use uTP from then on. Returns the
+    /// uTP socket.
+    pub async fn bind_udp(&self, addr: SocketAddr) -> RustyTorrentResult<Arc<UtpSocket>> {
+        let udp = SharedUdpSocket::bind(addr).await?;
+        let utp = udp.utp();
+        self.connections.set_utp(Arc::clone(&utp));
+        self.tracker_clients.register(
+            "udp",
+            Arc::new(udp.tracker_client(Duration::from_secs(15), 2)),
+        );
+        *self.udp.write().await = Some(udp);
+        Ok(utp)
+    }
+
+    /// Starts a DHT node and bootstraps it. Torrents added afterwards use it to
+    /// find peers unless they are private.
+    ///
+    /// The node runs on the socket from [`Self::bind_udp`] if there is one,
+    /// ignoring the configured bind address.
+    pub async fn enable_dht(&self, config: DhtConfig) -> RustyTorrentResult<()> {
+        let dht = match &*self.udp.read().await {
+            Some(udp) => {
+                let dht = Arc::new(DhtNode::with_socket(udp.socket(), config).await?);
+                udp.set_dht(Arc::clone(&dht));
+                dht
+            }
+            None => Arc::new(DhtNode::start(config).await?),
+        };
+        dht.bootstrap().await?;
+        *self.dht.write().await = Some(dht);
+        Ok(())
+    }
+
+    /// Starts Local Service Discovery. Torrents started afterwards are
+    /// announced on the local network unless they are private.
+    pub async fn enable_lsd(&self, config: LsdConfig) -> RustyTorrentResult<()> {
+        let lsd = LocalServiceDiscovery::start(config, self.port as u16).await?;
+        *self.lsd.write().await = Some(Arc::new(lsd));
+        Ok(())
+    }
+
     pub async fn add_torrent(
         &self,
         torrent_path: String,
         name: Option<String>,
         location: Option<String>,
         start: bool,
-    ) -> RustyTorrentResult<()> {
+    ) -> RustyTorrentResult<TorrentId> {
         let location = location.unwrap_or(self.default_location.clone());
         let mut torrents = self.torrents.write().await;
         let meta = parse_torrent_file(&torrent_path)?;
-        let torrent = ManagedTorrent::from_torrent_metadata(
+        let mut torrent = ManagedTorrent::from_torrent_metadata(
             meta,
             name,
             location,
             self.peer_id.clone(),
             self.port,
-            Arc::clone(&self.http_client),
+            Arc::clone(&self.tracker_clients),
+            self.dht.read().await.clone(),
         );
+        torrent.bans = Arc::clone(&self.bans);
+        torrent.ip_filter = Arc::clone(&self.ip_filter);
         let id = Uuid::new_v4();
-        torrents.insert(id, torrent);
+        torrents.insert(id, Arc::new(torrent));
+        drop(torrents);
         if start {
             self.start_torrent(id).await?;
         }
 
-        Ok(())
+        Ok(id)
     }
 
-    pub async fn start_torrent(&self, id: TorrentId) -> RustyTorrentResult<()> {
-        let torrents = self.torrents.read().await;
-        let torrent = torrents
+    /// Adds a torrent from a magnet link. Its metadata is fetched from peers
+    /// first, see [`Self::finish_metadata_downloads`].
+    pub async fn add_magnet(
+        &self,
+        uri: &str,
+        name: Option<String>,
+        location: Option<String>,
+        start: bool,
+    ) -> RustyTorrentResult<TorrentId> {
+        let link = MagnetLink::parse(uri)?;
+        let magnet = MagnetTorrent::new(
+            link,
+            name,
+            location.unwrap_or(self.default_location.clone()),
+            start,
+            self.peer_id.clone(),
+            self.port,
+            Arc::clone(&self.tracker_clients),
+            self.dht.read().await.clone(),
+            self.peer_filter(),
+        );
+        let id = Uuid::new_v4();
+        self.magnets.write().await.insert(id, magnet);
+        Ok(id)
+    }
+
+    /// Moves torrents whose metadata has been fetched into normal download,
+    /// starting them if they were added with `start`. Returns their ids.
+    /// Magnets whose metadata cannot be used are kept as they are.
+    pub async fn finish_metadata_downloads(&self) -> Vec<TorrentId> {
+        let finished = self
+            .magnets
+            .read()
+            .await
+            .iter()
+            .filter_map(|(id, magnet)| magnet.metadata().map(|metadata| (*id, metadata)))
+            .collect::<Vec<_>>();
+
+        let mut ids = Vec::new();
+        for (id, metadata) in finished {
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!("invalid metadata for magnet {}: {}", id, e);
+                    continue;
+                }
+            };
+            let Some(magnet) = self.magnets.write().await.remove(&id) else {
+                continue;
+            };
+            let mut torrent = ManagedTorrent::from_torrent_metadata(
+                metadata,
+                magnet.name.clone().or(magnet.link.name.clone()),
+                magnet.location.clone(),
+                self.peer_id.clone(),
+                self.port,
+                Arc::clone(&self.tracker_clients),
+                self.dht.read().await.clone(),
+            );
+            torrent.peers = Arc::clone(&magnet.peers);
+            torrent.bans = Arc::clone(&self.bans);
+            torrent.ip_filter = Arc::clone(&self.ip_filter);
+            self.torrents.write().await.insert(id, Arc::new(torrent));
+            if magnet.start {
+                if let Err(e) = self.start_torrent(id).await {
+                    warn!("failed to start torrent {}: {}", id, e);
+                }
+            }
+            ids.push(id);
+        }
+        ids
+    }
+
+    /// The state of a torrent, after moving finished metadata downloads
+    /// along.
+    pub async fn torrent_state(&self, id: TorrentId) -> RustyTorrentResult<TorrentState> {
+        self.finish_metadata_downloads().await;
+        if let Some(magnet) = self.magnets.read().await.get(&id) {
+            return Ok(TorrentState::FetchingMetadata(magnet.progress()));
+        }
+        self.torrents
+            .read()
+            .await
             .get(&id)
-            .ok_or(RustyTorrentError::TorrentNotFound(id))?;
+            .map(|torrent| torrent.state())
+            .ok_or(RustyTorrentError::TorrentNotFound(id))
+    }
+
+    /// A `ut_metadata` extension for a connection to `remote`, fetching the
+    /// metadata of a magnet link or serving it for a complete torrent.
+    pub async fn metadata_extension(
+        &self,
+        id: TorrentId,
+        remote: SocketAddr,
+    ) -> RustyTorrentResult<MetadataExtension> {
+        let source = match self.magnets.read().await.get(&id) {
+            Some(magnet) => magnet.metadata_source(),
+            None => self
+                .torrents
+                .read()
+                .await
+                .get(&id)
+                .ok_or(RustyTorrentError::TorrentNotFound(id))?
+                .metadata_source(),
+        };
+        Ok(MetadataExtension::new(source, remote))
+    }
+
+    /// Starts announcing, checks the pieces on disk and starts connecting to
+    /// peers. Does nothing if the torrent is already started.
+    pub async fn start_torrent(&self, id: TorrentId) -> RustyTorrentResult<()> {
+        let torrent = self.torrent(id).await?;
 
         let download_dir = Path::new(&torrent.location);
         if !download_dir.exists() {
             std::fs::create_dir_all(download_dir)?;
         }
 
-        let torrent_path = download_dir.join(&torrent.name);
+        if !torrent.start() {
+            return Ok(());
+        }
+        torrent.check_pieces().await?;
+        if let Some(info_hash) = torrent.info_hash() {
+            let handler = Arc::new(TorrentConnections::new(
+                id,
+                Arc::clone(&torrent),
+                Arc::clone(&self.bandwidth),
+                self.events.clone(),
+            ));
+            self.connections
+                .register_torrent(info_hash, handler.clone());
+            self.handlers.lock().unwrap().insert(id, handler);
+            let dialer = spawn(dial_peers(
+                Arc::clone(&self.connections),
+                Arc::clone(&torrent.peers),
+                info_hash,
+            ));
+            if let Some(previous) = self.dialers.lock().unwrap().insert(id, dialer) {
+                previous.abort();
+            }
+        }
+        if let Some(lsd) = self.lsd.read().await.as_ref() {
+            if torrent.lsd_enabled() {
+                lsd.add_torrent(
+                    torrent.metadata.info_hash.clone(),
+                    Arc::clone(&torrent.peers),
+                    torrent.peer_filter(),
+                )
+                .await;
+            }
+        }
 
-        torrent.start();
+        Ok(())
+    }
 
+    /// Connects to a peer of a started torrent.
+    pub async fn connect_peer(&self, id: TorrentId, addr: SocketAddr) -> RustyTorrentResult<()> {
+        let info_hash = self
+            .torrents
+            .read()
+            .await
+            .get(&id)
+            .ok_or(RustyTorrentError::TorrentNotFound(id))?
+            .info_hash()
+            .ok_or(PwpError::UnknownInfoHash)?;
+        self.connections.connect(info_hash, addr).await?;
         Ok(())
     }
+
+    pub async fn torrent(&self, id: TorrentId) -> RustyTorrentResult<Arc<ManagedTorrent>> {
+        self.torrents
+            .read()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or(RustyTorrentError::TorrentNotFound(id))
+    }
+
+    /// Events of all torrents from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<TorrentEvent> {
+        self.events.subscribe()
+    }
+
+    /// The connected peers of a torrent, none if it is not started.
+    pub async fn peer_snapshots(&self, id: TorrentId) -> RustyTorrentResult<Vec<PeerSnapshot>> {
+        Ok(self
+            .handler(id)
+            .await?
+            .map(|handler| handler.peer_snapshots())
+            .unwrap_or_default())
+    }
+
+    /// The pipeline depth and outstanding requests of the connected peers of
+    /// a torrent, none if it is not started.
+    pub async fn pipeline_stats(
+        &self,
+        id: TorrentId,
+    ) -> RustyTorrentResult<Vec<(SocketAddr, PipelineStats)>> {
+        Ok(self
+            .handler(id)
+            .await?
+            .map(|handler| handler.pipeline_stats())
+            .unwrap_or_default())
+    }
+
+    /// Why the last connection of a torrent to `peer` ended.
+    pub async fn disconnect_reason(
+        &self,
+        id: TorrentId,
+        peer: SocketAddr,
+    ) -> RustyTorrentResult<Option<DisconnectReason>> {
+        Ok(self
+            .handler(id)
+            .await?
+            .and_then(|handler| handler.disconnect_reason(peer)))
+    }
+
+    // the connections of a torrent, if it is started
+    async fn handler(&self, id: TorrentId) -> RustyTorrentResult<Option<Arc<TorrentConnections>>> {
+        self.torrent(id).await?;
+        Ok(self.handlers.lock().unwrap().get(&id).cloned())
+    }
+
+    /// Stops a torrent or magnet and forgets it, closing its connections. The
+    /// downloaded data is kept.
+    pub async fn remove_torrent(&self, id: TorrentId) -> RustyTorrentResult<()> {
+        if self.magnets.write().await.remove(&id).is_some() {
+            return Ok(());
+        }
+        let torrent = self
+            .torrents
+            .write()
+            .await
+            .remove(&id)
+            .ok_or(RustyTorrentError::TorrentNotFound(id))?;
+        if let Some(dialer) = self.dialers.lock().unwrap().remove(&id) {
+            dialer.abort();
+        }
+        self.handlers.lock().unwrap().remove(&id);
+        self.bandwidth.lock().unwrap().remove_torrent(id);
+        if let Some(info_hash) = torrent.info_hash() {
+            self.connections.unregister_torrent(&info_hash);
+        }
+        if let Some(lsd) = self.lsd.read().await.as_ref() {
+            lsd.remove_torrent(&torrent.metadata.info_hash);
+        }
+        Ok(())
+    }
+
+    /// Bans a peer from all torrents and drops it from their peers. Returns
+    /// whether it was not banned yet.
+    pub async fn ban_peer(&self, ip: IpAddr) -> bool {
+        let banned = self.bans.ban(ip, BanReason::Manual);
+        self.remove_blocked_peers().await;
+        banned
+    }
+
+    /// Lifts a ban, also one for sending corrupt data. Returns whether the
+    /// peer was banned.
+    pub fn unban_peer(&self, ip: IpAddr) -> bool {
+        self.bans.unban(ip)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.is_banned(ip)
+    }
+
+    pub fn banned_peers(&self) -> Vec<(IpAddr, BanReason)> {
+        self.bans.banned()
+    }
+
+    /// How many pieces failed their hash check with data from the peer.
+    pub fn hash_failures(&self, ip: IpAddr) -> u32 {
+        self.bans.hash_failures(ip)
+    }
+
+    /// Replaces the IP filter of all torrents and drops the peers it blocks.
+    pub async fn set_ip_filter(&self, filter: IpFilter) {
+        self.ip_filter.set(filter);
+        self.remove_blocked_peers().await;
+    }
+
+    /// Loads the IP filter from a file, replacing the current one; see
+    /// [`IpFilter::parse`] for the formats. Returns the number of ranges.
+    pub async fn load_ip_filter(&self, path: impl AsRef<Path>) -> RustyTorrentResult<usize> {
+        let filter = IpFilter::load(path)?;
+        let ranges = filter.len();
+        self.set_ip_filter(filter).await;
+        Ok(ranges)
+    }
+
+    pub fn ip_filter(&self) -> Arc<IpFilter> {
+        self.ip_filter.get()
+    }
+
+    /// Whether a connection to or from the address is allowed, to be checked
+    /// before connecting and when accepting a connection.
+    pub fn allows_peer(&self, ip: IpAddr) -> bool {
+        allowed(ip, &self.bans, &self.ip_filter)
+    }
+
+    /// [`Self::allows_peer`] as a filter for connections and peer sources,
+    /// following later bans and filter changes.
+    pub fn peer_filter(&self) -> PeerFilter {
+        peer_filter(&self.bans, &self.ip_filter)
+    }
+
+    // drops the peers of every torrent that are banned or filtered
+    async fn remove_blocked_peers(&self) {
+        for torrent in self.torrents.read().await.values() {
+            torrent.remove_blocked_peers().await;
+        }
+        for magnet in self.magnets.read().await.values() {
+            magnet
+                .peers
+                .write()
+                .await
+                .retain(|peer| peer.ip_addr().is_none_or(|ip| self.allows_peer(ip)));
+        }
+    }
+
+    /// The rate limiter every connection asks before sending or reading.
+    pub fn bandwidth(&self) -> Arc<Mutex<BandwidthLimiter>> {
+        Arc::clone(&self.bandwidth)
+    }
+
+    pub fn set_global_rate_limit(&self, limit: RateLimit) {
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .set_global_limit(limit, Instant::now());
+    }
+
+    pub async fn set_torrent_rate_limit(
+        &self,
+        id: TorrentId,
+        limit: RateLimit,
+    ) -> RustyTorrentResult<()> {
+        if !self.torrents.read().await.contains_key(&id)
+            && !self.magnets.read().await.contains_key(&id)
+        {
+            return Err(RustyTorrentError::TorrentNotFound(id));
+        }
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .set_torrent_limit(id, limit, Instant::now());
+        Ok(())
+    }
+
+    /// Returns whether the peer is connected.
+    pub fn set_peer_rate_limit(&self, peer: SocketAddr, limit: RateLimit) -> bool {
+        self.bandwidth
+            .lock()
+            .unwrap()
+            .set_peer_limit(peer, limit, Instant::now())
+    }
+
+    pub fn set_lan_exempt(&self, exempt: bool) {
+        self.bandwidth.lock().unwrap().set_exempt_lan(exempt);
+    }
+
+    pub fn set_count_overhead(&self, count: bool) {
+        self.bandwidth.lock().unwrap().set_count_overhead(count);
+    }
+}
+
+impl Drop for RustyTorrentSession {
+    fn drop(&mut self) {
+        for dialer in self.dialers.lock().unwrap().values() {
+            dialer.abort();
+        }
+    }
+}
+
+// dials the peers a torrent learns about, each at most once per redial
+// interval unless a connection limit got in the way
+async fn dial_peers(
+    connections: Arc<TcpConnectionManager>,
+    peers: Arc<RwLock<Vec<Peer>>>,
+    info_hash: InfoHash,
+) {
+    let mut dialed = HashMap::<SocketAddr, Instant>::new();
+    loop {
+        let now = Instant::now();
+        dialed.retain(|_, at| now.duration_since(*at) < REDIAL_INTERVAL);
+        let addrs = peers
+            .read()
+            .await
+            .iter()
+            .filter_map(|peer| Some(SocketAddr::new(peer.ip_addr()?, peer.port)))
+            .filter(|addr| !dialed.contains_key(addr))
+            .collect::<Vec<_>>();
+        let attempts = addrs.into_iter().map(|addr| {
+            dialed.insert(addr, now);
+            let connections = &connections;
+            async move { (addr, connections.connect(info_hash, addr).await) }
+        });
+        for (addr, result) in join_all(attempts).await {
+            match result {
+                Ok(_) => {}
+                Err(PwpError::ConnectionLimit | PwpError::TorrentConnectionLimit) => {
+                    dialed.remove(&addr);
+                }
+                Err(e) => debug!("connecting to {} failed: {}", addr, e),
+            }
+        }
+        sleep(DIAL_INTERVAL).await;
+    }
 }
@@ -0,0 +1,94 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+};
+
+use sha1::{Digest, Sha1};
+use torrent_pwp::message::BlockInfo;
+
+/// A block of a checked piece, with the peer that sent it if known.
+pub struct ReceivedBlock<'a> {
+    pub block: BlockInfo,
+    pub from: Option<SocketAddr>,
+    pub data: &'a [u8],
+}
+
+/// Failed pieces kept for comparison at most; the oldest is forgotten first.
+pub const MAX_FAILED_PIECES: usize = 256;
+
+fn hash(data: &[u8]) -> [u8; 20] {
+    Sha1::digest(data).into()
+}
+
+/// Finds the peers that sent corrupt data. When a piece fails, the hash of
+/// each block is kept along with its sender; once the piece passed after
+/// downloading it again, the blocks that differ show who sent bad data. A
+/// piece that came entirely from one peer needs no comparison.
+#[derive(Default)]
+pub struct SmartBan {
+    failed: HashMap<u32, Vec<(BlockInfo, IpAddr, [u8; 20])>>,
+    // failed pieces, oldest first
+    order: VecDeque<u32>,
+}
+
+impl SmartBan {
+    /// Records a piece that failed its hash check, returning the IPs known to
+    /// be responsible.
+    pub fn on_piece_failed(&mut self, index: u32, blocks: &[ReceivedBlock]) -> Vec<IpAddr> {
+        let senders = blocks
+            .iter()
+            .filter_map(|block| block.from.map(|from| from.ip()))
+            .collect::<HashSet<_>>();
+        let single_sender = senders.len() == 1 && blocks.iter().all(|block| block.from.is_some());
+        // earlier copies from several peers are still compared once the
+        // piece passes, so this one joins them
+        if single_sender && !self.failed.contains_key(&index) {
+            return senders.into_iter().collect();
+        }
+        if !self.failed.contains_key(&index) {
+            if self.order.len() == MAX_FAILED_PIECES {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.failed.remove(&oldest);
+                }
+            }
+            self.order.push_back(index);
+        }
+        let hashes = self.failed.entry(index).or_default();
+        for block in blocks {
+            if let Some(from) = block.from {
+                let entry = (block.block, from.ip(), hash(block.data));
+                if !hashes.contains(&entry) {
+                    hashes.push(entry);
+                }
+            }
+        }
+        match single_sender {
+            true => senders.into_iter().collect(),
+            false => Vec::new(),
+        }
+    }
+
+    /// Compares a piece that passed with its failed copies, returning the IPs
+    /// that sent blocks differing from the good data.
+    pub fn on_piece_passed(&mut self, index: u32, blocks: &[ReceivedBlock]) -> Vec<IpAddr> {
+        let Some(failed) = self.failed.remove(&index) else {
+            return Vec::new();
+        };
+        self.order.retain(|failed| *failed != index);
+        let good = blocks
+            .iter()
+            .map(|block| (block.block, hash(block.data)))
+            .collect::<HashMap<_, _>>();
+        let guilty = failed
+            .into_iter()
+            .filter(|(block, _, hash)| good.get(block).is_some_and(|good| good != hash))
+            .map(|(_, ip, _)| ip)
+            .collect::<HashSet<_>>();
+        guilty.into_iter().collect()
+    }
+
+    /// Pieces with failed copies waiting for a good one.
+    pub fn pending(&self) -> usize {
+        self.failed.len()
+    }
+}
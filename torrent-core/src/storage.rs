@@ -0,0 +1,129 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use torrent_parser::model::TorrentMetadata;
+
+use crate::error::RustyTorrentResult;
+
+/// Where the bytes of a torrent are kept, addressed by their offset in the
+/// torrent as if all files were one.
+pub trait Storage: Send + Sync {
+    /// Fills `buf` with the bytes at `offset`.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> RustyTorrentResult<()>;
+
+    fn write(&self, offset: u64, data: &[u8]) -> RustyTorrentResult<()>;
+}
+
+struct StorageFile {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// Keeps the files of a torrent on disk, creating them on the first write.
+pub struct FileStorage {
+    files: Vec<StorageFile>,
+}
+
+impl FileStorage {
+    /// Files with their length, in torrent order.
+    pub fn new(files: Vec<(PathBuf, u64)>) -> Self {
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let file = StorageFile {
+                    path,
+                    offset,
+                    length,
+                };
+                offset += length;
+                file
+            })
+            .collect();
+        FileStorage { files }
+    }
+
+    /// The layout of the metadata below `location`: a single file named after
+    /// the torrent, or a directory of that name with the files in it.
+    pub fn from_metadata(location: impl AsRef<Path>, metadata: &TorrentMetadata) -> Self {
+        let root = location.as_ref().join(sanitize(&metadata.info.name));
+        let files = match &metadata.info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    let path = file
+                        .path
+                        .iter()
+                        .fold(root.clone(), |path, part| path.join(sanitize(part)));
+                    (path, file.length as u64)
+                })
+                .collect(),
+            None => vec![(root, metadata.info.length.unwrap_or_default() as u64)],
+        };
+        FileStorage::new(files)
+    }
+
+    // the parts of the files covering `length` bytes at `offset`, as file
+    // index, offset in the file and position in the buffer
+    fn spans(&self, offset: u64, length: usize) -> Vec<(usize, u64, usize, usize)> {
+        let end = offset + length as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.offset < end && file.offset + file.length > offset)
+            .map(|(i, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                (
+                    i,
+                    start - file.offset,
+                    (start - offset) as usize,
+                    (stop - offset) as usize,
+                )
+            })
+            .collect()
+    }
+}
+
+// paths come from the metadata and must not leave the download directory
+fn sanitize(part: &str) -> PathBuf {
+    Path::new(part)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Storage for FileStorage {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> RustyTorrentResult<()> {
+        for (i, file_offset, from, to) in self.spans(offset, buf.len()) {
+            let mut file = File::open(&self.files[i].path)?;
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.read_exact(&mut buf[from..to])?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> RustyTorrentResult<()> {
+        for (i, file_offset, from, to) in self.spans(offset, data.len()) {
+            let path = &self.files[i].path;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?;
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.write_all(&data[from..to])?;
+        }
+        Ok(())
+    }
+}
@@ -1,29 +1,109 @@
-use std::{cell::RefCell, sync::Arc, time::Duration};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use reqwest::Client;
-use tokio::{spawn, sync::RwLock, task::JoinHandle, time::sleep};
-use torrent_parser::{
-    model::{TorrentMetadata, TrackerResponse},
-    parse_tracker_response,
+use sha1::{Digest, Sha1};
+use tokio::{
+    spawn,
+    sync::RwLock,
+    task::{spawn_blocking, JoinHandle},
+    time::sleep,
+};
+use torrent_dht::DhtNode;
+use torrent_parser::model::{TorrentMetadata, TrackerResponse};
+use torrent_pwp::{
+    message::{BlockInfo, Message},
+    InfoHash, PeerFilter,
 };
 
+use tracing::debug;
+
 use crate::{
+    ban::{BanList, BanReason},
+    choker::{Choker, ChokerConfig, RateBased},
+    error::{RustyTorrentError, RustyTorrentResult},
+    ipfilter::SharedIpFilter,
+    metadata::{MetadataProgress, MetadataSource},
     peer::Peer,
-    tracker::{Tracker, TrackerConnectionState},
+    picker::{PiecePicker, Priority},
+    scheduler::{BlockScheduler, SchedulerConfig},
+    smartban::{ReceivedBlock, SmartBan},
+    storage::{FileStorage, Storage},
+    tracker::{
+        client::{AnnounceEvent, AnnounceRequest, TrackerClientRegistry},
+        Tracker, TrackerConnectionState,
+    },
+    upload::UploadQueue,
 };
 
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+fn total_length(metadata: &TorrentMetadata) -> u64 {
+    match &metadata.info.files {
+        Some(files) => files.iter().map(|file| file.length as u64).sum(),
+        None => metadata.info.length.unwrap_or_default() as u64,
+    }
+}
+
+pub(crate) fn allowed(ip: IpAddr, bans: &BanList, ip_filter: &SharedIpFilter) -> bool {
+    !bans.is_banned(ip) && !ip_filter.is_blocked(ip)
+}
+
+pub(crate) fn peer_filter(bans: &Arc<BanList>, ip_filter: &Arc<SharedIpFilter>) -> PeerFilter {
+    let bans = Arc::clone(bans);
+    let ip_filter = Arc::clone(ip_filter);
+    Arc::new(move |ip| allowed(ip, &bans, &ip_filter))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Added from a magnet link, waiting for the metadata from peers.
+    FetchingMetadata(MetadataProgress),
+    Stopped,
+    Started,
+}
+
+/// The result of checking a piece against its hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceCheck {
+    pub passed: bool,
+    /// Peers banned for sending corrupt data, to be disconnected.
+    pub banned: Vec<IpAddr>,
+}
+
 pub struct ManagedTorrent {
     pub metadata: TorrentMetadata,
     pub name: String,
     pub location: String,
     pub peers: Arc<RwLock<Vec<Peer>>>,
     pub trackers: Vec<Arc<RwLock<Tracker>>>,
-    task_handles: RefCell<Vec<JoinHandle<()>>>,
+    task_handles: Mutex<Vec<JoinHandle<()>>>,
+    started: AtomicBool,
     pub downloaded: Arc<RwLock<u64>>,
     pub uploaded: Arc<RwLock<u64>>,
-    client: Arc<Client>,
+    /// Block requests of all connections of the torrent.
+    pub scheduler: Arc<Mutex<BlockScheduler>>,
+    /// Which peers we upload to.
+    pub choker: Arc<Mutex<Choker>>,
+    pub storage: Arc<dyn Storage>,
+    /// Banned peers, shared with the session.
+    pub bans: Arc<BanList>,
+    /// Addresses never connected to, shared with the session.
+    pub ip_filter: Arc<SharedIpFilter>,
+    smart_ban: Mutex<SmartBan>,
+    tracker_clients: Arc<TrackerClientRegistry>,
+    dht: Option<Arc<DhtNode>>,
     peer_id: String,
     port: u32,
+    // shared by all connections serving the metadata
+    info_bytes: Arc<Vec<u8>>,
 }
 
 impl ManagedTorrent {
@@ -33,7 +113,8 @@ impl ManagedTorrent {
         location: String,
         peer_id: String,
         port: u32,
-        client: Arc<Client>,
+        tracker_clients: Arc<TrackerClientRegistry>,
+        dht: Option<Arc<DhtNode>>,
     ) -> Self {
         let meta_name = metadata.info.name.clone();
         let tracker = metadata.announce.clone();
@@ -43,7 +124,7 @@ impl ManagedTorrent {
             .map(|trackers| trackers.into_iter().flatten().collect::<Vec<_>>())
             .unwrap_or_default();
 
-        let mut trackers: Vec<Tracker> = vec![tracker]
+        let mut trackers: Vec<Tracker> = tracker
             .into_iter()
             .chain(trackers_extra)
             .map(Tracker::from)
@@ -55,7 +136,22 @@ impl ManagedTorrent {
             .collect();
 
         let name = name.unwrap_or(meta_name);
+        let scheduler = BlockScheduler::new(
+            metadata.info.piece_length as u64,
+            total_length(&metadata),
+            SchedulerConfig::default(),
+        );
         ManagedTorrent {
+            storage: Arc::new(FileStorage::from_metadata(&location, &metadata)),
+            scheduler: Arc::new(Mutex::new(scheduler)),
+            choker: Arc::new(Mutex::new(Choker::new(
+                ChokerConfig::default(),
+                Box::new(RateBased),
+            ))),
+            bans: Default::default(),
+            ip_filter: Default::default(),
+            smart_ban: Default::default(),
+            info_bytes: Arc::new(metadata.info_bytes.clone()),
             metadata,
             name,
             location,
@@ -64,18 +160,303 @@ impl ManagedTorrent {
             peers: Default::default(),
             trackers,
             task_handles: Default::default(),
-            client,
+            started: Default::default(),
+            tracker_clients,
+            dht,
             peer_id,
             port,
         }
     }
 
-    pub fn start(&self) {
+    /// Total number of bytes described by the metadata.
+    pub fn total_length(&self) -> u64 {
+        total_length(&self.metadata)
+    }
+
+    /// Offset and length of each file in the torrent's bytes.
+    pub fn file_ranges(&self) -> Vec<(u64, u64)> {
+        let Some(files) = &self.metadata.info.files else {
+            return vec![(0, self.total_length())];
+        };
+        let mut offset = 0;
+        files
+            .iter()
+            .map(|file| {
+                let range = (offset, file.length as u64);
+                offset += file.length as u64;
+                range
+            })
+            .collect()
+    }
+
+    fn file_range(&self, file: usize) -> RustyTorrentResult<(u64, u64)> {
+        self.file_ranges()
+            .get(file)
+            .copied()
+            .ok_or(RustyTorrentError::FileNotFound(file))
+    }
+
+    pub fn set_file_priority(&self, file: usize, priority: Priority) -> RustyTorrentResult<()> {
+        let (offset, length) = self.file_range(file)?;
+        self.picker(|picker| picker.set_file_priority(offset, length, priority));
+        Ok(())
+    }
+
+    pub fn set_piece_priority(&self, index: u32, priority: Priority) {
+        self.picker(|picker| picker.set_piece_priority(index, priority));
+    }
+
+    /// Downloads the whole torrent in order. Other pieces are still picked
+    /// now and then, so we have something to give to the swarm.
+    pub fn set_sequential(&self, sequential: bool) {
+        self.picker(|picker| picker.set_sequential(sequential));
+    }
+
+    pub fn set_file_sequential(&self, file: usize, sequential: bool) -> RustyTorrentResult<()> {
+        let (offset, length) = self.file_range(file)?;
+        self.picker(|picker| picker.set_file_sequential(offset, length, sequential));
+        Ok(())
+    }
+
+    /// Wants the piece within `deadline`, ahead of the other pieces.
+    pub fn set_piece_deadline(&self, index: u32, deadline: Duration) {
+        let deadline = Instant::now() + deadline;
+        self.picker(|picker| picker.set_deadline(index, deadline));
+    }
+
+    /// Wants `length` bytes at `offset` in the file within `deadline`, e.g.
+    /// the part of a video about to be played.
+    pub fn set_file_deadline(
+        &self,
+        file: usize,
+        offset: u64,
+        length: u64,
+        deadline: Duration,
+    ) -> RustyTorrentResult<()> {
+        let (file_offset, file_length) = self.file_range(file)?;
+        let length = length.min(file_length.saturating_sub(offset));
+        let deadline = Instant::now() + deadline;
+        self.picker(|picker| picker.set_range_deadline(file_offset + offset, length, deadline));
+        Ok(())
+    }
+
+    fn picker(&self, f: impl FnOnce(&mut PiecePicker)) {
+        f(self.scheduler.lock().unwrap().picker_mut());
+    }
+
+    /// Queues a request from a peer if it is for a block we have, returning
+    /// the message to refuse it with otherwise.
+    pub fn queue_request(&self, queue: &mut UploadQueue, block: BlockInfo) -> Option<Message> {
+        let scheduler = self.scheduler.lock().unwrap();
+        queue.on_request(block, scheduler.have(), scheduler.piece_size(block.index))
+    }
+
+    /// Reads the next request of `queue` from storage, counting the bytes into
+    /// `uploaded`.
+    pub async fn serve(&self, queue: &mut UploadQueue) -> RustyTorrentResult<Option<Message>> {
+        let Some(block) = queue.pop() else {
+            return Ok(None);
+        };
+        let offset =
+            block.index as u64 * self.metadata.info.piece_length as u64 + block.begin as u64;
+        let storage = Arc::clone(&self.storage);
+        let data = spawn_blocking(move || {
+            let mut data = vec![0; block.length as usize];
+            storage.read(offset, &mut data).map(|_| data)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        queue.sent(data.len() as u64, Instant::now());
+        *self.uploaded.write().await += data.len() as u64;
+        Ok(Some(Message::Piece {
+            index: block.index,
+            begin: block.begin,
+            data: data.into(),
+        }))
+    }
+
+    /// Checks a complete piece, after its blocks were written to storage. On a
+    /// failure every peer that sent part of it gets a hash failure counted and
+    /// the piece is downloaded again; the peers responsible are banned once
+    /// known.
+    pub async fn verify_piece(&self, index: u32) -> RustyTorrentResult<PieceCheck> {
+        let size = self.scheduler.lock().unwrap().piece_size(index);
+        let offset = index as u64 * self.metadata.info.piece_length as u64;
+        let storage = Arc::clone(&self.storage);
+        let data = spawn_blocking(move || {
+            let mut data = vec![0; size as usize];
+            storage.read(offset, &mut data).map(|_| data)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        let passed = self
+            .metadata
+            .info
+            .pieces
+            .get(index as usize)
+            .is_some_and(|hash| Sha1::digest(&data).as_slice() == hash.as_slice());
+
+        let senders = {
+            let mut scheduler = self.scheduler.lock().unwrap();
+            match passed {
+                true => scheduler.piece_passed(index),
+                false => scheduler.piece_failed(index),
+            }
+        };
+        let blocks = senders
+            .iter()
+            .map(|(block, from)| ReceivedBlock {
+                block: *block,
+                from: *from,
+                data: &data[block.begin as usize..(block.begin + block.length) as usize],
+            })
+            .collect::<Vec<_>>();
+        let responsible = match passed {
+            true => self
+                .smart_ban
+                .lock()
+                .unwrap()
+                .on_piece_passed(index, &blocks),
+            false => {
+                let contributors = senders
+                    .iter()
+                    .filter_map(|(_, from)| from.map(|from| from.ip()))
+                    .collect::<HashSet<_>>();
+                for ip in contributors {
+                    self.bans.add_hash_failure(ip);
+                }
+                self.smart_ban
+                    .lock()
+                    .unwrap()
+                    .on_piece_failed(index, &blocks)
+            }
+        };
+        let banned = responsible
+            .into_iter()
+            .filter(|ip| self.bans.ban(*ip, BanReason::CorruptData))
+            .collect::<Vec<_>>();
+        if !banned.is_empty() {
+            debug!("banned {banned:?} for corrupt data in piece {index}");
+            self.remove_blocked_peers().await;
+        }
+        Ok(PieceCheck { passed, banned })
+    }
+
+    /// Marks the pieces already in storage that match their hash as ours, so
+    /// a torrent can be seeded or resumed. Returns how many there are.
+    pub async fn check_pieces(&self) -> RustyTorrentResult<usize> {
+        let mut found = 0;
+        for (index, hash) in self.metadata.info.pieces.iter().enumerate() {
+            let size = self.scheduler.lock().unwrap().piece_size(index as u32);
+            let offset = index as u64 * self.metadata.info.piece_length as u64;
+            let storage = Arc::clone(&self.storage);
+            let data = spawn_blocking(move || {
+                let mut data = vec![0; size as usize];
+                storage.read(offset, &mut data).map(|_| data)
+            })
+            .await
+            .map_err(io::Error::other)?;
+            // missing or short files just mean we lack the piece
+            if data.is_ok_and(|data| Sha1::digest(&data).as_slice() == hash.as_slice()) {
+                self.scheduler.lock().unwrap().piece_passed(index as u32);
+                found += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    /// The info hash as used on the wire, if the metadata has a valid one.
+    pub fn info_hash(&self) -> Option<InfoHash> {
+        InfoHash::try_from(self.metadata.info_hash.as_slice()).ok()
+    }
+
+    pub(crate) fn listen_port(&self) -> u16 {
+        self.port as u16
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.metadata.info.private.unwrap_or(false)
+    }
+
+    /// PEX is turned off for private torrents, which must only get peers from
+    /// their trackers.
+    pub fn pex_enabled(&self) -> bool {
+        !self.is_private()
+    }
+
+    /// Local Service Discovery is off for private torrents as well.
+    pub fn lsd_enabled(&self) -> bool {
+        !self.is_private()
+    }
+
+    pub fn state(&self) -> TorrentState {
+        if self.started.load(Ordering::Relaxed) {
+            TorrentState::Started
+        } else {
+            TorrentState::Stopped
+        }
+    }
+
+    /// For the `ut_metadata` extension, so peers can fetch the metadata from
+    /// us.
+    pub fn metadata_source(&self) -> MetadataSource {
+        MetadataSource::Complete(Arc::clone(&self.info_bytes))
+    }
+
+    /// Whether we may connect to or accept a connection from the address: it
+    /// is neither banned nor blocked by the IP filter.
+    pub fn allows_peer(&self, ip: IpAddr) -> bool {
+        allowed(ip, &self.bans, &self.ip_filter)
+    }
+
+    /// [`Self::allows_peer`] for the peer sources and connections of this
+    /// torrent, following later bans and filter changes.
+    pub fn peer_filter(&self) -> PeerFilter {
+        peer_filter(&self.bans, &self.ip_filter)
+    }
+
+    /// Drops peers that are no longer allowed, after a ban or a new IP
+    /// filter.
+    pub async fn remove_blocked_peers(&self) {
+        self.peers
+            .write()
+            .await
+            .retain(|peer| peer.ip_addr().is_none_or(|ip| self.allows_peer(ip)));
+    }
+
+    /// Adds peers learned through PEX, returning how many were new. Peers
+    /// that are not allowed are left out.
+    pub async fn add_pex_peers(&self, addrs: Vec<SocketAddr>) -> RustyTorrentResult<usize> {
+        if !self.pex_enabled() {
+            return Err(RustyTorrentError::PexDisabled);
+        }
+        let mut peers = self.peers.write().await;
+        let mut added = 0;
+        for addr in addrs.into_iter().filter(|addr| self.allows_peer(addr.ip())) {
+            let peer = Peer::from(addr);
+            if !peers.contains(&peer) {
+                peers.push(peer);
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Starts announcing to the trackers and the DHT. Returns false, doing
+    /// nothing, if the torrent was already started.
+    pub fn start(&self) -> bool {
+        if self.started.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        self.start_dht();
+        let total_length = self.total_length();
         // spawn a job to contact trackers every interval
         for tracker in &self.trackers {
-            let client = Arc::clone(&self.client);
+            let tracker_clients = Arc::clone(&self.tracker_clients);
             let tracker = Arc::clone(tracker);
             let peers = Arc::clone(&self.peers);
+            let bans = Arc::clone(&self.bans);
+            let ip_filter = Arc::clone(&self.ip_filter);
             let downloaded = Arc::clone(&self.downloaded);
             let uploaded = Arc::clone(&self.uploaded);
             let info_hash = self.metadata.info_hash.clone();
@@ -89,76 +470,128 @@ impl ManagedTorrent {
                     drop(r_tracker);
                     sleep(Duration::from_secs(interval as u64)).await;
                     let mut tracker = tracker.write().await;
-                    let downloaded = downloaded.read().await;
-                    let uploaded = uploaded.read().await;
-                    let mut req = client
-                        .get(&tracker.announce)
-                        .timeout(Duration::from_secs(10))
-                        .query(&[("info_hash", &info_hash)])
-                        .query(&[("peer_id", &peer_id)])
-                        .query(&[("port", &port)])
-                        .query(&[("compact", &1)])
-                        .query(&[("uploaded", &*downloaded), ("downloaded", &*uploaded)]);
-
-                    if let Some(id) = &tracker.traker_id {
-                        req = req.query(&[("trackerid", id)]);
-                    }
-
-                    if matches!(tracker.state, TrackerConnectionState::NotContacted) {
-                        req = req.query(&[("event", "started")]);
-                    }
+                    let downloaded = *downloaded.read().await;
+                    let uploaded = *uploaded.read().await;
+                    let event = matches!(tracker.state, TrackerConnectionState::NotContacted)
+                        .then_some(AnnounceEvent::Started);
+                    let request = AnnounceRequest {
+                        info_hash: info_hash.clone(),
+                        peer_id: peer_id.clone(),
+                        port,
+                        uploaded,
+                        downloaded,
+                        left: total_length.saturating_sub(downloaded),
+                        event,
+                        tracker_id: tracker.traker_id.clone(),
+                        num_want: None,
+                    };
 
-                    let resp = req.send().await;
-                    match resp {
+                    match tracker_clients.announce(&tracker.announce, &request).await {
                         Err(e) => {
-                            tracker.state =
-                                crate::tracker::TrackerConnectionState::Timeout(e.to_string());
+                            tracker.state = TrackerConnectionState::Timeout(e.to_string());
                         }
-                        Ok(resp) => {
-                            let body = resp.bytes().await.unwrap().to_vec();
-                            let parsed = parse_tracker_response(body);
-                            if let Ok(parsed) = parsed {
-                                tracker.update(&parsed);
-
-                                if let TrackerResponse::Success(resp) = parsed {
-                                    let mut peers = peers.write().await;
-                                    for peer in resp.peers {
-                                        peers.push(Peer::from(peer));
+                        Ok(parsed) => {
+                            tracker.update(&parsed);
+
+                            if let TrackerResponse::Success(resp) = parsed {
+                                let mut peers = peers.write().await;
+                                for peer in resp.peers {
+                                    let peer = Peer::from(peer);
+                                    let blocked = peer
+                                        .ip_addr()
+                                        .is_some_and(|ip| !allowed(ip, &bans, &ip_filter));
+                                    if !blocked && !peers.contains(&peer) {
+                                        peers.push(peer);
                                     }
-                                    peers.dedup();
                                 }
                             }
                         }
                     }
                 }
             });
-            self.task_handles.borrow_mut().push(handle);
+            self.task_handles.lock().unwrap().push(handle);
         }
+        true
+    }
+
+    /// Looks up and announces to the DHT periodically. Private torrents only
+    /// get peers from their trackers.
+    fn start_dht(&self) {
+        let Some(dht) = &self.dht else {
+            return;
+        };
+        if self.is_private() {
+            return;
+        }
+        let dht = Arc::clone(dht);
+        let peers = Arc::clone(&self.peers);
+        let bans = Arc::clone(&self.bans);
+        let ip_filter = Arc::clone(&self.ip_filter);
+        let info_hash = self.metadata.info_hash.clone();
+        let nodes = self.metadata.nodes.clone().unwrap_or_default();
+        let port = self.port as u16;
+        let handle = spawn(async move {
+            for (host, node_port) in nodes {
+                if let Err(e) = dht.add_node(&host, node_port as u16).await {
+                    debug!(
+                        "dht node {}:{} from metadata failed: {}",
+                        host, node_port, e
+                    );
+                }
+            }
+            loop {
+                match dht.announce(&info_hash, Some(port)).await {
+                    Ok(found) => {
+                        let mut peers = peers.write().await;
+                        for addr in found
+                            .into_iter()
+                            .filter(|addr| allowed(addr.ip(), &bans, &ip_filter))
+                        {
+                            let peer = Peer::from(addr);
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                    }
+                    Err(e) => debug!("dht announce failed: {}", e),
+                }
+                sleep(DHT_ANNOUNCE_INTERVAL).await;
+            }
+        });
+        self.task_handles.lock().unwrap().push(handle);
     }
 }
 
 impl Drop for ManagedTorrent {
     fn drop(&mut self) {
-        for handle in self.task_handles.borrow_mut().drain(..) {
+        for handle in self.task_handles.lock().unwrap().drain(..) {
             handle.abort();
         }
         // send stopped event to trackers
         for tracker in &self.trackers {
-            let client = Arc::clone(&self.client);
+            let tracker_clients = Arc::clone(&self.tracker_clients);
             let tracker = Arc::clone(tracker);
+            let downloaded = Arc::clone(&self.downloaded);
+            let uploaded = Arc::clone(&self.uploaded);
+            let total_length = self.total_length();
             let info_hash = self.metadata.info_hash.clone();
             let peer_id = self.peer_id.clone();
             let port = self.port;
             spawn(async move {
                 let tracker = tracker.read().await;
-                let _ = client
-                    .get(&tracker.announce)
-                    .query(&[("info_hash", &info_hash)])
-                    .query(&[("peer_id", &peer_id)])
-                    .query(&[("port", &port)])
-                    .query(&[("event", "stopped")])
-                    .send()
-                    .await;
+                let downloaded = *downloaded.read().await;
+                let request = AnnounceRequest {
+                    info_hash,
+                    peer_id,
+                    port,
+                    uploaded: *uploaded.read().await,
+                    downloaded,
+                    left: total_length.saturating_sub(downloaded),
+                    event: Some(AnnounceEvent::Stopped),
+                    tracker_id: tracker.traker_id.clone(),
+                    num_want: Some(0),
+                };
+                let _ = tracker_clients.announce(&tracker.announce, &request).await;
             });
         }
     }
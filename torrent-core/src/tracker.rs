@@ -1,3 +1,8 @@
+pub mod client;
+pub mod http;
+pub mod mock;
+pub mod udp;
+
 use torrent_parser::model::TrackerResponse;
 
 pub struct TrackerStatus {
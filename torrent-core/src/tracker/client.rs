@@ -0,0 +1,136 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use torrent_parser::model::{ScrapeResponse, TrackerResponse};
+
+use crate::error::{RustyTorrentError, RustyTorrentResult};
+
+use super::{http::HttpTrackerClient, udp::UdpTrackerClient};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Stopped,
+    Completed,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Stopped => "stopped",
+            AnnounceEvent::Completed => "completed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: Vec<u8>,
+    pub peer_id: String,
+    pub port: u32,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    pub tracker_id: Option<String>,
+    pub num_want: Option<u32>,
+}
+
+/// A transport able to talk to trackers of one or more URL schemes.
+///
+/// Implementations are looked up by the scheme of the announce URL, so
+/// applications can plug in their own transports through
+/// [`TrackerClientRegistry::register`].
+#[async_trait]
+pub trait TrackerClient: Send + Sync {
+    async fn announce(
+        &self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> RustyTorrentResult<TrackerResponse>;
+
+    async fn scrape(
+        &self,
+        url: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> RustyTorrentResult<ScrapeResponse>;
+}
+
+pub struct TrackerClientRegistry {
+    clients: RwLock<HashMap<String, Arc<dyn TrackerClient>>>,
+}
+
+impl TrackerClientRegistry {
+    /// Creates a registry without any transport registered.
+    pub fn empty() -> Self {
+        TrackerClientRegistry {
+            clients: Default::default(),
+        }
+    }
+
+    pub fn register(&self, scheme: &str, client: Arc<dyn TrackerClient>) {
+        self.clients
+            .write()
+            .unwrap()
+            .insert(scheme.to_ascii_lowercase(), client);
+    }
+
+    pub fn unregister(&self, scheme: &str) -> Option<Arc<dyn TrackerClient>> {
+        self.clients
+            .write()
+            .unwrap()
+            .remove(&scheme.to_ascii_lowercase())
+    }
+
+    /// Finds the client responsible for the scheme of `url`.
+    pub fn get(&self, url: &str) -> RustyTorrentResult<Arc<dyn TrackerClient>> {
+        let scheme = url_scheme(url)
+            .ok_or_else(|| RustyTorrentError::UnsupportedTrackerScheme(url.to_string()))?;
+        self.clients
+            .read()
+            .unwrap()
+            .get(&scheme)
+            .cloned()
+            .ok_or(RustyTorrentError::UnsupportedTrackerScheme(scheme))
+    }
+
+    pub async fn announce(
+        &self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> RustyTorrentResult<TrackerResponse> {
+        self.get(url)?.announce(url, request).await
+    }
+
+    pub async fn scrape(
+        &self,
+        url: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> RustyTorrentResult<ScrapeResponse> {
+        self.get(url)?.scrape(url, info_hashes).await
+    }
+}
+
+impl Default for TrackerClientRegistry {
+    /// Creates a registry with the HTTP, HTTPS and UDP transports.
+    fn default() -> Self {
+        let registry = TrackerClientRegistry::empty();
+        let http = Arc::new(HttpTrackerClient::default());
+        registry.register("http", http.clone());
+        registry.register("https", http);
+        registry.register("udp", Arc::new(UdpTrackerClient::default()));
+        registry
+    }
+}
+
+fn url_scheme(url: &str) -> Option<String> {
+    let (scheme, _) = url.split_once("://")?;
+    if scheme.is_empty() {
+        return None;
+    }
+    Some(scheme.to_ascii_lowercase())
+}
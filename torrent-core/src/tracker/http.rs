@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::Client;
+use torrent_parser::{
+    model::{ScrapeResponse, TrackerResponse},
+    parse_scrape_response, parse_tracker_response,
+};
+
+use crate::error::{RustyTorrentError, RustyTorrentResult};
+
+use super::client::{AnnounceRequest, TrackerClient};
+
+/// Tracker client for `http://` and `https://` announce URLs.
+pub struct HttpTrackerClient {
+    client: Client,
+    timeout: Duration,
+}
+
+impl HttpTrackerClient {
+    pub fn new(client: Client, timeout: Duration) -> Self {
+        HttpTrackerClient { client, timeout }
+    }
+
+    async fn get(&self, url: String) -> RustyTorrentResult<Vec<u8>> {
+        let resp = self
+            .client
+            .get(url)
+            .timeout(self.timeout)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(resp.bytes().await?.to_vec())
+    }
+}
+
+impl Default for HttpTrackerClient {
+    fn default() -> Self {
+        HttpTrackerClient::new(Client::default(), Duration::from_secs(10))
+    }
+}
+
+#[async_trait]
+impl TrackerClient for HttpTrackerClient {
+    async fn announce(
+        &self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> RustyTorrentResult<TrackerResponse> {
+        let mut query = vec![
+            ("info_hash", url_encode(&request.info_hash)),
+            ("peer_id", url_encode(request.peer_id.as_bytes())),
+            ("port", request.port.to_string()),
+            ("uploaded", request.uploaded.to_string()),
+            ("downloaded", request.downloaded.to_string()),
+            ("left", request.left.to_string()),
+            ("compact", "1".to_string()),
+        ];
+        if let Some(event) = request.event {
+            query.push(("event", event.as_str().to_string()));
+        }
+        if let Some(id) = &request.tracker_id {
+            query.push(("trackerid", url_encode(id.as_bytes())));
+        }
+        if let Some(num_want) = request.num_want {
+            query.push(("numwant", num_want.to_string()));
+        }
+
+        let body = self.get(with_query(url, &query)).await?;
+        Ok(parse_tracker_response(body)?)
+    }
+
+    async fn scrape(
+        &self,
+        url: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> RustyTorrentResult<ScrapeResponse> {
+        let scrape_url = scrape_url(url)
+            .ok_or_else(|| RustyTorrentError::ScrapeNotSupported(url.to_string()))?;
+        let query = info_hashes
+            .iter()
+            .map(|info_hash| ("info_hash", url_encode(info_hash)))
+            .collect::<Vec<_>>();
+
+        let body = self.get(with_query(&scrape_url, &query)).await?;
+        Ok(parse_scrape_response(body)?)
+    }
+}
+
+/// Derives the scrape URL from an announce URL by the convention of
+/// replacing the last `announce` path segment with `scrape`.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+    let slash = path.rfind('/')?;
+    let last = &path[slash + 1..];
+    if !last.starts_with("announce") {
+        return None;
+    }
+    let mut url = format!("{}scrape{}", &path[..=slash], &last["announce".len()..]);
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+    Some(url)
+}
+
+// trackers expect binary parameters such as the info hash to be
+// percent-encoded byte by byte
+fn url_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for &b in bytes {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn with_query(url: &str, query: &[(&str, String)]) -> String {
+    let mut url = url.to_string();
+    let mut separator = if url.contains('?') { '&' } else { '?' };
+    for (key, value) in query {
+        url.push(separator);
+        url.push_str(key);
+        url.push('=');
+        url.push_str(value);
+        separator = '&';
+    }
+    url
+}
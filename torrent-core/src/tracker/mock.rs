@@ -0,0 +1,145 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use torrent_parser::model::{
+    ScrapeFile, ScrapeResponse, TrackerResponse, TrackerResponsePeer, TrackerSuccessResponse,
+};
+
+use crate::error::RustyTorrentResult;
+
+use super::client::{AnnounceEvent, AnnounceRequest, TrackerClient};
+
+struct MockPeer {
+    peer_id: String,
+    ip: String,
+    port: u16,
+    seed: bool,
+}
+
+/// In-memory tracker keeping one swarm per info hash, for tests.
+///
+/// Announcing peers are registered at `127.0.0.1` with the announced port,
+/// and every announce request is recorded so it can be inspected later.
+pub struct MockTrackerClient {
+    swarms: Mutex<HashMap<Vec<u8>, Vec<MockPeer>>>,
+    announces: Mutex<Vec<(String, AnnounceRequest)>>,
+    failure: Mutex<Option<String>>,
+    interval: i64,
+}
+
+impl MockTrackerClient {
+    pub fn new(interval: i64) -> Self {
+        MockTrackerClient {
+            swarms: Default::default(),
+            announces: Default::default(),
+            failure: Default::default(),
+            interval,
+        }
+    }
+
+    /// Adds a peer that was not announced through this client.
+    pub fn add_peer(&self, info_hash: &[u8], ip: &str, port: u16, seed: bool) {
+        self.swarms
+            .lock()
+            .unwrap()
+            .entry(info_hash.to_vec())
+            .or_default()
+            .push(MockPeer {
+                peer_id: String::new(),
+                ip: ip.to_string(),
+                port,
+                seed,
+            });
+    }
+
+    /// Makes every following request fail with `reason` until reset with `None`.
+    pub fn set_failure(&self, reason: Option<String>) {
+        *self.failure.lock().unwrap() = reason;
+    }
+
+    pub fn announces(&self) -> Vec<(String, AnnounceRequest)> {
+        self.announces.lock().unwrap().clone()
+    }
+}
+
+impl Default for MockTrackerClient {
+    fn default() -> Self {
+        MockTrackerClient::new(1800)
+    }
+}
+
+#[async_trait]
+impl TrackerClient for MockTrackerClient {
+    async fn announce(
+        &self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> RustyTorrentResult<TrackerResponse> {
+        self.announces
+            .lock()
+            .unwrap()
+            .push((url.to_string(), request.clone()));
+        if let Some(reason) = self.failure.lock().unwrap().clone() {
+            return Ok(TrackerResponse::Failure(reason));
+        }
+
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(request.info_hash.clone()).or_default();
+        swarm.retain(|peer| peer.peer_id != request.peer_id);
+        if request.event != Some(AnnounceEvent::Stopped) {
+            swarm.push(MockPeer {
+                peer_id: request.peer_id.clone(),
+                ip: "127.0.0.1".to_string(),
+                port: request.port as u16,
+                seed: request.left == 0,
+            });
+        }
+
+        let complete = swarm.iter().filter(|peer| peer.seed).count() as i64;
+        let peers = swarm
+            .iter()
+            .filter(|peer| peer.peer_id != request.peer_id)
+            .take(request.num_want.unwrap_or(50) as usize)
+            .map(|peer| TrackerResponsePeer {
+                peer_id: (!peer.peer_id.is_empty()).then(|| peer.peer_id.clone().into_bytes()),
+                ip: peer.ip.clone(),
+                port: peer.port as i64,
+            })
+            .collect();
+
+        Ok(TrackerResponse::Success(TrackerSuccessResponse {
+            interval: self.interval,
+            min_interval: None,
+            tracker_id: None,
+            complete,
+            incomplete: swarm.len() as i64 - complete,
+            peers,
+        }))
+    }
+
+    async fn scrape(
+        &self,
+        _url: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> RustyTorrentResult<ScrapeResponse> {
+        if let Some(reason) = self.failure.lock().unwrap().clone() {
+            return Ok(ScrapeResponse::Failure(reason));
+        }
+
+        let swarms = self.swarms.lock().unwrap();
+        let files = info_hashes
+            .iter()
+            .map(|info_hash| {
+                let swarm = swarms.get(info_hash).map(Vec::as_slice).unwrap_or_default();
+                let complete = swarm.iter().filter(|peer| peer.seed).count() as i64;
+                let file = ScrapeFile {
+                    complete,
+                    downloaded: complete,
+                    incomplete: swarm.len() as i64 - complete,
+                };
+                (info_hash.clone(), file)
+            })
+            .collect();
+        Ok(ScrapeResponse::Success(files))
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::mpsc,
+    time::timeout,
+};
+use torrent_parser::model::{
+    ScrapeFile, ScrapeResponse, TrackerResponse, TrackerResponsePeer, TrackerSuccessResponse,
+};
+
+use crate::error::{RustyTorrentError, RustyTorrentResult};
+
+use super::client::{AnnounceEvent, AnnounceRequest, TrackerClient};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// a connection id may be reused for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+// BEP 15 allows at most 74 info hashes in a single scrape
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Routes tracker responses received on a socket shared with other
+/// protocols to the request waiting for them, by transaction id.
+#[derive(Default)]
+pub struct UdpTrackerResponses {
+    pending: Mutex<HashMap<u32, mpsc::UnboundedSender<Vec<u8>>>>,
+}
+
+impl UdpTrackerResponses {
+    /// Returns whether a request was waiting for the packet.
+    pub fn handle_packet(&self, packet: &[u8]) -> bool {
+        if packet.len() < 8 {
+            return false;
+        }
+        match self.pending.lock().unwrap().get(&read_u32(packet, 4)) {
+            Some(waiting) => waiting.send(packet.to_vec()).is_ok(),
+            None => false,
+        }
+    }
+
+    fn subscribe(&self, transaction_id: u32) -> Subscription<'_> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.pending.lock().unwrap().insert(transaction_id, tx);
+        Subscription {
+            responses: self,
+            transaction_id,
+            rx,
+        }
+    }
+}
+
+struct Subscription<'a> {
+    responses: &'a UdpTrackerResponses,
+    transaction_id: u32,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        self.responses
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.transaction_id);
+    }
+}
+
+enum TrackerSocket {
+    /// Bound for one request and connected to the tracker.
+    Own(UdpSocket),
+    Shared(Arc<UdpSocket>, SocketAddr, Arc<UdpTrackerResponses>),
+}
+
+impl TrackerSocket {
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            TrackerSocket::Own(socket) => socket.send(packet).await?,
+            TrackerSocket::Shared(socket, addr, _) => socket.send_to(packet, addr).await?,
+        };
+        Ok(())
+    }
+
+    async fn recv(
+        &self,
+        subscription: &mut Option<Subscription<'_>>,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        match (self, subscription) {
+            (_, Some(subscription)) => {
+                let packet = subscription
+                    .rx
+                    .recv()
+                    .await
+                    .ok_or(io::ErrorKind::BrokenPipe)?;
+                let len = packet.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet[..len]);
+                Ok(len)
+            }
+            (TrackerSocket::Own(socket), None) => socket.recv(buf).await,
+            (TrackerSocket::Shared(..), None) => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+/// Tracker client for `udp://` announce URLs (BEP 15).
+pub struct UdpTrackerClient {
+    connection_ids: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+    shared: Option<(Arc<UdpSocket>, Arc<UdpTrackerResponses>)>,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl UdpTrackerClient {
+    pub fn new(timeout: Duration, retries: u32) -> Self {
+        UdpTrackerClient {
+            connection_ids: Default::default(),
+            shared: None,
+            timeout,
+            retries,
+        }
+    }
+
+    /// Sends requests on a socket read elsewhere, which passes tracker
+    /// responses to `responses`.
+    pub fn with_socket(
+        socket: Arc<UdpSocket>,
+        responses: Arc<UdpTrackerResponses>,
+        timeout: Duration,
+        retries: u32,
+    ) -> Self {
+        UdpTrackerClient {
+            shared: Some((socket, responses)),
+            ..UdpTrackerClient::new(timeout, retries)
+        }
+    }
+
+    async fn resolve(url: &str) -> RustyTorrentResult<SocketAddr> {
+        let host = url
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(url)
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        lookup_host(host)
+            .await?
+            .next()
+            .ok_or_else(|| RustyTorrentError::TrackerError(format!("cannot resolve {}", host)))
+    }
+
+    /// Sends `packet` and waits for a response with the same transaction id,
+    /// retrying with exponential back-off.
+    async fn transact(
+        &self,
+        socket: &TrackerSocket,
+        packet: &[u8],
+        transaction_id: u32,
+    ) -> RustyTorrentResult<Vec<u8>> {
+        let mut buf = vec![0u8; 2048];
+        let mut subscription = match socket {
+            TrackerSocket::Own(_) => None,
+            TrackerSocket::Shared(_, _, responses) => Some(responses.subscribe(transaction_id)),
+        };
+        for attempt in 0..=self.retries {
+            socket.send(packet).await?;
+            let wait = self.timeout * 2u32.pow(attempt);
+            let deadline = Instant::now() + wait;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let Ok(received) =
+                    timeout(remaining, socket.recv(&mut subscription, &mut buf)).await
+                else {
+                    break;
+                };
+                let len = received?;
+                if len < 8 || read_u32(&buf, 4) != transaction_id {
+                    continue;
+                }
+                let action = read_u32(&buf, 0);
+                if action == ACTION_ERROR {
+                    let msg = String::from_utf8_lossy(&buf[8..len]).to_string();
+                    return Err(RustyTorrentError::TrackerError(msg));
+                }
+                return Ok(buf[..len].to_vec());
+            }
+        }
+        Err(RustyTorrentError::TrackerTimeout)
+    }
+
+    async fn connection_id(
+        &self,
+        socket: &TrackerSocket,
+        addr: SocketAddr,
+    ) -> RustyTorrentResult<u64> {
+        if let Some((id, received)) = self.connection_ids.lock().unwrap().get(&addr) {
+            if received.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(*id);
+            }
+        }
+
+        let transaction_id = rand::random::<u32>();
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+
+        let resp = self.transact(socket, &packet, transaction_id).await?;
+        if resp.len() < 16 || read_u32(&resp, 0) != ACTION_CONNECT {
+            return Err(RustyTorrentError::TrackerError(
+                "invalid connect response".to_string(),
+            ));
+        }
+        let id = u64::from_be_bytes(resp[8..16].try_into().unwrap());
+        self.connection_ids
+            .lock()
+            .unwrap()
+            .insert(addr, (id, Instant::now()));
+        Ok(id)
+    }
+
+    async fn socket_for(&self, url: &str) -> RustyTorrentResult<(TrackerSocket, SocketAddr)> {
+        let addr = Self::resolve(url).await?;
+        if let Some((socket, responses)) = &self.shared {
+            let shared = TrackerSocket::Shared(Arc::clone(socket), addr, Arc::clone(responses));
+            return Ok((shared, addr));
+        }
+        let bind: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(addr).await?;
+        Ok((TrackerSocket::Own(socket), addr))
+    }
+}
+
+impl Default for UdpTrackerClient {
+    fn default() -> Self {
+        UdpTrackerClient::new(Duration::from_secs(15), 2)
+    }
+}
+
+#[async_trait]
+impl TrackerClient for UdpTrackerClient {
+    async fn announce(
+        &self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> RustyTorrentResult<TrackerResponse> {
+        let (socket, addr) = self.socket_for(url).await?;
+        let connection_id = self.connection_id(&socket, addr).await?;
+
+        let transaction_id = rand::random::<u32>();
+        let event: u32 = match request.event {
+            None => 0,
+            Some(AnnounceEvent::Completed) => 1,
+            Some(AnnounceEvent::Started) => 2,
+            Some(AnnounceEvent::Stopped) => 3,
+        };
+        let mut peer_id = [0u8; 20];
+        let id_len = request.peer_id.len().min(20);
+        peer_id[..id_len].copy_from_slice(&request.peer_id.as_bytes()[..id_len]);
+
+        let mut packet = Vec::with_capacity(98);
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(&request.info_hash);
+        packet.extend_from_slice(&peer_id);
+        packet.extend_from_slice(&request.downloaded.to_be_bytes());
+        packet.extend_from_slice(&request.left.to_be_bytes());
+        packet.extend_from_slice(&request.uploaded.to_be_bytes());
+        packet.extend_from_slice(&event.to_be_bytes());
+        // ip address, 0 means the sender address
+        packet.extend_from_slice(&0u32.to_be_bytes());
+        packet.extend_from_slice(&rand::random::<u32>().to_be_bytes());
+        let num_want = request.num_want.map(|n| n as i32).unwrap_or(-1);
+        packet.extend_from_slice(&num_want.to_be_bytes());
+        packet.extend_from_slice(&(request.port as u16).to_be_bytes());
+        append_url_data(&mut packet, url);
+
+        let resp = match self.transact(&socket, &packet, transaction_id).await {
+            Err(RustyTorrentError::TrackerError(msg)) => return Ok(TrackerResponse::Failure(msg)),
+            other => other?,
+        };
+        if resp.len() < 20 || read_u32(&resp, 0) != ACTION_ANNOUNCE {
+            return Err(RustyTorrentError::TrackerError(
+                "invalid announce response".to_string(),
+            ));
+        }
+
+        // peers are 6 bytes for IPv4 trackers and 18 bytes for IPv6 trackers
+        let peer_len = if addr.is_ipv4() { 6 } else { 18 };
+        let peers = resp[20..]
+            .chunks_exact(peer_len)
+            .map(|chunk| {
+                let (ip, port) = chunk.split_at(peer_len - 2);
+                let ip = match ip.len() {
+                    4 => std::net::IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+                    _ => std::net::IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+                };
+                TrackerResponsePeer {
+                    peer_id: None,
+                    ip: ip.to_string(),
+                    port: u16::from_be_bytes([port[0], port[1]]) as i64,
+                }
+            })
+            .collect();
+
+        Ok(TrackerResponse::Success(TrackerSuccessResponse {
+            interval: read_u32(&resp, 8) as i64,
+            min_interval: None,
+            tracker_id: None,
+            incomplete: read_u32(&resp, 12) as i64,
+            complete: read_u32(&resp, 16) as i64,
+            peers,
+        }))
+    }
+
+    async fn scrape(
+        &self,
+        url: &str,
+        info_hashes: &[Vec<u8>],
+    ) -> RustyTorrentResult<ScrapeResponse> {
+        let (socket, addr) = self.socket_for(url).await?;
+        let mut files = HashMap::new();
+
+        for info_hashes in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let connection_id = self.connection_id(&socket, addr).await?;
+            let transaction_id = rand::random::<u32>();
+            let mut packet = Vec::with_capacity(16 + 20 * info_hashes.len());
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            for info_hash in info_hashes {
+                packet.extend_from_slice(info_hash);
+            }
+            append_url_data(&mut packet, url);
+
+            let resp = match self.transact(&socket, &packet, transaction_id).await {
+                Err(RustyTorrentError::TrackerError(msg)) => {
+                    return Ok(ScrapeResponse::Failure(msg))
+                }
+                other => other?,
+            };
+            if read_u32(&resp, 0) != ACTION_SCRAPE {
+                return Err(RustyTorrentError::TrackerError(
+                    "invalid scrape response".to_string(),
+                ));
+            }
+            for (info_hash, stats) in info_hashes.iter().zip(resp[8..].chunks_exact(12)) {
+                files.insert(
+                    info_hash.clone(),
+                    ScrapeFile {
+                        complete: read_u32(stats, 0) as i64,
+                        downloaded: read_u32(stats, 4) as i64,
+                        incomplete: read_u32(stats, 8) as i64,
+                    },
+                );
+            }
+        }
+
+        Ok(ScrapeResponse::Success(files))
+    }
+}
+
+/// Appends the path and query of `url` as BEP 41 URL data options, which
+/// private trackers use to carry passkeys.
+fn append_url_data(packet: &mut Vec<u8>, url: &str) {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let Some(start) = rest.find('/') else {
+        return;
+    };
+    let url_data = &rest.as_bytes()[start..];
+    if url_data == b"/" || url_data == b"/announce" {
+        return;
+    }
+    for chunk in url_data.chunks(255) {
+        packet.push(2);
+        packet.push(chunk.len() as u8);
+        packet.extend_from_slice(chunk);
+    }
+    packet.push(0);
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
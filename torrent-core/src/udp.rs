@@ -0,0 +1,111 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, spawn, task::JoinHandle};
+use torrent_dht::{krpc::is_krpc, DhtNode};
+use torrent_pwp::utp::{is_utp, UtpSocket};
+use tracing::debug;
+
+use crate::{
+    error::RustyTorrentResult,
+    tracker::udp::{UdpTrackerClient, UdpTrackerResponses},
+};
+
+#[derive(Default)]
+struct Routes {
+    dht: RwLock<Option<Arc<DhtNode>>>,
+    utp: RwLock<Option<Arc<UtpSocket>>>,
+    trackers: Arc<UdpTrackerResponses>,
+}
+
+/// One UDP socket for the DHT, uTP and UDP trackers, so the session uses a
+/// single port. Received packets are told apart by their first byte: KRPC
+/// messages are dictionaries, uTP packets carry their version in the low
+/// nibble and tracker responses start with a small action number.
+///
+/// Receiving stops when this is dropped.
+pub struct SharedUdpSocket {
+    socket: Arc<UdpSocket>,
+    routes: Arc<Routes>,
+    task_handle: JoinHandle<()>,
+}
+
+impl SharedUdpSocket {
+    pub async fn bind(addr: SocketAddr) -> RustyTorrentResult<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let routes = Arc::new(Routes::default());
+        let recv_socket = Arc::clone(&socket);
+        let recv_routes = Arc::clone(&routes);
+        let task_handle = spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                match recv_socket.recv_from(&mut buf).await {
+                    Ok((len, from)) => recv_routes.dispatch(&buf[..len], from).await,
+                    Err(e) => debug!("udp receive failed: {}", e),
+                }
+            }
+        });
+        Ok(SharedUdpSocket {
+            socket,
+            routes,
+            task_handle,
+        })
+    }
+
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        Arc::clone(&self.socket)
+    }
+
+    pub fn local_addr(&self) -> RustyTorrentResult<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Passes KRPC packets to `dht`, which must send on [`Self::socket`].
+    pub fn set_dht(&self, dht: Arc<DhtNode>) {
+        *self.routes.dht.write().unwrap() = Some(dht);
+    }
+
+    /// Creates the uTP socket on this socket, replacing any previous one.
+    pub fn utp(&self) -> Arc<UtpSocket> {
+        let utp = Arc::new(UtpSocket::with_socket(self.socket()));
+        *self.routes.utp.write().unwrap() = Some(Arc::clone(&utp));
+        utp
+    }
+
+    /// A tracker client sending on this socket.
+    pub fn tracker_client(&self, timeout: Duration, retries: u32) -> UdpTrackerClient {
+        UdpTrackerClient::with_socket(
+            self.socket(),
+            Arc::clone(&self.routes.trackers),
+            timeout,
+            retries,
+        )
+    }
+}
+
+impl Drop for SharedUdpSocket {
+    fn drop(&mut self) {
+        self.task_handle.abort();
+    }
+}
+
+impl Routes {
+    async fn dispatch(&self, packet: &[u8], from: SocketAddr) {
+        if is_krpc(packet) {
+            let dht = self.dht.read().unwrap().clone();
+            if let Some(dht) = dht {
+                dht.handle_packet(packet, from).await;
+            }
+        } else if is_utp(packet) {
+            let utp = self.utp.read().unwrap().clone();
+            if let Some(utp) = utp {
+                utp.handle_packet(packet, from);
+            }
+        } else if !self.trackers.handle_packet(packet) {
+            debug!("unexpected udp packet from {}", from);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::VecDeque, time::Instant};
+
+use torrent_pwp::{
+    bitfield::Bitfield,
+    message::{BlockInfo, Message},
+};
+
+use crate::{rate::RateMeter, scheduler::BLOCK_SIZE};
+
+/// Requests queued per peer beyond this are refused. Also what we should
+/// advertise as `reqq`.
+pub const MAX_QUEUED_REQUESTS: usize = 250;
+/// Longer requests are refused; everyone asks for 16 KiB blocks.
+pub const MAX_REQUEST_LENGTH: u32 = BLOCK_SIZE;
+
+/// The requests a peer made to us, served in order. Requests for pieces we
+/// do not have, of a bad size or beyond the queue limit are refused, with a
+/// `reject` if the fast extension is enabled.
+pub struct UploadQueue {
+    fast: bool,
+    max_queued: usize,
+    pending: VecDeque<BlockInfo>,
+    rate: RateMeter,
+}
+
+impl UploadQueue {
+    pub fn new(fast: bool) -> Self {
+        UploadQueue::with_limit(fast, MAX_QUEUED_REQUESTS)
+    }
+
+    pub fn with_limit(fast: bool, max_queued: usize) -> Self {
+        UploadQueue {
+            fast,
+            max_queued,
+            pending: VecDeque::new(),
+            rate: RateMeter::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues a request from the peer, which the session only passes on if
+    /// the peer is unchoked or the piece allowed fast. `piece_size` is the
+    /// length of the requested piece. Returns the message to refuse it with.
+    pub fn on_request(
+        &mut self,
+        block: BlockInfo,
+        have: &Bitfield,
+        piece_size: u32,
+    ) -> Option<Message> {
+        let valid = have.has(block.index as usize)
+            && block.length > 0
+            && block.length <= MAX_REQUEST_LENGTH
+            && block
+                .begin
+                .checked_add(block.length)
+                .is_some_and(|end| end <= piece_size);
+        if !valid || self.pending.len() >= self.max_queued || self.pending.contains(&block) {
+            return self.reject(block);
+        }
+        self.pending.push_back(block);
+        None
+    }
+
+    /// Drops a queued request, returning whether it was still queued.
+    pub fn on_cancel(&mut self, block: &BlockInfo) -> bool {
+        let queued = self.pending.len();
+        self.pending.retain(|pending| pending != block);
+        self.pending.len() != queued
+    }
+
+    /// We choked the peer, which drops its requests except for pieces it may
+    /// still request. Returns the rejects for the dropped ones.
+    pub fn on_choke(&mut self, allowed: impl Fn(u32) -> bool) -> Vec<Message> {
+        let (kept, dropped) = self
+            .pending
+            .drain(..)
+            .partition::<VecDeque<_>, _>(|block| allowed(block.index));
+        self.pending = kept;
+        dropped
+            .into_iter()
+            .filter_map(|block| self.reject(block))
+            .collect()
+    }
+
+    /// The next request to serve, left in the queue.
+    pub fn peek(&self) -> Option<&BlockInfo> {
+        self.pending.front()
+    }
+
+    /// The next request to serve.
+    pub fn pop(&mut self) -> Option<BlockInfo> {
+        self.pending.pop_front()
+    }
+
+    /// Counts a served block for the upload rate.
+    pub fn sent(&mut self, bytes: u64, now: Instant) {
+        self.rate.add(bytes, now);
+    }
+
+    /// Bytes sent to the peer.
+    pub fn uploaded(&self) -> u64 {
+        self.rate.total()
+    }
+
+    pub fn upload_rate(&mut self, now: Instant) -> f64 {
+        self.rate.rate(now)
+    }
+
+    fn reject(&self, block: BlockInfo) -> Option<Message> {
+        self.fast.then_some(Message::RejectRequest(block))
+    }
+}
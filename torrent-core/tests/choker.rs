@@ -0,0 +1,174 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use torrent_core::choker::{ChokeStrategy, Choker, ChokerConfig, ChokerPeer, FairShare, RateBased};
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], port))
+}
+
+fn peer(port: u16, rate: f64, connected_at: Instant) -> ChokerPeer {
+    ChokerPeer {
+        addr: addr(port),
+        interested: true,
+        download_rate: rate,
+        upload_rate: 0.0,
+        downloaded: 0,
+        uploaded: 0,
+        connected_at,
+    }
+}
+
+fn choker(seed: u64) -> Choker {
+    Choker::new(
+        ChokerConfig {
+            upload_slots: 2,
+            seed,
+            ..Default::default()
+        },
+        Box::new(RateBased),
+    )
+}
+
+#[test]
+fn test_rechoke_by_download_rate() {
+    let mut choker = choker(1);
+    let start = Instant::now();
+    let mut peers = (1..=5)
+        .map(|port| peer(port, port as f64 * 1000.0, start))
+        .collect::<Vec<_>>();
+    peers[3].interested = false;
+
+    let decision = choker.poll(&peers, false, start).unwrap();
+    assert!(decision.choke.is_empty());
+    assert_eq!(decision.unchoke.len(), 3);
+    // the two fastest interested peers, and one optimistic unchoke
+    assert!(choker.is_unchoked(addr(5)));
+    assert!(choker.is_unchoked(addr(3)));
+    assert!(!choker.is_unchoked(addr(4)));
+    let optimistic = choker.optimistic().unwrap();
+    assert!([addr(1), addr(2)].contains(&optimistic));
+
+    assert_eq!(
+        choker.poll(&peers, false, start + Duration::from_secs(5)),
+        None
+    );
+
+    // a slot changes hands once the rates do
+    peers[0].download_rate = 10_000.0;
+    peers[1].download_rate = 9_000.0;
+    let decision = choker
+        .poll(&peers, false, start + Duration::from_secs(10))
+        .unwrap();
+    assert!(choker.is_unchoked(addr(1)) && choker.is_unchoked(addr(2)));
+    // the optimistic unchoke got a regular slot, so another one is picked
+    let optimistic = choker.optimistic().unwrap();
+    assert!([addr(3), addr(5)].contains(&optimistic));
+    assert_eq!(decision.choke.len(), 1);
+    assert!(!decision.choke.contains(&optimistic));
+
+    // seeding ranks by upload rate
+    peers[2].upload_rate = 50_000.0;
+    peers[4].upload_rate = 40_000.0;
+    choker.poll(&peers, true, start + Duration::from_secs(20));
+    assert!(choker.is_unchoked(addr(3)) && choker.is_unchoked(addr(5)));
+}
+
+#[test]
+fn test_optimistic_unchoke_rotates_and_prefers_new_peers() {
+    let start = Instant::now();
+    let old = start - Duration::from_secs(600);
+    let mut new_picked = 0;
+    for seed in 0..400 {
+        let mut choker = Choker::new(
+            ChokerConfig {
+                upload_slots: 1,
+                seed,
+                ..Default::default()
+            },
+            Box::new(RateBased),
+        );
+        // one fast regular peer, three old ones and a new one
+        let peers = vec![
+            peer(1, 100_000.0, old),
+            peer(2, 0.0, old),
+            peer(3, 0.0, old),
+            peer(4, 0.0, old),
+            peer(5, 0.0, start),
+        ];
+        choker.poll(&peers, false, start);
+        new_picked += (choker.optimistic() == Some(addr(5))) as usize;
+    }
+    // half the time with the bias, a quarter without
+    assert!(new_picked > 150, "new peer picked {} times", new_picked);
+
+    let mut choker = choker(7);
+    let peers = (1..=6).map(|port| peer(port, 0.0, old)).collect::<Vec<_>>();
+    let mut optimistic = Vec::new();
+    for round in 0..30 {
+        let now = start + Duration::from_secs(10 * round);
+        choker.poll(&peers, false, now);
+        optimistic.push(choker.optimistic().unwrap());
+    }
+    // kept for 30 s, then rotated
+    assert!(optimistic
+        .chunks(3)
+        .all(|chunk| chunk.iter().all(|peer| *peer == chunk[0])));
+    assert!(optimistic.windows(2).any(|pair| pair[0] != pair[1]));
+
+    choker.remove_peer(optimistic[29]);
+    assert!(!choker.is_unchoked(optimistic[29]));
+}
+
+/// Unchokes peers by port, to check strategies are pluggable.
+struct LowestPort;
+
+impl ChokeStrategy for LowestPort {
+    fn rank(&mut self, peers: &[&ChokerPeer], _seeding: bool) -> Vec<SocketAddr> {
+        let mut addrs = peers.iter().map(|peer| peer.addr).collect::<Vec<_>>();
+        addrs.sort_by_key(|addr| addr.port());
+        addrs
+    }
+}
+
+#[test]
+fn test_pluggable_strategies() {
+    let start = Instant::now();
+    let mut peers = (1..=4)
+        .map(|port| peer(port, port as f64, start))
+        .collect::<Vec<_>>();
+    let mut choker = choker(1);
+    choker.set_strategy(Box::new(LowestPort));
+    choker.set_upload_slots(3);
+    choker.poll(&peers, false, start);
+    assert!((1..=3).all(|port| choker.is_unchoked(addr(port))));
+    assert_eq!(choker.optimistic(), Some(addr(4)));
+
+    // fair share favours the peers we owe the most
+    peers[3].downloaded = 1_000_000;
+    peers[0].uploaded = 1_000_000;
+    let mut fair = FairShare;
+    let ranked = fair.rank(&peers.iter().collect::<Vec<_>>(), false);
+    assert_eq!(ranked.first(), Some(&addr(4)));
+    assert_eq!(ranked.last(), Some(&addr(1)));
+}
+
+#[test]
+fn test_new_interest_fills_free_slot() {
+    let start = Instant::now();
+    let mut peers = vec![peer(1, 0.0, start), peer(2, 0.0, start)];
+    peers[1].interested = false;
+    let mut choker = choker(1);
+    choker.poll(&peers, false, start);
+    assert!(choker.is_unchoked(addr(1)));
+
+    // the second peer gets the free slot before the rechoke interval
+    let later = start + Duration::from_secs(1);
+    peers[1].interested = true;
+    assert_eq!(choker.poll(&peers, false, later), None);
+    choker.on_interested();
+    let decision = choker.poll(&peers, false, later).unwrap();
+    assert_eq!(decision.unchoke, vec![addr(2)]);
+}
@@ -0,0 +1,338 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+use tokio::time::{sleep, timeout};
+use torrent_core::{
+    error::RustyTorrentError,
+    peer::{session::DisconnectReason, Peer},
+    ratelimit::RateLimit,
+    scheduler::SchedulerEvent,
+    session::{RustyTorrentSession, TorrentEvent},
+};
+use torrent_pwp::error::PwpError;
+
+// one file of 100000 bytes in pieces of 32 KiB, with a tracker no client is
+// registered for; returns the data and the path of the .torrent
+fn torrent_file(dir: &Path) -> (Vec<u8>, PathBuf) {
+    let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let pieces = data
+        .chunks(32768)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect::<Vec<_>>();
+    let mut bencoded = format!(
+        "d8:announce17:none://t/announce4:infod6:lengthi{}e4:name4:data12:piece lengthi32768e6:pieces{}:",
+        data.len(),
+        pieces.len()
+    )
+    .into_bytes();
+    bencoded.extend(pieces);
+    bencoded.extend(b"ee");
+    let path = dir.join("data.torrent");
+    std::fs::write(&path, bencoded).unwrap();
+    (data, path)
+}
+
+// sessions made in the same second get the same peer id unless the version
+// differs, and peers with our own id are refused
+fn session(location: &Path, version: char) -> RustyTorrentSession {
+    RustyTorrentSession::new(
+        location.to_string_lossy().into_owned(),
+        &['R', 'T'],
+        &['0', '0', '0', version],
+        0,
+    )
+}
+
+fn local(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], addr.port()))
+}
+
+#[tokio::test]
+async fn test_download_from_seeder() {
+    let dir = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join("seed")).unwrap();
+    let (data, path) = torrent_file(&dir);
+    std::fs::write(dir.join("seed").join("data"), &data).unwrap();
+    let path = path.to_string_lossy().into_owned();
+
+    let seeder = session(&dir.join("seed"), '1');
+    let leecher = session(&dir.join("leech"), '2');
+    let seeding = seeder
+        .add_torrent(path.clone(), None, None, true)
+        .await
+        .unwrap();
+    let leeching = leecher.add_torrent(path, None, None, true).await.unwrap();
+    let seeding = seeder.torrent(seeding).await.unwrap();
+    assert!(seeding.scheduler.lock().unwrap().is_complete());
+
+    let addr = seeder.listen().await.unwrap();
+    let mut events = leecher.subscribe();
+    leecher.connect_peer(leeching, local(addr)).await.unwrap();
+    let id = leeching;
+    let leeching = leecher.torrent(leeching).await.unwrap();
+    timeout(Duration::from_secs(20), async {
+        while !leeching.scheduler.lock().unwrap().is_complete() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(std::fs::read(dir.join("leech").join("data")).unwrap(), data);
+    assert_eq!(*leeching.downloaded.read().await, data.len() as u64);
+    assert_eq!(*seeding.uploaded.read().await, data.len() as u64);
+    // every block is requested at once, which is end-game
+    let event = timeout(Duration::from_secs(5), events.recv()).await;
+    assert_eq!(
+        event.unwrap().unwrap(),
+        TorrentEvent {
+            torrent: id,
+            event: SchedulerEvent::EndGameStarted
+        }
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_blocked_peers_are_not_connected() {
+    let dir = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (_, path) = torrent_file(&dir);
+    let path = path.to_string_lossy().into_owned();
+    let first = session(&dir.join("first"), '1');
+    let second = session(&dir.join("second"), '2');
+    first
+        .add_torrent(path.clone(), None, None, true)
+        .await
+        .unwrap();
+    let id = second.add_torrent(path, None, None, true).await.unwrap();
+    let addr = local(first.listen().await.unwrap());
+    let localhost = addr.ip();
+
+    // refused when it comes in
+    assert!(first.ban_peer(localhost).await);
+    assert!(second.connect_peer(id, addr).await.is_err());
+    assert!(first.unban_peer(localhost));
+
+    // and never dialed
+    assert!(second.ban_peer(localhost).await);
+    assert!(matches!(
+        second.connect_peer(id, addr).await,
+        Err(RustyTorrentError::PwpError(PwpError::PeerBlocked(_)))
+    ));
+    assert!(second.unban_peer(localhost));
+    second.connect_peer(id, addr).await.unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_peers_are_exchanged_over_pex() {
+    let dir = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (_, path) = torrent_file(&dir);
+    let path = path.to_string_lossy().into_owned();
+    let hub = session(&dir.join("hub"), '1');
+    let first = session(&dir.join("first"), '2');
+    let second = session(&dir.join("second"), '3');
+    let hub_id = hub
+        .add_torrent(path.clone(), None, None, true)
+        .await
+        .unwrap();
+    let first_id = first
+        .add_torrent(path.clone(), None, None, true)
+        .await
+        .unwrap();
+    second.add_torrent(path, None, None, true).await.unwrap();
+    let first_addr = local(first.listen().await.unwrap());
+    let second_addr = local(second.listen().await.unwrap());
+
+    // the hub tells each peer about the other
+    hub.connect_peer(hub_id, first_addr).await.unwrap();
+    hub.connect_peer(hub_id, second_addr).await.unwrap();
+    let first = first.torrent(first_id).await.unwrap();
+    timeout(Duration::from_secs(10), async {
+        while !first
+            .peers
+            .read()
+            .await
+            .iter()
+            .any(|peer| peer.ip_addr() == Some(second_addr.ip()) && peer.port == second_addr.port())
+        {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_uploads_are_throttled() {
+    let dir = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join("seed")).unwrap();
+    let (data, path) = torrent_file(&dir);
+    std::fs::write(dir.join("seed").join("data"), &data).unwrap();
+    let path = path.to_string_lossy().into_owned();
+
+    let seeder = session(&dir.join("seed"), '1');
+    let leecher = session(&dir.join("leech"), '2');
+    seeder
+        .add_torrent(path.clone(), None, None, true)
+        .await
+        .unwrap();
+    let leeching = leecher.add_torrent(path, None, None, true).await.unwrap();
+    // the bucket starts full, so the first 40000 bytes go out at once
+    seeder.set_global_rate_limit(RateLimit {
+        upload: Some(40_000),
+        download: None,
+    });
+
+    let addr = local(seeder.listen().await.unwrap());
+    assert!(!leecher.set_peer_rate_limit(addr, RateLimit::default()));
+    let start = Instant::now();
+    leecher.connect_peer(leeching, addr).await.unwrap();
+    let leeching = leecher.torrent(leeching).await.unwrap();
+    timeout(Duration::from_secs(20), async {
+        // known to the limiter once its connection runs
+        while !leecher.set_peer_rate_limit(addr, RateLimit::default()) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        while !leeching.scheduler.lock().unwrap().is_complete() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(
+        start.elapsed() >= Duration::from_secs(1),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(std::fs::read(dir.join("leech").join("data")).unwrap(), data);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_peer_snapshots() {
+    let dir = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(dir.join("seed")).unwrap();
+    let (data, path) = torrent_file(&dir);
+    std::fs::write(dir.join("seed").join("data"), &data).unwrap();
+    let path = path.to_string_lossy().into_owned();
+    let seeder = session(&dir.join("seed"), '1');
+    let leecher = session(&dir.join("leech"), '2');
+    let seeding = seeder
+        .add_torrent(path.clone(), None, None, true)
+        .await
+        .unwrap();
+    let leeching = leecher.add_torrent(path, None, None, true).await.unwrap();
+    let addr = local(seeder.listen().await.unwrap());
+    leecher.connect_peer(leeching, addr).await.unwrap();
+
+    // the seeder's pieces show once its bitfield arrived
+    let snapshot = timeout(Duration::from_secs(10), async {
+        loop {
+            let snapshots = leecher.peer_snapshots(leeching).await.unwrap();
+            if let Some(snapshot) = snapshots.into_iter().find(|s| s.pieces > 0) {
+                return snapshot;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        (snapshot.ip.as_str(), snapshot.port),
+        ("127.0.0.1", addr.port())
+    );
+    assert_eq!(snapshot.pieces, snapshot.num_pieces);
+    assert!(snapshot.id.is_some());
+    assert_eq!(
+        leecher.disconnect_reason(leeching, addr).await.unwrap(),
+        None
+    );
+    let stats = leecher.pipeline_stats(leeching).await.unwrap();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].0, addr);
+    assert!(stats[0].1.depth > 0);
+
+    // the reason stays after the seeder goes away
+    seeder.remove_torrent(seeding).await.unwrap();
+    timeout(Duration::from_secs(10), async {
+        while !leecher.peer_snapshots(leeching).await.unwrap().is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        leecher.disconnect_reason(leeching, addr).await.unwrap(),
+        Some(DisconnectReason::Closed)
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_extended_handshake_is_kept_in_peer_list() {
+    let dir = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (_, path) = torrent_file(&dir);
+    let path = path.to_string_lossy().into_owned();
+    // a fixed port, as it is what the extended handshake advertises
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let first = RustyTorrentSession::new(
+        dir.join("first").to_string_lossy().into_owned(),
+        &['R', 'T'],
+        &['0', '0', '0', '1'],
+        port as u32,
+    );
+    let second = session(&dir.join("second"), '2');
+    first
+        .add_torrent(path.clone(), None, None, true)
+        .await
+        .unwrap();
+    let id = second.add_torrent(path, None, None, true).await.unwrap();
+    let addr = local(first.listen().await.unwrap());
+    let torrent = second.torrent(id).await.unwrap();
+    torrent.peers.write().await.push(Peer::from(addr));
+    second.connect_peer(id, addr).await.unwrap();
+
+    let peer = timeout(Duration::from_secs(10), async {
+        loop {
+            let peers = torrent.peers.read().await;
+            if let Some(peer) = peers.iter().find(|peer| peer.client.is_some()) {
+                return peer.clone();
+            }
+            drop(peers);
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        peer.client,
+        Some(format!("rusty-torrent/{}", env!("CARGO_PKG_VERSION")))
+    );
+    assert_eq!(peer.listen_port, Some(port));
+    assert!(peer.id.is_some());
+    let client = peer.client_info().unwrap();
+    assert_eq!(client.name, "rusty-torrent");
+    assert_eq!(client.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+    // and the same for the connection
+    timeout(Duration::from_secs(10), async {
+        while second.peer_snapshots(id).await.unwrap()[0].client != Some(client.to_string()) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
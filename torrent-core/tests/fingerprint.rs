@@ -0,0 +1,77 @@
+use torrent_core::{
+    fingerprint::{from_extended_handshake, from_peer_id, ClientInfo},
+    peer::Peer,
+};
+use torrent_pwp::PeerId;
+
+fn id(prefix: &[u8]) -> PeerId {
+    // the random part is not UTF-8
+    let mut id = [0xff; 20];
+    id[..prefix.len()].copy_from_slice(prefix);
+    id
+}
+
+fn client(id: &PeerId) -> Option<String> {
+    from_peer_id(id).map(|client| client.to_string())
+}
+
+#[test]
+fn test_peer_id_styles() {
+    assert_eq!(
+        client(&id(b"-qB4620-")).as_deref(),
+        Some("qBittorrent 4.6.2")
+    );
+    assert_eq!(
+        client(&id(b"-TR4050-")).as_deref(),
+        Some("Transmission 4.0.5")
+    );
+    assert_eq!(client(&id(b"-UT355W-")).as_deref(), Some("µTorrent 3.5.5"));
+    assert_eq!(
+        client(&id(b"-LT1234-")).as_deref(),
+        Some("libtorrent 1.2.3.4")
+    );
+    assert_eq!(client(&id(b"-ZZ1000-")).as_deref(), Some("ZZ 1.0.0"));
+    assert_eq!(client(&id(b"M4-3-6--")).as_deref(), Some("Mainline 4.3.6"));
+    assert_eq!(client(&id(b"M4-20-8-")).as_deref(), Some("Mainline 4.20.8"));
+    assert_eq!(client(&id(b"S58B-----")).as_deref(), Some("Shadow 5.8.11"));
+    assert_eq!(client(&id(b"T03I--")).as_deref(), Some("BitTornado 0.3.18"));
+    assert_eq!(client(&id(b"")), None);
+    assert_eq!(client(&id(b"-qB46")), None);
+}
+
+#[test]
+fn test_extended_handshake_version() {
+    let parsed = |v| from_extended_handshake(v).map(|client| client.to_string());
+    assert_eq!(
+        from_extended_handshake("qBittorrent/4.6.2"),
+        Some(ClientInfo {
+            name: "qBittorrent".to_string(),
+            version: Some("4.6.2".to_string())
+        })
+    );
+    assert_eq!(
+        parsed("Transmission 4.0.5").as_deref(),
+        Some("Transmission 4.0.5")
+    );
+    assert_eq!(
+        parsed("BitTorrent v7.11").as_deref(),
+        Some("BitTorrent 7.11")
+    );
+    assert_eq!(
+        parsed("libTorrent (Rakshasa) 0.13.8").as_deref(),
+        Some("libTorrent (Rakshasa) 0.13.8")
+    );
+    assert_eq!(parsed("SomeClient").as_deref(), Some("SomeClient"));
+    assert_eq!(parsed(" "), None);
+
+    // the extended handshake wins over the peer id
+    let mut peer = Peer::from("10.0.0.1:6881".parse::<std::net::SocketAddr>().unwrap());
+    assert_eq!(peer.client_info(), None);
+    peer.id = Some(id(b"-qB4620-"));
+    assert_eq!(peer.client_info().unwrap().name, "qBittorrent");
+    peer.client = Some("qBittorrent/4.6.3".to_string());
+    assert_eq!(
+        peer.client_info().unwrap().version.as_deref(),
+        Some("4.6.3")
+    );
+}
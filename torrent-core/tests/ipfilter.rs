@@ -0,0 +1,126 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use torrent_core::{
+    error::RustyTorrentError, ipfilter::IpFilter, session::RustyTorrentSession,
+    torrent::ManagedTorrent,
+};
+use torrent_parser::parse_torrent_metadata;
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+#[test]
+fn test_parse_filter_formats() {
+    let filter = IpFilter::parse(
+        "# eMule ipfilter.dat
+001.002.003.000 - 001.002.003.255 , 000 , Some Org, Inc.
+005.000.000.000 - 005.255.255.255 , 200 , allowed by level
+// PeerGuardian P2P
+Bad Peers: tracker 2:10.0.0.0-10.0.0.9
+10.0.0.10 - 10.0.0.20
+192.168.0.0/16
+2001:db8::/32
+fe80::1
+
+",
+    )
+    .unwrap();
+    // the two ranges in 10.0.0.0 touch and are merged
+    assert_eq!(filter.len(), 5);
+    for blocked in [
+        "1.2.3.0",
+        "1.2.3.255",
+        "10.0.0.0",
+        "10.0.0.15",
+        "10.0.0.20",
+        "192.168.44.1",
+        "2001:db8:ffff::1",
+        "fe80::1",
+        "::ffff:1.2.3.4",
+    ] {
+        assert!(filter.is_blocked(ip(blocked)), "{}", blocked);
+    }
+    for allowed in [
+        "1.2.2.255",
+        "1.2.4.0",
+        "5.1.1.1",
+        "10.0.0.21",
+        "2001:db9::1",
+        "fe80::2",
+        "::",
+    ] {
+        assert!(!filter.is_blocked(ip(allowed)), "{}", allowed);
+    }
+
+    let Err(RustyTorrentError::InvalidIpFilter(error)) =
+        IpFilter::parse("1.2.3.4/8\n1.2.3.300 - 1.2.3.4")
+    else {
+        panic!("invalid line accepted");
+    };
+    assert!(error.starts_with("line 2"));
+    assert!(IpFilter::parse("1.2.3.4 - ::1").is_err());
+    assert!(IpFilter::parse("1.2.3.4 - 1.2.3.1").is_err());
+
+    let mut filter = IpFilter::default();
+    assert!(filter.is_empty());
+    filter.add_range(ip("::"), ip("ffff::")).unwrap();
+    assert!(filter.is_blocked(ip("::1")));
+    assert!(!filter.is_blocked(ip("1.2.3.4")));
+}
+
+#[tokio::test]
+async fn test_filter_reloads_at_runtime() {
+    let path = std::env::temp_dir().join(format!("rusty-torrent-{}.p2p", uuid::Uuid::new_v4()));
+    std::fs::write(&path, "Compliance:10.0.0.0-10.0.0.255\n").unwrap();
+    let session = RustyTorrentSession::new(
+        std::env::temp_dir().to_string_lossy().into_owned(),
+        &['R', 'T'],
+        &['0', '0', '0', '1'],
+        6881,
+    );
+    assert!(session.allows_peer(ip("10.0.0.1")));
+    assert_eq!(session.load_ip_filter(&path).await.unwrap(), 1);
+    assert!(!session.allows_peer(ip("10.0.0.1")));
+    assert!(session.allows_peer(ip("10.0.1.1")));
+    session.set_ip_filter(IpFilter::default()).await;
+    assert!(session.allows_peer(ip("10.0.0.1")));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_filter_applies_to_torrent_peers() {
+    let info = "d6:lengthi100e4:name4:data12:piece lengthi32768e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let bencoded = format!("d8:announce17:http://t/announce4:info{}e", info);
+    let torrent = ManagedTorrent::from_torrent_metadata(
+        parse_torrent_metadata(bencoded.into_bytes()).unwrap(),
+        None,
+        std::env::temp_dir().to_string_lossy().into_owned(),
+        "-RT0001-aaaaaaaaaaaa".to_string(),
+        6881,
+        Arc::new(Default::default()),
+        None,
+    );
+    let addr = |host: &str| SocketAddr::new(ip(host), 6881);
+    torrent
+        .ip_filter
+        .set(IpFilter::parse("10.0.0.0/24").unwrap());
+    let added = torrent
+        .add_pex_peers(vec![addr("10.0.0.1"), addr("10.0.1.1"), addr("10.0.2.1")])
+        .await
+        .unwrap();
+    assert_eq!(added, 2);
+    assert!(!torrent.allows_peer(ip("10.0.0.7")));
+
+    // a new filter drops the peers it blocks
+    torrent
+        .ip_filter
+        .set(IpFilter::parse("10.0.1.0 - 10.0.1.255").unwrap());
+    torrent.remove_blocked_peers().await;
+    let peers = torrent.peers.read().await;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].ip, "10.0.2.1");
+}
@@ -0,0 +1,150 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::RwLock, time::sleep};
+use torrent_core::lsd::{LocalServiceDiscovery, LsdAnnounce, LsdConfig, LsdInterface};
+use torrent_pwp::PeerFilter;
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+fn config(port: u16) -> LsdConfig {
+    LsdConfig {
+        interfaces: vec![LsdInterface::V4(Ipv4Addr::LOCALHOST)],
+        multicast_v4: SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), port),
+        ..Default::default()
+    }
+}
+
+fn allow_all() -> PeerFilter {
+    Arc::new(|_| true)
+}
+
+fn random_port() -> u16 {
+    std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[test]
+fn test_announce_roundtrip() {
+    let announce = LsdAnnounce {
+        port: 6881,
+        info_hashes: vec![vec![0xab; 20], vec![0x01; 20]],
+        cookie: Some("cafe".to_string()),
+    };
+    let encoded = announce.encode("239.192.152.143:6771");
+    assert!(encoded.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+    assert!(encoded.ends_with(b"\r\n\r\n\r\n"));
+    assert_eq!(LsdAnnounce::decode(&encoded), Some(announce));
+
+    let lowercase = b"BT-SEARCH * HTTP/1.1\r\nhost: [ff15::efc0:988f]:6771\r\nport: 7000\r\n\
+        infohash: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n\r\n";
+    let decoded = LsdAnnounce::decode(lowercase).unwrap();
+    assert_eq!(decoded.port, 7000);
+    assert_eq!(decoded.info_hashes, vec![vec![0xab; 20]]);
+    assert_eq!(decoded.cookie, None);
+
+    assert!(LsdAnnounce::decode(b"NOTIFY * HTTP/1.1\r\nPort: 1\r\n\r\n").is_none());
+    assert!(
+        LsdAnnounce::decode(b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: ab\r\n\r\n").is_none()
+    );
+}
+
+#[tokio::test]
+async fn test_incoming_announces() {
+    let lsd = LocalServiceDiscovery::start(config(random_port()), 6881)
+        .await
+        .unwrap();
+    let peers = Arc::new(RwLock::new(Vec::new()));
+    // the peer at 192.168.9.9 is blocked
+    lsd.add_torrent(
+        vec![0x11; 20],
+        Arc::clone(&peers),
+        Arc::new(|ip: IpAddr| ip.to_string() != "192.168.9.9"),
+    )
+    .await;
+
+    let packet = |info_hash: u8, cookie: &str| {
+        LsdAnnounce {
+            port: 7000,
+            info_hashes: vec![vec![info_hash; 20]],
+            cookie: Some(cookie.to_string()),
+        }
+        .encode("239.192.152.143:6771")
+    };
+    let from = addr("192.168.1.2:40000");
+
+    // our own packets are looped back and must be ignored
+    let own = packet(0x11, lsd.cookie());
+    assert!(lsd.handle_packet(&own, from).await.is_empty());
+    // unknown torrents and blocked peers are ignored
+    assert!(lsd
+        .handle_packet(&packet(0x11, "other"), addr("192.168.9.9:40000"))
+        .await
+        .is_empty());
+    assert!(lsd
+        .handle_packet(&packet(0x22, "other"), from)
+        .await
+        .is_empty());
+
+    assert_eq!(
+        lsd.handle_packet(&packet(0x11, "other"), from).await,
+        vec![addr("192.168.1.2:7000")]
+    );
+    assert_eq!(peers.read().await.len(), 1);
+
+    // the same host is rate limited per torrent
+    let again = addr("192.168.1.2:40001");
+    assert!(lsd
+        .handle_packet(&packet(0x11, "other"), again)
+        .await
+        .is_empty());
+
+    lsd.remove_torrent(&[0x11; 20]);
+    let other = addr("192.168.1.3:40000");
+    assert!(lsd
+        .handle_packet(&packet(0x11, "other"), other)
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_multicast_discovery() {
+    let port = random_port();
+    let first = LocalServiceDiscovery::start(config(port), 6881)
+        .await
+        .unwrap();
+    let second = LocalServiceDiscovery::start(config(port), 6882)
+        .await
+        .unwrap();
+
+    let info_hash = vec![0x33; 20];
+    let first_peers = Arc::new(RwLock::new(Vec::new()));
+    let second_peers = Arc::new(RwLock::new(Vec::new()));
+    first
+        .add_torrent(info_hash.clone(), Arc::clone(&first_peers), allow_all())
+        .await;
+    second
+        .add_torrent(info_hash.clone(), Arc::clone(&second_peers), allow_all())
+        .await;
+
+    // a torrent is not announced twice within the minimum interval
+    assert!(!first.announce(&info_hash).await);
+
+    for _ in 0..50 {
+        if !first_peers.read().await.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    let found = first_peers.read().await;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].port, 6882);
+}
@@ -0,0 +1,244 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use torrent_core::{
+    magnet::MagnetLink,
+    metadata::{
+        MetadataExtension, MetadataFetcher, MetadataMessage, MetadataSource, METADATA_PIECE_SIZE,
+    },
+    session::RustyTorrentSession,
+    torrent::TorrentState,
+};
+use torrent_parser::field::{dict, info_hash, Field};
+use torrent_pwp::extension::{ExtendedHandshake, Extension};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+// an info dictionary spanning three metadata pieces
+fn info() -> Vec<u8> {
+    dict([
+        ("length", Field::Integer(2000 * 16384)),
+        ("name", Field::from("big")),
+        ("piece length", Field::Integer(16384)),
+        ("pieces", Field::String(vec![7; 20 * 2000])),
+    ])
+    .encode()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// runs requests of `fetching` against `serving` until nothing is left to ask
+fn exchange(fetching: &mut MetadataExtension, serving: &mut MetadataExtension) {
+    let mut handshake = ExtendedHandshake::default();
+    serving.extend_handshake(&mut handshake);
+    fetching.on_handshake(&handshake);
+    loop {
+        let requests = fetching.poll();
+        if requests.is_empty() {
+            break;
+        }
+        for request in requests {
+            for reply in serving.on_message(&request).unwrap() {
+                assert!(fetching.on_message(&reply).unwrap().is_empty());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_message_round_trip() {
+    let data = MetadataMessage::Data {
+        piece: 1,
+        total_size: 16390,
+        data: vec![b'e'; 6],
+    };
+    let encoded = data.encode();
+    assert!(encoded.starts_with(b"d8:msg_typei1e5:piecei1e10:total_sizei16390ee"));
+    assert_eq!(MetadataMessage::decode(&encoded).unwrap(), Some(data));
+    for message in [MetadataMessage::Request(3), MetadataMessage::Reject(0)] {
+        assert_eq!(
+            MetadataMessage::decode(&message.encode()).unwrap(),
+            Some(message)
+        );
+    }
+    assert_eq!(
+        MetadataMessage::decode(b"d8:msg_typei9e5:piecei0ee").unwrap(),
+        None
+    );
+    assert!(MetadataMessage::decode(b"d8:msg_typei0ee").is_err());
+}
+
+#[test]
+fn test_fetch_from_several_peers() {
+    let info = info();
+    let fetcher = Arc::new(Mutex::new(MetadataFetcher::new(
+        info_hash(&info),
+        Duration::from_secs(30),
+    )));
+    let source = MetadataSource::Fetching(Arc::clone(&fetcher));
+    let served = MetadataSource::Complete(Arc::new(info.clone()));
+
+    // the pieces are spread over the peers
+    let mut first = MetadataExtension::new(source.clone(), addr("10.0.0.1:1"));
+    let mut second = MetadataExtension::new(source.clone(), addr("10.0.0.2:1"));
+    let mut handshake = ExtendedHandshake::default();
+    MetadataExtension::new(served.clone(), addr("10.0.0.1:1")).extend_handshake(&mut handshake);
+    assert_eq!(handshake.metadata_size, Some(info.len() as u64));
+    first.on_handshake(&handshake);
+    assert_eq!(first.poll().len(), 2);
+    let pending = second.poll();
+    assert_eq!(pending, vec![MetadataMessage::Request(2).encode()]);
+    // the pieces of a peer that goes away are handed to the others
+    drop(first);
+
+    let mut server = MetadataExtension::new(served, addr("10.0.0.3:1"));
+    for reply in server.on_message(&pending[0]).unwrap() {
+        second.on_message(&reply).unwrap();
+    }
+    exchange(&mut second, &mut server);
+
+    let fetcher = fetcher.lock().unwrap();
+    assert!(fetcher.is_complete());
+    assert_eq!(fetcher.metadata().unwrap().as_slice(), info.as_slice());
+    let progress = fetcher.progress();
+    assert_eq!(
+        progress.pieces_total,
+        Some(info.len().div_ceil(METADATA_PIECE_SIZE))
+    );
+    assert_eq!(progress.pieces_received, 3);
+}
+
+#[test]
+fn test_hash_mismatch_starts_over() {
+    let info = info();
+    let mut fetcher = MetadataFetcher::new(vec![0; 20], Duration::from_secs(30));
+    let peer = addr("10.0.0.1:1");
+    assert!(fetcher.set_size(info.len() as u64));
+    while let Some(piece) = fetcher.next_request(peer) {
+        let begin = piece as usize * METADATA_PIECE_SIZE;
+        let end = info.len().min(begin + METADATA_PIECE_SIZE);
+        assert!(fetcher.on_data(peer, piece, info.len(), info[begin..end].to_vec()));
+    }
+    assert!(!fetcher.is_complete());
+    assert_eq!(fetcher.hash_failures(), 1);
+    assert_eq!(fetcher.progress().pieces_total, None);
+
+    // a piece of the wrong length is refused
+    assert!(fetcher.set_size(info.len() as u64));
+    assert!(!fetcher.on_data(peer, 0, info.len(), vec![0; 10]));
+}
+
+#[test]
+fn test_serving_rejects_without_metadata() {
+    let fetcher = MetadataFetcher::new(vec![0; 20], Duration::from_secs(30));
+    let mut extension = MetadataExtension::new(
+        MetadataSource::Fetching(Arc::new(Mutex::new(fetcher))),
+        addr("10.0.0.1:1"),
+    );
+    let replies = extension
+        .on_message(&MetadataMessage::Request(0).encode())
+        .unwrap();
+    assert_eq!(replies, vec![MetadataMessage::Reject(0).encode()]);
+}
+
+#[test]
+fn test_parse_magnet_link() {
+    let hash = [0xab; 20];
+    let link = MagnetLink::parse(&format!(
+        "magnet:?xt=urn:btih:{}&dn=My+File%20.iso&tr=udp%3A%2F%2Ft%3A80&tr=http://t/a&x.pe=10.0.0.1:6881",
+        hex(&hash)
+    ))
+    .unwrap();
+    assert_eq!(link.info_hash, hash);
+    assert_eq!(link.name.as_deref(), Some("My File .iso"));
+    assert_eq!(link.trackers, vec!["udp://t:80", "http://t/a"]);
+    assert_eq!(link.peers, vec![addr("10.0.0.1:6881")]);
+
+    // base32 form of the same hash
+    let base32 = MagnetLink::parse("magnet:?xt=urn:btih:VOV2XK5LVOV2XK5LVOV2XK5LVOV2XK5L").unwrap();
+    assert_eq!(base32.info_hash, hash);
+
+    assert!(MagnetLink::parse("magnet:?dn=x").is_err());
+    assert!(MagnetLink::parse("http://x").is_err());
+}
+
+#[tokio::test]
+async fn test_session_moves_magnet_to_download() {
+    let info = info();
+    let session = RustyTorrentSession::new(
+        std::env::temp_dir().to_string_lossy().to_string(),
+        &['R', 'T'],
+        &['0', '0', '0', '1'],
+        6881,
+    );
+    let id = session
+        .add_magnet(
+            &format!("magnet:?xt=urn:btih:{}&dn=renamed", hex(&info_hash(&info))),
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        session.torrent_state(id).await.unwrap(),
+        TorrentState::FetchingMetadata(Default::default())
+    );
+
+    // an info dictionary that matches its hash but is not a valid torrent
+    let invalid = dict([("name", Field::from("broken"))]).encode();
+    let invalid_id = session
+        .add_magnet(
+            &format!("magnet:?xt=urn:btih:{}", hex(&info_hash(&invalid))),
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+
+    for (id, info) in [(invalid_id, &invalid), (id, &info)] {
+        let mut fetching = session
+            .metadata_extension(id, addr("10.0.0.1:1"))
+            .await
+            .unwrap();
+        let mut server = MetadataExtension::new(
+            MetadataSource::Complete(Arc::new(info.clone())),
+            addr("10.0.0.2:1"),
+        );
+        exchange(&mut fetching, &mut server);
+    }
+
+    assert_eq!(session.finish_metadata_downloads().await, vec![id]);
+    assert!(matches!(
+        session.torrent_state(invalid_id).await.unwrap(),
+        TorrentState::FetchingMetadata(_)
+    ));
+    assert_eq!(
+        session.torrent_state(id).await.unwrap(),
+        TorrentState::Stopped
+    );
+
+    // the torrent now serves the metadata itself
+    let mut serving = session
+        .metadata_extension(id, addr("10.0.0.3:1"))
+        .await
+        .unwrap();
+    let mut handshake = ExtendedHandshake::default();
+    serving.extend_handshake(&mut handshake);
+    assert_eq!(handshake.metadata_size, Some(info.len() as u64));
+    let reply = serving
+        .on_message(&MetadataMessage::Request(1).encode())
+        .unwrap();
+    assert!(matches!(
+        MetadataMessage::decode(&reply[0]).unwrap(),
+        Some(MetadataMessage::Data { piece: 1, .. })
+    ));
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use torrent_core::{
+    peer::{
+        session::{DisconnectReason, PeerEvent, PeerSession},
+        Peer,
+    },
+    pex::{PexExtension, PexFlags, PexMessage, PexState},
+};
+use torrent_pwp::{
+    bitfield::Bitfield,
+    extension::ExtendedHandshake,
+    handshake::{Feature, Reserved},
+    message::{BlockInfo, Message},
+};
+
+fn session(num_pieces: usize) -> PeerSession {
+    PeerSession::new(
+        Peer::from("10.0.0.1:6881".parse::<std::net::SocketAddr>().unwrap()),
+        num_pieces,
+    )
+}
+
+fn bitfield(bytes: &[u8]) -> Bitfield {
+    Bitfield::from_bytes(bytes, 10).unwrap()
+}
+
+#[test]
+fn test_flags_and_interest() {
+    let mut session = session(10);
+    let ours = bitfield(&[0b1111_1111, 0b1100_0000]);
+
+    assert_eq!(
+        session.receive(Message::Unchoke).unwrap(),
+        vec![PeerEvent::Unchoked]
+    );
+    assert!(!session.peer().peer_choking);
+    session.receive(Message::Interested).unwrap();
+    assert!(session.peer().peer_interested);
+
+    // nothing they have is new to us
+    session.receive(Message::Have(3)).unwrap();
+    assert_eq!(session.update_interest(&ours), None);
+    // a duplicate have is not reported again
+    assert!(session.receive(Message::Have(3)).unwrap().is_empty());
+
+    assert_eq!(
+        session.receive(Message::Have(9)).unwrap(),
+        vec![PeerEvent::Have(9)]
+    );
+    assert_eq!(session.update_interest(&ours), None);
+    assert_eq!(
+        session.update_interest(&bitfield(&[0xff, 0x80])),
+        Some(Message::Interested)
+    );
+    assert!(session.peer().am_interested);
+    assert_eq!(
+        session.update_interest(&bitfield(&[0xff, 0xc0])),
+        Some(Message::NotInterested)
+    );
+
+    assert_eq!(session.unchoke(), Some(Message::Unchoke));
+    assert_eq!(session.unchoke(), None);
+    let block = BlockInfo {
+        index: 1,
+        begin: 0,
+        length: 16384,
+    };
+    assert_eq!(
+        session.receive(Message::Request(block)).unwrap(),
+        vec![PeerEvent::Request(block)]
+    );
+    assert_eq!(session.choke(), Some(Message::Choke));
+    // requests while we choke them are dropped
+    assert!(session.receive(Message::Request(block)).unwrap().is_empty());
+
+    let snapshot = session.snapshot();
+    assert_eq!(snapshot.pieces, 2);
+    assert_eq!(snapshot.num_pieces, 10);
+    assert!(snapshot.am_choking && !snapshot.peer_choking);
+}
+
+#[test]
+fn test_bitfield() {
+    let mut session = session(10);
+    assert!(session.receive(Message::KeepAlive).unwrap().is_empty());
+    assert_eq!(
+        session
+            .receive(Message::Bitfield(Bytes::from_static(&[
+                0b1000_0001,
+                0b0100_0000
+            ])))
+            .unwrap(),
+        vec![PeerEvent::Bitfield(vec![0, 7, 9])]
+    );
+    assert_eq!(
+        session.update_interest(&Bitfield::new(10)),
+        Some(Message::Interested)
+    );
+}
+
+#[test]
+fn test_illegal_messages_disconnect() {
+    let mut late = session(10);
+    late.receive(Message::Unchoke).unwrap();
+    assert_eq!(
+        late.receive(Message::Bitfield(Bytes::from_static(&[0, 0]))),
+        Err(DisconnectReason::BitfieldNotFirst)
+    );
+    assert_eq!(
+        late.disconnect_reason(),
+        Some(&DisconnectReason::BitfieldNotFirst)
+    );
+    // every later message is refused with the same reason
+    assert_eq!(
+        late.receive(Message::Choke),
+        Err(DisconnectReason::BitfieldNotFirst)
+    );
+    assert_eq!(
+        late.snapshot().disconnect_reason,
+        Some(DisconnectReason::BitfieldNotFirst)
+    );
+
+    // spare bits set
+    let mut spare = session(10);
+    assert_eq!(
+        spare.receive(Message::Bitfield(Bytes::from_static(&[0, 0b0010_0000]))),
+        Err(DisconnectReason::InvalidBitfield)
+    );
+    // wrong length
+    let mut short = session(10);
+    assert_eq!(
+        short.receive(Message::Bitfield(Bytes::from_static(&[0]))),
+        Err(DisconnectReason::InvalidBitfield)
+    );
+
+    let mut out_of_range = session(10);
+    assert_eq!(
+        out_of_range.receive(Message::Have(10)),
+        Err(DisconnectReason::InvalidPieceIndex(10))
+    );
+}
+
+#[test]
+fn test_fast_extension() {
+    let peer = Peer::from("80.4.4.200:6881".parse::<std::net::SocketAddr>().unwrap());
+    let mut session = PeerSession::with_extensions(peer, 1313, Reserved::with(&[Feature::FAST]));
+    assert!(session.fast_enabled());
+    assert_eq!(
+        session.initial_message(&Bitfield::new(1313)),
+        Message::HaveNone
+    );
+    assert_eq!(
+        session.initial_message(&Bitfield::full(1313)),
+        Message::HaveAll
+    );
+
+    assert_eq!(
+        session.receive(Message::HaveAll).unwrap(),
+        vec![PeerEvent::Bitfield((0..1313).collect())]
+    );
+    assert!(session.peer().bitfield.is_full());
+
+    // requesting while choked only works for allowed fast pieces
+    assert!(!session.can_request(7));
+    assert_eq!(
+        session.receive(Message::AllowedFast(7)).unwrap(),
+        vec![PeerEvent::AllowedFast(7)]
+    );
+    assert!(session.can_request(7));
+    assert!(!session.can_request(8));
+
+    let block = BlockInfo {
+        index: 7,
+        begin: 0,
+        length: 16384,
+    };
+    assert_eq!(
+        session.receive(Message::RejectRequest(block)).unwrap(),
+        vec![PeerEvent::Rejected(block)]
+    );
+    assert_eq!(
+        session.receive(Message::SuggestPiece(3)).unwrap(),
+        vec![PeerEvent::Suggest(3)]
+    );
+
+    // requests while we choke them are rejected unless allowed fast
+    let allowed = session.allow_fast(&[0xaa; 20]);
+    assert_eq!(allowed.first(), Some(&Message::AllowedFast(1059)));
+    let allowed_block = BlockInfo {
+        index: 1059,
+        ..block
+    };
+    assert_eq!(
+        session.receive(Message::Request(allowed_block)).unwrap(),
+        vec![PeerEvent::Request(allowed_block)]
+    );
+    assert!(session.receive(Message::Request(block)).unwrap().is_empty());
+    assert_eq!(
+        session.drain_outgoing(),
+        vec![Message::RejectRequest(block)]
+    );
+    assert!(session.drain_outgoing().is_empty());
+
+    assert_eq!(
+        session.receive(Message::HaveNone),
+        Err(DisconnectReason::BitfieldNotFirst)
+    );
+}
+
+#[test]
+fn test_fast_messages_need_negotiation() {
+    let mut session = session(10);
+    assert!(session.allow_fast(&[0xaa; 20]).is_empty());
+    assert_eq!(
+        session.receive(Message::HaveAll),
+        Err(DisconnectReason::FastNotNegotiated)
+    );
+}
+
+#[test]
+fn test_extension_protocol() {
+    let addr = "10.0.0.1:51413".parse().unwrap();
+    let mut session =
+        PeerSession::with_extensions(Peer::from(addr), 10, Reserved::with(&[Feature::EXTENSION]));
+    let connected = Arc::new(Mutex::new(HashMap::from([(
+        "10.0.0.2:6881".parse().unwrap(),
+        PexFlags::SEED,
+    )])));
+    let (tx, mut discovered) = mpsc::unbounded_channel();
+    session.register_extension(Box::new(PexExtension::new(
+        PexState::new(),
+        addr,
+        connected,
+        tx,
+    )));
+
+    let Some(Message::Extended { id: 0, payload }) =
+        session.extended_handshake(ExtendedHandshake::default())
+    else {
+        panic!("no extended handshake");
+    };
+    let ours = ExtendedHandshake::decode(&payload).unwrap();
+    assert_eq!(ours.extensions.get("ut_pex"), Some(&1));
+
+    let theirs = ExtendedHandshake {
+        extensions: [("ut_pex".to_string(), 5)].into(),
+        client: Some("Transmission 4.0".to_string()),
+        listen_port: Some(6881),
+        reqq: Some(500),
+        ..Default::default()
+    };
+    let events = session
+        .receive(Message::Extended {
+            id: 0,
+            payload: theirs.encode().into(),
+        })
+        .unwrap();
+    assert_eq!(events, vec![PeerEvent::ExtendedHandshake(theirs)]);
+    assert_eq!(session.peer().client.as_deref(), Some("Transmission 4.0"));
+    assert_eq!(session.peer().listen_port, Some(6881));
+    assert_eq!(session.remote_reqq(), Some(500));
+    // the bitfield may still follow the extended handshake
+    session
+        .receive(Message::Bitfield(Bytes::from_static(&[0, 0])))
+        .unwrap();
+
+    let polled = session.poll_extensions();
+    assert_eq!(polled.len(), 1);
+    let Message::Extended { id: 5, payload } = &polled[0] else {
+        panic!("unexpected message {:?}", polled[0]);
+    };
+    assert_eq!(PexMessage::decode(payload).unwrap().added.len(), 1);
+
+    let pex = PexMessage {
+        added: vec![("10.0.0.3:6881".parse().unwrap(), PexFlags::NONE)],
+        dropped: vec![],
+    };
+    session
+        .receive(Message::Extended {
+            id: 1,
+            payload: pex.encode().into(),
+        })
+        .unwrap();
+    assert_eq!(
+        discovered.try_recv().unwrap(),
+        vec!["10.0.0.3:6881".parse().unwrap()]
+    );
+
+    assert!(matches!(
+        session.receive(Message::Extended {
+            id: 1,
+            payload: Bytes::from_static(b"nonsense"),
+        }),
+        Err(DisconnectReason::InvalidExtensionMessage(_))
+    ));
+}
+
+#[test]
+fn test_extended_messages_need_negotiation() {
+    let mut session = session(10);
+    assert!(session
+        .extended_handshake(ExtendedHandshake::default())
+        .is_none());
+    assert_eq!(
+        session.receive(Message::Extended {
+            id: 0,
+            payload: Bytes::from_static(b"de"),
+        }),
+        Err(DisconnectReason::ExtensionNotNegotiated)
+    );
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use torrent_core::{
+    error::RustyTorrentError,
+    pex::{PexFlags, PexMessage, PexState},
+    torrent::ManagedTorrent,
+};
+use torrent_parser::parse_torrent_metadata;
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+fn torrent(private: bool) -> ManagedTorrent {
+    let info = format!(
+        "4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei{}ee",
+        private as u8
+    );
+    let bencoded = format!("d8:announce17:http://t/announce{}e", info);
+    ManagedTorrent::from_torrent_metadata(
+        parse_torrent_metadata(bencoded.into_bytes()).unwrap(),
+        None,
+        ".".to_string(),
+        "-RT0001-aaaaaaaaaaaa".to_string(),
+        6881,
+        Arc::new(Default::default()),
+        None,
+    )
+}
+
+#[test]
+fn test_message_roundtrip() {
+    let message = PexMessage {
+        added: vec![
+            (addr("10.0.0.1:6881"), PexFlags::SEED | PexFlags::UTP),
+            (addr("[2001:db8::1]:51413"), PexFlags::ENCRYPTION),
+        ],
+        dropped: vec![addr("10.0.0.2:6882"), addr("[2001:db8::2]:6883")],
+    };
+    let decoded = PexMessage::decode(&message.encode()).unwrap();
+    assert_eq!(decoded, message);
+    assert!(decoded.added[0].1.contains(PexFlags::SEED));
+    assert!(!decoded.added[0].1.contains(PexFlags::HOLEPUNCH));
+
+    assert!(PexMessage::decode(b"d5:added5:abcdee").is_err());
+}
+
+#[test]
+fn test_deltas() {
+    let remote = addr("10.0.0.9:6881");
+    let mut connected = HashMap::from([
+        (addr("10.0.0.1:6881"), PexFlags::NONE),
+        (addr("10.0.0.2:6881"), PexFlags::SEED),
+        (remote, PexFlags::NONE),
+    ]);
+
+    let mut state = PexState::new();
+    let first = state.next_message(remote, None, &connected).unwrap();
+    assert_eq!(first.added.len(), 2);
+    assert!(first.dropped.is_empty());
+    // nothing is sent again before the interval is over
+    assert!(state.next_message(remote, None, &connected).is_none());
+
+    let mut state = PexState::with_interval(Duration::ZERO);
+    state.next_message(remote, None, &connected).unwrap();
+    assert!(state.next_message(remote, None, &connected).is_none());
+    connected.remove(&addr("10.0.0.1:6881"));
+    connected.insert(addr("10.0.0.3:6881"), PexFlags::OUTGOING);
+    let delta = state.next_message(remote, None, &connected).unwrap();
+    assert_eq!(
+        delta.added,
+        vec![(addr("10.0.0.3:6881"), PexFlags::OUTGOING)]
+    );
+    assert_eq!(delta.dropped, vec![addr("10.0.0.1:6881")]);
+
+    // an incoming peer is not told about its own listen address
+    let listen_addr = addr("10.0.0.9:51413");
+    let mut state = PexState::new();
+    connected.insert(listen_addr, PexFlags::NONE);
+    let message = state
+        .next_message(addr("10.0.0.9:40000"), Some(listen_addr), &connected)
+        .unwrap();
+    assert!(message.added.iter().all(|(added, _)| *added != listen_addr));
+
+    let many = (1..=120)
+        .map(|i| (addr(&format!("10.1.0.{}:6881", i)), PexFlags::NONE))
+        .collect::<HashMap<_, _>>();
+    let mut state = PexState::with_interval(Duration::ZERO);
+    assert_eq!(
+        state.next_message(remote, None, &many).unwrap().added.len(),
+        50
+    );
+    assert_eq!(
+        state.next_message(remote, None, &many).unwrap().added.len(),
+        50
+    );
+    assert_eq!(
+        state.next_message(remote, None, &many).unwrap().added.len(),
+        20
+    );
+}
+
+#[test]
+fn test_incoming_limits() {
+    let mut state = PexState::new();
+    let message = PexMessage {
+        added: vec![
+            (addr("10.0.0.1:6881"), PexFlags::NONE),
+            (addr("0.0.0.0:6881"), PexFlags::NONE),
+            (addr("10.0.0.2:0"), PexFlags::NONE),
+            (addr("224.0.0.1:6881"), PexFlags::NONE),
+        ],
+        dropped: vec![],
+    };
+    assert_eq!(
+        state.receive(message.clone()).unwrap(),
+        vec![addr("10.0.0.1:6881")]
+    );
+    // a second message right away is a violation
+    assert!(matches!(
+        state.receive(message),
+        Err(RustyTorrentError::PexRateLimited)
+    ));
+
+    let flood = PexMessage {
+        added: (1..=51)
+            .map(|i| (addr(&format!("10.1.0.{}:6881", i)), PexFlags::NONE))
+            .collect(),
+        dropped: vec![],
+    };
+    assert!(PexState::new().receive(flood).is_err());
+}
+
+#[tokio::test]
+async fn test_disabled_for_private_torrents() {
+    let public = torrent(false);
+    assert!(public.pex_enabled());
+    assert_eq!(
+        public
+            .add_pex_peers(vec![addr("10.0.0.1:6881"), addr("10.0.0.2:6881")])
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(public.peers.read().await.len(), 2);
+    // peers already known are not added again
+    assert_eq!(
+        public
+            .add_pex_peers(vec![addr("10.0.0.1:6881")])
+            .await
+            .unwrap(),
+        0
+    );
+
+    let private = torrent(true);
+    assert!(!private.pex_enabled());
+    assert!(matches!(
+        private.add_pex_peers(vec![addr("10.0.0.1:6881")]).await,
+        Err(RustyTorrentError::PexDisabled)
+    ));
+}
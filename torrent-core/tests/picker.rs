@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant};
+
+use torrent_core::picker::{InProgress, PickerConfig, PiecePicker, Priority};
+use torrent_pwp::bitfield::Bitfield;
+
+fn picker(seed: u64) -> PiecePicker {
+    let mut picker = PiecePicker::new(
+        10,
+        8,
+        PickerConfig {
+            random_first_pieces: 2,
+            seed,
+            background_interval: 3,
+        },
+    );
+    // piece i is on i % 4 + 1 peers
+    for peers in 1..=4 {
+        let mut bitfield = Bitfield::new(8);
+        for index in (0..8).filter(|index| index % 4 + 1 >= peers) {
+            bitfield.set(index, true);
+        }
+        picker.add_bitfield(&bitfield);
+    }
+    picker
+}
+
+#[test]
+fn test_random_first_then_rarest() {
+    let all = Bitfield::full(8);
+    let none = Bitfield::new(8);
+    assert_eq!(picker(1).availability(3), 4);
+    assert_eq!(picker(1).availability(4), 1);
+
+    // the same seed gives the same picks
+    let first = picker(1).pick(&all, &none, &[], false, 8);
+    assert_eq!(first, picker(1).pick(&all, &none, &[], false, 8));
+    assert_eq!(first.len(), 8);
+    let random = (0..10)
+        .map(|seed| picker(seed).pick(&all, &none, &[], false, 8))
+        .collect::<Vec<_>>();
+    assert!(random.iter().any(|picks| picks != &random[0]));
+
+    let mut have = Bitfield::new(8);
+    have.set(0, true);
+    have.set(7, true);
+    let mut picker = picker(1);
+    let picks = picker.pick(&all, &have, &[], false, 8);
+    let availability = picks
+        .iter()
+        .map(|index| picker.availability(*index))
+        .collect::<Vec<_>>();
+    assert_eq!(availability, vec![1, 2, 2, 3, 3, 4]);
+    assert_eq!(picker.pick(&all, &have, &[], false, 2).len(), 2);
+
+    picker.remove_bitfield(&all);
+    assert_eq!(picker.availability(4), 0);
+}
+
+#[test]
+fn test_priorities() {
+    let mut picker = picker(1);
+    let all = Bitfield::full(8);
+    let mut have = Bitfield::new(8);
+    have.set(0, true);
+    have.set(1, true);
+    // piece 4 is shared by both files and gets the higher priority
+    picker.set_file_priority(0, 45, Priority::Skip);
+    picker.set_file_priority(45, 35, Priority::High);
+    picker.set_piece_priority(7, Priority::Low);
+    assert_eq!(picker.priority(4), Priority::High);
+    assert_eq!(picker.priority(3), Priority::Skip);
+
+    let picks = picker.pick(&all, &have, &[], false, 8);
+    assert_eq!(picks.len(), 4);
+    assert_eq!(&picks[..3], &[4, 5, 6]);
+    assert_eq!(picks[3], 7);
+
+    picker.set_file_priority(0, 45, Priority::Normal);
+    assert_eq!(picker.pick(&all, &have, &[], false, 8).len(), 6);
+}
+
+#[test]
+fn test_partial_pieces_first() {
+    let mut picker = picker(1);
+    let all = Bitfield::full(8);
+    let mut have = Bitfield::new(8);
+    have.set(0, true);
+    have.set(1, true);
+    let in_progress = [
+        InProgress {
+            index: 6,
+            has_free_blocks: true,
+            slow: true,
+        },
+        InProgress {
+            index: 5,
+            has_free_blocks: true,
+            slow: false,
+        },
+        InProgress {
+            index: 4,
+            has_free_blocks: false,
+            slow: false,
+        },
+    ];
+    // a fast peer keeps out of the piece of a slow one unless nothing else is left
+    let fast = picker.pick(&all, &have, &in_progress, false, 8);
+    assert_eq!(fast, vec![5, 2, 3, 7, 6]);
+    let slow = picker.pick(&all, &have, &in_progress, true, 8);
+    assert_eq!(&slow[..2], &[5, 6]);
+    assert!(!slow.contains(&4));
+}
+
+#[test]
+fn test_streaming_keeps_background_pieces() {
+    let mut picker = picker(1);
+    let all = Bitfield::full(8);
+    let mut have = Bitfield::new(8);
+    have.set(0, true);
+    have.set(1, true);
+    let now = Instant::now();
+    picker.set_deadline(6, now + Duration::from_secs(2));
+    picker.set_range_deadline(25, 10, now + Duration::from_secs(1));
+    picker.set_piece_priority(7, Priority::Skip);
+    picker.set_deadline(7, now + Duration::from_secs(3));
+    assert_eq!(picker.deadline(3), Some(now + Duration::from_secs(1)));
+
+    // every third pick is the rarest piece left
+    let picks = picker.pick(&all, &have, &[], false, 8);
+    assert_eq!(picks, vec![2, 3, 4, 6, 7, 5]);
+
+    picker.clear_deadline(6);
+    picker.clear_deadline(7);
+    picker.set_range_deadline(0, 40, now);
+    for index in 0..4 {
+        picker.clear_deadline(index);
+    }
+    picker.set_sequential(true);
+    assert_eq!(picker.pick(&all, &have, &[], false, 8), vec![2, 3, 4, 5, 6]);
+    picker.set_sequential(false);
+    picker.set_file_sequential(50, 30, true);
+    assert!(picker
+        .pick(&all, &have, &[], false, 8)
+        .starts_with(&[5, 6, 4]));
+}
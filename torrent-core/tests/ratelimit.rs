@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use torrent_core::ratelimit::{
+    is_local, BandwidthConfig, BandwidthLimiter, Direction, RateLimit, MIN_RATE,
+};
+use uuid::Uuid;
+
+const BLOCK: u64 = 16384;
+
+fn addr(host: u8) -> SocketAddr {
+    SocketAddr::from(([80, 0, 0, host], 6881))
+}
+
+fn upload(rate: u64) -> RateLimit {
+    RateLimit {
+        upload: Some(rate),
+        download: None,
+    }
+}
+
+// every peer keeps one block waiting; returns the bytes each got
+fn run(
+    limiter: &mut BandwidthLimiter,
+    peers: &[SocketAddr],
+    start: Instant,
+    seconds: u64,
+) -> HashMap<SocketAddr, u64> {
+    let mut sent = HashMap::new();
+    for peer in peers {
+        limiter.request(*peer, Direction::Upload, BLOCK);
+    }
+    for step in 1..=seconds * 100 {
+        let now = start + Duration::from_millis(step * 10);
+        for grant in limiter.poll(now) {
+            *sent.entry(grant.peer).or_default() += grant.bytes;
+            limiter.request(grant.peer, Direction::Upload, BLOCK);
+        }
+    }
+    sent
+}
+
+#[test]
+fn test_global_limit_shared_fairly() {
+    let start = Instant::now();
+    let mut limiter = BandwidthLimiter::new(
+        BandwidthConfig {
+            global: upload(100_000),
+            ..Default::default()
+        },
+        start,
+    );
+    let peers = [addr(1), addr(2), addr(3)];
+    let sent = run(&mut limiter, &peers, start, 10);
+    let total = sent.values().sum::<u64>();
+    // ten seconds at the rate, plus the initial burst and one block of debt
+    assert!((1_000_000..=1_100_000 + BLOCK).contains(&total), "{total}");
+    for peer in peers {
+        assert!(sent[&peer].abs_diff(total / 3) <= BLOCK, "{sent:?}");
+    }
+    // downloads are not limited
+    limiter.request(addr(1), Direction::Download, 1 << 30);
+    assert_eq!(limiter.poll(start + Duration::from_secs(10)).len(), 1);
+}
+
+#[test]
+fn test_torrent_and_peer_limits() {
+    let start = Instant::now();
+    let mut limiter = BandwidthLimiter::new(BandwidthConfig::default(), start);
+    let (torrent, other) = (Uuid::new_v4(), Uuid::new_v4());
+    let (slow, fast, unrelated) = (addr(1), addr(2), addr(3));
+    limiter.add_peer(slow, torrent, start);
+    limiter.add_peer(fast, torrent, start);
+    limiter.add_peer(unrelated, other, start);
+    limiter.set_torrent_limit(torrent, upload(100_000), start);
+    assert!(limiter.set_peer_limit(slow, upload(20_000), start));
+    assert!(!limiter.set_peer_limit(addr(9), upload(20_000), start));
+
+    let sent = run(&mut limiter, &[slow, fast, unrelated], start, 10);
+    assert!(sent[&slow] <= 220_000 + BLOCK, "{sent:?}");
+    assert!(
+        sent[&slow] + sent[&fast] <= 1_100_000 + 2 * BLOCK,
+        "{sent:?}"
+    );
+    assert!(sent[&fast] > 800_000, "{sent:?}");
+    // the other torrent is not limited, it got a block every poll
+    assert_eq!(sent[&unrelated], 1000 * BLOCK);
+}
+
+#[test]
+fn test_limits_change_at_runtime_without_starving() {
+    let start = Instant::now();
+    let mut limiter = BandwidthLimiter::new(
+        BandwidthConfig {
+            global: upload(1_000_000),
+            ..Default::default()
+        },
+        start,
+    );
+    let peer = addr(1);
+    let fast = run(&mut limiter, &[peer], start, 1)[&peer];
+    assert!(fast > 1_000_000, "{fast}");
+
+    // a zero limit still lets the peer through, slowly
+    let now = start + Duration::from_secs(1);
+    limiter.set_global_limit(upload(0), now);
+    assert_eq!(limiter.global_limit(), upload(0));
+    limiter.remove_peer(peer);
+    assert_eq!(limiter.pending(), 0);
+    let slow = run(&mut limiter, &[peer], now, 60)[&peer];
+    assert!(slow <= 60 * MIN_RATE + BLOCK, "{slow}");
+    assert!(slow >= 2 * BLOCK, "{slow}");
+}
+
+#[test]
+fn test_lan_exemption_and_overhead() {
+    let start = Instant::now();
+    let mut limiter = BandwidthLimiter::new(
+        BandwidthConfig {
+            global: upload(10_000),
+            exempt_lan: true,
+            count_overhead: true,
+        },
+        start,
+    );
+    let lan = SocketAddr::from(([192, 168, 1, 20], 6881));
+    let wan = addr(1);
+    // overhead uses up the tokens
+    limiter.add_overhead(wan, Direction::Upload, 10_000);
+    limiter.request(wan, Direction::Upload, BLOCK);
+    limiter.request(lan, Direction::Upload, BLOCK);
+    let grants = limiter.poll(start);
+    assert_eq!(grants.len(), 1);
+    assert_eq!(grants[0].peer, lan);
+    assert_eq!(limiter.pending(), 1);
+    assert_eq!(
+        limiter.poll(start + Duration::from_millis(100))[0].peer,
+        wan
+    );
+
+    assert!(is_local("10.1.2.3".parse().unwrap()));
+    assert!(is_local("fd00::1".parse().unwrap()));
+    assert!(is_local("::ffff:127.0.0.1".parse().unwrap()));
+    assert!(!is_local("8.8.8.8".parse().unwrap()));
+    assert!(!is_local("2001:db8::1".parse().unwrap()));
+
+    // without the exemption LAN peers wait like the others
+    limiter.set_exempt_lan(false);
+    limiter.set_count_overhead(false);
+    limiter.add_overhead(wan, Direction::Upload, 1 << 30);
+    let now = start + Duration::from_secs(10);
+    limiter.request(lan, Direction::Upload, BLOCK);
+    limiter.request(lan, Direction::Upload, BLOCK);
+    assert_eq!(limiter.poll(now).len(), 1);
+}
+
+#[test]
+fn test_poll_peer_keeps_other_grants() {
+    let start = Instant::now();
+    let mut limiter = BandwidthLimiter::new(BandwidthConfig::default(), start);
+    let (first, second) = (addr(1), addr(2));
+    limiter.request(first, Direction::Upload, BLOCK);
+    limiter.request(second, Direction::Upload, BLOCK);
+    limiter.request(second, Direction::Download, BLOCK);
+    assert_eq!(limiter.poll_peer(first, Direction::Upload, start), BLOCK);
+    assert_eq!(limiter.poll_peer(first, Direction::Upload, start), 0);
+    assert_eq!(limiter.poll_peer(second, Direction::Upload, start), BLOCK);
+
+    // a disconnected peer's grants are dropped
+    limiter.remove_peer(second);
+    assert_eq!(limiter.poll_peer(second, Direction::Download, start), 0);
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use torrent_core::{
+    picker::PickerConfig,
+    scheduler::{BlockOutcome, BlockScheduler, SchedulerConfig, SchedulerEvent, BLOCK_SIZE},
+};
+use torrent_pwp::{bitfield::Bitfield, message::BlockInfo};
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], port))
+}
+
+// four pieces of two blocks, the last piece a single short block
+fn scheduler() -> BlockScheduler {
+    scheduler_with_pipeline(2)
+}
+
+fn scheduler_with_pipeline(min_pipeline: usize) -> BlockScheduler {
+    BlockScheduler::new(
+        2 * BLOCK_SIZE as u64,
+        6 * BLOCK_SIZE as u64 + 100,
+        SchedulerConfig {
+            min_pipeline,
+            // a seed whose picks happen to follow the piece order
+            picker: PickerConfig {
+                seed: 35,
+                random_first_pieces: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+}
+
+#[test]
+fn test_pieces_split_into_blocks() {
+    let mut scheduler = scheduler();
+    assert_eq!(scheduler.num_pieces(), 4);
+    assert_eq!(scheduler.blocks_in_piece(0), 2);
+    assert_eq!(scheduler.piece_size(3), 100);
+
+    let peer = addr(1);
+    let now = Instant::now();
+    let all = Bitfield::full(4);
+    // the pipeline of an unknown peer is shallow
+    assert_eq!(scheduler.request_blocks(peer, &all, |_| true, now).len(), 2);
+    scheduler.remove_peer(peer);
+    let mut requested = Vec::new();
+    loop {
+        let requests = scheduler.request_blocks(peer, &all, |_| true, now);
+        if requests.is_empty() {
+            break;
+        }
+        for block in requests {
+            let outcome = scheduler.on_block(peer, block.index, block.begin, block.length, now);
+            assert!(matches!(outcome, BlockOutcome::Accepted { .. }));
+            requested.push(block);
+        }
+    }
+    assert_eq!(requested.len(), 7);
+    assert_eq!(
+        requested.last(),
+        Some(&BlockInfo {
+            index: 3,
+            begin: 0,
+            length: 100
+        })
+    );
+    assert_eq!(scheduler.partial_pieces().count(), 4);
+}
+
+#[test]
+fn test_pipeline_follows_throughput() {
+    let mut scheduler = BlockScheduler::new(
+        1024 * BLOCK_SIZE as u64,
+        64 * 1024 * BLOCK_SIZE as u64,
+        SchedulerConfig::default(),
+    );
+    let (fast, limited) = (addr(1), addr(2));
+    scheduler.set_reqq(limited, 8);
+    let all = Bitfield::full(64);
+    let mut now = Instant::now();
+    let mut queues = [VecDeque::new(), VecDeque::new()];
+    // both peers serve one request every 10 ms, in order
+    for _ in 0..500 {
+        for (peer, queue) in [fast, limited].into_iter().zip(&mut queues) {
+            queue.extend(scheduler.request_blocks(peer, &all, |_| true, now));
+            if let Some(block) = queue.pop_front() {
+                scheduler.on_block(peer, block.index, block.begin, block.length, now);
+            }
+        }
+        now += Duration::from_millis(10);
+    }
+    scheduler.request_blocks(fast, &all, |_| true, now);
+    let stats = scheduler.stats(fast, now).unwrap();
+    // 1.6 MB/s for three seconds is about 300 blocks, capped at 250
+    assert_eq!(stats.depth, 250);
+    assert_eq!(stats.outstanding, stats.depth);
+    assert!(stats.download_rate > 1_000_000.0);
+    assert!(stats.latency.unwrap() > Duration::from_millis(100));
+    assert_eq!(scheduler.stats(limited, now).unwrap().depth, 8);
+}
+
+#[test]
+fn test_choke_drops_requests() {
+    let mut scheduler = scheduler();
+    let (choking, other) = (addr(1), addr(2));
+    let now = Instant::now();
+    let all = Bitfield::full(4);
+    let allowed_fast = |index: u32| index == 0;
+    let requests = scheduler.request_blocks(choking, &all, |_| true, now);
+    assert_eq!(requests.len(), 2);
+    // the other peer gets different blocks while these are outstanding
+    let others = scheduler.request_blocks(other, &all, |_| true, now);
+    assert!(others.iter().all(|block| !requests.contains(block)));
+
+    scheduler.on_block(
+        other,
+        others[0].index,
+        others[0].begin,
+        others[0].length,
+        now,
+    );
+    scheduler.on_choke(choking, allowed_fast);
+    assert_eq!(scheduler.stats(choking, now).unwrap().outstanding, 2);
+
+    // requests outside the allowed fast set are given up
+    scheduler.on_block(choking, 0, 0, BLOCK_SIZE, now);
+    let requests = scheduler.request_blocks(choking, &all, |_| true, now);
+    assert!(!requests.is_empty());
+    assert!(requests.iter().all(|block| block.index == 2));
+    scheduler.on_choke(choking, |_| false);
+    assert_eq!(scheduler.stats(choking, now).unwrap().outstanding, 0);
+    let requests = scheduler.request_blocks(other, &all, |_| true, now);
+    assert!(requests.contains(&BlockInfo {
+        index: 2,
+        begin: 0,
+        length: BLOCK_SIZE
+    }));
+}
+
+#[test]
+fn test_timeout_requests_elsewhere_and_cancels() {
+    let mut scheduler = scheduler();
+    let (slow, fast) = (addr(1), addr(2));
+    let mut now = Instant::now();
+    let one_piece = {
+        let mut bitfield = Bitfield::new(4);
+        bitfield.set(0, true);
+        bitfield
+    };
+    let requests = scheduler.request_blocks(slow, &one_piece, |_| true, now);
+    assert_eq!(requests.len(), 2);
+    // nothing left for the other peer until the requests time out
+    assert!(scheduler
+        .request_blocks(fast, &one_piece, |_| true, now)
+        .is_empty());
+    now += SchedulerConfig::default().request_timeout;
+    assert_eq!(scheduler.poll_timeouts(now).len(), 2);
+    assert!(scheduler.poll_timeouts(now).is_empty());
+
+    let again = scheduler.request_blocks(fast, &one_piece, |_| true, now);
+    assert_eq!(again, requests);
+    // the timed out requests still fill the slow peer's pipeline
+    assert!(scheduler
+        .request_blocks(slow, &one_piece, |_| true, now)
+        .is_empty());
+
+    let block = again[0];
+    assert_eq!(
+        scheduler.on_block(fast, block.index, block.begin, block.length, now),
+        BlockOutcome::Accepted {
+            piece_complete: false,
+            cancels: vec![(slow, block)]
+        }
+    );
+    assert_eq!(scheduler.stats(slow, now).unwrap().outstanding, 1);
+    assert_eq!(
+        scheduler.on_block(slow, block.index, block.begin, block.length, now),
+        BlockOutcome::Duplicate
+    );
+    // a late block is still welcome
+    let block = again[1];
+    assert_eq!(
+        scheduler.on_block(slow, block.index, block.begin, block.length, now),
+        BlockOutcome::Accepted {
+            piece_complete: true,
+            cancels: vec![(fast, block)]
+        }
+    );
+    assert_eq!(
+        scheduler.on_block(slow, 0, 1, BLOCK_SIZE, now),
+        BlockOutcome::Unexpected
+    );
+}
+
+#[test]
+fn test_failed_piece_is_requested_again() {
+    let mut scheduler = scheduler();
+    let peer = addr(1);
+    let now = Instant::now();
+    let all = Bitfield::full(4);
+    for block in scheduler.request_blocks(peer, &all, |_| true, now) {
+        scheduler.on_block(peer, block.index, block.begin, block.length, now);
+    }
+    scheduler.piece_failed(0);
+    let requests = scheduler.request_blocks(peer, &all, |_| true, now);
+    assert_eq!(requests[0].index, 0);
+    for block in requests {
+        scheduler.on_block(peer, block.index, block.begin, block.length, now);
+    }
+    scheduler.piece_passed(0);
+    assert!(scheduler.have().has(0));
+    assert_eq!(
+        scheduler.on_block(peer, 0, 0, BLOCK_SIZE, now),
+        BlockOutcome::Duplicate
+    );
+}
+
+#[test]
+fn test_endgame_duplicates_remaining_blocks() {
+    let mut scheduler = scheduler_with_pipeline(8);
+    let (first, second, third) = (addr(1), addr(2), addr(3));
+    let now = Instant::now();
+    let all = Bitfield::full(4);
+    let requested = scheduler.request_blocks(first, &all, |_| true, now);
+    assert_eq!(requested.len(), 7);
+    assert!(scheduler.is_endgame());
+    assert_eq!(
+        scheduler.drain_events(),
+        vec![SchedulerEvent::EndGameStarted]
+    );
+
+    // each block goes to one more peer at most
+    let duplicates = scheduler.request_blocks(second, &all, |_| true, now);
+    assert_eq!(duplicates.len(), 7);
+    assert!(duplicates.iter().all(|block| requested.contains(block)));
+    assert!(scheduler
+        .request_blocks(third, &all, |_| true, now)
+        .is_empty());
+
+    for block in duplicates {
+        let outcome = scheduler.on_block(second, block.index, block.begin, block.length, now);
+        let BlockOutcome::Accepted {
+            piece_complete,
+            cancels,
+        } = outcome
+        else {
+            panic!("block not accepted");
+        };
+        assert_eq!(cancels, vec![(first, block)]);
+        if piece_complete {
+            scheduler.piece_passed(block.index);
+        }
+    }
+    assert!(scheduler.is_complete());
+    assert!(!scheduler.is_endgame());
+    assert_eq!(scheduler.drain_events(), vec![SchedulerEvent::EndGameEnded]);
+    assert_eq!(scheduler.stats(first, now).unwrap().outstanding, 0);
+}
+
+#[test]
+fn test_endgame_ends_when_requests_time_out() {
+    let mut scheduler = scheduler_with_pipeline(8);
+    let peer = addr(1);
+    let now = Instant::now();
+    let all = Bitfield::full(4);
+    assert_eq!(scheduler.request_blocks(peer, &all, |_| true, now).len(), 7);
+    assert!(scheduler.is_endgame());
+    scheduler.drain_events();
+
+    // timed out blocks are free for anyone again
+    let timed_out = scheduler.poll_timeouts(now + Duration::from_secs(60));
+    assert_eq!(timed_out.len(), 7);
+    assert!(!scheduler.is_endgame());
+    assert_eq!(scheduler.drain_events(), vec![SchedulerEvent::EndGameEnded]);
+}
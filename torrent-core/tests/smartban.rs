@@ -0,0 +1,269 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use sha1::{Digest, Sha1};
+use torrent_core::{
+    ban::BanReason,
+    picker::PickerConfig,
+    scheduler::{BlockScheduler, SchedulerConfig, BLOCK_SIZE},
+    smartban::{ReceivedBlock, SmartBan, MAX_FAILED_PIECES},
+    torrent::ManagedTorrent,
+};
+use torrent_parser::parse_torrent_metadata;
+use torrent_pwp::{bitfield::Bitfield, message::BlockInfo};
+
+fn addr(host: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, host], 6881))
+}
+
+fn ip(host: u8) -> IpAddr {
+    addr(host).ip()
+}
+
+// one file of two pieces of two blocks
+fn torrent(location: String, data: &[u8]) -> ManagedTorrent {
+    let pieces = data
+        .chunks(2 * BLOCK_SIZE as usize)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect::<Vec<_>>();
+    let mut info = format!(
+        "d6:lengthi{}e4:name4:data12:piece lengthi{}e6:pieces{}:",
+        data.len(),
+        2 * BLOCK_SIZE,
+        pieces.len()
+    )
+    .into_bytes();
+    info.extend(pieces);
+    info.push(b'e');
+    let mut bencoded = b"d8:announce17:http://t/announce4:info".to_vec();
+    bencoded.extend(info);
+    bencoded.push(b'e');
+    ManagedTorrent::from_torrent_metadata(
+        parse_torrent_metadata(bencoded).unwrap(),
+        None,
+        location,
+        "-RT0001-aaaaaaaaaaaa".to_string(),
+        6881,
+        Arc::new(Default::default()),
+        None,
+    )
+}
+
+// the peer sends the blocks of the piece, `data` holding what it sends
+fn receive(torrent: &ManagedTorrent, peer: SocketAddr, index: u32, data: &[u8]) {
+    let all = Bitfield::full(2);
+    let now = Instant::now();
+    let requests =
+        torrent
+            .scheduler
+            .lock()
+            .unwrap()
+            .request_blocks(peer, &all, |piece| piece == index, now);
+    for block in requests {
+        let offset = (index * 2 * BLOCK_SIZE + block.begin) as usize;
+        torrent
+            .storage
+            .write(offset as u64, &data[offset..offset + block.length as usize])
+            .unwrap();
+        torrent.scheduler.lock().unwrap().on_block(
+            peer,
+            block.index,
+            block.begin,
+            block.length,
+            now,
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_ban_peer_that_sent_bad_block() {
+    let location = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    let good = (0..4 * BLOCK_SIZE)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let mut bad = good.clone();
+    bad[BLOCK_SIZE as usize + 7] ^= 0xff;
+    let torrent = torrent(location.to_string_lossy().into_owned(), &good);
+    let (honest, liar) = (addr(1), addr(2));
+    assert_eq!(torrent.add_pex_peers(vec![honest, liar]).await.unwrap(), 2);
+    {
+        let mut scheduler = torrent.scheduler.lock().unwrap();
+        scheduler.add_peer(honest);
+        scheduler.add_peer(liar);
+    }
+
+    // each peer sends one block of the first piece
+    let all = Bitfield::full(2);
+    let now = Instant::now();
+    for (peer, data) in [(honest, &good), (liar, &bad)] {
+        let requests =
+            torrent
+                .scheduler
+                .lock()
+                .unwrap()
+                .request_blocks(peer, &all, |index| index == 0, now);
+        let block = requests[0];
+        if let Some(rejected) = requests.get(1) {
+            torrent.scheduler.lock().unwrap().on_reject(peer, *rejected);
+        }
+        let offset = block.begin as usize;
+        torrent
+            .storage
+            .write(offset as u64, &data[offset..offset + block.length as usize])
+            .unwrap();
+        torrent
+            .scheduler
+            .lock()
+            .unwrap()
+            .on_block(peer, 0, block.begin, block.length, now);
+    }
+    let check = torrent.verify_piece(0).await.unwrap();
+    assert!(!check.passed && check.banned.is_empty());
+    assert_eq!(torrent.bans.hash_failures(ip(1)), 1);
+    assert_eq!(torrent.bans.hash_failures(ip(2)), 1);
+
+    // the honest peer downloads it again alone, which shows who lied
+    receive(&torrent, honest, 0, &good);
+    let check = torrent.verify_piece(0).await.unwrap();
+    assert!(check.passed);
+    assert_eq!(check.banned, vec![ip(2)]);
+    assert!(torrent.bans.is_banned(ip(2)));
+    assert!(!torrent.bans.is_banned(ip(1)));
+    assert_eq!(torrent.bans.banned(), vec![(ip(2), BanReason::CorruptData)]);
+    // the liar is dropped and not let back in
+    assert_eq!(torrent.peers.read().await.len(), 1);
+    assert_eq!(torrent.add_pex_peers(vec![liar]).await.unwrap(), 0);
+
+    // a piece from a single peer needs no second download
+    receive(
+        &torrent,
+        addr(3),
+        1,
+        &bad[..2 * BLOCK_SIZE as usize].repeat(2),
+    );
+    let check = torrent.verify_piece(1).await.unwrap();
+    assert_eq!(check.banned, vec![ip(3)]);
+
+    assert!(torrent.bans.unban(ip(2)));
+    assert!(!torrent.bans.is_banned(ip(2)));
+    std::fs::remove_dir_all(location).unwrap();
+}
+
+#[test]
+fn test_failed_piece_downloaded_from_one_peer() {
+    let mut scheduler = BlockScheduler::new(
+        2 * BLOCK_SIZE as u64,
+        4 * BLOCK_SIZE as u64,
+        SchedulerConfig {
+            picker: PickerConfig {
+                seed: 3,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    let (first, second) = (addr(1), addr(2));
+    let now = Instant::now();
+    let all = Bitfield::full(2);
+    let only_first = |index| index == 0;
+    // the first peer sends one block and rejects the other
+    let requests = scheduler.request_blocks(first, &all, only_first, now);
+    scheduler.on_block(first, 0, requests[0].begin, requests[0].length, now);
+    scheduler.on_reject(first, requests[1]);
+    let request = scheduler.request_blocks(second, &all, only_first, now)[0];
+    scheduler.on_block(second, 0, request.begin, request.length, now);
+    let senders = scheduler.piece_failed(0);
+    assert_eq!(senders.len(), 2);
+    assert!(senders.contains(&(
+        BlockInfo {
+            index: 0,
+            begin: 0,
+            length: BLOCK_SIZE
+        },
+        Some(first)
+    )));
+
+    // the piece is left to the first peer asking for it
+    let request = scheduler.request_blocks(first, &all, only_first, now);
+    assert_eq!(request.len(), 2);
+    scheduler.on_block(first, 0, request[0].begin, request[0].length, now);
+    assert!(scheduler
+        .request_blocks(second, &all, only_first, now)
+        .is_empty());
+    // once it chokes us the piece is free for others
+    scheduler.on_choke(first, |_| false);
+    assert_eq!(
+        scheduler.request_blocks(second, &all, only_first, now),
+        vec![request[1]]
+    );
+}
+
+#[test]
+fn test_smart_ban_compares_blocks() {
+    let block = |begin| BlockInfo {
+        index: 0,
+        begin,
+        length: 4,
+    };
+    let mut smart_ban = SmartBan::default();
+    let failed = [
+        ReceivedBlock {
+            block: block(0),
+            from: Some(addr(1)),
+            data: b"good",
+        },
+        ReceivedBlock {
+            block: block(4),
+            from: Some(addr(2)),
+            data: b"evil",
+        },
+        ReceivedBlock {
+            block: block(8),
+            from: None,
+            data: b"????",
+        },
+    ];
+    assert!(smart_ban.on_piece_failed(0, &failed).is_empty());
+    assert_eq!(smart_ban.pending(), 1);
+    // a later copy from one peer is blamed at once, the earlier copies are
+    // still compared
+    let single = [ReceivedBlock {
+        block: block(0),
+        from: Some(addr(4)),
+        data: b"oops",
+    }];
+    assert_eq!(smart_ban.on_piece_failed(0, &single), vec![ip(4)]);
+    assert_eq!(smart_ban.pending(), 1);
+    let passed = [
+        ReceivedBlock {
+            block: block(0),
+            from: Some(addr(3)),
+            data: b"good",
+        },
+        ReceivedBlock {
+            block: block(4),
+            from: Some(addr(3)),
+            data: b"nice",
+        },
+        ReceivedBlock {
+            block: block(8),
+            from: Some(addr(3)),
+            data: b"fine",
+        },
+    ];
+    let mut guilty = smart_ban.on_piece_passed(0, &passed);
+    guilty.sort();
+    assert_eq!(guilty, vec![ip(2), ip(4)]);
+    assert_eq!(smart_ban.pending(), 0);
+    assert_eq!(smart_ban.on_piece_failed(1, &passed), vec![ip(3)]);
+
+    // pieces that never pass are forgotten, oldest first
+    for index in 0..MAX_FAILED_PIECES as u32 + 1 {
+        smart_ban.on_piece_failed(index, &failed);
+    }
+    assert_eq!(smart_ban.pending(), MAX_FAILED_PIECES);
+    assert!(smart_ban.on_piece_passed(0, &passed).is_empty());
+}
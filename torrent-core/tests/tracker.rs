@@ -0,0 +1,134 @@
+use std::{sync::Arc, time::Duration};
+
+use torrent_core::{
+    torrent::ManagedTorrent,
+    tracker::{
+        client::{AnnounceEvent, AnnounceRequest, TrackerClientRegistry},
+        http::scrape_url,
+        mock::MockTrackerClient,
+    },
+};
+use torrent_parser::{
+    model::{ScrapeResponse, TrackerResponse},
+    parse_torrent_metadata,
+};
+
+fn request(peer_id: &str, port: u32, left: u64) -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: vec![7; 20],
+        peer_id: peer_id.to_string(),
+        port,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        event: Some(AnnounceEvent::Started),
+        tracker_id: None,
+        num_want: None,
+    }
+}
+
+#[tokio::test]
+async fn test_registry_dispatches_by_scheme() {
+    let registry = TrackerClientRegistry::empty();
+    let mock = Arc::new(MockTrackerClient::default());
+    registry.register("unix", mock.clone());
+
+    let resp = registry
+        .announce(
+            "unix:///run/registry.sock",
+            &request("-RT0001-aaaaaaaaaaaa", 6881, 0),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(resp, TrackerResponse::Success(_)));
+    assert_eq!(mock.announces().len(), 1);
+
+    assert!(registry
+        .announce("wss://tracker.example/announce", &request("x", 1, 0))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_mock_swarm() {
+    let registry = TrackerClientRegistry::empty();
+    let mock = Arc::new(MockTrackerClient::default());
+    registry.register("http", mock.clone());
+    let url = "http://tracker.example/announce";
+
+    registry
+        .announce(url, &request("-RT0001-aaaaaaaaaaaa", 6881, 0))
+        .await
+        .unwrap();
+    let resp = registry
+        .announce(url, &request("-RT0001-bbbbbbbbbbbb", 6882, 100))
+        .await
+        .unwrap();
+    let TrackerResponse::Success(resp) = resp else {
+        panic!("expected success");
+    };
+    assert_eq!(resp.complete, 1);
+    assert_eq!(resp.incomplete, 1);
+    assert_eq!(resp.peers.len(), 1);
+    assert_eq!(resp.peers[0].port, 6881);
+
+    let ScrapeResponse::Success(files) = registry.scrape(url, &[vec![7; 20]]).await.unwrap() else {
+        panic!("expected success");
+    };
+    assert_eq!(files[&vec![7; 20]].complete, 1);
+
+    mock.set_failure(Some("unregistered torrent".to_string()));
+    let resp = registry.announce(url, &request("x", 1, 0)).await.unwrap();
+    assert!(matches!(resp, TrackerResponse::Failure(_)));
+}
+
+#[tokio::test]
+async fn test_compact_peers_are_kept_apart() {
+    let metadata = parse_torrent_metadata(
+        b"d8:announce31:http://tracker.example/announce4:infod6:lengthi4e4:name4:data12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+            .to_vec(),
+    )
+    .unwrap();
+    let registry = TrackerClientRegistry::empty();
+    let mock = Arc::new(MockTrackerClient::default());
+    registry.register("http", mock.clone());
+    // compact peers come without an id
+    mock.add_peer(&metadata.info_hash, "10.0.0.1", 6881, true);
+    mock.add_peer(&metadata.info_hash, "10.0.0.2", 6881, true);
+
+    let torrent = ManagedTorrent::from_torrent_metadata(
+        metadata,
+        None,
+        std::env::temp_dir().to_string_lossy().into_owned(),
+        "-RT0001-aaaaaaaaaaaa".to_string(),
+        6881,
+        Arc::new(registry),
+        None,
+    );
+    assert!(torrent.start());
+    // a second start announces nothing more
+    assert!(!torrent.start());
+    for _ in 0..50 {
+        if torrent.peers.read().await.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let peers = torrent.peers.read().await;
+    assert_eq!(peers.len(), 2);
+    assert_eq!(mock.announces().len(), 1);
+    assert_ne!(peers[0].ip, peers[1].ip);
+}
+
+#[test]
+fn test_scrape_url() {
+    assert_eq!(
+        scrape_url("http://example.com/announce").as_deref(),
+        Some("http://example.com/scrape")
+    );
+    assert_eq!(
+        scrape_url("http://example.com/x/announce.php?passkey=1").as_deref(),
+        Some("http://example.com/x/scrape.php?passkey=1")
+    );
+    assert_eq!(scrape_url("http://example.com/a"), None);
+}
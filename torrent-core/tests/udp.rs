@@ -0,0 +1,101 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    spawn,
+};
+use torrent_core::{
+    tracker::client::{AnnounceRequest, TrackerClient},
+    udp::SharedUdpSocket,
+};
+use torrent_dht::{DhtConfig, DhtNode};
+use torrent_parser::model::TrackerResponse;
+use torrent_pwp::utp::UtpSocket;
+
+fn dht_config() -> DhtConfig {
+    DhtConfig {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        bootstrap_nodes: Vec::new(),
+        routing_table_path: None,
+        query_timeout: Duration::from_millis(500),
+        maintenance_interval: Duration::from_secs(3600),
+    }
+}
+
+/// Answers one connect and one announce request with a single peer.
+async fn fake_tracker(socket: UdpSocket) {
+    let mut buf = [0u8; 2048];
+    for action in [0u32, 1] {
+        let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+        let mut resp = action.to_be_bytes().to_vec();
+        resp.extend_from_slice(&buf[12..16]);
+        if action == 0 {
+            resp.extend_from_slice(&42u64.to_be_bytes());
+        } else {
+            for value in [1800u32, 1, 2] {
+                resp.extend_from_slice(&value.to_be_bytes());
+            }
+            resp.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        }
+        socket.send_to(&resp, from).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_protocols_share_one_socket() {
+    let udp = SharedUdpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = udp.local_addr().unwrap();
+    let dht = Arc::new(
+        DhtNode::with_socket(udp.socket(), dht_config())
+            .await
+            .unwrap(),
+    );
+    udp.set_dht(Arc::clone(&dht));
+    let utp = udp.utp();
+
+    // DHT queries in both directions
+    let remote_dht = DhtNode::start(dht_config()).await.unwrap();
+    assert_eq!(
+        dht.ping(remote_dht.local_addr().unwrap()).await.unwrap(),
+        remote_dht.id()
+    );
+    assert_eq!(remote_dht.ping(addr).await.unwrap(), dht.id());
+
+    // a uTP connection from outside
+    let remote_utp = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let (connected, accepted) = tokio::join!(remote_utp.connect(addr), utp.accept());
+    let (mut outgoing, mut incoming) = (connected.unwrap(), accepted.unwrap());
+    outgoing.write_all(b"hello").await.unwrap();
+    let mut hello = [0u8; 5];
+    incoming.read_exact(&mut hello).await.unwrap();
+    assert_eq!(&hello, b"hello");
+
+    // a tracker announce
+    let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}/announce", tracker.local_addr().unwrap());
+    let server = spawn(fake_tracker(tracker));
+    let client = udp.tracker_client(Duration::from_secs(1), 0);
+    let request = AnnounceRequest {
+        info_hash: vec![7; 20],
+        peer_id: "-RT0001-aaaaaaaaaaaa".to_string(),
+        port: addr.port() as u32,
+        uploaded: 0,
+        downloaded: 0,
+        left: 0,
+        event: None,
+        tracker_id: None,
+        num_want: None,
+    };
+    let TrackerResponse::Success(success) = client.announce(&url, &request).await.unwrap() else {
+        panic!("announce failed");
+    };
+    assert_eq!(success.interval, 1800);
+    assert_eq!(success.peers[0].ip, "10.0.0.1");
+    assert_eq!(success.peers[0].port, 6881);
+    server.await.unwrap();
+}
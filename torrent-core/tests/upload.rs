@@ -0,0 +1,108 @@
+use std::{sync::Arc, time::Instant};
+
+use torrent_core::{
+    torrent::ManagedTorrent,
+    upload::{UploadQueue, MAX_REQUEST_LENGTH},
+};
+use torrent_parser::parse_torrent_metadata;
+use torrent_pwp::{
+    bitfield::Bitfield,
+    message::{BlockInfo, Message},
+};
+
+fn block(index: u32, begin: u32, length: u32) -> BlockInfo {
+    BlockInfo {
+        index,
+        begin,
+        length,
+    }
+}
+
+// two files of 20000 bytes in pieces of 32 KiB
+fn torrent(location: String) -> ManagedTorrent {
+    let info = "d5:filesld6:lengthi20000e4:pathl1:aeed6:lengthi20000e4:pathl3:sub1:beee\
+                4:name4:data12:piece lengthi32768e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaae";
+    let bencoded = format!("d8:announce17:http://t/announce4:info{}e", info);
+    ManagedTorrent::from_torrent_metadata(
+        parse_torrent_metadata(bencoded.into_bytes()).unwrap(),
+        None,
+        location,
+        "-RT0001-aaaaaaaaaaaa".to_string(),
+        6881,
+        Arc::new(Default::default()),
+        None,
+    )
+}
+
+#[test]
+fn test_queue_refuses_bad_requests() {
+    let mut have = Bitfield::new(2);
+    have.set(0, true);
+    let mut queue = UploadQueue::with_limit(true, 2);
+    assert_eq!(queue.on_request(block(0, 0, 16384), &have, 32768), None);
+    // a piece we lack, too long, past the piece end, a duplicate
+    for bad in [
+        block(1, 0, 16384),
+        block(0, 0, MAX_REQUEST_LENGTH + 1),
+        block(0, 32000, 16384),
+        block(0, 0, 16384),
+    ] {
+        assert_eq!(
+            queue.on_request(bad, &have, 32768),
+            Some(Message::RejectRequest(bad))
+        );
+    }
+    assert_eq!(queue.on_request(block(0, 16384, 16384), &have, 32768), None);
+    // over the limit
+    assert!(queue.on_request(block(0, 100, 10), &have, 32768).is_some());
+    assert_eq!(queue.len(), 2);
+
+    assert!(queue.on_cancel(&block(0, 0, 16384)));
+    assert!(!queue.on_cancel(&block(0, 0, 16384)));
+    assert_eq!(
+        queue.on_choke(|_| false),
+        vec![Message::RejectRequest(block(0, 16384, 16384))]
+    );
+    assert!(queue.is_empty());
+
+    // without the fast extension requests are dropped silently
+    let mut queue = UploadQueue::new(false);
+    assert_eq!(queue.on_request(block(1, 0, 16384), &have, 32768), None);
+    assert!(queue.is_empty());
+    queue.sent(16384, Instant::now());
+    assert_eq!(queue.uploaded(), 16384);
+}
+
+#[tokio::test]
+async fn test_serve_blocks_from_storage() {
+    let location = std::env::temp_dir().join(format!("rusty-torrent-{}", uuid::Uuid::new_v4()));
+    let torrent = torrent(location.to_string_lossy().into_owned());
+    let data = (0..40000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    torrent.storage.write(0, &data).unwrap();
+    assert!(location.join("data/sub/b").exists());
+    torrent.scheduler.lock().unwrap().piece_passed(0);
+
+    let mut queue = UploadQueue::new(true);
+    // spans both files
+    let spanning = block(0, 16384, 16384);
+    assert_eq!(torrent.queue_request(&mut queue, spanning), None);
+    assert!(torrent
+        .queue_request(&mut queue, block(1, 0, 7232))
+        .is_some());
+
+    let Some(Message::Piece {
+        index,
+        begin,
+        data: sent,
+    }) = torrent.serve(&mut queue).await.unwrap()
+    else {
+        panic!("nothing served");
+    };
+    assert_eq!((index, begin), (0, 16384));
+    assert_eq!(&sent[..], &data[16384..32768]);
+    assert_eq!(*torrent.uploaded.read().await, 16384);
+    assert_eq!(queue.uploaded(), 16384);
+    assert_eq!(torrent.serve(&mut queue).await.unwrap(), None);
+
+    std::fs::remove_dir_all(location).unwrap();
+}
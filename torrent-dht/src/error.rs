@@ -0,0 +1,31 @@
+use thiserror::Error;
+use torrent_parser::error::TorrentParserError;
+
+#[derive(Error, Debug)]
+pub enum DhtError {
+    #[error("IO Error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("Bencode Error: {0}")]
+    BencodeError(#[from] TorrentParserError),
+
+    #[error("Invalid Message: {0}")]
+    InvalidMessage(&'static str),
+
+    #[error("Unknown Method")]
+    UnknownMethod,
+
+    #[error("Remote Error {0}: {1}")]
+    RemoteError(i64, String),
+
+    #[error("Query Timeout")]
+    Timeout,
+
+    #[error("Invalid Routing Table")]
+    InvalidRoutingTable,
+
+    #[error("Unsupported Address: {0}")]
+    UnsupportedAddress(std::net::SocketAddr),
+}
+
+pub type DhtResult<T> = Result<T, DhtError>;
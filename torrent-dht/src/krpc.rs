@@ -0,0 +1,272 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use torrent_parser::{
+    field::{dict, Field},
+    parse_bencode,
+};
+
+use crate::{
+    error::{DhtError, DhtResult},
+    routing::{NodeId, NodeInfo},
+};
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping {
+        id: NodeId,
+    },
+    FindNode {
+        id: NodeId,
+        target: NodeId,
+    },
+    GetPeers {
+        id: NodeId,
+        info_hash: NodeId,
+    },
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: NodeId,
+        port: u16,
+        token: Vec<u8>,
+        implied_port: bool,
+    },
+}
+
+impl Query {
+    pub fn id(&self) -> NodeId {
+        match self {
+            Query::Ping { id }
+            | Query::FindNode { id, .. }
+            | Query::GetPeers { id, .. }
+            | Query::AnnouncePeer { id, .. } => *id,
+        }
+    }
+
+    fn method(&self) -> &'static str {
+        match self {
+            Query::Ping { .. } => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+        }
+    }
+}
+
+/// The return values of any query; which keys are present depends on the
+/// method that was called.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: Option<NodeId>,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddrV4>,
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageBody {
+    Query(Query),
+    Response(Response),
+    Error(i64, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub transaction_id: Vec<u8>,
+    pub body: MessageBody,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries = vec![("t", Field::String(self.transaction_id.clone()))];
+        match &self.body {
+            MessageBody::Query(query) => {
+                let mut args = vec![("id", Field::String(query.id().0.to_vec()))];
+                match query {
+                    Query::Ping { .. } => {}
+                    Query::FindNode { target, .. } => {
+                        args.push(("target", Field::String(target.0.to_vec())));
+                    }
+                    Query::GetPeers { info_hash, .. } => {
+                        args.push(("info_hash", Field::String(info_hash.0.to_vec())));
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                        ..
+                    } => {
+                        args.push(("info_hash", Field::String(info_hash.0.to_vec())));
+                        args.push(("port", Field::Integer(*port as i64)));
+                        args.push(("token", Field::String(token.clone())));
+                        args.push(("implied_port", Field::Integer(*implied_port as i64)));
+                    }
+                }
+                entries.push(("y", Field::from("q")));
+                entries.push(("q", Field::from(query.method())));
+                entries.push(("a", dict(args)));
+            }
+            MessageBody::Response(response) => {
+                let mut values = Vec::new();
+                if let Some(id) = response.id {
+                    values.push(("id", Field::String(id.0.to_vec())));
+                }
+                if !response.nodes.is_empty() {
+                    let mut nodes = Vec::with_capacity(response.nodes.len() * 26);
+                    for node in &response.nodes {
+                        node.to_compact(&mut nodes);
+                    }
+                    values.push(("nodes", Field::String(nodes)));
+                }
+                if !response.values.is_empty() {
+                    let peers = response
+                        .values
+                        .iter()
+                        .map(|addr| {
+                            let mut peer = addr.ip().octets().to_vec();
+                            peer.extend_from_slice(&addr.port().to_be_bytes());
+                            Field::String(peer)
+                        })
+                        .collect();
+                    values.push(("values", Field::List(peers)));
+                }
+                if let Some(token) = &response.token {
+                    values.push(("token", Field::String(token.clone())));
+                }
+                entries.push(("y", Field::from("r")));
+                entries.push(("r", dict(values)));
+            }
+            MessageBody::Error(code, msg) => {
+                entries.push(("y", Field::from("e")));
+                entries.push((
+                    "e",
+                    Field::List(vec![Field::Integer(*code), Field::from(msg.as_str())]),
+                ));
+            }
+        }
+        dict(entries).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> DhtResult<Message> {
+        let root = parse_bencode(bytes.to_vec())?;
+        let transaction_id = root
+            .get("t")
+            .and_then(Field::as_bytes)
+            .ok_or(DhtError::InvalidMessage("missing transaction id"))?
+            .to_vec();
+
+        let body = match root.get("y").and_then(Field::as_bytes) {
+            Some(b"q") => MessageBody::Query(decode_query(&root)?),
+            Some(b"r") => MessageBody::Response(decode_response(
+                root.get("r")
+                    .ok_or(DhtError::InvalidMessage("missing return values"))?,
+            )),
+            Some(b"e") => {
+                let error = root.get("e").and_then(Field::as_list).unwrap_or_default();
+                let code = error
+                    .first()
+                    .and_then(Field::as_integer)
+                    .unwrap_or(ERROR_GENERIC);
+                let msg = error
+                    .get(1)
+                    .and_then(Field::as_bytes)
+                    .map(|msg| String::from_utf8_lossy(msg).to_string())
+                    .unwrap_or_default();
+                MessageBody::Error(code, msg)
+            }
+            _ => return Err(DhtError::InvalidMessage("unknown message type")),
+        };
+
+        Ok(Message {
+            transaction_id,
+            body,
+        })
+    }
+}
+
+fn node_id(args: &Field, key: &'static str) -> DhtResult<NodeId> {
+    args.get(key)
+        .and_then(Field::as_bytes)
+        .and_then(NodeId::from_bytes)
+        .ok_or(DhtError::InvalidMessage(key))
+}
+
+fn decode_query(root: &Field) -> DhtResult<Query> {
+    let args = root
+        .get("a")
+        .ok_or(DhtError::InvalidMessage("missing arguments"))?;
+    let id = node_id(args, "id")?;
+
+    match root.get("q").and_then(Field::as_bytes) {
+        Some(b"ping") => Ok(Query::Ping { id }),
+        Some(b"find_node") => Ok(Query::FindNode {
+            id,
+            target: node_id(args, "target")?,
+        }),
+        Some(b"get_peers") => Ok(Query::GetPeers {
+            id,
+            info_hash: node_id(args, "info_hash")?,
+        }),
+        Some(b"announce_peer") => Ok(Query::AnnouncePeer {
+            id,
+            info_hash: node_id(args, "info_hash")?,
+            port: args
+                .get("port")
+                .and_then(Field::as_integer)
+                .and_then(|port| u16::try_from(port).ok())
+                .ok_or(DhtError::InvalidMessage("port"))?,
+            token: args
+                .get("token")
+                .and_then(Field::as_bytes)
+                .ok_or(DhtError::InvalidMessage("token"))?
+                .to_vec(),
+            implied_port: args
+                .get("implied_port")
+                .and_then(Field::as_integer)
+                .is_some_and(|implied| implied != 0),
+        }),
+        _ => Err(DhtError::UnknownMethod),
+    }
+}
+
+fn decode_response(values: &Field) -> Response {
+    Response {
+        id: values
+            .get("id")
+            .and_then(Field::as_bytes)
+            .and_then(NodeId::from_bytes),
+        nodes: values
+            .get("nodes")
+            .and_then(Field::as_bytes)
+            .map(NodeInfo::from_compact)
+            .unwrap_or_default(),
+        values: values
+            .get("values")
+            .and_then(Field::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Field::as_bytes)
+            .filter(|peer| peer.len() == 6)
+            .map(|peer| {
+                SocketAddrV4::new(
+                    Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
+                    u16::from_be_bytes([peer[4], peer[5]]),
+                )
+            })
+            .collect(),
+        token: values
+            .get("token")
+            .and_then(Field::as_bytes)
+            .map(<[u8]>::to_vec),
+    }
+}
+
+/// Whether `bytes` looks like a KRPC message, for sockets shared with other
+/// UDP protocols.
+pub fn is_krpc(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&b'd') && bytes.last() == Some(&b'e')
+}
@@ -0,0 +1,552 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use error::{DhtError, DhtResult};
+use krpc::{Message, MessageBody, Query, Response, ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL};
+use routing::{to_v4, NodeId, NodeInfo, RoutingTable, K};
+use token::TokenSecrets;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    spawn,
+    sync::oneshot,
+    task::{JoinHandle, JoinSet},
+    time::{interval, timeout},
+};
+use tracing::debug;
+
+pub mod error;
+pub mod krpc;
+pub mod routing;
+mod token;
+
+// number of queries in flight during a lookup
+const ALPHA: usize = 3;
+// announced peers are forgotten after this long
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+// bound on the peers stored per info hash
+const MAX_PEERS_PER_TORRENT: usize = 1000;
+
+pub struct DhtConfig {
+    pub bind_addr: SocketAddr,
+    /// `host:port` of nodes used to join the network.
+    pub bootstrap_nodes: Vec<String>,
+    /// The routing table is loaded from and saved to this file.
+    pub routing_table_path: Option<PathBuf>,
+    pub query_timeout: Duration,
+    /// How often stale buckets are refreshed and the routing table is saved.
+    pub maintenance_interval: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            bootstrap_nodes: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+            routing_table_path: None,
+            query_timeout: Duration::from_secs(5),
+            maintenance_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+// a query waiting for its reply
+struct PendingQuery {
+    // only the node queried may answer
+    addr: SocketAddr,
+    reply: oneshot::Sender<DhtResult<Response>>,
+}
+
+struct DhtState {
+    socket: Arc<UdpSocket>,
+    routing: Mutex<RoutingTable>,
+    peers: Mutex<HashMap<NodeId, Vec<(SocketAddrV4, Instant)>>>,
+    tokens: Mutex<TokenSecrets>,
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction_id: AtomicU16,
+    query_timeout: Duration,
+}
+
+/// A mainline DHT (BEP 5) node.
+///
+/// All tasks are stopped when the node is dropped.
+pub struct DhtNode {
+    state: Arc<DhtState>,
+    bootstrap_nodes: Vec<String>,
+    routing_table_path: Option<PathBuf>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+impl DhtNode {
+    /// Binds the socket, loads the routing table if one was saved and starts
+    /// answering queries. Call [`DhtNode::bootstrap`] to join the network.
+    pub async fn start(config: DhtConfig) -> DhtResult<Self> {
+        let socket = Arc::new(UdpSocket::bind(config.bind_addr).await?);
+        let mut node = DhtNode::with_socket(Arc::clone(&socket), config).await?;
+
+        let state = Arc::clone(&node.state);
+        node.task_handles.push(spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                match socket.recv_from(&mut buf).await {
+                    Ok((len, from)) => state.handle_packet(&buf[..len], from).await,
+                    Err(e) => debug!("dht receive failed: {}", e),
+                }
+            }
+        }));
+        Ok(node)
+    }
+
+    /// Creates a node on a socket that is read elsewhere; received KRPC
+    /// packets have to be passed to [`DhtNode::handle_packet`].
+    pub async fn with_socket(socket: Arc<UdpSocket>, config: DhtConfig) -> DhtResult<Self> {
+        let routing = match &config.routing_table_path {
+            Some(path) if path.exists() => RoutingTable::decode(tokio::fs::read(path).await?)
+                .unwrap_or_else(|e| {
+                    debug!("discarding routing table {}: {}", path.display(), e);
+                    RoutingTable::new(NodeId::random())
+                }),
+            _ => RoutingTable::new(NodeId::random()),
+        };
+
+        let state = Arc::new(DhtState {
+            socket,
+            routing: Mutex::new(routing),
+            peers: Default::default(),
+            tokens: Default::default(),
+            pending: Default::default(),
+            next_transaction_id: AtomicU16::new(rand::random()),
+            query_timeout: config.query_timeout,
+        });
+
+        let maintenance_state = Arc::clone(&state);
+        let path = config.routing_table_path.clone();
+        let maintenance = spawn(async move {
+            let mut ticker = interval(config.maintenance_interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                maintenance_state.maintain().await;
+                if let Some(path) = &path {
+                    if let Err(e) = maintenance_state.save(path).await {
+                        debug!("saving routing table failed: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(DhtNode {
+            state,
+            bootstrap_nodes: config.bootstrap_nodes,
+            routing_table_path: config.routing_table_path,
+            task_handles: vec![maintenance],
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.state.routing.lock().unwrap().id()
+    }
+
+    pub fn local_addr(&self) -> DhtResult<SocketAddr> {
+        Ok(self.state.socket.local_addr()?)
+    }
+
+    pub fn routing_table_len(&self) -> usize {
+        self.state.routing.lock().unwrap().len()
+    }
+
+    pub async fn handle_packet(&self, packet: &[u8], from: SocketAddr) {
+        self.state.handle_packet(packet, from).await;
+    }
+
+    /// Joins the network through the configured bootstrap nodes and then
+    /// looks up our own id to fill the routing table.
+    pub async fn bootstrap(&self) -> DhtResult<usize> {
+        let mut queries = JoinSet::new();
+        for host in &self.bootstrap_nodes {
+            let host = host.clone();
+            let state = Arc::clone(&self.state);
+            queries.spawn(async move {
+                let addrs = lookup_host(host.as_str()).await?;
+                for addr in addrs.filter(SocketAddr::is_ipv4) {
+                    state.ping(addr).await?;
+                }
+                Ok::<_, DhtError>(())
+            });
+        }
+        while let Some(result) = queries.join_next().await {
+            if let Ok(Err(e)) = result {
+                debug!("bootstrap node failed: {}", e);
+            }
+        }
+
+        let id = self.id();
+        self.state.lookup(id, false).await;
+        Ok(self.routing_table_len())
+    }
+
+    /// Adds a node such as one listed under `nodes` in a .torrent file. The
+    /// node is pinged and only kept if it answers.
+    pub async fn add_node(&self, host: &str, port: u16) -> DhtResult<NodeId> {
+        let addr = lookup_host((host, port))
+            .await?
+            .find(SocketAddr::is_ipv4)
+            .ok_or(DhtError::InvalidMessage("no IPv4 address for node"))?;
+        self.state.ping(addr).await
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> DhtResult<NodeId> {
+        // the routing table only holds IPv4 nodes
+        if !addr.is_ipv4() {
+            return Err(DhtError::UnsupportedAddress(addr));
+        }
+        self.state.ping(addr).await
+    }
+
+    /// The `K` nodes closest to `target` found by an iterative lookup.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        self.state
+            .lookup(target, false)
+            .await
+            .0
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect()
+    }
+
+    pub async fn get_peers(&self, info_hash: &[u8]) -> DhtResult<Vec<SocketAddr>> {
+        let info_hash =
+            NodeId::from_bytes(info_hash).ok_or(DhtError::InvalidMessage("info hash"))?;
+        let (_, peers) = self.state.lookup(info_hash, true).await;
+        Ok(peers.into_iter().map(SocketAddr::V4).collect())
+    }
+
+    /// Looks up peers for `info_hash` and announces ourselves to the closest
+    /// nodes. With no `port` the nodes use the source port of our packets.
+    pub async fn announce(
+        &self,
+        info_hash: &[u8],
+        port: Option<u16>,
+    ) -> DhtResult<Vec<SocketAddr>> {
+        let info_hash =
+            NodeId::from_bytes(info_hash).ok_or(DhtError::InvalidMessage("info hash"))?;
+        let (nodes, peers) = self.state.lookup(info_hash, true).await;
+
+        let id = self.id();
+        let mut queries = JoinSet::new();
+        for (node, token) in nodes {
+            let Some(token) = token else {
+                continue;
+            };
+            let state = Arc::clone(&self.state);
+            let query = Query::AnnouncePeer {
+                id,
+                info_hash,
+                port: port.unwrap_or_default(),
+                token,
+                implied_port: port.is_none(),
+            };
+            queries.spawn(async move { state.query(SocketAddr::V4(node.addr), query).await });
+        }
+        while queries.join_next().await.is_some() {}
+
+        Ok(peers.into_iter().map(SocketAddr::V4).collect())
+    }
+
+    pub async fn save_routing_table(&self) -> DhtResult<()> {
+        match &self.routing_table_path {
+            Some(path) => self.state.save(path).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        for handle in self.task_handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+impl DhtState {
+    fn own_id(&self) -> NodeId {
+        self.routing.lock().unwrap().id()
+    }
+
+    async fn send(&self, message: &Message, addr: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&message.encode(), addr).await {
+            debug!("dht send to {} failed: {}", addr, e);
+        }
+    }
+
+    async fn query(&self, addr: SocketAddr, query: Query) -> DhtResult<Response> {
+        let transaction_id = self
+            .next_transaction_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (reply, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction_id.clone(), PendingQuery { addr, reply });
+
+        let message = Message {
+            transaction_id: transaction_id.clone(),
+            body: MessageBody::Query(query),
+        };
+        self.send(&message, addr).await;
+
+        let response = timeout(self.query_timeout, rx).await;
+        self.pending.lock().unwrap().remove(&transaction_id);
+        match response {
+            Ok(Ok(Ok(response))) => {
+                if let (Some(id), Some(addr)) = (response.id, to_v4(addr)) {
+                    self.routing.lock().unwrap().insert(NodeInfo { id, addr });
+                }
+                Ok(response)
+            }
+            Ok(Ok(Err(e))) => Err(e),
+            _ => Err(DhtError::Timeout),
+        }
+    }
+
+    async fn ping(&self, addr: SocketAddr) -> DhtResult<NodeId> {
+        let id = self.own_id();
+        self.query(addr, Query::Ping { id })
+            .await?
+            .id
+            .ok_or(DhtError::InvalidMessage("missing id"))
+    }
+
+    /// Iterative Kademlia lookup. Returns the closest nodes that answered,
+    /// with the token they gave us, and for `get_peers` the peers found.
+    async fn lookup(
+        self: &Arc<Self>,
+        target: NodeId,
+        get_peers: bool,
+    ) -> (Vec<(NodeInfo, Option<Vec<u8>>)>, HashSet<SocketAddrV4>) {
+        let own_id = self.own_id();
+        let mut candidates = self.routing.lock().unwrap().closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut answered: Vec<(NodeInfo, Option<Vec<u8>>)> = Vec::new();
+        let mut peers = HashSet::new();
+
+        loop {
+            candidates.sort_by_key(|node| node.id.distance(&target));
+            candidates.dedup_by_key(|node| node.id);
+            // stop once the K closest candidates have all been queried
+            let batch = candidates
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for node in batch {
+                queried.insert(node.id);
+                let state = Arc::clone(self);
+                let query = if get_peers {
+                    Query::GetPeers {
+                        id: own_id,
+                        info_hash: target,
+                    }
+                } else {
+                    Query::FindNode { id: own_id, target }
+                };
+                queries.spawn(async move {
+                    let response = state.query(SocketAddr::V4(node.addr), query).await;
+                    if response.is_err() {
+                        state.routing.lock().unwrap().mark_failed(&node.id);
+                    }
+                    (node, response)
+                });
+            }
+
+            while let Some(result) = queries.join_next().await {
+                let Ok((node, Ok(response))) = result else {
+                    continue;
+                };
+                candidates.extend(response.nodes.iter().filter(|n| n.id != own_id));
+                peers.extend(response.values);
+                answered.push((node, response.token));
+            }
+            candidates
+                .retain(|node| !queried.contains(&node.id) || answered_contains(&answered, node));
+        }
+
+        answered.sort_by_key(|(node, _)| node.id.distance(&target));
+        answered.truncate(K);
+        (answered, peers)
+    }
+
+    async fn handle_packet(&self, packet: &[u8], from: SocketAddr) {
+        let message = match Message::decode(packet) {
+            Ok(message) => message,
+            Err(DhtError::UnknownMethod) => {
+                let error = Message {
+                    transaction_id: transaction_id_of(packet),
+                    body: MessageBody::Error(ERROR_METHOD_UNKNOWN, "Method Unknown".to_string()),
+                };
+                self.send(&error, from).await;
+                return;
+            }
+            Err(e) => {
+                debug!("invalid dht packet from {}: {}", from, e);
+                return;
+            }
+        };
+
+        match message.body {
+            MessageBody::Response(response) => {
+                if let Some(tx) = self.take_pending(&message.transaction_id, from) {
+                    let _ = tx.send(Ok(response));
+                }
+            }
+            MessageBody::Error(code, msg) => {
+                debug!("dht error from {}: {} {}", from, code, msg);
+                if let Some(tx) = self.take_pending(&message.transaction_id, from) {
+                    let _ = tx.send(Err(DhtError::RemoteError(code, msg)));
+                }
+            }
+            MessageBody::Query(query) => {
+                let body = match self.answer(query, from) {
+                    Ok(response) => MessageBody::Response(response),
+                    Err(msg) => MessageBody::Error(ERROR_PROTOCOL, msg.to_string()),
+                };
+                let reply = Message {
+                    transaction_id: message.transaction_id,
+                    body,
+                };
+                self.send(&reply, from).await;
+            }
+        }
+    }
+
+    // the query a reply is for, if it came from the node that was queried;
+    // replies from anyone else are ignored, so they cannot poison lookups
+    fn take_pending(
+        &self,
+        transaction_id: &[u8],
+        from: SocketAddr,
+    ) -> Option<oneshot::Sender<DhtResult<Response>>> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(transaction_id) {
+            Some(query) if query.addr == from => {
+                pending.remove(transaction_id).map(|query| query.reply)
+            }
+            Some(query) => {
+                debug!("dht reply for {} came from {}", query.addr, from);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn answer(&self, query: Query, from: SocketAddr) -> Result<Response, &'static str> {
+        let from = to_v4(from).ok_or("IPv4 only")?;
+        let mut routing = self.routing.lock().unwrap();
+        let own_id = routing.id();
+        routing.insert(NodeInfo {
+            id: query.id(),
+            addr: from,
+        });
+
+        let mut response = Response {
+            id: Some(own_id),
+            ..Default::default()
+        };
+        match query {
+            Query::Ping { .. } => {}
+            Query::FindNode { target, .. } => {
+                response.nodes = routing.closest(&target, K);
+            }
+            Query::GetPeers { info_hash, .. } => {
+                response.token = Some(self.tokens.lock().unwrap().token(*from.ip()));
+                let peers = self.peers.lock().unwrap();
+                match peers.get(&info_hash) {
+                    Some(stored) if !stored.is_empty() => {
+                        response.values = stored.iter().map(|(addr, _)| *addr).collect();
+                    }
+                    _ => response.nodes = routing.closest(&info_hash, K),
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+                ..
+            } => {
+                if !self.tokens.lock().unwrap().verify(*from.ip(), &token) {
+                    return Err("Bad Token");
+                }
+                let port = if implied_port { from.port() } else { port };
+                let addr = SocketAddrV4::new(*from.ip(), port);
+                let mut peers = self.peers.lock().unwrap();
+                let stored = peers.entry(info_hash).or_default();
+                stored.retain(|(peer, _)| *peer != addr);
+                if stored.len() >= MAX_PEERS_PER_TORRENT {
+                    stored.remove(0);
+                }
+                stored.push((addr, Instant::now()));
+            }
+        }
+        Ok(response)
+    }
+
+    /// Forgets expired peers and refreshes buckets with questionable nodes.
+    async fn maintain(self: &Arc<Self>) {
+        self.peers.lock().unwrap().retain(|_, stored| {
+            stored.retain(|(_, announced)| announced.elapsed() < PEER_LIFETIME);
+            !stored.is_empty()
+        });
+
+        let targets = {
+            let routing = self.routing.lock().unwrap();
+            routing
+                .stale_buckets()
+                .into_iter()
+                .map(|index| routing.random_id_in_bucket(index))
+                .collect::<Vec<_>>()
+        };
+        for target in targets {
+            self.lookup(target, false).await;
+        }
+    }
+
+    async fn save(&self, path: &PathBuf) -> DhtResult<()> {
+        let encoded = self.routing.lock().unwrap().encode();
+        tokio::fs::write(path, encoded).await?;
+        Ok(())
+    }
+}
+
+fn answered_contains(answered: &[(NodeInfo, Option<Vec<u8>>)], node: &NodeInfo) -> bool {
+    answered.iter().any(|(answered, _)| answered.id == node.id)
+}
+
+// best effort extraction so that unknown methods can still be answered
+fn transaction_id_of(packet: &[u8]) -> Vec<u8> {
+    torrent_parser::parse_bencode(packet.to_vec())
+        .ok()
+        .and_then(|root| root.get("t").and_then(|t| t.as_bytes()).map(<[u8]>::to_vec))
+        .unwrap_or_default()
+}
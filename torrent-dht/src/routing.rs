@@ -0,0 +1,276 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use torrent_parser::{field::dict, field::Field, parse_bencode};
+
+use crate::error::{DhtError, DhtResult};
+
+/// Maximum number of nodes per bucket.
+pub const K: usize = 8;
+const ID_BITS: usize = 160;
+// nodes that have not been heard from for this long are questionable
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+// nodes that failed to respond this many times in a row are bad
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        NodeId(rand::random())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(NodeId(bytes.try_into().ok()?))
+    }
+
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0u8; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        NodeId(distance)
+    }
+
+    fn leading_zeros(&self) -> usize {
+        let mut zeros = 0;
+        for byte in self.0 {
+            if byte == 0 {
+                zeros += 8;
+            } else {
+                zeros += byte.leading_zeros() as usize;
+                break;
+            }
+        }
+        zeros
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// A node id with its address, 26 bytes in compact node info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+impl NodeInfo {
+    pub fn to_compact(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.id.0);
+        buffer.extend_from_slice(&self.addr.ip().octets());
+        buffer.extend_from_slice(&self.addr.port().to_be_bytes());
+    }
+
+    pub fn from_compact(bytes: &[u8]) -> Vec<NodeInfo> {
+        bytes
+            .chunks_exact(26)
+            .map(|chunk| NodeInfo {
+                id: NodeId::from_bytes(&chunk[..20]).unwrap(),
+                addr: SocketAddrV4::new(
+                    Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]),
+                    u16::from_be_bytes([chunk[24], chunk[25]]),
+                ),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Entry {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    fn is_questionable(&self) -> bool {
+        self.last_seen.elapsed() > QUESTIONABLE_AFTER
+    }
+}
+
+/// Kademlia routing table with one bucket per bit of distance from our id.
+///
+/// Bucket `i` holds nodes whose distance to us has `i` leading zero bits, so
+/// the buckets close to us are the ones with the highest index.
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        RoutingTable {
+            id,
+            buckets: vec![Vec::new(); ID_BITS],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let zeros = self.id.distance(id).leading_zeros();
+        (zeros < ID_BITS).then_some(zeros)
+    }
+
+    /// Records that `node` is alive, inserting it if there is room.
+    ///
+    /// A full bucket makes room by evicting a bad node, or else the most
+    /// questionable one; if every node is good the new node is dropped.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return false;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.last_seen = Instant::now();
+            entry.failures = 0;
+            return true;
+        }
+
+        let entry = Entry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+
+        let evict = bucket.iter().position(Entry::is_bad).or_else(|| {
+            bucket
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| entry.is_questionable())
+                .min_by_key(|(_, entry)| entry.last_seen)
+                .map(|(i, _)| i)
+        });
+        match evict {
+            Some(i) => {
+                bucket[i] = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a query to `id` that went unanswered.
+    pub fn mark_failed(&mut self, id: &NodeId) {
+        let Some(index) = self.bucket_index(id) else {
+            return;
+        };
+        if let Some(entry) = self.buckets[index]
+            .iter_mut()
+            .find(|entry| entry.node.id == *id)
+        {
+            entry.failures += 1;
+        }
+    }
+
+    /// The `count` good nodes closest to `target`.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.is_bad())
+            .map(|entry| entry.node)
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Indices of buckets with a questionable node, which need a refresh.
+    pub fn stale_buckets(&self) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| bucket.iter().any(Entry::is_questionable))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// A random id that falls into bucket `index`.
+    pub fn random_id_in_bucket(&self, index: usize) -> NodeId {
+        let mut id = NodeId::random().0;
+        for bit in 0..=index.min(ID_BITS - 1) {
+            let (byte, mask) = (bit / 8, 0x80u8 >> (bit % 8));
+            let own = self.id.0[byte] & mask;
+            // share the first `index` bits with our id and differ in the next
+            let value = if bit == index { own ^ mask } else { own };
+            id[byte] = (id[byte] & !mask) | value;
+        }
+        NodeId(id)
+    }
+
+    /// Serializes our id and all known nodes as a bencoded dictionary.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut nodes = Vec::with_capacity(self.len() * 26);
+        for entry in self.buckets.iter().flatten() {
+            entry.node.to_compact(&mut nodes);
+        }
+        dict([
+            ("id", Field::String(self.id.0.to_vec())),
+            ("nodes", Field::String(nodes)),
+        ])
+        .encode()
+    }
+
+    pub fn decode(bytes: Vec<u8>) -> DhtResult<Self> {
+        let root = parse_bencode(bytes)?;
+        let id = root
+            .get("id")
+            .and_then(Field::as_bytes)
+            .and_then(NodeId::from_bytes)
+            .ok_or(DhtError::InvalidRoutingTable)?;
+        let nodes = root
+            .get("nodes")
+            .and_then(Field::as_bytes)
+            .ok_or(DhtError::InvalidRoutingTable)?;
+
+        let mut table = RoutingTable::new(id);
+        for node in NodeInfo::from_compact(nodes) {
+            table.insert(node);
+        }
+        Ok(table)
+    }
+}
+
+pub(crate) fn to_v4(addr: SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        SocketAddr::V4(addr) => Some(addr),
+        SocketAddr::V6(addr) => addr
+            .ip()
+            .to_ipv4_mapped()
+            .map(|ip| SocketAddrV4::new(ip, addr.port())),
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+const ROTATE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Issues and checks `get_peers` tokens.
+///
+/// A token is derived from the requester's IP and a secret that rotates every
+/// five minutes. Tokens made with the previous secret are still accepted, so
+/// a token stays valid for five to ten minutes.
+pub struct TokenSecrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl TokenSecrets {
+    pub fn new() -> Self {
+        TokenSecrets {
+            current: rand::random(),
+            previous: rand::random(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= ROTATE_AFTER {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+
+    pub fn token(&mut self, ip: Ipv4Addr) -> Vec<u8> {
+        self.rotate_if_due();
+        make_token(&self.current, ip)
+    }
+
+    pub fn verify(&mut self, ip: Ipv4Addr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+        token == make_token(&self.current, ip) || token == make_token(&self.previous, ip)
+    }
+}
+
+impl Default for TokenSecrets {
+    fn default() -> Self {
+        TokenSecrets::new()
+    }
+}
+
+fn make_token(secret: &[u8], ip: Ipv4Addr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(ip.octets());
+    hasher.finalize()[..8].to_vec()
+}
@@ -0,0 +1,189 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::net::UdpSocket;
+use torrent_dht::{
+    error::DhtError,
+    krpc::{Message, MessageBody, Query, ERROR_PROTOCOL},
+    routing::NodeId,
+    DhtConfig, DhtNode,
+};
+
+fn config(bootstrap: Option<SocketAddr>) -> DhtConfig {
+    DhtConfig {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        bootstrap_nodes: bootstrap.into_iter().map(|addr| addr.to_string()).collect(),
+        routing_table_path: None,
+        query_timeout: Duration::from_millis(500),
+        maintenance_interval: Duration::from_secs(3600),
+    }
+}
+
+#[tokio::test]
+async fn test_announce_and_get_peers() {
+    let router = DhtNode::start(config(None)).await.unwrap();
+    let router_addr = router.local_addr().unwrap();
+
+    let mut nodes = Vec::new();
+    for _ in 0..4 {
+        let node = DhtNode::start(config(Some(router_addr))).await.unwrap();
+        node.bootstrap().await.unwrap();
+        nodes.push(node);
+    }
+    assert!(router.routing_table_len() >= 4);
+
+    let info_hash = [0x42; 20];
+    let found = nodes[0].announce(&info_hash, Some(7000)).await.unwrap();
+    assert!(found.is_empty());
+
+    let found = nodes[3].get_peers(&info_hash).await.unwrap();
+    assert_eq!(found, vec!["127.0.0.1:7000".parse::<SocketAddr>().unwrap()]);
+
+    // implied_port announces the source port of the node instead
+    nodes[1].announce(&info_hash, None).await.unwrap();
+    let mut found = nodes[2].get_peers(&info_hash).await.unwrap();
+    found.sort();
+    assert_eq!(found.len(), 2);
+    assert!(found.contains(&nodes[1].local_addr().unwrap()));
+}
+
+#[tokio::test]
+async fn test_find_node() {
+    let router = DhtNode::start(config(None)).await.unwrap();
+    let router_addr = router.local_addr().unwrap();
+    let first = DhtNode::start(config(Some(router_addr))).await.unwrap();
+    first.bootstrap().await.unwrap();
+    let second = DhtNode::start(config(Some(router_addr))).await.unwrap();
+    second.bootstrap().await.unwrap();
+
+    let found = second.find_node(first.id()).await;
+    assert_eq!(found.first().map(|node| node.id), Some(first.id()));
+}
+
+#[tokio::test]
+async fn test_routing_table_persists() {
+    let path = std::env::temp_dir().join(format!("dht-{}.dat", rand_suffix()));
+    let router = DhtNode::start(config(None)).await.unwrap();
+
+    let node = DhtNode::start(DhtConfig {
+        routing_table_path: Some(path.clone()),
+        ..config(Some(router.local_addr().unwrap()))
+    })
+    .await
+    .unwrap();
+    node.bootstrap().await.unwrap();
+    node.save_routing_table().await.unwrap();
+    let id = node.id();
+    drop(node);
+
+    let restored = DhtNode::start(DhtConfig {
+        routing_table_path: Some(path.clone()),
+        ..config(None)
+    })
+    .await
+    .unwrap();
+    assert_eq!(restored.id(), id);
+    assert_eq!(restored.routing_table_len(), 1);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_rejects_bad_token() {
+    let node = DhtNode::start(config(None)).await.unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let query = Message {
+        transaction_id: b"aa".to_vec(),
+        body: MessageBody::Query(Query::AnnouncePeer {
+            id: NodeId::random(),
+            info_hash: NodeId([1; 20]),
+            port: 6881,
+            token: b"forged".to_vec(),
+            implied_port: false,
+        }),
+    };
+    socket
+        .send_to(&query.encode(), node.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 1024];
+    let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+    let reply = Message::decode(&buf[..len]).unwrap();
+    assert_eq!(reply.transaction_id, b"aa");
+    assert!(matches!(reply.body, MessageBody::Error(ERROR_PROTOCOL, _)));
+}
+
+#[tokio::test]
+async fn test_remote_error() {
+    let node = DhtNode::start(config(None)).await.unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote = socket.local_addr().unwrap();
+
+    let answer = tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        let query = Message::decode(&buf[..len]).unwrap();
+        let reply = Message {
+            transaction_id: query.transaction_id,
+            body: MessageBody::Error(ERROR_PROTOCOL, "go away".to_string()),
+        };
+        socket.send_to(&reply.encode(), from).await.unwrap();
+    });
+    let result = node.ping(remote).await;
+    answer.await.unwrap();
+    assert!(matches!(
+        result,
+        Err(DhtError::RemoteError(ERROR_PROTOCOL, msg)) if msg == "go away"
+    ));
+
+    assert!(matches!(
+        node.ping("[::1]:6881".parse().unwrap()).await,
+        Err(DhtError::UnsupportedAddress(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_replies_from_other_addresses_are_ignored() {
+    let node = DhtNode::start(config(None)).await.unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote = socket.local_addr().unwrap();
+
+    let answer = tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        let query = Message::decode(&buf[..len]).unwrap();
+        let reply = Message {
+            transaction_id: query.transaction_id,
+            body: MessageBody::Error(ERROR_PROTOCOL, "spoofed".to_string()),
+        };
+        // the right transaction id from the wrong host
+        spoofer.send_to(&reply.encode(), from).await.unwrap();
+    });
+    let result = node.ping(remote).await;
+    answer.await.unwrap();
+    assert!(matches!(result, Err(DhtError::Timeout)));
+}
+
+#[test]
+fn test_krpc_roundtrip() {
+    let message = Message {
+        transaction_id: vec![0, 1],
+        body: MessageBody::Query(Query::GetPeers {
+            id: NodeId([3; 20]),
+            info_hash: NodeId([4; 20]),
+        }),
+    };
+    assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+
+    let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    let decoded = Message::decode(ping).unwrap();
+    assert_eq!(decoded.encode(), ping);
+}
+
+fn rand_suffix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
@@ -1,14 +1,17 @@
-use std::{collections::HashMap, iter::Peekable, vec::IntoIter};
+use std::{collections::BTreeMap, iter::Peekable};
 
 use sha1::{Digest, Sha1};
 
 use crate::error::TorrentParserError;
 
-pub(crate) enum Field {
+/// A bencoded value. Dictionary keys are kept as raw bytes, sorted as
+/// required by the encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
     String(Vec<u8>),
     Integer(i64),
     List(Vec<Field>),
-    Dict(HashMap<String, Field>),
+    Dict(BTreeMap<Vec<u8>, Field>),
 }
 
 impl Field {
@@ -20,10 +23,108 @@ impl Field {
             Field::Dict(_) => "Dict".to_string(),
         }
     }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Field::String(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Field::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Field]> {
+        match self {
+            Field::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Field>> {
+        match self {
+            Field::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: &str) -> Option<&Field> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.encode_into(&mut buffer);
+        buffer
+    }
+
+    pub fn encode_into(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Field::String(bytes) => {
+                buffer.extend_from_slice(bytes.len().to_string().as_bytes());
+                buffer.push(b':');
+                buffer.extend_from_slice(bytes);
+            }
+            Field::Integer(integer) => {
+                buffer.push(b'i');
+                buffer.extend_from_slice(integer.to_string().as_bytes());
+                buffer.push(b'e');
+            }
+            Field::List(list) => {
+                buffer.push(b'l');
+                for field in list {
+                    field.encode_into(buffer);
+                }
+                buffer.push(b'e');
+            }
+            Field::Dict(dict) => {
+                // BTreeMap iterates in key order, as bencode requires
+                buffer.push(b'd');
+                for (key, value) in dict {
+                    Field::String(key.clone()).encode_into(buffer);
+                    value.encode_into(buffer);
+                }
+                buffer.push(b'e');
+            }
+        }
+    }
 }
 
-pub(crate) fn get_field_type(
-    buffer: &mut Peekable<IntoIter<u8>>,
+impl From<&str> for Field {
+    fn from(value: &str) -> Self {
+        Field::String(value.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Field {
+    fn from(value: Vec<u8>) -> Self {
+        Field::String(value)
+    }
+}
+
+impl From<i64> for Field {
+    fn from(value: i64) -> Self {
+        Field::Integer(value)
+    }
+}
+
+/// Builds a dictionary field from `(key, value)` pairs.
+pub fn dict<K: AsRef<[u8]>>(entries: impl IntoIterator<Item = (K, Field)>) -> Field {
+    Field::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_ref().to_vec(), value))
+            .collect(),
+    )
+}
+
+pub(crate) fn get_field_type<I: Iterator<Item = u8>>(
+    buffer: &mut Peekable<I>,
 ) -> Result<Option<Field>, TorrentParserError> {
     let specifier = buffer.next();
 
@@ -114,7 +215,7 @@ pub(crate) fn get_field_type(
                 Ok(Some(Field::List(list)))
             } else if c == b'd' {
                 // dictionary
-                let mut dict = HashMap::new();
+                let mut dict = BTreeMap::new();
                 loop {
                     let peek_next = buffer.peek().ok_or(TorrentParserError::InvalidStructure(
                         "Unexpected end for dict".to_string(),
@@ -126,7 +227,7 @@ pub(crate) fn get_field_type(
                     match get_field_type(buffer)? {
                         Some(field) => {
                             let key = match field {
-                                Field::String(key) => String::from_utf8(key)?,
+                                Field::String(key) => key,
                                 other => {
                                     return Err(TorrentParserError::FieldTypeError {
                                         expected: "String".to_string(),
@@ -156,56 +257,33 @@ pub(crate) fn get_field_type(
     }
 }
 
-pub(crate) fn extract_info_hash(bencoded: &[u8]) -> Result<Vec<u8>, TorrentParserError> {
-    let mut buffer = bencoded.iter().peekable();
-
-    loop {
-        match buffer.next() {
-            Some(c) => {
-                if *c == b'4' {
-                    let mut slice = Vec::new();
-                    slice.push(*c);
-                    for _ in 0..=4 {
-                        match buffer.next() {
-                            Some(c) => slice.push(*c),
-                            None => {
-                                return Err(TorrentParserError::InvalidStructure(
-                                    "Unexpected end for info".to_string(),
-                                ))
-                            }
-                        }
-                    }
-                    if slice == b"4:info" {
-                        break;
-                    }
-                }
-            }
-            None => {
-                return Err(TorrentParserError::InvalidStructure(
-                    "Unexpected end for info".to_string(),
-                ))
-            }
-        }
+/// Returns the top level `info` dictionary exactly as it appears in
+/// `bencoded`, which is what the info hash is computed over.
+pub(crate) fn extract_info_dict(bencoded: &[u8]) -> Result<Vec<u8>, TorrentParserError> {
+    if bencoded.first() != Some(&b'd') {
+        return Err(TorrentParserError::InvalidStructure(
+            "Expected dict at root".to_string(),
+        ));
     }
-    let mut info_text = Vec::new();
+    let mut buffer = bencoded[1..].iter().copied().peekable();
     loop {
-        match buffer.next() {
-            Some(c) => {
-                if *c == b'e' {
-                    break;
-                } else {
-                    info_text.push(*c);
-                }
-            }
-            None => {
-                return Err(TorrentParserError::InvalidStructure(
-                    "Unexpected end for info".to_string(),
-                ))
-            }
+        if matches!(buffer.peek(), None | Some(b'e')) {
+            return Err(TorrentParserError::MissingRequiredField("info".to_string()));
+        }
+        let key = get_field_type(&mut buffer)?;
+        let start = bencoded.len() - buffer.len();
+        get_field_type(&mut buffer)?.ok_or(TorrentParserError::InvalidStructure(
+            "Expected value for dictionary".to_string(),
+        ))?;
+        if key.as_ref().and_then(Field::as_bytes) == Some(b"info") {
+            return Ok(bencoded[start..bencoded.len() - buffer.len()].to_vec());
         }
     }
+}
+
+/// SHA1 of a bencoded info dictionary.
+pub fn info_hash(info: &[u8]) -> Vec<u8> {
     let mut hasher = Sha1::new();
-    hasher.update(info_text);
-    let result = hasher.finalize();
-    Ok(result.to_vec())
+    hasher.update(info);
+    hasher.finalize().to_vec()
 }
@@ -1,13 +1,49 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+};
+
 use error::TorrentParserError;
-use field::{extract_info_hash, get_field_type, Field};
-use model::{Info, TorrentMetadata, TrackerResponse, TrackerResponsePeer, TrackerSuccessResponse};
+use field::{dict, extract_info_dict, get_field_type, info_hash, Field};
+use model::{
+    Info, ScrapeFile, ScrapeResponse, TorrentMetadata, TrackerResponse, TrackerResponsePeer,
+    TrackerSuccessResponse,
+};
 
 pub mod error;
-mod field;
+pub mod field;
 pub mod model;
 
+/// Parses a single bencoded value.
+pub fn parse_bencode(bencoded: Vec<u8>) -> Result<Field, TorrentParserError> {
+    let mut iter = bencoded.into_iter().peekable();
+    get_field_type(&mut iter)?.ok_or(TorrentParserError::InvalidStructure(
+        "Expected field".to_string(),
+    ))
+}
+
+/// Parses the bencoded value at the start of `bencoded`, returning it along
+/// with the number of bytes it took. Anything after it is left alone.
+pub fn parse_bencode_prefix(bencoded: &[u8]) -> Result<(Field, usize), TorrentParserError> {
+    let mut iter = bencoded.iter().copied().peekable();
+    let field = get_field_type(&mut iter)?.ok_or(TorrentParserError::InvalidStructure(
+        "Expected field".to_string(),
+    ))?;
+    Ok((field, bencoded.len() - iter.len()))
+}
+
+/// Builds metadata from a bare info dictionary, as received from peers for
+/// magnet links. Everything outside the info dictionary is left empty.
+pub fn parse_info_dict(info: &[u8]) -> Result<TorrentMetadata, TorrentParserError> {
+    let mut bencoded = b"d4:info".to_vec();
+    bencoded.extend_from_slice(info);
+    bencoded.push(b'e');
+    parse_torrent_metadata(bencoded)
+}
+
 pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, TorrentParserError> {
-    let info_hash = extract_info_hash(&bencoded)?;
+    let info_bytes = extract_info_dict(&bencoded)?;
+    let info_hash = info_hash(&info_bytes);
 
     let mut iter = bencoded.into_iter().peekable();
     let parsed_structure = get_field_type(&mut iter)?.ok_or(
@@ -25,18 +61,45 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
         }
     };
 
-    // read announce
-    let announce = match dict.get("announce") {
-        Some(Field::String(announce)) => String::from_utf8(announce.clone())?,
-        _ => {
-            return Err(TorrentParserError::MissingRequiredField(
-                "announce".to_string(),
-            ))
+    // read optional announce, trackerless torrents only have nodes
+    let announce = match dict.get("announce".as_bytes()) {
+        Some(Field::String(announce)) => Some(String::from_utf8(announce.clone())?),
+        None => None,
+        Some(other) => {
+            return Err(TorrentParserError::FieldTypeError {
+                expected: "String".to_string(),
+                found: other.field_type(),
+            })
+        }
+    };
+
+    // read optional DHT nodes, a list of [host, port] pairs (BEP 5)
+    let nodes = match dict.get("nodes".as_bytes()) {
+        Some(Field::List(nodes)) => Some(
+            nodes
+                .iter()
+                .map(|node| match node.as_list() {
+                    Some([Field::String(host), Field::Integer(port)]) => {
+                        Ok((String::from_utf8(host.clone())?, *port))
+                    }
+                    _ => Err(TorrentParserError::FieldTypeError {
+                        expected: "List of host and port".to_string(),
+                        found: node.field_type(),
+                    }),
+                })
+                .collect::<Result<Vec<(String, i64)>, TorrentParserError>>()?,
+        ),
+        None => None,
+        Some(other) => {
+            return Err(TorrentParserError::FieldTypeError {
+                expected: "List".to_string(),
+                found: other.field_type(),
+            })
         }
     };
 
     // read optional announce-list
-    let announce_list = match dict.get("announce-list") {
+    let announce_list = match dict.get("announce-list".as_bytes()) {
         Some(Field::List(announce_list)) => {
             let mut announce_list = announce_list
                 .iter()
@@ -71,7 +134,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read optional comment
-    let comment = match dict.get("comment") {
+    let comment = match dict.get("comment".as_bytes()) {
         Some(Field::String(comment)) => Some(String::from_utf8(comment.clone())?),
         None => None,
         Some(other) => {
@@ -83,7 +146,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read optional created by
-    let created_by = match dict.get("created by") {
+    let created_by = match dict.get("created by".as_bytes()) {
         Some(Field::String(create_by)) => Some(String::from_utf8(create_by.clone())?),
         None => None,
         Some(other) => {
@@ -95,7 +158,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read optional creation date
-    let creation_date = match dict.get("creation date") {
+    let creation_date = match dict.get("creation date".as_bytes()) {
         Some(Field::Integer(creation_date)) => Some(*creation_date),
         None => None,
         Some(other) => {
@@ -107,7 +170,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read optional encoding
-    let encoding = match dict.get("encoding") {
+    let encoding = match dict.get("encoding".as_bytes()) {
         Some(Field::String(encoding)) => Some(String::from_utf8(encoding.clone())?),
         None => None,
         Some(other) => {
@@ -119,7 +182,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read info
-    let info = match dict.get("info") {
+    let info = match dict.get("info".as_bytes()) {
         Some(Field::Dict(info)) => info,
         None => return Err(TorrentParserError::MissingRequiredField("info".to_string())),
         Some(other) => {
@@ -131,7 +194,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read piece length
-    let piece_length = match info.get("piece length") {
+    let piece_length = match info.get("piece length".as_bytes()) {
         Some(Field::Integer(piece_length)) => *piece_length,
         None => {
             return Err(TorrentParserError::MissingRequiredField(
@@ -147,7 +210,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read pieces
-    let pieces = match info.get("pieces") {
+    let pieces = match info.get("pieces".as_bytes()) {
         Some(Field::String(pieces)) => pieces,
         None => {
             return Err(TorrentParserError::MissingRequiredField(
@@ -169,7 +232,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
         .collect::<Vec<Vec<u8>>>();
 
     // read optional private
-    let private = match info.get("private") {
+    let private = match info.get("private".as_bytes()) {
         Some(Field::Integer(private)) => Some(*private != 0),
         None => None,
         Some(other) => {
@@ -181,7 +244,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read name
-    let name = match info.get("name") {
+    let name = match info.get("name".as_bytes()) {
         Some(Field::String(name)) => String::from_utf8(name.clone())?,
         None => return Err(TorrentParserError::MissingRequiredField("name".to_string())),
         Some(other) => {
@@ -193,13 +256,13 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read optional files
-    let files = match info.get("files") {
+    let files = match info.get("files".as_bytes()) {
         Some(Field::List(files)) => {
             let files = files
                 .iter()
                 .map(|file| match file {
                     Field::Dict(file) => {
-                        let length = match file.get("length") {
+                        let length = match file.get("length".as_bytes()) {
                             Some(Field::Integer(length)) => *length,
                             None => {
                                 return Err(TorrentParserError::MissingRequiredField(
@@ -214,7 +277,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
                             }
                         };
 
-                        let md5sum = match file.get("md5sum") {
+                        let md5sum = match file.get("md5sum".as_bytes()) {
                             Some(Field::String(md5sum)) => Some(String::from_utf8(md5sum.clone())?),
                             None => None,
                             Some(other) => {
@@ -225,7 +288,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
                             }
                         };
 
-                        let path = match file.get("path") {
+                        let path = match file.get("path".as_bytes()) {
                             Some(Field::List(path)) => path
                                 .iter()
                                 .map(|field| match field {
@@ -273,7 +336,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read optional length
-    let length = match info.get("length") {
+    let length = match info.get("length".as_bytes()) {
         Some(Field::Integer(length)) => Some(*length),
         None => None,
         Some(other) => {
@@ -285,7 +348,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     };
 
     // read optional md5sum
-    let md5sum = match info.get("md5sum") {
+    let md5sum = match info.get("md5sum".as_bytes()) {
         Some(Field::String(md5sum)) => Some(String::from_utf8(md5sum.clone())?),
         None => None,
         Some(other) => {
@@ -299,6 +362,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
     Ok(TorrentMetadata {
         announce,
         announce_list,
+        nodes,
         comment,
         created_by,
         creation_date,
@@ -313,6 +377,7 @@ pub fn parse_torrent_metadata(bencoded: Vec<u8>) -> Result<TorrentMetadata, Torr
             md5sum,
         },
         info_hash,
+        info_bytes,
     })
 }
 
@@ -337,7 +402,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         }
     };
 
-    let failure_reason = match root_field.get("failure reason") {
+    let failure_reason = match root_field.get("failure reason".as_bytes()) {
         Some(Field::String(failure_reason)) => Some(String::from_utf8(failure_reason.clone())?),
         None => None,
         Some(other) => {
@@ -352,7 +417,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         return Ok(TrackerResponse::Failure(msg));
     }
 
-    let warning_message = match root_field.get("warning message") {
+    let warning_message = match root_field.get("warning message".as_bytes()) {
         Some(Field::String(warning_message)) => Some(String::from_utf8(warning_message.clone())?),
         None => None,
         Some(other) => {
@@ -367,7 +432,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         return Ok(TrackerResponse::Warning(msg));
     }
 
-    let interval = match root_field.get("interval") {
+    let interval = match root_field.get("interval".as_bytes()) {
         Some(Field::Integer(interval)) => *interval,
         None => {
             return Err(TorrentParserError::MissingRequiredField(
@@ -382,7 +447,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         }
     };
 
-    let min_interval = match root_field.get("min interval") {
+    let min_interval = match root_field.get("min interval".as_bytes()) {
         Some(Field::Integer(min_interval)) => Some(*min_interval),
         None => None,
         Some(other) => {
@@ -393,7 +458,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         }
     };
 
-    let tracker_id = match root_field.get("tracker id") {
+    let tracker_id = match root_field.get("tracker id".as_bytes()) {
         Some(Field::String(tracker_id)) => Some(String::from_utf8(tracker_id.clone())?),
         None => None,
         Some(other) => {
@@ -404,7 +469,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         }
     };
 
-    let complete = match root_field.get("complete") {
+    let complete = match root_field.get("complete".as_bytes()) {
         Some(Field::Integer(complete)) => *complete,
         None => {
             return Err(TorrentParserError::MissingRequiredField(
@@ -419,7 +484,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         }
     };
 
-    let incomplete = match root_field.get("incomplete") {
+    let incomplete = match root_field.get("incomplete".as_bytes()) {
         Some(Field::Integer(incomplete)) => *incomplete,
         None => {
             return Err(TorrentParserError::MissingRequiredField(
@@ -434,13 +499,24 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         }
     };
 
-    let peers = match root_field.get("peers") {
+    let peers6 = match root_field.get("peers6".as_bytes()) {
+        Some(Field::String(peers6)) => Some(peers6),
+        None => None,
+        Some(other) => {
+            return Err(TorrentParserError::FieldTypeError {
+                expected: "String".to_string(),
+                found: other.field_type(),
+            })
+        }
+    };
+
+    let mut peers = match root_field.get("peers".as_bytes()) {
         Some(Field::List(peers)) => peers
             .iter()
             .map(|peer| match peer {
                 Field::Dict(peer) => {
-                    let peer_id = match peer.get("peer id") {
-                        Some(Field::String(peer_id)) => Some(String::from_utf8(peer_id.clone())?),
+                    let peer_id = match peer.get("peer id".as_bytes()) {
+                        Some(Field::String(peer_id)) => Some(peer_id.clone()),
                         None => None,
                         Some(other) => {
                             return Err(TorrentParserError::FieldTypeError {
@@ -450,7 +526,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
                         }
                     };
 
-                    let ip = match peer.get("ip") {
+                    let ip = match peer.get("ip".as_bytes()) {
                         Some(Field::String(ip)) => String::from_utf8(ip.clone())?,
                         None => {
                             return Err(TorrentParserError::MissingRequiredField("ip".to_string()))
@@ -463,7 +539,7 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
                         }
                     };
 
-                    let port = match peer.get("port") {
+                    let port = match peer.get("port".as_bytes()) {
                         Some(Field::Integer(port)) => *port,
                         None => {
                             return Err(TorrentParserError::MissingRequiredField(
@@ -517,6 +593,8 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
                 found: other.field_type(),
             })
         }
+        // an IPv6-only tracker may only send peers6
+        None if peers6.is_some() => Vec::new(),
         None => {
             return Err(TorrentParserError::MissingRequiredField(
                 "peers".to_string(),
@@ -524,6 +602,24 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
         }
     };
 
+    // compact IPv6 peers (BEP 7), 16 bytes of address and 2 bytes of port
+    if let Some(peers6) = peers6 {
+        if peers6.len() % 18 != 0 {
+            return Err(TorrentParserError::InvalidStructure(
+                "Invalid length for peers6 string".to_string(),
+            ));
+        }
+        peers.extend(peers6.chunks(18).map(|peer_str| {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&peer_str[..16]).unwrap());
+            let port = u16::from_be_bytes([peer_str[16], peer_str[17]]);
+            TrackerResponsePeer {
+                peer_id: None,
+                ip: ip.to_string(),
+                port: port as i64,
+            }
+        }));
+    }
+
     let resp = TrackerSuccessResponse {
         interval,
         min_interval,
@@ -535,3 +631,161 @@ pub fn parse_tracker_response(bencoded: Vec<u8>) -> Result<TrackerResponse, Torr
 
     Ok(TrackerResponse::Success(resp))
 }
+
+pub fn parse_scrape_response(bencoded: Vec<u8>) -> Result<ScrapeResponse, TorrentParserError> {
+    let mut bencoded = bencoded.into_iter().peekable();
+    let parsed_structure = get_field_type(&mut bencoded)?.ok_or(
+        TorrentParserError::InvalidStructure("Expected field".to_string()),
+    )?;
+
+    let root_field = match parsed_structure {
+        Field::Dict(dict) => dict,
+        other => {
+            return Err(TorrentParserError::FieldTypeError {
+                expected: "Dict".to_string(),
+                found: other.field_type(),
+            })
+        }
+    };
+
+    if let Some(Field::String(failure_reason)) = root_field.get("failure reason".as_bytes()) {
+        return Ok(ScrapeResponse::Failure(String::from_utf8(
+            failure_reason.clone(),
+        )?));
+    }
+
+    let files = match root_field.get("files".as_bytes()) {
+        Some(Field::Dict(files)) => files,
+        None => {
+            return Err(TorrentParserError::MissingRequiredField(
+                "files".to_string(),
+            ))
+        }
+        Some(other) => {
+            return Err(TorrentParserError::FieldTypeError {
+                expected: "Dict".to_string(),
+                found: other.field_type(),
+            })
+        }
+    };
+
+    // keys of the files dict are raw 20-byte info hashes
+    let files = files
+        .iter()
+        .map(|(info_hash, file)| {
+            let file = match file {
+                Field::Dict(file) => file,
+                other => {
+                    return Err(TorrentParserError::FieldTypeError {
+                        expected: "Dict".to_string(),
+                        found: other.field_type(),
+                    })
+                }
+            };
+            let get_integer = |key: &str| match file.get(key.as_bytes()) {
+                Some(Field::Integer(value)) => Ok(*value),
+                None => Err(TorrentParserError::MissingRequiredField(key.to_string())),
+                Some(other) => Err(TorrentParserError::FieldTypeError {
+                    expected: "Integer".to_string(),
+                    found: other.field_type(),
+                }),
+            };
+            Ok((
+                info_hash.clone(),
+                ScrapeFile {
+                    complete: get_integer("complete")?,
+                    downloaded: get_integer("downloaded")?,
+                    incomplete: get_integer("incomplete")?,
+                },
+            ))
+        })
+        .collect::<Result<HashMap<Vec<u8>, ScrapeFile>, TorrentParserError>>()?;
+
+    Ok(ScrapeResponse::Success(files))
+}
+
+/// Encodes a tracker response. With `compact`, IPv4 peers are sent in the
+/// compact `peers` string and IPv6 peers in `peers6` (BEP 7); otherwise a
+/// list of peer dictionaries is sent.
+pub fn encode_tracker_response(resp: &TrackerResponse, compact: bool) -> Vec<u8> {
+    let resp = match resp {
+        TrackerResponse::Failure(msg) => {
+            return dict([("failure reason", Field::from(msg.as_str()))]).encode()
+        }
+        TrackerResponse::Warning(msg) => {
+            return dict([("warning message", Field::from(msg.as_str()))]).encode()
+        }
+        TrackerResponse::Success(resp) => resp,
+    };
+
+    let mut entries = vec![
+        ("interval", Field::Integer(resp.interval)),
+        ("complete", Field::Integer(resp.complete)),
+        ("incomplete", Field::Integer(resp.incomplete)),
+    ];
+    if let Some(min_interval) = resp.min_interval {
+        entries.push(("min interval", Field::Integer(min_interval)));
+    }
+    if let Some(tracker_id) = &resp.tracker_id {
+        entries.push(("tracker id", Field::from(tracker_id.as_str())));
+    }
+
+    if compact {
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        for peer in &resp.peers {
+            match peer.ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => {
+                    peers.extend_from_slice(&ip.octets());
+                    peers.extend_from_slice(&(peer.port as u16).to_be_bytes());
+                }
+                Ok(IpAddr::V6(ip)) => {
+                    peers6.extend_from_slice(&ip.octets());
+                    peers6.extend_from_slice(&(peer.port as u16).to_be_bytes());
+                }
+                // hostnames cannot be sent in compact form
+                Err(_) => {}
+            }
+        }
+        entries.push(("peers", Field::String(peers)));
+        if !peers6.is_empty() {
+            entries.push(("peers6", Field::String(peers6)));
+        }
+    } else {
+        let peers = resp
+            .peers
+            .iter()
+            .map(|peer| {
+                let mut peer_entries = vec![
+                    ("ip", Field::from(peer.ip.as_str())),
+                    ("port", Field::Integer(peer.port)),
+                ];
+                if let Some(peer_id) = &peer.peer_id {
+                    peer_entries.push(("peer id", Field::from(peer_id.clone())));
+                }
+                dict(peer_entries)
+            })
+            .collect();
+        entries.push(("peers", Field::List(peers)));
+    }
+
+    dict(entries).encode()
+}
+
+pub fn encode_scrape_response(resp: &ScrapeResponse) -> Vec<u8> {
+    match resp {
+        ScrapeResponse::Failure(msg) => dict([("failure reason", Field::from(msg.as_str()))]),
+        ScrapeResponse::Success(files) => {
+            let files = files.iter().map(|(info_hash, file)| {
+                let file = dict([
+                    ("complete", Field::Integer(file.complete)),
+                    ("downloaded", Field::Integer(file.downloaded)),
+                    ("incomplete", Field::Integer(file.incomplete)),
+                ]);
+                (info_hash, file)
+            });
+            dict([("files", dict(files))])
+        }
+    }
+    .encode()
+}
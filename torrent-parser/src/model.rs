@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 pub struct InfoFile {
     pub length: i64,
     pub md5sum: Option<String>,
@@ -15,14 +17,18 @@ pub struct Info {
 }
 
 pub struct TorrentMetadata {
-    pub announce: String,
+    pub announce: Option<String>,
     pub announce_list: Option<Vec<Vec<String>>>,
+    pub nodes: Option<Vec<(String, i64)>>,
     pub comment: Option<String>,
     pub created_by: Option<String>,
     pub creation_date: Option<i64>,
     pub encoding: Option<String>,
     pub info: Info,
     pub info_hash: Vec<u8>,
+    /// The bencoded info dictionary as it appeared in the file, the info hash
+    /// is computed over these bytes.
+    pub info_bytes: Vec<u8>,
 }
 
 impl TorrentMetadata {
@@ -32,7 +38,8 @@ impl TorrentMetadata {
 }
 
 pub struct TrackerResponsePeer {
-    pub peer_id: Option<String>,
+    /// Raw bytes, peer ids are not UTF-8.
+    pub peer_id: Option<Vec<u8>>,
     pub ip: String,
     pub port: i64,
 }
@@ -51,3 +58,14 @@ pub enum TrackerResponse {
     Warning(String),
     Success(TrackerSuccessResponse),
 }
+
+pub struct ScrapeFile {
+    pub complete: i64,
+    pub downloaded: i64,
+    pub incomplete: i64,
+}
+
+pub enum ScrapeResponse {
+    Failure(String),
+    Success(HashMap<Vec<u8>, ScrapeFile>),
+}
@@ -13,3 +13,57 @@ fn test_info_hash() {
 
     dbg!(info_hash);
 }
+
+#[test]
+fn test_parse_trackerless_nodes() {
+    let bencoded = b"d5:nodesll9:127.0.0.1i6881eel7:dht.exai51413eee\
+        4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let metadata = torrent_parser::parse_torrent_metadata(bencoded.to_vec()).unwrap();
+    assert!(metadata.announce.is_none());
+    assert_eq!(
+        metadata.nodes,
+        Some(vec![
+            ("127.0.0.1".to_string(), 6881),
+            ("dht.exa".to_string(), 51413)
+        ])
+    );
+}
+
+#[test]
+fn test_info_bytes_span_whole_dict() {
+    let info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let mut bencoded = b"d8:announce3:url4:info".to_vec();
+    bencoded.extend_from_slice(info);
+    bencoded.extend_from_slice(b"7:comment1:xe");
+    let metadata = torrent_parser::parse_torrent_metadata(bencoded).unwrap();
+    assert_eq!(metadata.info_bytes, info);
+    assert_eq!(metadata.info_hash, torrent_parser::field::info_hash(info));
+
+    let from_info = torrent_parser::parse_info_dict(info).unwrap();
+    assert_eq!(from_info.info_hash, metadata.info_hash);
+    assert_eq!(from_info.info.name, "a");
+}
+
+#[test]
+fn test_parse_bencode_prefix() {
+    let (field, len) = torrent_parser::parse_bencode_prefix(b"d1:ai1eeraw").unwrap();
+    assert_eq!(field.get("a").and_then(|a| a.as_integer()), Some(1));
+    assert_eq!(len, 8);
+}
+
+#[test]
+fn test_binary_peer_id() {
+    let mut bencoded =
+        b"d8:completei1e10:incompletei0e8:intervali60e5:peersld2:ip8:10.0.0.17:peer id20:-qB4620-"
+            .to_vec();
+    bencoded.extend([0xff; 12]);
+    bencoded.extend(b"4:porti6881eeee");
+    let torrent_parser::model::TrackerResponse::Success(response) =
+        torrent_parser::parse_tracker_response(bencoded).unwrap()
+    else {
+        panic!("not a success response");
+    };
+    let peer_id = response.peers[0].peer_id.as_ref().unwrap();
+    assert_eq!(&peer_id[..8], b"-qB4620-");
+    assert_eq!(peer_id.len(), 20);
+}
@@ -0,0 +1,158 @@
+use crate::error::{PwpError, PwpResult};
+
+/// Which pieces of a torrent are present, one bit per piece with the highest
+/// bit of the first byte being piece 0, as on the wire.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+    // number of set bits, kept up to date so counting is free
+    ones: usize,
+}
+
+impl Bitfield {
+    /// An empty bitfield for `len` pieces.
+    pub fn new(len: usize) -> Self {
+        Bitfield {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+            ones: 0,
+        }
+    }
+
+    /// A bitfield with all `len` pieces set.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Bitfield {
+            bytes: vec![0xff; len.div_ceil(8)],
+            len,
+            ones: len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// Parses a bitfield as received from a peer, which must be exactly as
+    /// long as needed for `len` pieces and have no spare bits set.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> PwpResult<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(PwpError::InvalidBitfield);
+        }
+        let bitfield = Bitfield {
+            bytes: bytes.to_vec(),
+            len,
+            ones: bytes.iter().map(|byte| byte.count_ones() as usize).sum(),
+        };
+        if bytes
+            .last()
+            .is_some_and(|last| last & bitfield.spare_mask() != 0)
+        {
+            return Err(PwpError::InvalidBitfield);
+        }
+        Ok(bitfield)
+    }
+
+    /// The wire representation.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // bits of the last byte that do not belong to a piece
+    fn spare_mask(&self) -> u8 {
+        let spare_bits = self.bytes.len() * 8 - self.len;
+        ((1u16 << spare_bits) - 1) as u8
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let mask = self.spare_mask();
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= !mask;
+        }
+    }
+
+    /// Number of pieces.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Sets or clears a piece, returning whether it was set before. Indices
+    /// out of range are ignored.
+    pub fn set(&mut self, index: usize, value: bool) -> bool {
+        if index >= self.len {
+            return false;
+        }
+        let mask = 0x80 >> (index % 8);
+        let byte = &mut self.bytes[index / 8];
+        let previous = *byte & mask != 0;
+        match (previous, value) {
+            (false, true) => {
+                *byte |= mask;
+                self.ones += 1;
+            }
+            (true, false) => {
+                *byte &= !mask;
+                self.ones -= 1;
+            }
+            _ => {}
+        }
+        previous
+    }
+
+    /// Number of pieces set.
+    pub fn count_ones(&self) -> usize {
+        self.ones
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.ones == self.len
+    }
+
+    /// Pieces set here that are missing in `ours`, i.e. what a peer with this
+    /// bitfield can give us.
+    pub fn interesting(&self, ours: &Bitfield) -> Bitfield {
+        let bytes = self
+            .bytes
+            .iter()
+            .enumerate()
+            .map(|(i, theirs)| theirs & !ours.bytes.get(i).copied().unwrap_or_default())
+            .collect::<Vec<_>>();
+        Bitfield {
+            ones: bytes.iter().map(|byte| byte.count_ones() as usize).sum(),
+            bytes,
+            len: self.len,
+        }
+    }
+
+    /// Whether `interesting` would return any piece, without building it.
+    pub fn has_interesting(&self, ours: &Bitfield) -> bool {
+        self.bytes
+            .iter()
+            .enumerate()
+            .any(|(i, theirs)| theirs & !ours.bytes.get(i).copied().unwrap_or_default() != 0)
+    }
+
+    /// Indices of the pieces that are set.
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bytes
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte != 0)
+            .flat_map(|(i, byte)| {
+                (0..8)
+                    .filter(move |bit| byte & (0x80 >> bit) != 0)
+                    .map(move |bit| i * 8 + bit)
+            })
+    }
+
+    /// Indices of the pieces that are not set.
+    pub fn iter_unset(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| !self.has(*index))
+    }
+}
@@ -0,0 +1,63 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PwpError {
+    #[error("IO Error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("Invalid Protocol")]
+    InvalidProtocol,
+
+    #[error("Info Hash Mismatch")]
+    InfoHashMismatch,
+
+    #[error("Unknown Info Hash")]
+    UnknownInfoHash,
+
+    #[error("Connected To Self")]
+    SelfConnection,
+
+    #[error("Duplicate Connection")]
+    DuplicateConnection,
+
+    #[error("Timed Out")]
+    Timeout,
+
+    #[error("Connection Limit Reached")]
+    ConnectionLimit,
+
+    #[error("Torrent Connection Limit Reached")]
+    TorrentConnectionLimit,
+
+    #[error("Already Connected: {0}")]
+    AlreadyConnected(std::net::SocketAddr),
+
+    #[error("Peer Blocked: {0}")]
+    PeerBlocked(std::net::SocketAddr),
+
+    #[error("Invalid Bitfield")]
+    InvalidBitfield,
+
+    #[error("Invalid Extension Message: {0}")]
+    InvalidExtensionMessage(String),
+
+    #[error("Message Too Large: {0} bytes")]
+    MessageTooLarge(usize),
+
+    #[error("Invalid Length {1} For Message {0}")]
+    InvalidMessageLength(u8, usize),
+
+    #[error("Unknown Message: {0}")]
+    UnknownMessage(u8),
+
+    #[error("Encryption Required")]
+    EncryptionRequired,
+
+    #[error("Encryption Negotiation Failed: {0}")]
+    EncryptionNegotiation(String),
+
+    #[error("Invalid uTP Packet: {0}")]
+    InvalidUtpPacket(String),
+}
+
+pub type PwpResult<T> = Result<T, PwpError>;
@@ -0,0 +1,245 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use bytes::Bytes;
+use torrent_parser::{
+    field::{dict, Field},
+    parse_bencode,
+};
+
+use crate::{
+    error::{PwpError, PwpResult},
+    message::Message,
+};
+
+/// Extended message id of the extended handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The BEP 10 extended handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// `m`: extension names and the ids the sender wants them sent with.
+    pub extensions: BTreeMap<String, u8>,
+    /// `v`: client name and version.
+    pub client: Option<String>,
+    /// `p`: the sender's listen port.
+    pub listen_port: Option<u16>,
+    /// `reqq`: how many outstanding requests the sender accepts.
+    pub reqq: Option<u32>,
+    /// `yourip`: the receiver's address as seen by the sender.
+    pub your_ip: Option<IpAddr>,
+    /// `metadata_size`: size of the info dictionary, for BEP 9.
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let m = dict(
+            self.extensions
+                .iter()
+                .map(|(name, id)| (name.as_str(), Field::Integer(*id as i64))),
+        );
+        let mut entries = vec![("m", m)];
+        if let Some(client) = &self.client {
+            entries.push(("v", Field::from(client.as_str())));
+        }
+        if let Some(port) = self.listen_port {
+            entries.push(("p", Field::Integer(port as i64)));
+        }
+        if let Some(reqq) = self.reqq {
+            entries.push(("reqq", Field::Integer(reqq as i64)));
+        }
+        if let Some(ip) = self.your_ip {
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            entries.push(("yourip", Field::String(bytes)));
+        }
+        if let Some(size) = self.metadata_size {
+            entries.push(("metadata_size", Field::Integer(size as i64)));
+        }
+        dict(entries).encode()
+    }
+
+    /// Decodes a handshake. Unknown keys are ignored and malformed optional
+    /// values are treated as missing.
+    pub fn decode(payload: &[u8]) -> PwpResult<ExtendedHandshake> {
+        let root = parse_bencode(payload.to_vec())
+            .map_err(|e| PwpError::InvalidExtensionMessage(e.to_string()))?;
+        let m = root
+            .get("m")
+            .and_then(Field::as_dict)
+            .ok_or_else(|| PwpError::InvalidExtensionMessage("missing m".to_string()))?;
+        let integer = |key: &str| root.get(key).and_then(Field::as_integer);
+
+        Ok(ExtendedHandshake {
+            extensions: m
+                .iter()
+                .filter_map(|(name, id)| {
+                    let name = String::from_utf8(name.clone()).ok()?;
+                    let id = u8::try_from(id.as_integer()?).ok()?;
+                    Some((name, id))
+                })
+                .collect(),
+            client: root
+                .get("v")
+                .and_then(Field::as_bytes)
+                .map(|v| String::from_utf8_lossy(v).to_string()),
+            listen_port: integer("p").and_then(|p| u16::try_from(p).ok()),
+            reqq: integer("reqq").and_then(|reqq| u32::try_from(reqq).ok()),
+            your_ip: root
+                .get("yourip")
+                .and_then(Field::as_bytes)
+                .and_then(|ip| match ip.len() {
+                    4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?))),
+                    16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?))),
+                    _ => None,
+                }),
+            metadata_size: integer("metadata_size").and_then(|size| u64::try_from(size).ok()),
+        })
+    }
+}
+
+/// A protocol extension running on top of BEP 10, such as `ut_pex`.
+///
+/// An instance lives as long as one connection. Payloads are the bytes after
+/// the extended message id; the registry takes care of the ids.
+pub trait Extension: Send {
+    /// The name in the `m` dictionary.
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific keys to our handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called with every handshake the remote sends.
+    fn on_handshake(&mut self, _remote: &ExtendedHandshake) {}
+
+    /// Handles a message for this extension, returning payloads to send back.
+    fn on_message(&mut self, payload: &[u8]) -> PwpResult<Vec<Vec<u8>>>;
+
+    /// Payloads the extension wants to send on its own, polled regularly.
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+/// What came out of an extended message.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExtensionOutput {
+    /// Set if the message was the remote's handshake.
+    pub handshake: Option<ExtendedHandshake>,
+    /// Replies to send to the remote.
+    pub messages: Vec<Message>,
+}
+
+/// The extensions of one connection. Our ids are assigned in registration
+/// order starting at 1; the remote's ids come from its handshake.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    remote: BTreeMap<String, u8>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers an extension and returns the id we advertise for it.
+    pub fn register(&mut self, extension: Box<dyn Extension>) -> u8 {
+        assert!(
+            self.extensions.len() < u8::MAX as usize,
+            "too many extensions"
+        );
+        self.extensions.push(extension);
+        self.extensions.len() as u8
+    }
+
+    /// Whether the remote advertised the extension.
+    pub fn remote_supports(&self, name: &str) -> bool {
+        self.remote.contains_key(name)
+    }
+
+    /// Our handshake: `base` with the `m` dictionary and whatever the
+    /// extensions add.
+    pub fn handshake(&self, mut base: ExtendedHandshake) -> ExtendedHandshake {
+        for (i, extension) in self.extensions.iter().enumerate() {
+            base.extensions
+                .insert(extension.name().to_string(), i as u8 + 1);
+            extension.extend_handshake(&mut base);
+        }
+        base
+    }
+
+    pub fn handshake_message(&self, base: ExtendedHandshake) -> Message {
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: Bytes::from(self.handshake(base).encode()),
+        }
+    }
+
+    /// Builds a message for the extension `name`, if the remote supports it.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        self.remote.get(name).map(|id| Message::Extended {
+            id: *id,
+            payload: Bytes::from(payload),
+        })
+    }
+
+    fn messages(&self, name: &str, payloads: Vec<Vec<u8>>) -> Vec<Message> {
+        payloads
+            .into_iter()
+            .filter_map(|payload| self.message(name, payload))
+            .collect()
+    }
+
+    /// Handles an extended message sent with our id `id`.
+    pub fn receive(&mut self, id: u8, payload: &[u8]) -> PwpResult<ExtensionOutput> {
+        if id == HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::decode(payload)?;
+            // later handshakes update the ids, 0 disables an extension
+            for (name, id) in &handshake.extensions {
+                match id {
+                    0 => self.remote.remove(name),
+                    id => self.remote.insert(name.clone(), *id),
+                };
+            }
+            for extension in &mut self.extensions {
+                extension.on_handshake(&handshake);
+            }
+            return Ok(ExtensionOutput {
+                handshake: Some(handshake),
+                messages: Vec::new(),
+            });
+        }
+
+        // the remote may still use an id from before we changed ours
+        let Some(extension) = self.extensions.get_mut(id as usize - 1) else {
+            return Ok(ExtensionOutput::default());
+        };
+        let name = extension.name();
+        let replies = extension.on_message(payload)?;
+        Ok(ExtensionOutput {
+            handshake: None,
+            messages: self.messages(name, replies),
+        })
+    }
+
+    /// Collects what the extensions want to send on their own. Extensions
+    /// the remote does not support are not polled.
+    pub fn poll(&mut self) -> Vec<Message> {
+        let mut payloads = Vec::new();
+        for extension in &mut self.extensions {
+            if self.remote.contains_key(extension.name()) {
+                payloads.push((extension.name(), extension.poll()));
+            }
+        }
+        payloads
+            .into_iter()
+            .flat_map(|(name, payloads)| self.messages(name, payloads))
+            .collect()
+    }
+}
@@ -0,0 +1,38 @@
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+use crate::InfoHash;
+
+/// Number of pieces in the allowed fast set we give to a peer.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Generates the canonical allowed fast set of BEP 6: pieces a peer at `ip`
+/// may request while choked. Both sides compute the same set, so it can be
+/// checked without trusting the remote.
+///
+/// The algorithm is only defined for IPv4, other peers get no set.
+pub fn allowed_fast_set(ip: IpAddr, info_hash: &InfoHash, num_pieces: u32, k: usize) -> Vec<u32> {
+    let IpAddr::V4(ip) = ip else {
+        return Vec::new();
+    };
+    let k = k.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+
+    // peers on the same /24 get the same set
+    let mut x = (u32::from(ip) & 0xffff_ff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
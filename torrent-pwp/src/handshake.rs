@@ -0,0 +1,162 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    error::{PwpError, PwpResult},
+    InfoHash, PeerId,
+};
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+/// The 8 reserved bytes of the handshake, used to advertise extensions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Reserved(pub [u8; 8]);
+
+/// A single feature bit in the reserved bytes, as `(byte, mask)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Feature(usize, u8);
+
+impl Feature {
+    /// BEP 5, the peer runs a DHT node and accepts `port` messages.
+    pub const DHT: Feature = Feature(7, 0x01);
+    /// BEP 6, the fast extension.
+    pub const FAST: Feature = Feature(7, 0x04);
+    /// BEP 10, the extension protocol.
+    pub const EXTENSION: Feature = Feature(5, 0x10);
+}
+
+impl Reserved {
+    pub fn with(features: &[Feature]) -> Self {
+        let mut reserved = Reserved::default();
+        for feature in features {
+            reserved.set(*feature, true);
+        }
+        reserved
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.0[feature.0] & feature.1 != 0
+    }
+
+    pub fn set(&mut self, feature: Feature, enabled: bool) {
+        if enabled {
+            self.0[feature.0] |= feature.1;
+        } else {
+            self.0[feature.0] &= !feature.1;
+        }
+    }
+
+    /// Features are only used if both sides advertise them.
+    pub fn negotiate(&self, remote: &Reserved) -> Reserved {
+        let mut negotiated = [0u8; 8];
+        for (i, byte) in negotiated.iter_mut().enumerate() {
+            *byte = self.0[i] & remote.0[i];
+        }
+        Reserved(negotiated)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: Reserved,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+}
+
+impl Handshake {
+    pub fn encode(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0u8; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved.0);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8; HANDSHAKE_LEN]) -> PwpResult<Handshake> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(PwpError::InvalidProtocol);
+        }
+        Ok(Handshake {
+            reserved: Reserved(bytes[20..28].try_into().unwrap()),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        })
+    }
+}
+
+/// What we send in every handshake, regardless of the torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    pub reserved: Reserved,
+    pub peer_id: PeerId,
+}
+
+impl HandshakeConfig {
+    fn handshake(&self, info_hash: InfoHash) -> Handshake {
+        Handshake {
+            reserved: self.reserved,
+            info_hash,
+            peer_id: self.peer_id,
+        }
+    }
+
+    fn check_peer(&self, remote: &Handshake, is_connected: bool) -> PwpResult<()> {
+        if remote.peer_id == self.peer_id {
+            return Err(PwpError::SelfConnection);
+        }
+        if is_connected {
+            return Err(PwpError::DuplicateConnection);
+        }
+        Ok(())
+    }
+}
+
+async fn read_handshake<S: AsyncRead + Unpin>(stream: &mut S) -> PwpResult<Handshake> {
+    let mut bytes = [0u8; HANDSHAKE_LEN];
+    stream.read_exact(&mut bytes).await?;
+    Handshake::decode(&bytes)
+}
+
+/// Handshakes on a connection we opened for `info_hash`. `is_connected` tells
+/// whether we already have a connection to a peer id.
+pub async fn outgoing<S>(
+    stream: &mut S,
+    config: &HandshakeConfig,
+    info_hash: InfoHash,
+    is_connected: impl FnOnce(&PeerId) -> bool,
+) -> PwpResult<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&config.handshake(info_hash).encode())
+        .await?;
+    let remote = read_handshake(stream).await?;
+    if remote.info_hash != info_hash {
+        return Err(PwpError::InfoHashMismatch);
+    }
+    config.check_peer(&remote, is_connected(&remote.peer_id))?;
+    Ok(remote)
+}
+
+/// Handshakes on a connection the remote opened. The torrent is looked up by
+/// the info hash the remote asked for, and only then do we answer.
+pub async fn incoming<S, T>(
+    stream: &mut S,
+    config: &HandshakeConfig,
+    lookup: impl FnOnce(&InfoHash) -> Option<T>,
+    is_connected: impl FnOnce(&T, &PeerId) -> bool,
+) -> PwpResult<(T, Handshake)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let remote = read_handshake(stream).await?;
+    let torrent = lookup(&remote.info_hash).ok_or(PwpError::UnknownInfoHash)?;
+    config.check_peer(&remote, is_connected(&torrent, &remote.peer_id))?;
+    stream
+        .write_all(&config.handshake(remote.info_hash).encode())
+        .await?;
+    Ok((torrent, remote))
+}
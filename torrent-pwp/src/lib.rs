@@ -1,28 +1,541 @@
 use std::{
     collections::HashMap,
-    net::{SocketAddr, TcpStream},
-    sync::{Arc, RwLock},
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
 };
 
+use async_trait::async_trait;
+use error::{PwpError, PwpResult};
+use handshake::{Feature, Handshake, HandshakeConfig, Reserved};
+use message::MessageCodec;
+use mse::{EncryptionPolicy, MseStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    spawn,
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::{codec::Framed, sync::CancellationToken};
+use tracing::debug;
+use utp::{UtpSocket, UtpStream};
+
+pub mod bitfield;
+pub mod error;
+pub mod extension;
+pub mod fast;
+pub mod handshake;
+pub mod message;
+pub mod mse;
+pub mod utp;
+
+pub type InfoHash = [u8; 20];
+pub type PeerId = [u8; 20];
+/// Tells whether connections with a peer at the address are allowed.
+pub type PeerFilter = Arc<dyn Fn(IpAddr) -> bool + Send + Sync>;
+
+pub struct ConnectionConfig {
+    pub listen_addr: SocketAddr,
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    /// Connections over all torrents, including the ones being set up.
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    /// How long connection tasks get to finish after shutdown is requested.
+    pub shutdown_timeout: Duration,
+    pub reserved: Reserved,
+    pub encryption: EncryptionPolicy,
+    /// Peers it refuses are neither dialed nor answered.
+    pub peer_filter: Option<PeerFilter>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            shutdown_timeout: Duration::from_secs(5),
+            reserved: Reserved::with(&[Feature::DHT, Feature::FAST, Feature::EXTENSION]),
+            encryption: EncryptionPolicy::default(),
+            peer_filter: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+/// The transport a connection runs over.
+pub enum PeerStream {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerStream {
+    pub fn is_utp(&self) -> bool {
+        matches!(self, PeerStream::Utp(_))
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// An established connection, handed to the torrent's [`PeerHandler`].
+pub struct Connection {
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    /// The handshake the remote sent.
+    pub handshake: Handshake,
+    /// Features both sides support.
+    pub extensions: Reserved,
+    /// Decrypts and encrypts transparently if MSE selected RC4.
+    pub framed: Framed<MseStream<PeerStream>, MessageCodec>,
+    /// Cancelled when the connection should be closed; handlers should stop
+    /// soon after.
+    pub shutdown: CancellationToken,
+}
+
+/// Runs the connections of a torrent. Each connection runs in its own task
+/// and is closed when `run` returns.
+#[async_trait]
+pub trait PeerHandler: Send + Sync {
+    async fn run(&self, connection: Connection);
+}
+
+struct ConnectionEntry {
+    info_hash: InfoHash,
+    // unknown until the handshake is done
+    peer_id: Option<PeerId>,
+    shutdown: CancellationToken,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct ManagerState {
+    config: ConnectionConfig,
+    handshake: HandshakeConfig,
+    torrents: Mutex<HashMap<InfoHash, Arc<dyn PeerHandler>>>,
+    connections: Mutex<HashMap<SocketAddr, ConnectionEntry>>,
+    utp: Mutex<Option<Arc<UtpSocket>>>,
+}
+
+/// Sets up peer wire connections for all torrents of a session: accepts
+/// incoming connections on the listen port and dials peers, within the
+/// configured connection limits.
+///
+/// With a uTP socket set, peers are dialed over uTP first and over TCP if
+/// that fails, and incoming uTP connections are accepted too.
+///
+/// All connections are closed when this is dropped.
 pub struct TcpConnectionManager {
-    connections: Arc<RwLock<HashMap<SocketAddr, TcpStream>>>,
-    peer_id: String,
-    info_hash: Vec<u8>,
+    state: Arc<ManagerState>,
+    listener: Mutex<Option<JoinHandle<()>>>,
+    utp_listener: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl TcpConnectionManager {
-    pub fn new(peer_id: String, info_hash: Vec<u8>) -> Self {
+    pub fn new(config: ConnectionConfig, peer_id: PeerId) -> Self {
         TcpConnectionManager {
-            connections: Default::default(),
-            peer_id,
-            info_hash,
+            state: Arc::new(ManagerState {
+                handshake: HandshakeConfig {
+                    reserved: config.reserved,
+                    peer_id,
+                },
+                config,
+                torrents: Default::default(),
+                connections: Default::default(),
+                utp: Default::default(),
+            }),
+            listener: Default::default(),
+            utp_listener: Default::default(),
+        }
+    }
+
+    /// Uses `socket` for uTP connections and accepts incoming ones on it in
+    /// the background.
+    pub fn set_utp(&self, socket: Arc<UtpSocket>) {
+        *self.state.utp.lock().unwrap() = Some(Arc::clone(&socket));
+        let state = Arc::clone(&self.state);
+        let handle = spawn(async move {
+            while let Ok(stream) = socket.accept().await {
+                let state = Arc::clone(&state);
+                let addr = stream.peer_addr();
+                spawn(async move {
+                    if let Err(e) = state.accept(PeerStream::Utp(stream), addr).await {
+                        debug!("incoming uTP connection from {} failed: {}", addr, e);
+                    }
+                });
+            }
+        });
+        if let Some(previous) = self.utp_listener.lock().unwrap().replace(handle) {
+            previous.abort();
+        }
+    }
+
+    pub fn register_torrent(&self, info_hash: InfoHash, handler: Arc<dyn PeerHandler>) {
+        self.state
+            .torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash, handler);
+    }
+
+    /// Stops accepting connections for the torrent and closes its connections.
+    pub fn unregister_torrent(&self, info_hash: &InfoHash) {
+        self.state.torrents.lock().unwrap().remove(info_hash);
+        for entry in self.state.connections.lock().unwrap().values() {
+            if entry.info_hash == *info_hash {
+                entry.shutdown.cancel();
+            }
         }
     }
 
-    pub fn connect_to_peer(&self, addr: SocketAddr) {
-        let stream = TcpStream::connect(addr).unwrap();
-        self.connections.write().unwrap().insert(addr, stream);
+    /// Binds the listen address and accepts connections in the background.
+    /// Returns the bound address.
+    pub async fn listen(&self) -> PwpResult<SocketAddr> {
+        let listener = TcpListener::bind(self.state.config.listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let state = Arc::clone(&self.state);
+        let handle = spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let state = Arc::clone(&state);
+                        spawn(async move {
+                            if let Err(e) = state.accept(PeerStream::Tcp(stream), addr).await {
+                                debug!("incoming connection from {} failed: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => debug!("accept failed: {}", e),
+                }
+            }
+        });
+        if let Some(previous) = self.listener.lock().unwrap().replace(handle) {
+            previous.abort();
+        }
+        Ok(local_addr)
     }
 
-    pub fn listen(&self) {}
+    /// Connects to `addr` for a registered torrent and starts the connection
+    /// task. Returns the remote handshake.
+    pub async fn connect(&self, info_hash: InfoHash, addr: SocketAddr) -> PwpResult<Handshake> {
+        let handler = self
+            .state
+            .torrents
+            .lock()
+            .unwrap()
+            .get(&info_hash)
+            .cloned()
+            .ok_or(PwpError::UnknownInfoHash)?;
+        self.state.check_filter(addr)?;
+        self.state.reserve(addr, info_hash)?;
+
+        let config = &self.state.config;
+        let result = async {
+            let mut stream = self.state.open(addr, info_hash).await?;
+            let remote = with_timeout(
+                config.handshake_timeout,
+                handshake::outgoing(&mut stream, &self.state.handshake, info_hash, |id| {
+                    self.state.is_connected(&info_hash, id)
+                }),
+            )
+            .await?;
+            Ok((stream, remote))
+        }
+        .await;
+
+        match result {
+            Ok((stream, remote)) => {
+                self.state
+                    .start(handler, stream, addr, Direction::Outgoing, remote.clone());
+                Ok(remote)
+            }
+            Err(e) => {
+                self.state.release(&addr);
+                Err(e)
+            }
+        }
+    }
+
+    /// Asks the connection to `addr` to close.
+    pub fn disconnect(&self, addr: &SocketAddr) {
+        if let Some(entry) = self.state.connections.lock().unwrap().get(addr) {
+            entry.shutdown.cancel();
+        }
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.state.connections.lock().unwrap().len()
+    }
+
+    pub fn torrent_connection_count(&self, info_hash: &InfoHash) -> usize {
+        self.state.torrent_connection_count(info_hash)
+    }
+
+    /// Stops listening, asks every connection to close and waits for them up
+    /// to the shutdown timeout before aborting what is left.
+    pub async fn shutdown(&self) {
+        for listener in [&self.listener, &self.utp_listener] {
+            if let Some(listener) = listener.lock().unwrap().take() {
+                listener.abort();
+            }
+        }
+        let handles = {
+            let mut connections = self.state.connections.lock().unwrap();
+            connections
+                .iter_mut()
+                .filter_map(|(addr, entry)| {
+                    entry.shutdown.cancel();
+                    entry.handle.take().map(|handle| (*addr, handle))
+                })
+                .collect::<Vec<_>>()
+        };
+        for (addr, mut handle) in handles {
+            if timeout(self.state.config.shutdown_timeout, &mut handle)
+                .await
+                .is_err()
+            {
+                handle.abort();
+                self.state.release(&addr);
+            }
+        }
+    }
+}
+
+impl Drop for TcpConnectionManager {
+    fn drop(&mut self) {
+        for listener in [&self.listener, &self.utp_listener] {
+            if let Some(listener) = listener.lock().unwrap().take() {
+                listener.abort();
+            }
+        }
+        for entry in self.state.connections.lock().unwrap().values_mut() {
+            entry.shutdown.cancel();
+            if let Some(handle) = entry.handle.take() {
+                handle.abort();
+            }
+        }
+    }
+}
+
+async fn with_timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = PwpResult<T>>,
+) -> PwpResult<T> {
+    timeout(duration, future)
+        .await
+        .map_err(|_| PwpError::Timeout)?
+}
+
+impl ManagerState {
+    fn torrent_connection_count(&self, info_hash: &InfoHash) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.info_hash == *info_hash)
+            .count()
+    }
+
+    fn is_connected(&self, info_hash: &InfoHash, peer_id: &PeerId) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .any(|entry| entry.info_hash == *info_hash && entry.peer_id.as_ref() == Some(peer_id))
+    }
+
+    /// Takes a connection slot for `addr`, failing if a limit is reached.
+    fn reserve(&self, addr: SocketAddr, info_hash: InfoHash) -> PwpResult<()> {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.contains_key(&addr) {
+            return Err(PwpError::AlreadyConnected(addr));
+        }
+        if connections.len() >= self.config.max_connections {
+            return Err(PwpError::ConnectionLimit);
+        }
+        let torrent_connections = connections
+            .values()
+            .filter(|entry| entry.info_hash == info_hash)
+            .count();
+        if torrent_connections >= self.config.max_connections_per_torrent {
+            return Err(PwpError::TorrentConnectionLimit);
+        }
+        connections.insert(
+            addr,
+            ConnectionEntry {
+                info_hash,
+                peer_id: None,
+                shutdown: CancellationToken::new(),
+                handle: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn release(&self, addr: &SocketAddr) {
+        self.connections.lock().unwrap().remove(addr);
+    }
+
+    async fn dial(&self, addr: SocketAddr) -> PwpResult<PeerStream> {
+        let utp = self.utp.lock().unwrap().clone();
+        if let Some(utp) = utp {
+            match with_timeout(self.config.connect_timeout, utp.connect(addr)).await {
+                Ok(stream) => return Ok(PeerStream::Utp(stream)),
+                Err(e) => debug!("uTP connection to {} failed, trying TCP: {}", addr, e),
+            }
+        }
+        timeout(self.config.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| PwpError::Timeout)?
+            .map(PeerStream::Tcp)
+            .map_err(Into::into)
+    }
+
+    /// Connects and negotiates encryption. If encryption is only preferred,
+    /// peers that do not speak MSE are dialed again in plaintext.
+    async fn open(
+        &self,
+        addr: SocketAddr,
+        info_hash: InfoHash,
+    ) -> PwpResult<MseStream<PeerStream>> {
+        let policy = self.config.encryption;
+        let stream = self.dial(addr).await?;
+        let result = with_timeout(
+            self.config.handshake_timeout,
+            mse::outgoing(stream, &info_hash, policy),
+        )
+        .await;
+        match result {
+            Err(e) if policy == EncryptionPolicy::Enabled => {
+                debug!(
+                    "encryption with {} failed, retrying in plaintext: {}",
+                    addr, e
+                );
+                Ok(MseStream::plain(self.dial(addr).await?))
+            }
+            result => result,
+        }
+    }
+
+    fn check_filter(&self, addr: SocketAddr) -> PwpResult<()> {
+        match &self.config.peer_filter {
+            Some(filter) if !filter(addr.ip()) => Err(PwpError::PeerBlocked(addr)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn accept(self: Arc<Self>, stream: PeerStream, addr: SocketAddr) -> PwpResult<()> {
+        self.check_filter(addr)?;
+        // refuse early instead of reading a handshake we cannot serve
+        if self.connections.lock().unwrap().len() >= self.config.max_connections {
+            return Err(PwpError::ConnectionLimit);
+        }
+        let info_hashes = self
+            .torrents
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        let (stream, (handler, remote)) = with_timeout(self.config.handshake_timeout, async {
+            let mut stream = mse::incoming(stream, &info_hashes, self.config.encryption).await?;
+            let handshake = handshake::incoming(
+                &mut stream,
+                &self.handshake,
+                |info_hash| {
+                    self.torrents
+                        .lock()
+                        .unwrap()
+                        .get(info_hash)
+                        .map(|handler| (*info_hash, Arc::clone(handler)))
+                },
+                |(info_hash, _), peer_id| self.is_connected(info_hash, peer_id),
+            )
+            .await?;
+            Ok((stream, handshake))
+        })
+        .await?;
+        let (info_hash, handler) = handler;
+        self.reserve(addr, info_hash)?;
+        self.start(handler, stream, addr, Direction::Incoming, remote);
+        Ok(())
+    }
+
+    /// Spawns the task for a connection whose slot is reserved.
+    fn start(
+        self: &Arc<Self>,
+        handler: Arc<dyn PeerHandler>,
+        stream: MseStream<PeerStream>,
+        addr: SocketAddr,
+        direction: Direction,
+        remote: Handshake,
+    ) {
+        let mut connections = self.connections.lock().unwrap();
+        let Some(entry) = connections.get_mut(&addr) else {
+            return;
+        };
+        entry.peer_id = Some(remote.peer_id);
+        let connection = Connection {
+            addr,
+            direction,
+            extensions: self.handshake.reserved.negotiate(&remote.reserved),
+            handshake: remote,
+            framed: Framed::new(stream, MessageCodec::default()),
+            shutdown: entry.shutdown.clone(),
+        };
+        let state = Arc::clone(self);
+        entry.handle = Some(spawn(async move {
+            handler.run(connection).await;
+            state.release(&addr);
+        }));
+    }
 }
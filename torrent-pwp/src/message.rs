@@ -0,0 +1,260 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::PwpError;
+
+/// Enough for a bitfield of two million pieces or a 128 KiB block.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+pub const ID_CHOKE: u8 = 0;
+pub const ID_UNCHOKE: u8 = 1;
+pub const ID_INTERESTED: u8 = 2;
+pub const ID_NOT_INTERESTED: u8 = 3;
+pub const ID_HAVE: u8 = 4;
+pub const ID_BITFIELD: u8 = 5;
+pub const ID_REQUEST: u8 = 6;
+pub const ID_PIECE: u8 = 7;
+pub const ID_CANCEL: u8 = 8;
+pub const ID_PORT: u8 = 9;
+// BEP 6, only valid when the fast extension was negotiated
+pub const ID_SUGGEST_PIECE: u8 = 0x0d;
+pub const ID_HAVE_ALL: u8 = 0x0e;
+pub const ID_HAVE_NONE: u8 = 0x0f;
+pub const ID_REJECT_REQUEST: u8 = 0x10;
+pub const ID_ALLOWED_FAST: u8 = 0x11;
+// BEP 10, only valid when the extension protocol was negotiated
+pub const ID_EXTENDED: u8 = 20;
+
+/// A block of a piece as used in `request` and `cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockInfo {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bytes),
+    Request(BlockInfo),
+    Piece { index: u32, begin: u32, data: Bytes },
+    Cancel(BlockInfo),
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(BlockInfo),
+    AllowedFast(u32),
+    Extended { id: u8, payload: Bytes },
+}
+
+impl Message {
+    /// The message id, `None` for keep-alives which have none.
+    pub fn id(&self) -> Option<u8> {
+        Some(match self {
+            Message::KeepAlive => return None,
+            Message::Choke => ID_CHOKE,
+            Message::Unchoke => ID_UNCHOKE,
+            Message::Interested => ID_INTERESTED,
+            Message::NotInterested => ID_NOT_INTERESTED,
+            Message::Have(_) => ID_HAVE,
+            Message::Bitfield(_) => ID_BITFIELD,
+            Message::Request(_) => ID_REQUEST,
+            Message::Piece { .. } => ID_PIECE,
+            Message::Cancel(_) => ID_CANCEL,
+            Message::Port(_) => ID_PORT,
+            Message::SuggestPiece(_) => ID_SUGGEST_PIECE,
+            Message::HaveAll => ID_HAVE_ALL,
+            Message::HaveNone => ID_HAVE_NONE,
+            Message::RejectRequest(_) => ID_REJECT_REQUEST,
+            Message::AllowedFast(_) => ID_ALLOWED_FAST,
+            Message::Extended { .. } => ID_EXTENDED,
+        })
+    }
+
+    // length of everything after the id
+    fn payload_len(&self) -> usize {
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => 0,
+            Message::Have(_) | Message::SuggestPiece(_) | Message::AllowedFast(_) => 4,
+            Message::Bitfield(bitfield) => bitfield.len(),
+            Message::Request(_) | Message::Cancel(_) | Message::RejectRequest(_) => 12,
+            Message::Piece { data, .. } => 8 + data.len(),
+            Message::Port(_) => 2,
+            Message::Extended { payload, .. } => 1 + payload.len(),
+        }
+    }
+}
+
+/// Length-prefixed framing for peer wire messages after the handshake.
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+    max_message_size: usize,
+}
+
+impl MessageCodec {
+    pub fn new(max_message_size: usize) -> Self {
+        MessageCodec { max_message_size }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        MessageCodec::new(DEFAULT_MAX_MESSAGE_SIZE)
+    }
+}
+
+fn expect_len(id: u8, len: usize, expected: usize) -> Result<(), PwpError> {
+    if len != expected {
+        return Err(PwpError::InvalidMessageLength(id, len));
+    }
+    Ok(())
+}
+
+fn block_info(payload: &mut BytesMut) -> BlockInfo {
+    BlockInfo {
+        index: payload.get_u32(),
+        begin: payload.get_u32(),
+        length: payload.get_u32(),
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = PwpError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, PwpError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        if len > self.max_message_size {
+            return Err(PwpError::MessageTooLarge(len));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        if len == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+
+        let id = src.get_u8();
+        let mut payload = src.split_to(len - 1);
+        let payload_len = payload.len();
+        let message = match id {
+            ID_CHOKE | ID_UNCHOKE | ID_INTERESTED | ID_NOT_INTERESTED | ID_HAVE_ALL
+            | ID_HAVE_NONE => {
+                expect_len(id, payload_len, 0)?;
+                match id {
+                    ID_CHOKE => Message::Choke,
+                    ID_UNCHOKE => Message::Unchoke,
+                    ID_INTERESTED => Message::Interested,
+                    ID_NOT_INTERESTED => Message::NotInterested,
+                    ID_HAVE_ALL => Message::HaveAll,
+                    _ => Message::HaveNone,
+                }
+            }
+            ID_HAVE | ID_SUGGEST_PIECE | ID_ALLOWED_FAST => {
+                expect_len(id, payload_len, 4)?;
+                let index = payload.get_u32();
+                match id {
+                    ID_HAVE => Message::Have(index),
+                    ID_SUGGEST_PIECE => Message::SuggestPiece(index),
+                    _ => Message::AllowedFast(index),
+                }
+            }
+            ID_BITFIELD => Message::Bitfield(payload.freeze()),
+            ID_REQUEST | ID_CANCEL | ID_REJECT_REQUEST => {
+                expect_len(id, payload_len, 12)?;
+                let block = block_info(&mut payload);
+                match id {
+                    ID_REQUEST => Message::Request(block),
+                    ID_CANCEL => Message::Cancel(block),
+                    _ => Message::RejectRequest(block),
+                }
+            }
+            ID_PIECE => {
+                if payload_len < 8 {
+                    return Err(PwpError::InvalidMessageLength(id, payload_len));
+                }
+                let index = payload.get_u32();
+                let begin = payload.get_u32();
+                Message::Piece {
+                    index,
+                    begin,
+                    data: payload.freeze(),
+                }
+            }
+            ID_PORT => {
+                expect_len(id, payload_len, 2)?;
+                Message::Port(payload.get_u16())
+            }
+            ID_EXTENDED => {
+                if payload_len < 1 {
+                    return Err(PwpError::InvalidMessageLength(id, payload_len));
+                }
+                Message::Extended {
+                    id: payload.get_u8(),
+                    payload: payload.freeze(),
+                }
+            }
+            _ => return Err(PwpError::UnknownMessage(id)),
+        };
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = PwpError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), PwpError> {
+        let Some(id) = message.id() else {
+            dst.put_u32(0);
+            return Ok(());
+        };
+        let len = 1 + message.payload_len();
+        if len > self.max_message_size {
+            return Err(PwpError::MessageTooLarge(len));
+        }
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        dst.put_u8(id);
+        match message {
+            Message::Have(index) | Message::SuggestPiece(index) | Message::AllowedFast(index) => {
+                dst.put_u32(index)
+            }
+            Message::Bitfield(bitfield) => dst.extend_from_slice(&bitfield),
+            Message::Request(block) | Message::Cancel(block) | Message::RejectRequest(block) => {
+                dst.put_u32(block.index);
+                dst.put_u32(block.begin);
+                dst.put_u32(block.length);
+            }
+            Message::Piece { index, begin, data } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.extend_from_slice(&data);
+            }
+            Message::Port(port) => dst.put_u16(port),
+            Message::Extended { id, payload } => {
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use rand::{thread_rng, Rng, RngCore};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    error::{PwpError, PwpResult},
+    handshake::PROTOCOL,
+    InfoHash,
+};
+
+/// The 768 bit prime of the key exchange, the generator is 2.
+const DH_PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245\
+    E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+// verification constant, eight zero bytes
+const VC: [u8; 8] = [0; 8];
+// RC4 output is discarded this far to hide weak initial key stream
+const RC4_DISCARD: usize = 1024;
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether connections use Message Stream Encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Only RC4 encrypted connections.
+    Forced,
+    /// Encrypted where possible: outgoing connections try MSE first and
+    /// incoming ones may use either.
+    #[default]
+    Enabled,
+    /// Only plaintext connections.
+    Disabled,
+}
+
+/// The RC4 stream cipher used by MSE.
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts `data` in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+
+    fn discarding(key: &[u8]) -> Self {
+        let mut rc4 = Rc4::new(key);
+        rc4.apply(&mut [0; RC4_DISCARD]);
+        rc4
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    out
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(DH_PRIME.as_bytes(), 16).unwrap()
+}
+
+fn to_key(value: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut private = [0u8; 20];
+        thread_rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = to_key(&BigUint::from(2u32).modpow(&private, &prime()));
+        KeyPair { private, public }
+    }
+
+    fn shared_secret(&self, remote: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        to_key(&BigUint::from_bytes_be(remote).modpow(&self.private, &prime()))
+    }
+}
+
+fn padding() -> Vec<u8> {
+    let mut pad = vec![0u8; thread_rng().gen_range(0..=MAX_PAD)];
+    thread_rng().fill_bytes(&mut pad);
+    pad
+}
+
+fn negotiation_error(reason: &str) -> PwpError {
+    PwpError::EncryptionNegotiation(reason.to_string())
+}
+
+/// A connection after MSE negotiation. Reads are decrypted and writes
+/// encrypted if RC4 was selected; plaintext connections pass through.
+pub struct MseStream<S> {
+    inner: S,
+    // already decrypted bytes read during negotiation
+    prefix: BytesMut,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    // encrypted bytes accepted from the caller but not written yet
+    pending: BytesMut,
+}
+
+impl<S> MseStream<S> {
+    /// A plaintext stream that does no negotiation.
+    pub fn plain(inner: S) -> Self {
+        MseStream::new(inner, BytesMut::new(), None)
+    }
+
+    fn new(inner: S, prefix: BytesMut, ciphers: Option<(Rc4, Rc4)>) -> Self {
+        let (read_cipher, write_cipher) = ciphers.unzip();
+        MseStream {
+            inner,
+            prefix,
+            read_cipher,
+            write_cipher,
+            pending: BytesMut::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.prefix.is_empty() {
+            let len = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..len]);
+            this.prefix.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // the cipher state moves on with every byte, so encrypted bytes are
+        // kept until written rather than encrypted again
+        ready!(this.poll_write_pending(cx))?;
+        let mut encrypted = BytesMut::from(buf);
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut encrypted);
+        }
+        this.pending = encrypted;
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// reads single bytes until the last `pattern.len()` read match `pattern`,
+// giving up after `max` bytes
+async fn synchronize<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pattern: &[u8],
+    max: usize,
+) -> PwpResult<()> {
+    let mut window = Vec::with_capacity(max);
+    while window.len() < max {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(negotiation_error("no synchronization point"))
+}
+
+fn crypto_provide(policy: EncryptionPolicy) -> u32 {
+    match policy {
+        EncryptionPolicy::Forced => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    }
+}
+
+/// Negotiates encryption on a connection we opened for `info_hash`. Our
+/// BitTorrent handshake is sent afterwards through the returned stream.
+pub async fn outgoing<S>(
+    mut stream: S,
+    info_hash: &InfoHash,
+    policy: EncryptionPolicy,
+) -> PwpResult<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if policy == EncryptionPolicy::Disabled {
+        return Ok(MseStream::plain(stream));
+    }
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+    let mut remote = [0u8; KEY_LEN];
+    stream.read_exact(&mut remote).await?;
+    let secret = keys.shared_secret(&remote);
+
+    let mut encrypt = Rc4::discarding(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::discarding(&hash(&[b"keyB", &secret, info_hash]));
+    let mut request = [
+        &hash(&[b"req1", &secret])[..],
+        &xor(&hash(&[b"req2", info_hash]), &hash(&[b"req3", &secret])),
+    ]
+    .concat();
+    let mut negotiation = [
+        &VC[..],
+        &crypto_provide(policy).to_be_bytes(),
+        // no padding and no initial payload
+        &0u16.to_be_bytes(),
+        &0u16.to_be_bytes(),
+    ]
+    .concat();
+    encrypt.apply(&mut negotiation);
+    request.extend_from_slice(&negotiation);
+    stream.write_all(&request).await?;
+
+    // the answer starts with the encrypted VC somewhere after the padding
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    synchronize(&mut stream, &encrypted_vc, MAX_PAD + VC.len()).await?;
+    let mut answer = [0u8; 6];
+    stream.read_exact(&mut answer).await?;
+    decrypt.apply(&mut answer);
+    let select = u32::from_be_bytes(answer[..4].try_into().unwrap());
+    let mut pad = vec![0u8; u16::from_be_bytes([answer[4], answer[5]]) as usize];
+    if pad.len() > MAX_PAD {
+        return Err(negotiation_error("padding too long"));
+    }
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    match select {
+        CRYPTO_RC4 => Ok(MseStream::new(
+            stream,
+            BytesMut::new(),
+            Some((decrypt, encrypt)),
+        )),
+        CRYPTO_PLAINTEXT if policy == EncryptionPolicy::Enabled => Ok(MseStream::plain(stream)),
+        _ => Err(negotiation_error("unexpected crypto_select")),
+    }
+}
+
+/// Accepts a connection the remote opened, which is either a plaintext
+/// BitTorrent handshake or an MSE negotiation for one of `info_hashes`. The
+/// BitTorrent handshake is read from the returned stream afterwards.
+pub async fn incoming<S>(
+    mut stream: S,
+    info_hashes: &[InfoHash],
+    policy: EncryptionPolicy,
+) -> PwpResult<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut remote = [0u8; KEY_LEN];
+    stream.read_exact(&mut remote[..1 + PROTOCOL.len()]).await?;
+    if remote[0] as usize == PROTOCOL.len() && &remote[1..1 + PROTOCOL.len()] == PROTOCOL {
+        if policy == EncryptionPolicy::Forced {
+            return Err(PwpError::EncryptionRequired);
+        }
+        let prefix = BytesMut::from(&remote[..1 + PROTOCOL.len()]);
+        return Ok(MseStream::new(stream, prefix, None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(PwpError::InvalidProtocol);
+    }
+    stream.read_exact(&mut remote[1 + PROTOCOL.len()..]).await?;
+
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+    let secret = keys.shared_secret(&remote);
+    synchronize(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD + 20).await?;
+
+    // the info hash is only sent hashed, so try every torrent we have
+    let mut skey_hash = [0u8; 20];
+    stream.read_exact(&mut skey_hash).await?;
+    let skey_hash = xor(&skey_hash, &hash(&[b"req3", &secret]));
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", &info_hash[..]]) == skey_hash)
+        .ok_or(PwpError::UnknownInfoHash)?;
+
+    let mut decrypt = Rc4::discarding(&hash(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = Rc4::discarding(&hash(&[b"keyB", &secret, info_hash]));
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        return Err(negotiation_error("bad verification constant"));
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(negotiation_error("padding too long"));
+    }
+    // padding and the length of the initial payload
+    let mut pad = vec![0u8; pad_len + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let mut initial = vec![0u8; u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize];
+    stream.read_exact(&mut initial).await?;
+    decrypt.apply(&mut initial);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy == EncryptionPolicy::Enabled {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(negotiation_error("no common crypto method"));
+    };
+    let mut answer = [&VC[..], &select.to_be_bytes(), &0u16.to_be_bytes()].concat();
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+
+    let ciphers = (select == CRYPTO_RC4).then_some((decrypt, encrypt));
+    Ok(MseStream::new(
+        stream,
+        BytesMut::from(&initial[..]),
+        ciphers,
+    ))
+}
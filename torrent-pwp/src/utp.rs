@@ -0,0 +1,431 @@
+//! uTP (BEP 29): reliable, LEDBAT congestion controlled streams over UDP.
+//!
+//! A [`UtpSocket`] multiplexes connections over one UDP socket, which can be
+//! shared with the DHT and UDP trackers by feeding it the packets
+//! [`is_utp`] recognizes.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    spawn,
+    sync::{mpsc, Mutex as AsyncMutex, Notify},
+    task::JoinHandle,
+    time::timeout_at,
+};
+use tracing::debug;
+
+use crate::error::{PwpError, PwpResult};
+
+pub mod congestion;
+pub mod connection;
+pub mod packet;
+
+pub use connection::{State, UtpConnection};
+pub use packet::is_utp;
+use packet::{Packet, PacketType};
+
+/// Incoming connections waiting to be accepted; more are reset.
+const ACCEPT_BACKLOG: usize = 64;
+
+type ConnectionKey = (SocketAddr, u16);
+
+struct Inner {
+    connection: UtpConnection,
+    addr: SocketAddr,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    dropped: bool,
+}
+
+impl Inner {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Entry {
+    inner: Mutex<Inner>,
+    /// Wakes the connection's timer after sending, as the timeout may have
+    /// moved.
+    timer: Notify,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct SocketState {
+    socket: Arc<UdpSocket>,
+    /// Packets for the send task, as sends from the stream's poll functions
+    /// cannot wait.
+    outgoing: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+    connections: Mutex<HashMap<ConnectionKey, Arc<Entry>>>,
+    incoming: mpsc::Sender<UtpStream>,
+}
+
+/// uTP connections over a UDP socket.
+///
+/// Connections are reset when this is dropped.
+pub struct UtpSocket {
+    state: Arc<SocketState>,
+    incoming: AsyncMutex<mpsc::Receiver<UtpStream>>,
+    send_task: JoinHandle<()>,
+    recv_task: Option<JoinHandle<()>>,
+}
+
+impl UtpSocket {
+    /// Binds a socket of its own and receives on it in the background.
+    pub async fn bind(addr: SocketAddr) -> PwpResult<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let mut utp = UtpSocket::with_socket(Arc::clone(&socket));
+        let state = Arc::clone(&utp.state);
+        utp.recv_task = Some(spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                match socket.recv_from(&mut buf).await {
+                    Ok((len, from)) => state.handle_packet(&buf[..len], from),
+                    Err(e) => debug!("uTP receive failed: {}", e),
+                }
+            }
+        }));
+        Ok(utp)
+    }
+
+    /// Sends on a socket received on elsewhere, which passes the uTP packets
+    /// to [`handle_packet`](Self::handle_packet).
+    pub fn with_socket(socket: Arc<UdpSocket>) -> Self {
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        let (outgoing, mut packets) = mpsc::unbounded_channel::<(Vec<u8>, SocketAddr)>();
+        let send_socket = Arc::clone(&socket);
+        let send_task = spawn(async move {
+            while let Some((packet, addr)) = packets.recv().await {
+                // a packet that cannot be sent is lost and resent later
+                if let Err(e) = send_socket.send_to(&packet, addr).await {
+                    debug!("uTP send to {} failed: {}", addr, e);
+                }
+            }
+        });
+        UtpSocket {
+            state: Arc::new(SocketState {
+                socket,
+                outgoing,
+                connections: Default::default(),
+                incoming: tx,
+            }),
+            incoming: AsyncMutex::new(rx),
+            send_task,
+            recv_task: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> PwpResult<SocketAddr> {
+        Ok(self.state.socket.local_addr()?)
+    }
+
+    pub fn handle_packet(&self, bytes: &[u8], from: SocketAddr) {
+        self.state.handle_packet(bytes, from);
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.state.connections.lock().unwrap().len()
+    }
+
+    /// Opens a connection to `addr`, waiting until the remote answers or the
+    /// SYN was sent too often.
+    pub async fn connect(&self, addr: SocketAddr) -> PwpResult<UtpStream> {
+        let stream = {
+            let mut connections = self.state.connections.lock().unwrap();
+            let recv_id = loop {
+                let id: u16 = rand::random();
+                if !connections.contains_key(&(addr, id))
+                    && !connections.contains_key(&(addr, id.wrapping_add(1)))
+                {
+                    break id;
+                }
+            };
+            let connection = UtpConnection::connect(recv_id, Instant::now());
+            self.state.register(&mut connections, connection, addr)
+        };
+        std::future::poll_fn(|cx| {
+            let mut inner = stream.entry.inner.lock().unwrap();
+            match inner.connection.state() {
+                State::SynSent => {
+                    inner.write_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Connected => Poll::Ready(Ok(())),
+                State::Reset => Poll::Ready(Err(PwpError::IOError(
+                    io::ErrorKind::ConnectionRefused.into(),
+                ))),
+                State::Closed | State::TimedOut => Poll::Ready(Err(PwpError::Timeout)),
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    pub async fn accept(&self) -> PwpResult<UtpStream> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(PwpError::IOError(io::ErrorKind::NotConnected.into()))
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        self.send_task.abort();
+        if let Some(task) = self.recv_task.take() {
+            task.abort();
+        }
+        for entry in self.state.connections.lock().unwrap().values() {
+            if let Some(task) = entry.task.lock().unwrap().take() {
+                task.abort();
+            }
+            let mut inner = entry.inner.lock().unwrap();
+            let reset = Packet::new(PacketType::Reset, inner.connection.recv_id(), 0, 0);
+            // best effort, the send task is gone
+            let _ = self.state.socket.try_send_to(&reset.encode(), inner.addr);
+            inner.connection.handle_packet(reset, Instant::now());
+            inner.wake();
+        }
+    }
+}
+
+impl SocketState {
+    /// Tracks a new connection, sends its first packets and starts its timer.
+    fn register(
+        self: &Arc<Self>,
+        connections: &mut HashMap<ConnectionKey, Arc<Entry>>,
+        connection: UtpConnection,
+        addr: SocketAddr,
+    ) -> UtpStream {
+        let key = (addr, connection.recv_id());
+        let entry = Arc::new(Entry {
+            inner: Mutex::new(Inner {
+                connection,
+                addr,
+                read_waker: None,
+                write_waker: None,
+                dropped: false,
+            }),
+            timer: Notify::new(),
+            task: Mutex::new(None),
+        });
+        connections.insert(key, Arc::clone(&entry));
+        self.transmit(&entry, &mut entry.inner.lock().unwrap());
+        let handle = spawn(Arc::clone(self).drive(key, Arc::clone(&entry)));
+        *entry.task.lock().unwrap() = Some(handle);
+        UtpStream {
+            entry,
+            state: Arc::clone(self),
+            peer_addr: addr,
+        }
+    }
+
+    fn transmit(&self, entry: &Entry, inner: &mut Inner) {
+        let packets = inner.connection.poll_transmit(Instant::now());
+        if packets.is_empty() {
+            return;
+        }
+        for packet in packets {
+            let _ = self.outgoing.send((packet.encode(), inner.addr));
+        }
+        entry.timer.notify_one();
+    }
+
+    /// Handles retransmission timeouts until the connection is finished, or
+    /// dropped with everything acked.
+    async fn drive(self: Arc<Self>, key: ConnectionKey, entry: Arc<Entry>) {
+        loop {
+            let deadline = {
+                let inner = entry.inner.lock().unwrap();
+                if inner.connection.is_finished() || (inner.dropped && inner.connection.all_acked())
+                {
+                    break;
+                }
+                inner.connection.next_timeout()
+            };
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline.into(), entry.timer.notified())
+                        .await
+                        .is_ok()
+                    {
+                        continue;
+                    }
+                }
+                None => {
+                    entry.timer.notified().await;
+                    continue;
+                }
+            }
+            let mut inner = entry.inner.lock().unwrap();
+            inner.connection.poll_timeout(Instant::now());
+            self.transmit(&entry, &mut inner);
+            inner.wake();
+        }
+        self.connections.lock().unwrap().remove(&key);
+        entry.inner.lock().unwrap().wake();
+    }
+
+    fn handle_packet(self: &Arc<Self>, bytes: &[u8], from: SocketAddr) {
+        let packet = match Packet::decode(bytes) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("invalid uTP packet from {}: {}", from, e);
+                return;
+            }
+        };
+        let id = packet.connection_id;
+        let mut connections = self.connections.lock().unwrap();
+        let entry = match packet.packet_type {
+            PacketType::Syn => connections.get(&(from, id.wrapping_add(1))),
+            // resets for unknown connections carry the id they were sent
+            // to, which is off by one from ours
+            PacketType::Reset => [id, id.wrapping_sub(1), id.wrapping_add(1)]
+                .iter()
+                .find_map(|id| connections.get(&(from, *id))),
+            _ => connections.get(&(from, id)),
+        }
+        .cloned();
+        match entry {
+            Some(entry) => {
+                drop(connections);
+                let mut inner = entry.inner.lock().unwrap();
+                inner.connection.handle_packet(packet, Instant::now());
+                self.transmit(&entry, &mut inner);
+                inner.wake();
+            }
+            None if packet.packet_type == PacketType::Syn && self.incoming.capacity() > 0 => {
+                let connection = UtpConnection::accept(&packet, Instant::now());
+                let stream = self.register(&mut connections, connection, from);
+                let _ = self.incoming.try_send(stream);
+            }
+            None if packet.packet_type != PacketType::Reset => {
+                let reset = Packet::new(PacketType::Reset, id, rand::random(), packet.seq_nr);
+                let _ = self.outgoing.send((reset.encode(), from));
+            }
+            None => {}
+        }
+    }
+}
+
+/// A uTP connection. Writes are buffered and sent as the windows allow, so
+/// flushing does not wait for acks.
+///
+/// Dropping the stream closes it after the written data is delivered.
+pub struct UtpStream {
+    entry: Arc<Entry>,
+    state: Arc<SocketState>,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// The UDP payload size the connection settled on.
+    pub fn packet_size(&self) -> usize {
+        self.entry.inner.lock().unwrap().connection.packet_size()
+    }
+}
+
+fn closed_error(state: State) -> io::Error {
+    match state {
+        State::TimedOut => io::ErrorKind::TimedOut.into(),
+        State::Reset => io::ErrorKind::ConnectionReset.into(),
+        _ => io::ErrorKind::BrokenPipe.into(),
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut inner = self.entry.inner.lock().unwrap();
+        let len = inner.connection.read(buf.initialize_unfilled());
+        if len > 0 {
+            buf.advance(len);
+            // may announce a reopened window
+            self.state.transmit(&self.entry, &mut inner);
+            return Poll::Ready(Ok(()));
+        }
+        if inner.connection.is_eof() {
+            return Poll::Ready(Ok(()));
+        }
+        match inner.connection.state() {
+            State::SynSent | State::Connected => {
+                inner.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            State::Closed => Poll::Ready(Ok(())),
+            state => Poll::Ready(Err(closed_error(state))),
+        }
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = self.entry.inner.lock().unwrap();
+        let state = inner.connection.state();
+        if inner.connection.is_finished() || inner.connection.is_closing() {
+            return Poll::Ready(Err(closed_error(state)));
+        }
+        let len = inner.connection.write(buf);
+        if len == 0 && !buf.is_empty() {
+            inner.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.state.transmit(&self.entry, &mut inner);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let inner = self.entry.inner.lock().unwrap();
+        match inner.connection.state() {
+            State::Reset | State::TimedOut => {
+                Poll::Ready(Err(closed_error(inner.connection.state())))
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.entry.inner.lock().unwrap();
+        inner.connection.close();
+        self.state.transmit(&self.entry, &mut inner);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut inner = self.entry.inner.lock().unwrap();
+        inner.dropped = true;
+        inner.connection.close();
+        self.state.transmit(&self.entry, &mut inner);
+        self.entry.timer.notify_one();
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Queuing delay LEDBAT aims for.
+pub const TARGET_DELAY: Duration = Duration::from_millis(100);
+/// Most the window grows per round trip, in packets.
+const GAIN: f64 = 1.0;
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+/// The base delay is the minimum over this many one minute buckets, so a
+/// changed route is picked up eventually.
+const BASE_DELAY_BUCKETS: usize = 10;
+const BASE_DELAY_BUCKET: Duration = Duration::from_secs(60);
+/// Delay samples are the minimum of the last few, to filter noise.
+const CURRENT_DELAY_SAMPLES: usize = 3;
+
+/// LEDBAT congestion control (RFC 6817) with the round trip estimate used
+/// for retransmission timeouts.
+#[derive(Debug)]
+pub struct Ledbat {
+    mss: usize,
+    cwnd: f64,
+    base_delays: VecDeque<u32>,
+    bucket_started: Option<Instant>,
+    current_delays: VecDeque<u32>,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    last_decrease: Option<Instant>,
+}
+
+impl Ledbat {
+    pub fn new(mss: usize) -> Self {
+        Ledbat {
+            mss,
+            cwnd: (2 * mss) as f64,
+            base_delays: VecDeque::new(),
+            bucket_started: None,
+            current_delays: VecDeque::new(),
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_TIMEOUT,
+            last_decrease: None,
+        }
+    }
+
+    /// Bytes that may be in flight.
+    pub fn window(&self) -> usize {
+        self.cwnd as usize
+    }
+
+    pub fn set_mss(&mut self, mss: usize) {
+        self.mss = mss;
+        self.cwnd = self.cwnd.max(mss as f64);
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// The current one-way delay above the base delay, in microseconds.
+    pub fn queuing_delay(&self) -> Option<u32> {
+        let current = self.current_delays.iter().min()?;
+        let base = self.base_delays.iter().min()?;
+        Some(current.saturating_sub(*base))
+    }
+
+    /// Records a one-way delay measured by the remote.
+    pub fn on_delay_sample(&mut self, delay: u32, now: Instant) {
+        match self.bucket_started {
+            Some(started) if now.duration_since(started) < BASE_DELAY_BUCKET => {
+                let base = self.base_delays.back_mut().unwrap();
+                *base = (*base).min(delay);
+            }
+            _ => {
+                self.bucket_started = Some(now);
+                self.base_delays.push_back(delay);
+                if self.base_delays.len() > BASE_DELAY_BUCKETS {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        self.current_delays.push_back(delay);
+        if self.current_delays.len() > CURRENT_DELAY_SAMPLES {
+            self.current_delays.pop_front();
+        }
+    }
+
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(rtt);
+                self.rtt_var = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.rto = (self.rtt.unwrap() + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /// Grows or shrinks the window by how far the queuing delay is from the
+    /// target.
+    pub fn on_ack(&mut self, bytes_acked: usize) {
+        let Some(queuing_delay) = self.queuing_delay() else {
+            return;
+        };
+        let target = TARGET_DELAY.as_micros() as f64;
+        let off_target = (target - queuing_delay as f64) / target;
+        let gain = GAIN * off_target * bytes_acked as f64 * self.mss as f64 / self.cwnd;
+        self.cwnd = (self.cwnd + gain).max(self.mss as f64);
+    }
+
+    /// Halves the window, at most once per round trip.
+    pub fn on_loss(&mut self, now: Instant) {
+        let rtt = self.rtt.unwrap_or(INITIAL_TIMEOUT);
+        if self
+            .last_decrease
+            .is_some_and(|last| now.duration_since(last) < rtt)
+        {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.cwnd = (self.cwnd / 2.0).max(self.mss as f64);
+    }
+
+    /// Falls back to a single packet and backs the timeout off.
+    pub fn on_timeout(&mut self) {
+        self.cwnd = self.mss as f64;
+        self.rto = (self.rto * 2).min(MAX_TIMEOUT);
+    }
+}
@@ -0,0 +1,616 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use super::{
+    congestion::Ledbat,
+    packet::{Packet, PacketType, HEADER_LEN},
+};
+
+/// UDP payload sizes tried by path MTU discovery: 576 and 1500 byte IP
+/// packets without the IP and UDP headers.
+pub const MIN_PACKET_SIZE: usize = 548;
+pub const MAX_PACKET_SIZE: usize = 1472;
+/// Discovery stops once the range is this narrow.
+const MTU_SEARCH_DONE: usize = 16;
+/// Selective acks are capped at 64 packets, so they fit in every packet.
+const SACK_BYTES: usize = 8;
+const SACK_ROOM: usize = 2 + SACK_BYTES;
+const DUPLICATE_ACKS: u32 = 3;
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+const MAX_TRANSMISSIONS: u32 = 8;
+const MAX_OUT_OF_ORDER: u16 = 1024;
+
+pub const DEFAULT_SEND_BUFFER: usize = 256 * 1024;
+pub const DEFAULT_RECV_BUFFER: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    Connected,
+    /// Both sides sent FIN and everything was acked.
+    Closed,
+    Reset,
+    TimedOut,
+}
+
+/// Whether sequence number `a` comes after `b`, allowing for wrapping.
+fn seq_after(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+#[derive(Debug)]
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    needs_resend: bool,
+    fast_resent: bool,
+    probe: bool,
+}
+
+/// Binary search for the largest packet that gets through, by sending one
+/// larger packet at a time. Lost probes are resent at the same size and
+/// left to IP fragmentation.
+#[derive(Debug)]
+struct MtuDiscovery {
+    floor: usize,
+    ceiling: usize,
+    probing: bool,
+}
+
+impl MtuDiscovery {
+    fn probe_size(&self) -> Option<usize> {
+        (!self.probing && self.ceiling - self.floor >= MTU_SEARCH_DONE)
+            .then(|| (self.floor + self.ceiling) / 2)
+    }
+
+    fn on_probe_acked(&mut self, size: usize) {
+        self.probing = false;
+        self.floor = self.floor.max(size);
+    }
+
+    fn on_probe_lost(&mut self, size: usize) {
+        self.probing = false;
+        self.ceiling = self.ceiling.min(size - 1).max(self.floor);
+    }
+}
+
+fn packet_size(payload: usize) -> usize {
+    HEADER_LEN + SACK_ROOM + payload
+}
+
+/// The state of one uTP connection, without any IO: packets are fed in with
+/// [`handle_packet`](Self::handle_packet) and the ones to send are taken
+/// with [`poll_transmit`](Self::poll_transmit).
+#[derive(Debug)]
+pub struct UtpConnection {
+    state: State,
+    epoch: Instant,
+    recv_id: u16,
+    send_id: u16,
+    /// The next sequence number to send.
+    seq_nr: u16,
+    /// The last sequence number received in order.
+    ack_nr: u16,
+    congestion: Ledbat,
+    mtu: MtuDiscovery,
+    remote_wnd: usize,
+    /// Our last measurement of the remote's one-way delay, echoed back.
+    reply_delay: u32,
+    send_buffer: BytesMut,
+    send_capacity: usize,
+    in_flight: VecDeque<SentPacket>,
+    last_ack: u16,
+    duplicate_acks: u32,
+    recv_buffer: BytesMut,
+    recv_capacity: usize,
+    out_of_order: HashMap<u16, (PacketType, Bytes)>,
+    remote_fin: Option<u16>,
+    eof: bool,
+    close_requested: bool,
+    fin_sent: bool,
+    ack_needed: bool,
+    /// Set on accepted connections until the initiator is heard from: acks
+    /// carry the first sequence number, so the initiator learns it even if
+    /// the first reply was lost.
+    initial_seq: Option<u16>,
+    timeout_at: Option<Instant>,
+}
+
+impl UtpConnection {
+    fn new(recv_id: u16, send_id: u16, seq_nr: u16, state: State, now: Instant) -> Self {
+        UtpConnection {
+            state,
+            epoch: now,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            congestion: Ledbat::new(MIN_PACKET_SIZE - HEADER_LEN - SACK_ROOM),
+            mtu: MtuDiscovery {
+                floor: MIN_PACKET_SIZE,
+                ceiling: MAX_PACKET_SIZE,
+                probing: false,
+            },
+            remote_wnd: DEFAULT_RECV_BUFFER,
+            reply_delay: 0,
+            send_buffer: BytesMut::new(),
+            send_capacity: DEFAULT_SEND_BUFFER,
+            in_flight: VecDeque::new(),
+            last_ack: 0,
+            duplicate_acks: 0,
+            recv_buffer: BytesMut::new(),
+            recv_capacity: DEFAULT_RECV_BUFFER,
+            out_of_order: HashMap::new(),
+            remote_fin: None,
+            eof: false,
+            close_requested: false,
+            fin_sent: false,
+            ack_needed: false,
+            initial_seq: None,
+            timeout_at: None,
+        }
+    }
+
+    /// Starts a connection by queueing a SYN. Packets from the remote will
+    /// carry `recv_id`.
+    pub fn connect(recv_id: u16, now: Instant) -> Self {
+        let mut connection =
+            UtpConnection::new(recv_id, recv_id.wrapping_add(1), 1, State::SynSent, now);
+        let syn = Packet::new(PacketType::Syn, recv_id, 1, 0);
+        connection.seq_nr = 2;
+        connection.push_in_flight(syn, false);
+        connection
+    }
+
+    /// Accepts the connection a SYN asks for and queues the reply.
+    pub fn accept(syn: &Packet, now: Instant) -> Self {
+        let mut connection = UtpConnection::new(
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            rand::random(),
+            State::Connected,
+            now,
+        );
+        connection.ack_nr = syn.seq_nr;
+        connection.initial_seq = Some(connection.seq_nr);
+        connection.remote_wnd = syn.wnd_size as usize;
+        connection.ack_needed = true;
+        connection
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The connection id the remote puts on its packets.
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// Whether no more packets will be exchanged.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, State::Closed | State::Reset | State::TimedOut)
+    }
+
+    /// Whether [`close`](Self::close) was called.
+    pub fn is_closing(&self) -> bool {
+        self.close_requested
+    }
+
+    /// Whether the remote sent FIN and all data before it was read.
+    pub fn is_eof(&self) -> bool {
+        self.eof && self.recv_buffer.is_empty()
+    }
+
+    /// Whether everything written, including our FIN if closing, was acked.
+    pub fn all_acked(&self) -> bool {
+        self.send_buffer.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// The UDP payload size packets are sent at, counting room for a
+    /// selective ack.
+    pub fn packet_size(&self) -> usize {
+        self.mtu.floor
+    }
+
+    pub fn congestion_window(&self) -> usize {
+        self.congestion.window()
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.congestion.rtt()
+    }
+
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.timeout_at
+    }
+
+    fn mss(&self) -> usize {
+        self.mtu.floor - HEADER_LEN - SACK_ROOM
+    }
+
+    fn timestamp(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    fn recv_window(&self) -> usize {
+        let buffered = self.recv_buffer.len()
+            + self
+                .out_of_order
+                .values()
+                .map(|(_, payload)| payload.len())
+                .sum::<usize>();
+        self.recv_capacity.saturating_sub(buffered)
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|sent| !sent.needs_resend)
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    /// Buffers data to send. Returns how much was taken, 0 if the buffer is
+    /// full or the connection is closing.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        if self.close_requested || self.is_finished() {
+            return 0;
+        }
+        let len = data
+            .len()
+            .min(self.send_capacity.saturating_sub(self.send_buffer.len()));
+        self.send_buffer.extend_from_slice(&data[..len]);
+        len
+    }
+
+    /// Takes received data.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let closed_window = self.recv_window() < self.mss();
+        let len = buf.len().min(self.recv_buffer.len());
+        self.recv_buffer.copy_to_slice(&mut buf[..len]);
+        // tell a stalled sender the window opened
+        if len > 0 && closed_window && self.recv_window() >= self.mss() {
+            self.ack_needed = true;
+        }
+        len
+    }
+
+    /// Sends FIN once the buffered data is out.
+    pub fn close(&mut self) {
+        self.close_requested = true;
+    }
+
+    pub fn handle_packet(&mut self, packet: Packet, now: Instant) {
+        if self.state == State::Closed {
+            // our last ack was lost
+            self.ack_needed |= packet.packet_type == PacketType::Fin;
+            return;
+        }
+        if self.is_finished() {
+            return;
+        }
+        match (self.state, packet.packet_type) {
+            // a remote that already forgot the connection after both sides
+            // sent FIN
+            (_, PacketType::Reset) if self.eof && self.fin_sent => {
+                self.state = State::Closed;
+                return;
+            }
+            (_, PacketType::Reset) => {
+                self.state = State::Reset;
+                return;
+            }
+            (State::SynSent, PacketType::State) => {
+                self.state = State::Connected;
+                // the reply carries the sequence number its first data
+                // packet will use
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                let ack_nr = self.ack_nr;
+                self.out_of_order.retain(|seq, _| seq_after(*seq, ack_nr));
+                self.deliver_in_order();
+                self.ack_needed = true;
+            }
+            (State::SynSent, PacketType::Data | PacketType::Fin) => {
+                // data overtaking the reply is kept until it arrives
+                if self.out_of_order.len() < MAX_OUT_OF_ORDER as usize {
+                    if packet.packet_type == PacketType::Fin {
+                        self.remote_fin = Some(packet.seq_nr);
+                    }
+                    self.out_of_order
+                        .insert(packet.seq_nr, (packet.packet_type, packet.payload));
+                }
+                return;
+            }
+            (State::SynSent, _) => return,
+            (_, PacketType::Syn) => {
+                // our reply was lost
+                self.ack_needed = true;
+                return;
+            }
+            _ => self.initial_seq = None,
+        }
+
+        self.remote_wnd = packet.wnd_size as usize;
+        if packet.timestamp != 0 {
+            self.reply_delay = self.timestamp(now).wrapping_sub(packet.timestamp);
+        }
+        if packet.timestamp_difference != 0 {
+            self.congestion
+                .on_delay_sample(packet.timestamp_difference, now);
+        }
+        self.process_ack(&packet, now);
+        if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) {
+            self.receive(packet);
+        }
+        if self.fin_sent && self.in_flight.is_empty() && self.eof {
+            self.state = State::Closed;
+        }
+    }
+
+    fn on_acked(&mut self, sent: SentPacket, now: Instant) -> usize {
+        if sent.transmissions == 1 {
+            self.congestion
+                .on_rtt_sample(now.duration_since(sent.sent_at));
+        }
+        if sent.probe {
+            self.mtu
+                .on_probe_acked(packet_size(sent.packet.payload.len()));
+            let mss = self.mss();
+            self.congestion.set_mss(mss);
+        }
+        sent.packet.payload.len()
+    }
+
+    fn on_lost(&mut self, index: usize, now: Instant) {
+        let sent = &mut self.in_flight[index];
+        sent.needs_resend = true;
+        if sent.probe {
+            // a lost probe says nothing about congestion
+            sent.probe = false;
+            let size = packet_size(sent.packet.payload.len());
+            self.mtu.on_probe_lost(size);
+        } else {
+            self.congestion.on_loss(now);
+        }
+    }
+
+    fn process_ack(&mut self, packet: &Packet, now: Instant) {
+        let ack = packet.ack_nr;
+        // acks for packets never sent are ignored
+        if seq_after(ack, self.seq_nr.wrapping_sub(1)) {
+            return;
+        }
+        let mut acked = 0;
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|sent| !seq_after(sent.packet.seq_nr, ack))
+        {
+            let sent = self.in_flight.pop_front().unwrap();
+            acked += self.on_acked(sent, now);
+        }
+
+        let mut sacked = Vec::new();
+        if let Some(sack) = &packet.selective_ack {
+            for (i, byte) in sack.iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (1 << bit) != 0 {
+                        sacked.push(ack.wrapping_add(2 + (i * 8 + bit) as u16));
+                    }
+                }
+            }
+        }
+        for seq in &sacked {
+            if let Some(index) = self
+                .in_flight
+                .iter()
+                .position(|sent| sent.packet.seq_nr == *seq)
+            {
+                let sent = self.in_flight.remove(index).unwrap();
+                acked += self.on_acked(sent, now);
+            }
+        }
+        // a packet is lost once several sent after it arrived
+        for index in 0..self.in_flight.len() {
+            let sent = &self.in_flight[index];
+            if sent.needs_resend || sent.fast_resent {
+                continue;
+            }
+            let later = sacked
+                .iter()
+                .filter(|seq| seq_after(**seq, sent.packet.seq_nr))
+                .count();
+            if later >= DUPLICATE_ACKS as usize {
+                self.in_flight[index].fast_resent = true;
+                self.on_lost(index, now);
+            }
+        }
+
+        if acked > 0 {
+            self.duplicate_acks = 0;
+            self.congestion.on_ack(acked);
+            self.timeout_at = (!self.in_flight.is_empty()).then(|| now + self.congestion.rto());
+        } else if packet.packet_type == PacketType::State
+            && ack == self.last_ack
+            && !self.in_flight.is_empty()
+        {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS && !self.in_flight[0].fast_resent {
+                self.in_flight[0].fast_resent = true;
+                self.on_lost(0, now);
+            }
+        }
+        self.last_ack = ack;
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        let seq = packet.seq_nr;
+        self.ack_needed = true;
+        if !seq_after(seq, self.ack_nr)
+            || seq.wrapping_sub(self.ack_nr) > MAX_OUT_OF_ORDER
+            || self.remote_fin.is_some_and(|fin| seq_after(seq, fin))
+        {
+            return;
+        }
+        if packet.payload.len() > self.recv_window() {
+            return;
+        }
+        if packet.packet_type == PacketType::Fin {
+            self.remote_fin = Some(seq);
+        }
+        self.out_of_order
+            .insert(seq, (packet.packet_type, packet.payload));
+        self.deliver_in_order();
+    }
+
+    fn deliver_in_order(&mut self) {
+        while let Some((packet_type, payload)) =
+            self.out_of_order.remove(&self.ack_nr.wrapping_add(1))
+        {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.recv_buffer.extend_from_slice(&payload);
+            if packet_type == PacketType::Fin {
+                self.eof = true;
+            }
+        }
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut sack = vec![0u8; SACK_BYTES];
+        for i in 0..SACK_BYTES * 8 {
+            let seq = self.ack_nr.wrapping_add(2 + i as u16);
+            if self.out_of_order.contains_key(&seq) {
+                sack[i / 8] |= 1 << (i % 8);
+            }
+        }
+        Some(sack)
+    }
+
+    /// Fills in the fields that reflect the current state.
+    fn stamp(&self, mut packet: Packet, now: Instant) -> Packet {
+        if packet.packet_type != PacketType::Syn {
+            packet.connection_id = self.send_id;
+            packet.ack_nr = self.ack_nr;
+            packet.selective_ack = self.selective_ack();
+        }
+        packet.timestamp = self.timestamp(now);
+        packet.timestamp_difference = self.reply_delay;
+        packet.wnd_size = self.recv_window() as u32;
+        packet
+    }
+
+    fn push_in_flight(&mut self, packet: Packet, probe: bool) {
+        self.in_flight.push_back(SentPacket {
+            packet,
+            // set when sent
+            sent_at: self.epoch,
+            transmissions: 0,
+            needs_resend: true,
+            fast_resent: false,
+            probe,
+        });
+    }
+
+    /// Packetizes buffered data as far as the windows allow and returns
+    /// everything to send now, including retransmissions and acks.
+    pub fn poll_transmit(&mut self, now: Instant) -> Vec<Packet> {
+        if self.state == State::Closed && self.ack_needed {
+            self.ack_needed = false;
+            let state = Packet::new(PacketType::State, self.send_id, self.seq_nr, 0);
+            return vec![self.stamp(state, now)];
+        }
+        if self.is_finished() {
+            return Vec::new();
+        }
+        if self.state == State::Connected {
+            let window = self.congestion.window().min(self.remote_wnd);
+            let mut flight = self.bytes_in_flight();
+            while !self.send_buffer.is_empty() {
+                let probe = self
+                    .mtu
+                    .probe_size()
+                    .map(|size| size - HEADER_LEN - SACK_ROOM)
+                    .filter(|payload| self.send_buffer.len() >= *payload);
+                let len = probe.unwrap_or(self.mss()).min(self.send_buffer.len());
+                if flight > 0 && flight + len > window {
+                    break;
+                }
+                let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, 0);
+                packet.payload = self.send_buffer.split_to(len).freeze();
+                self.seq_nr = self.seq_nr.wrapping_add(1);
+                self.mtu.probing |= probe.is_some();
+                self.push_in_flight(packet, probe.is_some());
+                flight += len;
+            }
+            if self.close_requested && self.send_buffer.is_empty() && !self.fin_sent {
+                let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, 0);
+                self.seq_nr = self.seq_nr.wrapping_add(1);
+                self.fin_sent = true;
+                self.push_in_flight(fin, false);
+            }
+        }
+
+        let mut outgoing = Vec::new();
+        for index in 0..self.in_flight.len() {
+            if !self.in_flight[index].needs_resend {
+                continue;
+            }
+            let packet = self.stamp(self.in_flight[index].packet.clone(), now);
+            let sent = &mut self.in_flight[index];
+            sent.needs_resend = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            outgoing.push(packet);
+        }
+        if self.ack_needed && (outgoing.is_empty() || self.initial_seq.is_some()) {
+            let seq_nr = self.initial_seq.unwrap_or(self.seq_nr);
+            let state = Packet::new(PacketType::State, self.send_id, seq_nr, 0);
+            outgoing.insert(0, self.stamp(state, now));
+        }
+        self.ack_needed = false;
+        if !self.in_flight.is_empty() && self.timeout_at.is_none() {
+            self.timeout_at = Some(now + self.congestion.rto());
+        }
+        outgoing
+    }
+
+    /// Handles an expired retransmission timeout: the oldest packet is sent
+    /// again with the window collapsed, until it was sent too often.
+    pub fn poll_timeout(&mut self, now: Instant) {
+        if self.timeout_at.is_none_or(|at| now < at) || self.is_finished() {
+            return;
+        }
+        let Some(front) = self.in_flight.front() else {
+            self.timeout_at = None;
+            return;
+        };
+        let limit = match front.packet.packet_type {
+            PacketType::Syn => MAX_SYN_TRANSMISSIONS,
+            _ => MAX_TRANSMISSIONS,
+        };
+        if front.transmissions >= limit {
+            self.state = State::TimedOut;
+            return;
+        }
+        if front.probe {
+            self.on_lost(0, now);
+        } else {
+            self.in_flight[0].needs_resend = true;
+            self.congestion.on_timeout();
+        }
+        self.timeout_at = Some(now + self.congestion.rto());
+    }
+}
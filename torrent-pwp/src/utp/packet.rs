@@ -0,0 +1,153 @@
+use bytes::{Buf, BufMut, Bytes};
+
+use crate::error::{PwpError, PwpResult};
+
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 20;
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = PwpError;
+
+    fn try_from(value: u8) -> PwpResult<Self> {
+        Ok(match value {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return Err(invalid("unknown packet type")),
+        })
+    }
+}
+
+fn invalid(reason: &str) -> PwpError {
+    PwpError::InvalidUtpPacket(reason.to_string())
+}
+
+/// A uTP packet (BEP 29).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    /// Send time in microseconds of the sender's clock.
+    pub timestamp: u32,
+    /// The sender's last measured one-way delay, in microseconds.
+    pub timestamp_difference: u32,
+    /// Bytes the sender can still receive.
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    /// Received packets after `ack_nr + 1`, bit 0 of the first byte being
+    /// `ack_nr + 2`. A multiple of 4 bytes long.
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Bytes,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Packet {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Bytes::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        HEADER_LEN
+            + self.selective_ack.as_ref().map_or(0, |sack| 2 + sack.len())
+            + self.payload.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        bytes.put_u8((self.packet_type as u8) << 4 | VERSION);
+        bytes.put_u8(match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => EXTENSION_NONE,
+        });
+        bytes.put_u16(self.connection_id);
+        bytes.put_u32(self.timestamp);
+        bytes.put_u32(self.timestamp_difference);
+        bytes.put_u32(self.wnd_size);
+        bytes.put_u16(self.seq_nr);
+        bytes.put_u16(self.ack_nr);
+        if let Some(sack) = &self.selective_ack {
+            bytes.put_u8(EXTENSION_NONE);
+            bytes.put_u8(sack.len() as u8);
+            bytes.extend_from_slice(sack);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> PwpResult<Packet> {
+        if bytes.len() < HEADER_LEN {
+            return Err(invalid("too short"));
+        }
+        let mut buf = bytes;
+        let first = buf.get_u8();
+        if first & 0x0f != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let packet_type = PacketType::try_from(first >> 4)?;
+        let mut extension = buf.get_u8();
+        let mut packet = Packet {
+            packet_type,
+            connection_id: buf.get_u16(),
+            timestamp: buf.get_u32(),
+            timestamp_difference: buf.get_u32(),
+            wnd_size: buf.get_u32(),
+            seq_nr: buf.get_u16(),
+            ack_nr: buf.get_u16(),
+            selective_ack: None,
+            payload: Bytes::new(),
+        };
+        // unknown extensions are skipped
+        while extension != EXTENSION_NONE {
+            if buf.len() < 2 || buf.len() < 2 + buf[1] as usize {
+                return Err(invalid("truncated extension"));
+            }
+            let next = buf.get_u8();
+            let len = buf.get_u8() as usize;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                if len == 0 || !len.is_multiple_of(4) {
+                    return Err(invalid("bad selective ack length"));
+                }
+                packet.selective_ack = Some(buf[..len].to_vec());
+            }
+            buf.advance(len);
+            extension = next;
+        }
+        packet.payload = Bytes::copy_from_slice(buf);
+        Ok(packet)
+    }
+}
+
+/// Whether `bytes` looks like a uTP packet, for sockets shared with other
+/// UDP protocols.
+pub fn is_utp(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_LEN
+        && bytes[0] & 0x0f == VERSION
+        && bytes[0] >> 4 <= PacketType::Syn as u8
+}
@@ -0,0 +1,57 @@
+use torrent_pwp::{bitfield::Bitfield, error::PwpError};
+
+#[test]
+fn test_wire_format() {
+    let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b0100_0000], 10).unwrap();
+    assert_eq!(bitfield.len(), 10);
+    assert_eq!(bitfield.count_ones(), 3);
+    assert!(bitfield.has(0) && bitfield.has(2) && bitfield.has(9));
+    assert!(!bitfield.has(1) && !bitfield.has(10));
+    assert_eq!(bitfield.as_bytes(), &[0b1010_0000, 0b0100_0000]);
+
+    // spare bits must be zero and the length exact
+    assert!(matches!(
+        Bitfield::from_bytes(&[0, 0b0010_0000], 10),
+        Err(PwpError::InvalidBitfield)
+    ));
+    assert!(Bitfield::from_bytes(&[0], 10).is_err());
+    assert!(Bitfield::from_bytes(&[0, 0, 0], 10).is_err());
+    assert!(Bitfield::from_bytes(&[0xff], 8).unwrap().is_full());
+    assert!(Bitfield::from_bytes(&[], 0).unwrap().is_empty());
+}
+
+#[test]
+fn test_set_and_count() {
+    let mut bitfield = Bitfield::new(12);
+    assert!(!bitfield.set(11, true));
+    assert!(bitfield.set(11, true));
+    assert!(!bitfield.set(12, true));
+    assert_eq!(bitfield.count_ones(), 1);
+    assert!(bitfield.set(11, false));
+    assert_eq!(bitfield.count_ones(), 0);
+
+    let full = Bitfield::full(12);
+    assert!(full.is_full());
+    assert_eq!(full.count_ones(), 12);
+    assert_eq!(full.as_bytes(), &[0xff, 0xf0]);
+}
+
+#[test]
+fn test_interesting_and_iterators() {
+    let theirs = Bitfield::from_bytes(&[0b1100_0001, 0b1000_0000], 9).unwrap();
+    let mut ours = Bitfield::new(9);
+    ours.set(0, true);
+    ours.set(7, true);
+
+    let interesting = theirs.interesting(&ours);
+    assert_eq!(interesting.iter_set().collect::<Vec<_>>(), vec![1, 8]);
+    assert_eq!(interesting.count_ones(), 2);
+    assert!(theirs.has_interesting(&ours));
+    assert!(!ours.has_interesting(&theirs));
+
+    assert_eq!(ours.iter_set().collect::<Vec<_>>(), vec![0, 7]);
+    assert_eq!(
+        ours.iter_unset().collect::<Vec<_>>(),
+        vec![1, 2, 3, 4, 5, 6, 8]
+    );
+}
@@ -0,0 +1,199 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpListener, sync::mpsc, time::sleep};
+use torrent_pwp::{
+    error::PwpError, message::Message, Connection, ConnectionConfig, Direction, PeerHandler,
+    TcpConnectionManager,
+};
+
+const INFO_HASH: [u8; 20] = [0x11; 20];
+
+fn config() -> ConnectionConfig {
+    ConnectionConfig {
+        listen_addr: "127.0.0.1:0".parse().unwrap(),
+        connect_timeout: Duration::from_secs(1),
+        handshake_timeout: Duration::from_millis(200),
+        shutdown_timeout: Duration::from_millis(200),
+        ..Default::default()
+    }
+}
+
+/// Sends `interested`, then reports every message until shut down.
+struct Recorder(mpsc::UnboundedSender<(Direction, Message)>);
+
+#[async_trait]
+impl PeerHandler for Recorder {
+    async fn run(&self, mut connection: Connection) {
+        connection.framed.send(Message::Interested).await.unwrap();
+        loop {
+            tokio::select! {
+                _ = connection.shutdown.cancelled() => return,
+                message = connection.framed.next() => match message {
+                    Some(Ok(message)) => {
+                        let _ = self.0.send((connection.direction, message));
+                    }
+                    _ => return,
+                },
+            }
+        }
+    }
+}
+
+fn recorder() -> (Arc<Recorder>, mpsc::UnboundedReceiver<(Direction, Message)>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (Arc::new(Recorder(tx)), rx)
+}
+
+async fn wait_for(condition: impl Fn() -> bool) {
+    for _ in 0..50 {
+        if condition() {
+            return;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met");
+}
+
+#[tokio::test]
+async fn test_connect_and_accept() {
+    let server = TcpConnectionManager::new(config(), [2; 20]);
+    let (handler, mut server_messages) = recorder();
+    server.register_torrent(INFO_HASH, handler);
+    let addr = server.listen().await.unwrap();
+
+    let client = TcpConnectionManager::new(config(), [1; 20]);
+    let (handler, mut client_messages) = recorder();
+    client.register_torrent(INFO_HASH, handler);
+
+    let remote = client.connect(INFO_HASH, addr).await.unwrap();
+    assert_eq!(remote.peer_id, [2; 20]);
+    assert_eq!(
+        server_messages.recv().await.unwrap(),
+        (Direction::Incoming, Message::Interested)
+    );
+    assert_eq!(
+        client_messages.recv().await.unwrap(),
+        (Direction::Outgoing, Message::Interested)
+    );
+    assert_eq!(client.torrent_connection_count(&INFO_HASH), 1);
+    assert_eq!(server.connection_count(), 1);
+
+    assert!(matches!(
+        client.connect(INFO_HASH, addr).await,
+        Err(PwpError::AlreadyConnected(_))
+    ));
+    assert!(matches!(
+        client.connect([0x22; 20], addr).await,
+        Err(PwpError::UnknownInfoHash)
+    ));
+
+    // closing one side ends the connection task on the other
+    client.shutdown().await;
+    assert_eq!(client.connection_count(), 0);
+    wait_for(|| server.connection_count() == 0).await;
+}
+
+#[tokio::test]
+async fn test_connect_errors() {
+    let client = TcpConnectionManager::new(
+        ConnectionConfig {
+            max_connections_per_torrent: 1,
+            ..config()
+        },
+        [1; 20],
+    );
+    let (handler, _messages) = recorder();
+    client.register_torrent(INFO_HASH, handler);
+
+    // nothing listens on the port of a dropped listener
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed_addr = closed.local_addr().unwrap();
+    drop(closed);
+    assert!(matches!(
+        client.connect(INFO_HASH, closed_addr).await,
+        Err(PwpError::IOError(_))
+    ));
+    assert_eq!(client.connection_count(), 0);
+
+    // a listener that never answers the handshake
+    let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+    assert!(matches!(
+        client.connect(INFO_HASH, silent_addr).await,
+        Err(PwpError::Timeout)
+    ));
+
+    let server = TcpConnectionManager::new(config(), [2; 20]);
+    let (handler, _server_messages) = recorder();
+    server.register_torrent(INFO_HASH, handler);
+    let addr = server.listen().await.unwrap();
+    client.connect(INFO_HASH, addr).await.unwrap();
+
+    let other: SocketAddr = silent_addr;
+    assert!(matches!(
+        client.connect(INFO_HASH, other).await,
+        Err(PwpError::TorrentConnectionLimit)
+    ));
+}
+
+#[tokio::test]
+async fn test_unregister_closes_connections() {
+    let server = TcpConnectionManager::new(config(), [2; 20]);
+    let (handler, _server_messages) = recorder();
+    server.register_torrent(INFO_HASH, handler);
+    let addr = server.listen().await.unwrap();
+
+    let client = TcpConnectionManager::new(config(), [1; 20]);
+    let (handler, _client_messages) = recorder();
+    client.register_torrent(INFO_HASH, handler);
+    client.connect(INFO_HASH, addr).await.unwrap();
+
+    wait_for(|| server.connection_count() == 1).await;
+    server.unregister_torrent(&INFO_HASH);
+    wait_for(|| server.connection_count() == 0 && client.connection_count() == 0).await;
+
+    // the server no longer knows the torrent
+    let client = TcpConnectionManager::new(config(), [3; 20]);
+    let (handler, _client_messages) = recorder();
+    client.register_torrent(INFO_HASH, handler);
+    assert!(client.connect(INFO_HASH, addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_peer_filter() {
+    let refuse_all: torrent_pwp::PeerFilter = Arc::new(|_| false);
+    let server = TcpConnectionManager::new(
+        ConnectionConfig {
+            peer_filter: Some(Arc::clone(&refuse_all)),
+            ..config()
+        },
+        [2; 20],
+    );
+    let (handler, _server_messages) = recorder();
+    server.register_torrent(INFO_HASH, handler);
+    let addr = server.listen().await.unwrap();
+
+    // the server hangs up without answering the handshake
+    let client = TcpConnectionManager::new(config(), [1; 20]);
+    let (handler, _messages) = recorder();
+    client.register_torrent(INFO_HASH, handler);
+    assert!(client.connect(INFO_HASH, addr).await.is_err());
+    assert_eq!(server.connection_count(), 0);
+
+    // and a filtered peer is not dialed
+    let filtered = TcpConnectionManager::new(
+        ConnectionConfig {
+            peer_filter: Some(refuse_all),
+            ..config()
+        },
+        [3; 20],
+    );
+    let (handler, _messages) = recorder();
+    filtered.register_torrent(INFO_HASH, handler);
+    assert!(matches!(
+        filtered.connect(INFO_HASH, addr).await,
+        Err(PwpError::PeerBlocked(_))
+    ));
+}
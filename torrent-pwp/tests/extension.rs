@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use torrent_pwp::{
+    error::PwpResult,
+    extension::{ExtendedHandshake, Extension, ExtensionRegistry},
+    message::Message,
+};
+
+/// Answers every message with its payload reversed.
+struct Echo;
+
+impl Extension for Echo {
+    fn name(&self) -> &'static str {
+        "x_echo"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = Some(1234);
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> PwpResult<Vec<Vec<u8>>> {
+        Ok(vec![payload.iter().rev().copied().collect()])
+    }
+
+    fn poll(&mut self) -> Vec<Vec<u8>> {
+        vec![b"tick".to_vec()]
+    }
+}
+
+fn handshake_payload(message: Message) -> Bytes {
+    match message {
+        Message::Extended { id: 0, payload } => payload,
+        other => panic!("not a handshake: {:?}", other),
+    }
+}
+
+#[test]
+fn test_handshake_roundtrip() {
+    let handshake = ExtendedHandshake {
+        extensions: BTreeMap::from([("ut_pex".to_string(), 1), ("ut_metadata".to_string(), 2)]),
+        client: Some("RustyTorrent 0.0.1".to_string()),
+        listen_port: Some(6881),
+        reqq: Some(250),
+        your_ip: Some("10.0.0.1".parse().unwrap()),
+        metadata_size: Some(31235),
+    };
+    assert_eq!(
+        ExtendedHandshake::decode(&handshake.encode()).unwrap(),
+        handshake
+    );
+
+    let minimal = ExtendedHandshake::decode(b"d1:md6:ut_pexi1eee").unwrap();
+    assert_eq!(minimal.extensions.get("ut_pex"), Some(&1));
+    assert_eq!(minimal.client, None);
+    // a port that does not fit is ignored rather than wrapped
+    let odd = ExtendedHandshake::decode(b"d1:mde1:pi70000ee").unwrap();
+    assert_eq!(odd.listen_port, None);
+
+    assert!(ExtendedHandshake::decode(b"d1:pi1ee").is_err());
+    assert!(ExtendedHandshake::decode(b"garbage").is_err());
+}
+
+#[test]
+fn test_registry() {
+    let mut ours = ExtensionRegistry::new();
+    assert_eq!(ours.register(Box::new(Echo)), 1);
+    let handshake = ours.handshake(ExtendedHandshake::default());
+    assert_eq!(handshake.extensions.get("x_echo"), Some(&1));
+    assert_eq!(handshake.metadata_size, Some(1234));
+
+    // nothing is sent before the remote advertised the extension
+    assert!(ours.poll().is_empty());
+    assert!(ours.message("x_echo", vec![1]).is_none());
+
+    let remote = ExtendedHandshake::decode(b"d1:md6:x_echoi7eee").unwrap();
+    let output = ours.receive(0, &remote.encode()).unwrap();
+    assert_eq!(output.handshake, Some(remote));
+    assert!(ours.remote_supports("x_echo"));
+
+    let output = ours.receive(1, b"abc").unwrap();
+    assert_eq!(
+        output.messages,
+        vec![Message::Extended {
+            id: 7,
+            payload: Bytes::from_static(b"cba"),
+        }]
+    );
+    assert_eq!(
+        ours.poll(),
+        vec![Message::Extended {
+            id: 7,
+            payload: Bytes::from_static(b"tick"),
+        }]
+    );
+    // unknown ids are ignored
+    assert_eq!(ours.receive(9, b"abc").unwrap().messages, vec![]);
+
+    // a later handshake can disable the extension
+    ours.receive(0, b"d1:md6:x_echoi0eee").unwrap();
+    assert!(!ours.remote_supports("x_echo"));
+
+    let mut other = ExtensionRegistry::new();
+    other.register(Box::new(Echo));
+    let message = other.handshake_message(ExtendedHandshake::default());
+    assert_eq!(
+        ExtendedHandshake::decode(&handshake_payload(message))
+            .unwrap()
+            .extensions
+            .get("x_echo"),
+        Some(&1)
+    );
+}
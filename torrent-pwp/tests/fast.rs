@@ -0,0 +1,28 @@
+use std::net::IpAddr;
+
+use torrent_pwp::fast::allowed_fast_set;
+
+#[test]
+fn test_allowed_fast_set() {
+    // the example from BEP 6
+    let ip: IpAddr = "80.4.4.200".parse().unwrap();
+    let info_hash = [0xaa; 20];
+    assert_eq!(
+        allowed_fast_set(ip, &info_hash, 1313, 7),
+        vec![1059, 431, 808, 1217, 287, 376, 1188]
+    );
+    assert_eq!(
+        allowed_fast_set(ip, &info_hash, 1313, 9),
+        vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+    );
+    // the whole /24 gets the same set
+    assert_eq!(
+        allowed_fast_set("80.4.4.1".parse().unwrap(), &info_hash, 1313, 7),
+        allowed_fast_set(ip, &info_hash, 1313, 7)
+    );
+
+    let mut small = allowed_fast_set(ip, &info_hash, 3, 10);
+    small.sort();
+    assert_eq!(small, vec![0, 1, 2]);
+    assert!(allowed_fast_set("::1".parse().unwrap(), &info_hash, 1313, 7).is_empty());
+}
@@ -0,0 +1,126 @@
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use torrent_pwp::{
+    error::PwpError,
+    handshake::{self, Feature, Handshake, HandshakeConfig, Reserved, HANDSHAKE_LEN},
+};
+
+fn config(peer_id: u8) -> HandshakeConfig {
+    HandshakeConfig {
+        reserved: Reserved::with(&[Feature::DHT, Feature::EXTENSION]),
+        peer_id: [peer_id; 20],
+    }
+}
+
+#[test]
+fn test_encode_decode() {
+    let handshake = Handshake {
+        reserved: Reserved::with(&[Feature::DHT, Feature::FAST, Feature::EXTENSION]),
+        info_hash: [0xaa; 20],
+        peer_id: *b"-RT0001-abcdefghijkl",
+    };
+    let bytes = handshake.encode();
+    assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+    assert_eq!(&bytes[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+    assert_eq!(Handshake::decode(&bytes).unwrap(), handshake);
+
+    let mut invalid = bytes;
+    invalid[0] = 18;
+    assert!(matches!(
+        Handshake::decode(&invalid),
+        Err(PwpError::InvalidProtocol)
+    ));
+}
+
+#[test]
+fn test_reserved_bits() {
+    let ours = Reserved::with(&[Feature::DHT, Feature::FAST]);
+    let theirs = Reserved::with(&[Feature::FAST, Feature::EXTENSION]);
+    let negotiated = ours.negotiate(&theirs);
+    assert!(negotiated.supports(Feature::FAST));
+    assert!(!negotiated.supports(Feature::DHT));
+    assert!(!negotiated.supports(Feature::EXTENSION));
+
+    let mut reserved = ours;
+    reserved.set(Feature::DHT, false);
+    assert_eq!(reserved, Reserved::with(&[Feature::FAST]));
+}
+
+#[tokio::test]
+async fn test_outgoing_and_incoming() {
+    let (mut client, mut server) = duplex(256);
+    let info_hash = [0x11; 20];
+
+    let remote = tokio::spawn(async move {
+        handshake::incoming(
+            &mut server,
+            &config(2),
+            |info_hash| (*info_hash == [0x11; 20]).then_some("torrent"),
+            |_, _| false,
+        )
+        .await
+    });
+    let handshake = handshake::outgoing(&mut client, &config(1), info_hash, |_| false)
+        .await
+        .unwrap();
+    assert_eq!(handshake.peer_id, [2; 20]);
+    assert!(handshake.reserved.supports(Feature::EXTENSION));
+
+    let (torrent, handshake) = remote.await.unwrap().unwrap();
+    assert_eq!(torrent, "torrent");
+    assert_eq!(handshake.peer_id, [1; 20]);
+    assert_eq!(handshake.info_hash, info_hash);
+}
+
+#[tokio::test]
+async fn test_validation() {
+    // the incoming side does not know the torrent and drops the connection
+    let (mut client, mut server) = duplex(256);
+    let server_config = config(2);
+    let result = handshake::incoming(&mut server, &server_config, |_| None::<()>, |_, _| false);
+    client
+        .write_all(
+            &Handshake {
+                reserved: Reserved::default(),
+                info_hash: [0x22; 20],
+                peer_id: [1; 20],
+            }
+            .encode(),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(result.await, Err(PwpError::UnknownInfoHash)));
+
+    // a remote answering for another torrent
+    let (mut client, mut server) = duplex(256);
+    let mut reply = Handshake {
+        reserved: Reserved::default(),
+        info_hash: [0x33; 20],
+        peer_id: [2; 20],
+    };
+    server.write_all(&reply.encode()).await.unwrap();
+    assert!(matches!(
+        handshake::outgoing(&mut client, &config(1), [0x11; 20], |_| false).await,
+        Err(PwpError::InfoHashMismatch)
+    ));
+    let mut sent = [0u8; HANDSHAKE_LEN];
+    server.read_exact(&mut sent).await.unwrap();
+
+    // connected to ourselves
+    let (mut client, mut server) = duplex(256);
+    reply.info_hash = [0x11; 20];
+    reply.peer_id = [1; 20];
+    server.write_all(&reply.encode()).await.unwrap();
+    assert!(matches!(
+        handshake::outgoing(&mut client, &config(1), [0x11; 20], |_| false).await,
+        Err(PwpError::SelfConnection)
+    ));
+
+    // already connected to this peer
+    let (mut client, mut server) = duplex(256);
+    reply.peer_id = [2; 20];
+    server.write_all(&reply.encode()).await.unwrap();
+    assert!(matches!(
+        handshake::outgoing(&mut client, &config(1), [0x11; 20], |id| *id == [2; 20]).await,
+        Err(PwpError::DuplicateConnection)
+    ));
+}
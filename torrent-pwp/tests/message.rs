@@ -0,0 +1,135 @@
+use bytes::{Bytes, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::duplex;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use torrent_pwp::{
+    error::PwpError,
+    message::{BlockInfo, Message, MessageCodec},
+};
+
+fn encode(message: Message) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    MessageCodec::default().encode(message, &mut buf).unwrap();
+    buf.to_vec()
+}
+
+fn decode(bytes: &[u8]) -> Result<Option<Message>, PwpError> {
+    MessageCodec::default().decode(&mut BytesMut::from(bytes))
+}
+
+#[test]
+fn test_byte_vectors() {
+    let block = BlockInfo {
+        index: 1,
+        begin: 0x4000,
+        length: 0x4000,
+    };
+    let vectors = vec![
+        (Message::KeepAlive, vec![0, 0, 0, 0]),
+        (Message::Choke, vec![0, 0, 0, 1, 0]),
+        (Message::Unchoke, vec![0, 0, 0, 1, 1]),
+        (Message::Interested, vec![0, 0, 0, 1, 2]),
+        (Message::NotInterested, vec![0, 0, 0, 1, 3]),
+        (Message::Have(0x0102), vec![0, 0, 0, 5, 4, 0, 0, 1, 2]),
+        (
+            Message::Bitfield(Bytes::from_static(&[0xff, 0x80])),
+            vec![0, 0, 0, 3, 5, 0xff, 0x80],
+        ),
+        (
+            Message::Request(block),
+            vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        ),
+        (
+            Message::Piece {
+                index: 1,
+                begin: 2,
+                data: Bytes::from_static(b"abc"),
+            },
+            vec![0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0, 2, b'a', b'b', b'c'],
+        ),
+        (
+            Message::Cancel(block),
+            vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        ),
+        (Message::Port(6881), vec![0, 0, 0, 3, 9, 0x1a, 0xe1]),
+        (Message::SuggestPiece(2), vec![0, 0, 0, 5, 0x0d, 0, 0, 0, 2]),
+        (Message::HaveAll, vec![0, 0, 0, 1, 0x0e]),
+        (Message::HaveNone, vec![0, 0, 0, 1, 0x0f]),
+        (
+            Message::RejectRequest(block),
+            vec![0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        ),
+        (Message::AllowedFast(5), vec![0, 0, 0, 5, 0x11, 0, 0, 0, 5]),
+        (
+            Message::Extended {
+                id: 3,
+                payload: Bytes::from_static(b"de"),
+            },
+            vec![0, 0, 0, 4, 20, 3, b'd', b'e'],
+        ),
+    ];
+    for (message, bytes) in vectors {
+        assert_eq!(encode(message.clone()), bytes, "{:?}", message);
+        assert_eq!(decode(&bytes).unwrap(), Some(message));
+    }
+}
+
+#[test]
+fn test_partial_frames() {
+    let mut codec = MessageCodec::default();
+    let mut buf = BytesMut::from(&[0, 0, 0, 5, 4, 0][..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    buf.extend_from_slice(&[0, 0, 7, 0, 0, 0, 1]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(7)));
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    buf.extend_from_slice(&[1]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Unchoke));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_malformed() {
+    assert!(matches!(
+        decode(&[0, 0, 0, 2, 0, 0]),
+        Err(PwpError::InvalidMessageLength(0, 1))
+    ));
+    assert!(matches!(
+        decode(&[0, 0, 0, 4, 4, 0, 0, 1]),
+        Err(PwpError::InvalidMessageLength(4, 3))
+    ));
+    assert!(matches!(
+        decode(&[0, 0, 0, 5, 7, 0, 0, 0, 1]),
+        Err(PwpError::InvalidMessageLength(7, 4))
+    ));
+    assert!(matches!(
+        decode(&[0, 0, 0, 1, 20]),
+        Err(PwpError::InvalidMessageLength(20, 0))
+    ));
+    assert!(matches!(
+        decode(&[0, 0, 0, 1, 99]),
+        Err(PwpError::UnknownMessage(99))
+    ));
+    // rejected from the length prefix alone
+    assert!(matches!(
+        MessageCodec::new(16).decode(&mut BytesMut::from(&[0, 0, 0, 17][..])),
+        Err(PwpError::MessageTooLarge(17))
+    ));
+    assert!(MessageCodec::new(8)
+        .encode(
+            Message::Bitfield(Bytes::from(vec![0; 8])),
+            &mut BytesMut::new()
+        )
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_framed() {
+    let (client, server) = duplex(1024);
+    let mut client = Framed::new(client, MessageCodec::default());
+    let mut server = Framed::new(server, MessageCodec::default());
+
+    client.send(Message::Interested).await.unwrap();
+    client.send(Message::Have(3)).await.unwrap();
+    assert_eq!(server.next().await.unwrap().unwrap(), Message::Interested);
+    assert_eq!(server.next().await.unwrap().unwrap(), Message::Have(3));
+}
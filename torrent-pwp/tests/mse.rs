@@ -0,0 +1,186 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::mpsc,
+};
+use torrent_pwp::{
+    error::PwpError,
+    handshake::{Handshake, Reserved},
+    message::Message,
+    mse::{self, EncryptionPolicy, MseStream, Rc4},
+    Connection, ConnectionConfig, PeerHandler, TcpConnectionManager,
+};
+
+const INFO_HASH: [u8; 20] = [0x11; 20];
+
+async fn pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (client.unwrap(), server.unwrap().0)
+}
+
+async fn negotiate(
+    outgoing: EncryptionPolicy,
+    incoming: EncryptionPolicy,
+    info_hashes: &[[u8; 20]],
+) -> (
+    Result<MseStream<TcpStream>, PwpError>,
+    Result<MseStream<TcpStream>, PwpError>,
+) {
+    let (client, server) = pair().await;
+    let info_hashes = info_hashes.to_vec();
+    let server = spawn(async move { mse::incoming(server, &info_hashes, incoming).await });
+    let client = mse::outgoing(client, &INFO_HASH, outgoing).await;
+    (client, server.await.unwrap())
+}
+
+#[test]
+fn test_rc4_vector() {
+    let mut data = *b"Plaintext";
+    Rc4::new(b"Key").apply(&mut data);
+    assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+}
+
+#[tokio::test]
+async fn test_encrypted_exchange() {
+    let (client, server) = negotiate(
+        EncryptionPolicy::Forced,
+        EncryptionPolicy::Enabled,
+        &[[0x22; 20], INFO_HASH],
+    )
+    .await;
+    let (mut client, mut server) = (client.unwrap(), server.unwrap());
+    assert!(client.is_encrypted() && server.is_encrypted());
+
+    // the BitTorrent handshake and messages flow through the ciphers
+    let handshake = Handshake {
+        reserved: Reserved::default(),
+        info_hash: INFO_HASH,
+        peer_id: [1; 20],
+    };
+    client.write_all(&handshake.encode()).await.unwrap();
+    let mut received = [0u8; 68];
+    server.read_exact(&mut received).await.unwrap();
+    assert_eq!(Handshake::decode(&received).unwrap(), handshake);
+
+    server.write_all(b"pong").await.unwrap();
+    server.flush().await.unwrap();
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"pong");
+}
+
+#[tokio::test]
+async fn test_policies() {
+    // a plaintext handshake is accepted unless encryption is forced
+    for (policy, accepted) in [
+        (EncryptionPolicy::Enabled, true),
+        (EncryptionPolicy::Disabled, true),
+        (EncryptionPolicy::Forced, false),
+    ] {
+        let (mut client, server) = pair().await;
+        let handshake = [&[19u8][..], b"BitTorrent protocol"].concat();
+        client.write_all(&handshake).await.unwrap();
+        match mse::incoming(server, &[INFO_HASH], policy).await {
+            Ok(mut server) => {
+                assert!(accepted);
+                assert!(!server.is_encrypted());
+                let mut received = [0u8; 20];
+                server.read_exact(&mut received).await.unwrap();
+                assert_eq!(received[..], handshake[..]);
+            }
+            Err(e) => {
+                assert!(!accepted);
+                assert!(matches!(e, PwpError::EncryptionRequired));
+            }
+        }
+    }
+
+    let (client, server) = negotiate(
+        EncryptionPolicy::Enabled,
+        EncryptionPolicy::Disabled,
+        &[INFO_HASH],
+    )
+    .await;
+    assert!(matches!(server, Err(PwpError::InvalidProtocol)));
+    assert!(client.is_err());
+}
+
+#[tokio::test]
+async fn test_unknown_skey() {
+    let (client, server) = negotiate(
+        EncryptionPolicy::Forced,
+        EncryptionPolicy::Enabled,
+        &[[0x22; 20]],
+    )
+    .await;
+    assert!(matches!(server, Err(PwpError::UnknownInfoHash)));
+    assert!(client.is_err());
+}
+
+/// Reports whether each connection is encrypted and pings the other side.
+struct Reporter(mpsc::UnboundedSender<bool>);
+
+#[async_trait]
+impl PeerHandler for Reporter {
+    async fn run(&self, mut connection: Connection) {
+        let _ = self.0.send(connection.framed.get_ref().is_encrypted());
+        connection.framed.send(Message::Interested).await.unwrap();
+        let _ = connection.framed.next().await;
+    }
+}
+
+fn manager(
+    encryption: EncryptionPolicy,
+    id: u8,
+) -> (TcpConnectionManager, mpsc::UnboundedReceiver<bool>) {
+    let manager = TcpConnectionManager::new(
+        ConnectionConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            handshake_timeout: Duration::from_secs(1),
+            encryption,
+            ..Default::default()
+        },
+        [id; 20],
+    );
+    let (tx, rx) = mpsc::unbounded_channel();
+    manager.register_torrent(INFO_HASH, Arc::new(Reporter(tx)));
+    (manager, rx)
+}
+
+#[tokio::test]
+async fn test_manager_negotiation() {
+    for (server_policy, client_policy, encrypted) in [
+        (
+            EncryptionPolicy::Forced,
+            EncryptionPolicy::Enabled,
+            Some(true),
+        ),
+        // the client falls back to plaintext
+        (
+            EncryptionPolicy::Disabled,
+            EncryptionPolicy::Enabled,
+            Some(false),
+        ),
+        (EncryptionPolicy::Disabled, EncryptionPolicy::Forced, None),
+    ] {
+        let (server, mut server_reports) = manager(server_policy, 2);
+        let addr = server.listen().await.unwrap();
+        let (client, mut client_reports) = manager(client_policy, 1);
+        let result = client.connect(INFO_HASH, addr).await;
+        match encrypted {
+            Some(encrypted) => {
+                assert_eq!(result.unwrap().peer_id, [2; 20]);
+                assert_eq!(client_reports.recv().await, Some(encrypted));
+                assert_eq!(server_reports.recv().await, Some(encrypted));
+            }
+            None => assert!(result.is_err()),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use torrent_pwp::{
+    message::Message,
+    utp::{
+        connection::MAX_PACKET_SIZE,
+        is_utp,
+        packet::{Packet, PacketType},
+        State, UtpConnection, UtpSocket,
+    },
+    Connection, ConnectionConfig, PeerHandler, TcpConnectionManager,
+};
+
+const INFO_HASH: [u8; 20] = [0x11; 20];
+
+/// Drops every `loss`th packet, and packets above `mtu` the first time they
+/// are sent, as the kernel fragments them once it learned the path MTU.
+struct Link {
+    loss: usize,
+    mtu: usize,
+    sent: usize,
+    oversized: HashSet<(u16, u16)>,
+}
+
+impl Link {
+    fn new(loss: usize, mtu: usize) -> Self {
+        Link {
+            loss,
+            mtu,
+            sent: 0,
+            oversized: HashSet::new(),
+        }
+    }
+
+    fn pass(&mut self, packet: &Packet) -> bool {
+        self.sent += 1;
+        if self.loss > 0 && self.sent.is_multiple_of(self.loss) {
+            return false;
+        }
+        packet.encode().len() <= self.mtu
+            || !self.oversized.insert((packet.connection_id, packet.seq_nr))
+    }
+}
+
+struct Peer {
+    connection: UtpConnection,
+    to_send: Vec<u8>,
+    written: usize,
+    received: Vec<u8>,
+}
+
+impl Peer {
+    fn new(connection: UtpConnection, to_send: Vec<u8>) -> Self {
+        Peer {
+            connection,
+            to_send,
+            written: 0,
+            received: Vec::new(),
+        }
+    }
+
+    fn step(&mut self, now: Instant) {
+        self.connection.poll_timeout(now);
+        self.written += self.connection.write(&self.to_send[self.written..]);
+        if self.written == self.to_send.len() {
+            self.connection.close();
+        }
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = self.connection.read(&mut buf);
+            if len == 0 {
+                break;
+            }
+            self.received.extend_from_slice(&buf[..len]);
+        }
+    }
+}
+
+fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+/// Runs a connection between two state machines until both closed, in
+/// simulated time with the link delivering instantly.
+fn transfer(link: &mut Link, a_data: Vec<u8>, b_data: Vec<u8>) -> (Peer, Peer) {
+    let mut now = Instant::now();
+    let mut a = UtpConnection::connect(7, now);
+    let syn = a.poll_transmit(now).remove(0);
+    assert_eq!(syn.packet_type, PacketType::Syn);
+    let mut a = Peer::new(a, a_data);
+    let mut b = Peer::new(UtpConnection::accept(&syn, now), b_data);
+
+    for _ in 0..100_000 {
+        now += Duration::from_millis(5);
+        a.step(now);
+        b.step(now);
+        if a.connection.is_finished() && b.connection.is_finished() {
+            return (a, b);
+        }
+        for packet in a.connection.poll_transmit(now) {
+            if link.pass(&packet) {
+                b.connection.handle_packet(packet, now);
+            }
+        }
+        for packet in b.connection.poll_transmit(now) {
+            if link.pass(&packet) {
+                a.connection.handle_packet(packet, now);
+            }
+        }
+    }
+    panic!(
+        "transfer stalled: {:?} {:?}",
+        a.connection.state(),
+        b.connection.state()
+    );
+}
+
+#[test]
+fn test_packet_round_trip() {
+    let mut packet = Packet::new(PacketType::Data, 0x1234, 10, 9);
+    packet.timestamp = 1_000_000;
+    packet.timestamp_difference = 20;
+    packet.wnd_size = 65536;
+    packet.selective_ack = Some(vec![0b101, 0, 0, 0]);
+    packet.payload = Bytes::from_static(b"payload");
+    let encoded = packet.encode();
+    assert_eq!(encoded[0], 0x01);
+    assert_eq!(encoded.len(), packet.len());
+    assert!(is_utp(&encoded));
+    assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+
+    // DHT and UDP tracker packets on a shared socket
+    assert!(!is_utp(
+        b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+    ));
+    assert!(!is_utp(&[
+        0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0
+    ]));
+    assert!(Packet::decode(&encoded[..10]).is_err());
+}
+
+#[test]
+fn test_transfer_over_lossy_link() {
+    let (a, b) = transfer(&mut Link::new(7, usize::MAX), data(300_000), data(50_000));
+    assert_eq!(a.connection.state(), State::Closed);
+    assert_eq!(b.connection.state(), State::Closed);
+    assert!(b.received == data(300_000));
+    assert!(a.received == data(50_000));
+}
+
+#[test]
+fn test_mtu_discovery() {
+    let mtu = 1000;
+    let (a, b) = transfer(&mut Link::new(0, mtu), data(200_000), Vec::new());
+    assert!(b.received == data(200_000));
+    // packets without a selective ack are 10 bytes shorter
+    let size = a.connection.packet_size() - 10;
+    assert!(size <= mtu && size > mtu - 32, "settled on {}", size);
+
+    let (a, _) = transfer(&mut Link::new(0, usize::MAX), data(200_000), Vec::new());
+    assert!(a.connection.packet_size() > MAX_PACKET_SIZE - 32);
+}
+
+#[test]
+fn test_connect_times_out() {
+    let mut now = Instant::now();
+    let mut connection = UtpConnection::connect(1, now);
+    let mut syns = 0;
+    while !connection.is_finished() {
+        syns += connection.poll_transmit(now).len();
+        now += Duration::from_millis(100);
+        connection.poll_timeout(now);
+    }
+    assert_eq!(connection.state(), State::TimedOut);
+    assert_eq!(syns, 3);
+}
+
+#[tokio::test]
+async fn test_stream_over_loopback() {
+    let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let (connected, accepted) = tokio::join!(client.connect(addr), server.accept());
+    let (mut client_stream, mut server_stream) = (connected.unwrap(), accepted.unwrap());
+    assert_eq!(server_stream.peer_addr(), client.local_addr().unwrap());
+
+    let sent = data(200_000);
+    let writer = {
+        let sent = sent.clone();
+        tokio::spawn(async move {
+            client_stream.write_all(&sent).await.unwrap();
+            client_stream.shutdown().await.unwrap();
+            client_stream
+        })
+    };
+    let mut received = Vec::new();
+    server_stream.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, sent);
+
+    // the other direction still works after the client closed its side
+    server_stream.write_all(b"bye").await.unwrap();
+    let mut client_stream = writer.await.unwrap();
+    let mut reply = [0u8; 3];
+    client_stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"bye");
+}
+
+/// Reports whether each connection runs over uTP.
+struct Reporter(mpsc::UnboundedSender<bool>);
+
+#[async_trait]
+impl PeerHandler for Reporter {
+    async fn run(&self, mut connection: Connection) {
+        let _ = self.0.send(connection.framed.get_ref().get_ref().is_utp());
+        connection.framed.send(Message::Interested).await.unwrap();
+        let _ = connection.framed.next().await;
+    }
+}
+
+fn manager(id: u8) -> (TcpConnectionManager, mpsc::UnboundedReceiver<bool>) {
+    let manager = TcpConnectionManager::new(
+        ConnectionConfig {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            connect_timeout: Duration::from_secs(1),
+            ..Default::default()
+        },
+        [id; 20],
+    );
+    let (tx, rx) = mpsc::unbounded_channel();
+    manager.register_torrent(INFO_HASH, Arc::new(Reporter(tx)));
+    (manager, rx)
+}
+
+#[tokio::test]
+async fn test_manager_prefers_utp() {
+    let (server, mut server_reports) = manager(2);
+    let addr = server.listen().await.unwrap();
+    // uTP listens on the same port as TCP
+    server.set_utp(Arc::new(UtpSocket::bind(addr).await.unwrap()));
+    let (client, mut client_reports) = manager(1);
+    client.set_utp(Arc::new(
+        UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap(),
+    ));
+
+    assert_eq!(
+        client.connect(INFO_HASH, addr).await.unwrap().peer_id,
+        [2; 20]
+    );
+    assert_eq!(client_reports.recv().await, Some(true));
+    assert_eq!(server_reports.recv().await, Some(true));
+}
+
+#[tokio::test]
+async fn test_manager_falls_back_to_tcp() {
+    let (server, mut server_reports) = manager(2);
+    let addr = server.listen().await.unwrap();
+    let (client, mut client_reports) = manager(1);
+    client.set_utp(Arc::new(
+        UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap(),
+    ));
+
+    assert_eq!(
+        client.connect(INFO_HASH, addr).await.unwrap().peer_id,
+        [2; 20]
+    );
+    assert_eq!(client_reports.recv().await, Some(false));
+    assert_eq!(server_reports.recv().await, Some(false));
+}
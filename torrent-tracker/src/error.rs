@@ -0,0 +1,35 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TrackerServerError {
+    #[error("IO Error: {0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("Invalid Config: {0}")]
+    InvalidConfig(&'static str),
+}
+
+pub type TrackerServerResult<T> = Result<T, TrackerServerError>;
+
+/// Reasons for refusing a request, sent back to the client as the failure
+/// reason.
+#[derive(Error, Debug)]
+pub enum AnnounceError {
+    #[error("invalid info_hash")]
+    InvalidInfoHash,
+
+    #[error("invalid peer_id")]
+    InvalidPeerId,
+
+    #[error("missing parameter: {0}")]
+    MissingParameter(&'static str),
+
+    #[error("invalid parameter: {0}")]
+    InvalidParameter(&'static str),
+
+    #[error("invalid passkey")]
+    InvalidPasskey,
+
+    #[error("torrent not registered with this tracker")]
+    NotWhitelisted,
+}
@@ -0,0 +1,222 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    time::timeout,
+};
+use torrent_parser::{
+    encode_scrape_response, encode_tracker_response,
+    model::{ScrapeResponse, TrackerResponse, TrackerSuccessResponse},
+};
+use tracing::debug;
+
+use crate::{
+    error::AnnounceError,
+    swarm::{to_response_peers, Announce, AnnounceEvent},
+    TrackerState,
+};
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) async fn serve(listener: TcpListener, state: Arc<TrackerState>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                debug!("tracker accept failed: {}", e);
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+        spawn(async move {
+            if let Err(e) = timeout(REQUEST_TIMEOUT, handle(stream, addr, &state)).await {
+                debug!("tracker request from {} timed out: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, addr: SocketAddr, state: &TrackerState) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            let _ = write_response(&mut stream, "413 Payload Too Large", b"").await;
+            return;
+        }
+    }
+
+    // only the request line matters, e.g. "GET /announce?... HTTP/1.1"
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split(' ');
+    let (Some("GET"), Some(target)) = (parts.next(), parts.next()) else {
+        let _ = write_response(&mut stream, "400 Bad Request", b"").await;
+        return;
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let (passkey, endpoint) = match segments.as_slice() {
+        [endpoint] => (None, *endpoint),
+        [passkey, endpoint] => (Some(*passkey), *endpoint),
+        _ => (None, ""),
+    };
+    let query = parse_query(query);
+
+    let body = match endpoint {
+        "announce" => {
+            let resp = announce(state, addr, passkey, &query)
+                .unwrap_or_else(|e| TrackerResponse::Failure(e.to_string()));
+            let compact = first(&query, "compact").map(|v| v.as_slice()) != Some(b"0");
+            encode_tracker_response(&resp, compact)
+        }
+        "scrape" => {
+            let resp = scrape(state, passkey, &query)
+                .unwrap_or_else(|e| ScrapeResponse::Failure(e.to_string()));
+            encode_scrape_response(&resp)
+        }
+        _ => {
+            let _ = write_response(&mut stream, "404 Not Found", b"").await;
+            return;
+        }
+    };
+    let _ = write_response(&mut stream, "200 OK", &body).await;
+}
+
+fn announce(
+    state: &TrackerState,
+    addr: SocketAddr,
+    passkey: Option<&str>,
+    query: &HashMap<String, Vec<Vec<u8>>>,
+) -> Result<TrackerResponse, AnnounceError> {
+    let info_hash =
+        first(query, "info_hash").ok_or(AnnounceError::MissingParameter("info_hash"))?;
+    state.check_access(info_hash, passkey)?;
+    let peer_id = first(query, "peer_id").ok_or(AnnounceError::MissingParameter("peer_id"))?;
+    if peer_id.len() != 20 {
+        return Err(AnnounceError::InvalidPeerId);
+    }
+    let port = integer(query, "port")?.ok_or(AnnounceError::MissingParameter("port"))?;
+    let port = u16::try_from(port).map_err(|_| AnnounceError::InvalidParameter("port"))?;
+
+    let event = match first(query, "event").map(|v| v.as_slice()) {
+        None | Some(b"") => AnnounceEvent::None,
+        Some(b"started") => AnnounceEvent::Started,
+        Some(b"stopped") => AnnounceEvent::Stopped,
+        Some(b"completed") => AnnounceEvent::Completed,
+        Some(_) => return Err(AnnounceError::InvalidParameter("event")),
+    };
+
+    let announce = Announce {
+        info_hash: info_hash.clone(),
+        peer_id: peer_id.clone(),
+        addr: SocketAddr::new(addr.ip().to_canonical(), port),
+        uploaded: integer(query, "uploaded")?.unwrap_or_default() as u64,
+        downloaded: integer(query, "downloaded")?.unwrap_or_default() as u64,
+        left: integer(query, "left")?.unwrap_or_default() as u64,
+        event,
+        num_want: state.num_want(integer(query, "numwant")?),
+    };
+    let result = state.swarms.announce(&announce);
+
+    Ok(TrackerResponse::Success(TrackerSuccessResponse {
+        interval: state.interval.as_secs() as i64,
+        min_interval: state.min_interval.map(|d| d.as_secs() as i64),
+        tracker_id: None,
+        complete: result.complete,
+        incomplete: result.incomplete,
+        peers: to_response_peers(&result.peers),
+    }))
+}
+
+fn scrape(
+    state: &TrackerState,
+    passkey: Option<&str>,
+    query: &HashMap<String, Vec<Vec<u8>>>,
+) -> Result<ScrapeResponse, AnnounceError> {
+    let info_hashes = query
+        .get("info_hash")
+        .ok_or(AnnounceError::MissingParameter("info_hash"))?;
+    for info_hash in info_hashes {
+        state.check_access(info_hash, passkey)?;
+    }
+    Ok(ScrapeResponse::Success(state.swarms.scrape(info_hashes)))
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) -> std::io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+fn first<'a>(query: &'a HashMap<String, Vec<Vec<u8>>>, key: &str) -> Option<&'a Vec<u8>> {
+    query.get(key).and_then(|values| values.first())
+}
+
+fn integer(
+    query: &HashMap<String, Vec<Vec<u8>>>,
+    key: &'static str,
+) -> Result<Option<i64>, AnnounceError> {
+    first(query, key)
+        .map(|value| {
+            std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .ok_or(AnnounceError::InvalidParameter(key))
+        })
+        .transpose()
+}
+
+// values are kept as raw bytes since info_hash and peer_id are binary
+fn parse_query(query: &str) -> HashMap<String, Vec<Vec<u8>>> {
+    let mut params: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = String::from_utf8_lossy(&percent_decode(key)).to_string();
+        params.entry(key).or_default().push(percent_decode(value));
+    }
+    params
+}
+
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use error::{AnnounceError, TrackerServerError, TrackerServerResult};
+use swarm::Swarms;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    spawn,
+    task::JoinHandle,
+    time::interval,
+};
+
+pub mod error;
+mod http;
+pub mod swarm;
+mod udp;
+
+pub struct TrackerServerConfig {
+    pub http_addr: Option<SocketAddr>,
+    pub udp_addr: Option<SocketAddr>,
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    /// Peers that have not announced for this long are dropped from the swarm.
+    pub peer_timeout: Duration,
+    /// Upper bound for the number of peers in one response.
+    pub max_peers: usize,
+    /// When set, only these info hashes are tracked.
+    pub whitelist: Option<HashSet<Vec<u8>>>,
+    /// When set, the tracker is private and every request must carry one of
+    /// these passkeys as the first path segment, e.g. `/<passkey>/announce`.
+    pub passkeys: Option<HashSet<String>>,
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> Self {
+        TrackerServerConfig {
+            http_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            udp_addr: Some(SocketAddr::from(([0, 0, 0, 0], 6969))),
+            interval: Duration::from_secs(1800),
+            min_interval: Some(Duration::from_secs(300)),
+            peer_timeout: Duration::from_secs(3600),
+            max_peers: 200,
+            whitelist: None,
+            passkeys: None,
+        }
+    }
+}
+
+/// State shared by the HTTP and UDP front ends.
+pub(crate) struct TrackerState {
+    pub swarms: Swarms,
+    pub interval: Duration,
+    pub min_interval: Option<Duration>,
+    pub max_peers: usize,
+    whitelist: RwLock<Option<HashSet<Vec<u8>>>>,
+    passkeys: RwLock<Option<HashSet<String>>>,
+}
+
+impl TrackerState {
+    pub fn check_access(
+        &self,
+        info_hash: &[u8],
+        passkey: Option<&str>,
+    ) -> Result<(), AnnounceError> {
+        if info_hash.len() != 20 {
+            return Err(AnnounceError::InvalidInfoHash);
+        }
+        if let Some(passkeys) = &*self.passkeys.read().unwrap() {
+            if !passkey.is_some_and(|passkey| passkeys.contains(passkey)) {
+                return Err(AnnounceError::InvalidPasskey);
+            }
+        }
+        if let Some(whitelist) = &*self.whitelist.read().unwrap() {
+            if !whitelist.contains(info_hash) {
+                return Err(AnnounceError::NotWhitelisted);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn num_want(&self, requested: Option<i64>) -> usize {
+        match requested {
+            Some(n) if n >= 0 => (n as usize).min(self.max_peers),
+            _ => self.max_peers.min(50),
+        }
+    }
+}
+
+/// A tracker serving HTTP and UDP announces and scrapes from memory.
+///
+/// All tasks are stopped when the server is dropped.
+pub struct TrackerServer {
+    state: Arc<TrackerState>,
+    http_addr: Option<SocketAddr>,
+    udp_addr: Option<SocketAddr>,
+    task_handles: Vec<JoinHandle<()>>,
+}
+
+impl TrackerServer {
+    pub async fn start(config: TrackerServerConfig) -> TrackerServerResult<Self> {
+        if config.peer_timeout.is_zero() {
+            return Err(TrackerServerError::InvalidConfig(
+                "peer_timeout must not be zero",
+            ));
+        }
+        let state = Arc::new(TrackerState {
+            swarms: Swarms::default(),
+            interval: config.interval,
+            min_interval: config.min_interval,
+            max_peers: config.max_peers,
+            whitelist: RwLock::new(config.whitelist),
+            passkeys: RwLock::new(config.passkeys),
+        });
+        let mut task_handles = Vec::new();
+
+        let http_addr = match config.http_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let addr = listener.local_addr()?;
+                task_handles.push(spawn(http::serve(listener, Arc::clone(&state))));
+                Some(addr)
+            }
+            None => None,
+        };
+
+        let udp_addr = match config.udp_addr {
+            Some(addr) => {
+                let socket = UdpSocket::bind(addr).await?;
+                let addr = socket.local_addr()?;
+                task_handles.push(spawn(udp::serve(socket, Arc::clone(&state))));
+                Some(addr)
+            }
+            None => None,
+        };
+
+        // sweep stale peers twice per timeout period
+        let expiry_state = Arc::clone(&state);
+        let peer_timeout = config.peer_timeout;
+        task_handles.push(spawn(async move {
+            let mut ticker = interval(peer_timeout / 2);
+            loop {
+                ticker.tick().await;
+                expiry_state.swarms.expire(peer_timeout);
+            }
+        }));
+
+        Ok(TrackerServer {
+            state,
+            http_addr,
+            udp_addr,
+            task_handles,
+        })
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_addr
+    }
+
+    pub fn swarms(&self) -> &Swarms {
+        &self.state.swarms
+    }
+
+    pub fn set_whitelist(&self, whitelist: Option<HashSet<Vec<u8>>>) {
+        *self.state.whitelist.write().unwrap() = whitelist;
+    }
+
+    pub fn set_passkeys(&self, passkeys: Option<HashSet<String>>) {
+        *self.state.passkeys.write().unwrap() = passkeys;
+    }
+}
+
+impl Drop for TrackerServer {
+    fn drop(&mut self) {
+        for handle in self.task_handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
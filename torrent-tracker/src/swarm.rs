@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+use torrent_parser::model::{ScrapeFile, TrackerResponsePeer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Stopped,
+    Completed,
+}
+
+/// An announce after it was decoded from either HTTP or UDP.
+pub struct Announce {
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub addr: SocketAddr,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub num_want: usize,
+}
+
+pub struct AnnounceResult {
+    pub complete: i64,
+    pub incomplete: i64,
+    pub peers: Vec<(Vec<u8>, SocketAddr)>,
+}
+
+struct SwarmPeer {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<Vec<u8>, SwarmPeer>,
+    downloaded: i64,
+}
+
+impl Swarm {
+    fn complete(&self) -> i64 {
+        self.peers.values().filter(|peer| peer.left == 0).count() as i64
+    }
+}
+
+/// In-memory state of every swarm the tracker knows about.
+#[derive(Default)]
+pub struct Swarms {
+    swarms: RwLock<HashMap<Vec<u8>, Swarm>>,
+}
+
+impl Swarms {
+    pub fn announce(&self, announce: &Announce) -> AnnounceResult {
+        let mut swarms = self.swarms.write().unwrap();
+        let swarm = swarms.entry(announce.info_hash.clone()).or_default();
+
+        if announce.event == AnnounceEvent::Stopped {
+            swarm.peers.remove(&announce.peer_id);
+        } else {
+            if announce.event == AnnounceEvent::Completed {
+                swarm.downloaded += 1;
+            }
+            swarm.peers.insert(
+                announce.peer_id.clone(),
+                SwarmPeer {
+                    addr: announce.addr,
+                    left: announce.left,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+
+        let is_seed = announce.left == 0;
+        let peers = if announce.event == AnnounceEvent::Stopped {
+            Vec::new()
+        } else {
+            swarm
+                .peers
+                .iter()
+                // seeds have no use for other seeds
+                .filter(|(id, peer)| **id != announce.peer_id && !(is_seed && peer.left == 0))
+                .map(|(id, peer)| (id.clone(), peer.addr))
+                .choose_multiple(&mut rand::thread_rng(), announce.num_want)
+        };
+
+        let complete = swarm.complete();
+        AnnounceResult {
+            complete,
+            incomplete: swarm.peers.len() as i64 - complete,
+            peers,
+        }
+    }
+
+    pub fn scrape(&self, info_hashes: &[Vec<u8>]) -> HashMap<Vec<u8>, ScrapeFile> {
+        let swarms = self.swarms.read().unwrap();
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                let file = match swarms.get(info_hash) {
+                    Some(swarm) => {
+                        let complete = swarm.complete();
+                        ScrapeFile {
+                            complete,
+                            downloaded: swarm.downloaded,
+                            incomplete: swarm.peers.len() as i64 - complete,
+                        }
+                    }
+                    None => ScrapeFile {
+                        complete: 0,
+                        downloaded: 0,
+                        incomplete: 0,
+                    },
+                };
+                (info_hash.clone(), file)
+            })
+            .collect()
+    }
+
+    /// Drops peers that have not announced within `timeout`, and swarms left
+    /// without peers.
+    pub fn expire(&self, timeout: Duration) {
+        let mut swarms = self.swarms.write().unwrap();
+        for swarm in swarms.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| peer.last_seen.elapsed() < timeout);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+    }
+
+    pub fn peer_count(&self, info_hash: &[u8]) -> usize {
+        self.swarms
+            .read()
+            .unwrap()
+            .get(info_hash)
+            .map(|swarm| swarm.peers.len())
+            .unwrap_or_default()
+    }
+}
+
+pub(crate) fn to_response_peers(peers: &[(Vec<u8>, SocketAddr)]) -> Vec<TrackerResponsePeer> {
+    peers
+        .iter()
+        .map(|(peer_id, addr)| TrackerResponsePeer {
+            peer_id: Some(peer_id.clone()),
+            ip: addr.ip().to_string(),
+            port: addr.port() as i64,
+        })
+        .collect()
+}
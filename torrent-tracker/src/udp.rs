@@ -0,0 +1,226 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use crate::{
+    error::AnnounceError,
+    swarm::{Announce, AnnounceEvent},
+    TrackerState,
+};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const ANNOUNCE_LEN: usize = 98;
+// BEP 41 option carrying the path and query of the announce URL
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_URL_DATA: u8 = 2;
+
+pub(crate) async fn serve(socket: UdpSocket, state: Arc<TrackerState>) {
+    // connection ids are derived from the client address, so no state has to
+    // be kept for clients that never announce
+    let secret: [u8; 20] = rand::random();
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("udp tracker receive failed: {}", e);
+                continue;
+            }
+        };
+        if let Some(resp) = handle(&buf[..len], addr, &secret, &state) {
+            if let Err(e) = socket.send_to(&resp, addr).await {
+                debug!("udp tracker send to {} failed: {}", addr, e);
+            }
+        }
+    }
+}
+
+fn handle(packet: &[u8], addr: SocketAddr, secret: &[u8], state: &TrackerState) -> Option<Vec<u8>> {
+    if packet.len() < 16 {
+        return None;
+    }
+    let connection_id = read_u64(packet, 0);
+    let action = read_u32(packet, 8);
+    let transaction_id = read_u32(packet, 12);
+
+    if action == ACTION_CONNECT {
+        if connection_id != PROTOCOL_ID {
+            return None;
+        }
+        let mut resp = Vec::with_capacity(16);
+        resp.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        resp.extend_from_slice(&transaction_id.to_be_bytes());
+        resp.extend_from_slice(&make_connection_id(secret, addr, current_minute()).to_be_bytes());
+        return Some(resp);
+    }
+
+    let minute = current_minute();
+    if connection_id != make_connection_id(secret, addr, minute)
+        && connection_id != make_connection_id(secret, addr, minute - 1)
+    {
+        return Some(error(transaction_id, "invalid connection id"));
+    }
+
+    let result = match action {
+        ACTION_ANNOUNCE => announce(packet, addr, state),
+        ACTION_SCRAPE => scrape(packet, state),
+        _ => return Some(error(transaction_id, "unknown action")),
+    };
+    match result {
+        Ok(mut resp) => {
+            let mut header = Vec::with_capacity(8 + resp.len());
+            header.extend_from_slice(&action.to_be_bytes());
+            header.extend_from_slice(&transaction_id.to_be_bytes());
+            header.append(&mut resp);
+            Some(header)
+        }
+        Err(e) => Some(error(transaction_id, &e.to_string())),
+    }
+}
+
+fn announce(
+    packet: &[u8],
+    addr: SocketAddr,
+    state: &TrackerState,
+) -> Result<Vec<u8>, AnnounceError> {
+    if packet.len() < ANNOUNCE_LEN {
+        return Err(AnnounceError::InvalidParameter("packet length"));
+    }
+    let info_hash = &packet[16..36];
+    state.check_access(info_hash, passkey(&packet[ANNOUNCE_LEN..]).as_deref())?;
+
+    let event = match read_u32(packet, 80) {
+        0 => AnnounceEvent::None,
+        1 => AnnounceEvent::Completed,
+        2 => AnnounceEvent::Started,
+        3 => AnnounceEvent::Stopped,
+        _ => return Err(AnnounceError::InvalidParameter("event")),
+    };
+    let ip = addr.ip().to_canonical();
+    let announce = Announce {
+        info_hash: info_hash.to_vec(),
+        peer_id: packet[36..56].to_vec(),
+        addr: SocketAddr::new(ip, u16::from_be_bytes([packet[96], packet[97]])),
+        downloaded: read_u64(packet, 56),
+        left: read_u64(packet, 64),
+        uploaded: read_u64(packet, 72),
+        event,
+        num_want: state.num_want(Some(read_u32(packet, 92) as i32 as i64)),
+    };
+    let result = state.swarms.announce(&announce);
+
+    let mut resp = Vec::new();
+    resp.extend_from_slice(&(state.interval.as_secs() as u32).to_be_bytes());
+    resp.extend_from_slice(&(result.incomplete as u32).to_be_bytes());
+    resp.extend_from_slice(&(result.complete as u32).to_be_bytes());
+    // the peer list format follows the address family of the request
+    for (_, peer) in result.peers {
+        match (ip, peer.ip()) {
+            (IpAddr::V4(_), IpAddr::V4(peer_ip)) => resp.extend_from_slice(&peer_ip.octets()),
+            (IpAddr::V6(_), IpAddr::V6(peer_ip)) => resp.extend_from_slice(&peer_ip.octets()),
+            _ => continue,
+        }
+        resp.extend_from_slice(&peer.port().to_be_bytes());
+    }
+    Ok(resp)
+}
+
+fn scrape(packet: &[u8], state: &TrackerState) -> Result<Vec<u8>, AnnounceError> {
+    // URL data options, if any, follow the last info hash
+    let body = &packet[16..];
+    let (count, passkey) = (1..=body.len() / 20)
+        .find_map(|count| passkey(&body[20 * count..]).map(|passkey| (count, Some(passkey))))
+        .unwrap_or((body.len() / 20, None));
+    let info_hashes = body[..20 * count]
+        .chunks_exact(20)
+        .map(<[u8]>::to_vec)
+        .collect::<Vec<_>>();
+    if info_hashes.is_empty() {
+        return Err(AnnounceError::MissingParameter("info_hash"));
+    }
+    for info_hash in &info_hashes {
+        state.check_access(info_hash, passkey.as_deref())?;
+    }
+
+    let files = state.swarms.scrape(&info_hashes);
+    let mut resp = Vec::with_capacity(12 * info_hashes.len());
+    for info_hash in &info_hashes {
+        let file = &files[info_hash];
+        resp.extend_from_slice(&(file.complete as u32).to_be_bytes());
+        resp.extend_from_slice(&(file.downloaded as u32).to_be_bytes());
+        resp.extend_from_slice(&(file.incomplete as u32).to_be_bytes());
+    }
+    Ok(resp)
+}
+
+/// Extracts the passkey from the BEP 41 URL data, which carries the path of
+/// the announce URL, e.g. `/<passkey>/announce`.
+fn passkey(options: &[u8]) -> Option<String> {
+    let mut url_data = Vec::new();
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            OPTION_URL_DATA => {
+                let len = *options.get(i + 1)? as usize;
+                url_data.extend_from_slice(options.get(i + 2..i + 2 + len)?);
+                i += 2 + len;
+            }
+            _ => break,
+        }
+    }
+    let url_data = String::from_utf8(url_data).ok()?;
+    let path = url_data.split('?').next()?;
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    match segments.as_slice() {
+        [passkey, "announce"] => Some(passkey.to_string()),
+        _ => None,
+    }
+}
+
+fn error(transaction_id: u32, msg: &str) -> Vec<u8> {
+    let mut resp = Vec::with_capacity(8 + msg.len());
+    resp.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+    resp.extend_from_slice(&transaction_id.to_be_bytes());
+    resp.extend_from_slice(msg.as_bytes());
+    resp
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+}
+
+fn make_connection_id(secret: &[u8], addr: SocketAddr, minute: u64) -> u64 {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(addr.ip().to_string().as_bytes());
+    hasher.update(minute.to_be_bytes());
+    u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
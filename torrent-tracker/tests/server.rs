@@ -0,0 +1,215 @@
+use std::{collections::HashSet, time::Duration};
+
+use torrent_core::tracker::{
+    client::{AnnounceEvent, AnnounceRequest, TrackerClient},
+    http::HttpTrackerClient,
+    udp::UdpTrackerClient,
+};
+use torrent_parser::model::{ScrapeResponse, TrackerResponse};
+use torrent_tracker::{TrackerServer, TrackerServerConfig};
+
+const INFO_HASH: [u8; 20] = [0xab; 20];
+
+fn config() -> TrackerServerConfig {
+    TrackerServerConfig {
+        http_addr: Some("127.0.0.1:0".parse().unwrap()),
+        udp_addr: Some("127.0.0.1:0".parse().unwrap()),
+        ..Default::default()
+    }
+}
+
+fn request(peer_id: &str, port: u32, left: u64) -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: INFO_HASH.to_vec(),
+        peer_id: peer_id.to_string(),
+        port,
+        uploaded: 0,
+        downloaded: 0,
+        left,
+        event: Some(AnnounceEvent::Started),
+        tracker_id: None,
+        num_want: None,
+    }
+}
+
+fn success(resp: TrackerResponse) -> torrent_parser::model::TrackerSuccessResponse {
+    match resp {
+        TrackerResponse::Success(resp) => resp,
+        TrackerResponse::Failure(msg) | TrackerResponse::Warning(msg) => panic!("{}", msg),
+    }
+}
+
+#[tokio::test]
+async fn test_http_and_udp_share_swarm() {
+    let server = TrackerServer::start(config()).await.unwrap();
+    let http_url = format!("http://{}/announce", server.http_addr().unwrap());
+    let udp_url = format!("udp://{}/announce", server.udp_addr().unwrap());
+    let http = HttpTrackerClient::default();
+    let udp = UdpTrackerClient::new(Duration::from_secs(1), 1);
+
+    let resp = success(
+        http.announce(&http_url, &request("-RT0001-aaaaaaaaaaaa", 6881, 0))
+            .await
+            .unwrap(),
+    );
+    assert!(resp.peers.is_empty());
+    assert_eq!(resp.complete, 1);
+
+    let resp = success(
+        udp.announce(&udp_url, &request("-RT0001-bbbbbbbbbbbb", 6882, 10))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(resp.peers.len(), 1);
+    assert_eq!(resp.peers[0].ip, "127.0.0.1");
+    assert_eq!(resp.peers[0].port, 6881);
+    assert_eq!(resp.complete, 1);
+    assert_eq!(resp.incomplete, 1);
+
+    let ScrapeResponse::Success(files) = udp.scrape(&udp_url, &[INFO_HASH.to_vec()]).await.unwrap()
+    else {
+        panic!("expected success");
+    };
+    assert_eq!(files[INFO_HASH.as_slice()].incomplete, 1);
+
+    let mut stopped = request("-RT0001-aaaaaaaaaaaa", 6881, 0);
+    stopped.event = Some(AnnounceEvent::Stopped);
+    http.announce(&http_url, &stopped).await.unwrap();
+    assert_eq!(server.swarms().peer_count(&INFO_HASH), 1);
+}
+
+#[tokio::test]
+async fn test_whitelist_and_passkeys() {
+    let server = TrackerServer::start(TrackerServerConfig {
+        whitelist: Some(HashSet::from([INFO_HASH.to_vec()])),
+        passkeys: Some(HashSet::from(["s3cret".to_string()])),
+        ..config()
+    })
+    .await
+    .unwrap();
+    let http = HttpTrackerClient::default();
+    let udp = UdpTrackerClient::new(Duration::from_secs(1), 1);
+    let http_addr = server.http_addr().unwrap();
+    let udp_addr = server.udp_addr().unwrap();
+
+    let resp = http
+        .announce(
+            &format!("http://{}/announce", http_addr),
+            &request("-RT0001-aaaaaaaaaaaa", 6881, 0),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(resp, TrackerResponse::Failure(_)));
+
+    let resp = http
+        .announce(
+            &format!("http://{}/s3cret/announce", http_addr),
+            &request("-RT0001-aaaaaaaaaaaa", 6881, 0),
+        )
+        .await
+        .unwrap();
+    success(resp);
+
+    let resp = udp
+        .announce(
+            &format!("udp://{}/s3cret/announce", udp_addr),
+            &request("-RT0001-bbbbbbbbbbbb", 6882, 5),
+        )
+        .await
+        .unwrap();
+    assert_eq!(success(resp).peers.len(), 1);
+
+    let resp = udp
+        .scrape(
+            &format!("udp://{}/s3cret/announce", udp_addr),
+            &[INFO_HASH.to_vec()],
+        )
+        .await
+        .unwrap();
+    let ScrapeResponse::Success(files) = resp else {
+        panic!("expected success");
+    };
+    assert_eq!(files[&INFO_HASH.to_vec()].complete, 1);
+    let resp = udp
+        .scrape(
+            &format!("udp://{}/announce", udp_addr),
+            &[INFO_HASH.to_vec()],
+        )
+        .await
+        .unwrap();
+    assert!(matches!(resp, ScrapeResponse::Failure(_)));
+
+    let mut unknown = request("-RT0001-cccccccccccc", 6883, 5);
+    unknown.info_hash = vec![1; 20];
+    let resp = http
+        .announce(&format!("http://{}/s3cret/announce", http_addr), &unknown)
+        .await
+        .unwrap();
+    assert!(matches!(resp, TrackerResponse::Failure(_)));
+}
+
+#[tokio::test]
+async fn test_ipv6_compact_peers() {
+    let Ok(server) = TrackerServer::start(TrackerServerConfig {
+        http_addr: Some("[::1]:0".parse().unwrap()),
+        udp_addr: Some("[::1]:0".parse().unwrap()),
+        ..Default::default()
+    })
+    .await
+    else {
+        // no IPv6 loopback in this environment
+        return;
+    };
+    let http_url = format!("http://{}/announce", server.http_addr().unwrap());
+    let udp_url = format!("udp://{}/announce", server.udp_addr().unwrap());
+    let http = HttpTrackerClient::default();
+    let udp = UdpTrackerClient::new(Duration::from_secs(1), 1);
+
+    success(
+        http.announce(&http_url, &request("-RT0001-aaaaaaaaaaaa", 6881, 0))
+            .await
+            .unwrap(),
+    );
+    let resp = success(
+        udp.announce(&udp_url, &request("-RT0001-bbbbbbbbbbbb", 6882, 5))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(resp.peers.len(), 1);
+    assert_eq!(resp.peers[0].ip, "::1");
+
+    let resp = success(
+        http.announce(&http_url, &request("-RT0001-cccccccccccc", 6883, 5))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(resp.peers.len(), 2);
+}
+
+#[tokio::test]
+async fn test_peer_expiry() {
+    assert!(TrackerServer::start(TrackerServerConfig {
+        peer_timeout: Duration::ZERO,
+        ..config()
+    })
+    .await
+    .is_err());
+
+    let server = TrackerServer::start(TrackerServerConfig {
+        peer_timeout: Duration::from_millis(200),
+        ..config()
+    })
+    .await
+    .unwrap();
+    let http_url = format!("http://{}/announce", server.http_addr().unwrap());
+    let http = HttpTrackerClient::default();
+
+    success(
+        http.announce(&http_url, &request("-RT0001-aaaaaaaaaaaa", 6881, 0))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(server.swarms().peer_count(&INFO_HASH), 1);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(server.swarms().peer_count(&INFO_HASH), 0);
+}